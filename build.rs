@@ -30,9 +30,18 @@ fn main() -> Result<()> {
         "cargo:rustc-env=TARGET={}",
         var("TARGET").context("Fetching environment variable TARGET")?
     );
+    #[cfg(feature = "grpc")]
+    compile_protos()?;
     Ok(())
 }
 
+/// Generate the gRPC messages and `Mesh` service from `proto/mesh.proto`.
+#[cfg(feature = "grpc")]
+fn compile_protos() -> Result<()> {
+    println!("cargo:rerun-if-changed=proto/mesh.proto");
+    tonic_build::compile_protos("proto/mesh.proto").context("Compiling proto/mesh.proto")
+}
+
 fn env_or_cmd(env: &str, cmd: &[&str]) -> Result<String> {
     // Try env first
     match var(env) {
@@ -0,0 +1,33 @@
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+
+//! 0x Mesh node, usable as a library through [`node::Node`] and
+//! [`node::NodeBuilder`]. The `mesh` binary runs it from the command line.
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod node;
+pub mod types;
+pub mod utils;
+
+mod prelude {
+    pub use anyhow::{Context, Result};
+    pub use async_trait::async_trait;
+    pub use futures::prelude::*;
+    pub use log::{debug, error, info, trace, warn};
+    pub use serde::{Deserialize, Serialize};
+    pub use smallvec::{smallvec, SmallVec};
+    pub use thiserror::Error;
+    pub use tokio::prelude::*;
+}
+
+#[cfg(test)]
+mod test {
+    pub mod prelude {
+        pub use pretty_assertions::assert_eq;
+    }
+}
+
+#[cfg(feature = "bench")]
+pub fn bench_main(c: &mut criterion::Criterion) {
+    server::bench::group(c);
+}
@@ -1,20 +1,9 @@
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
-mod node;
-mod utils;
-
-mod prelude {
-    pub use anyhow::{Context, Result};
-    pub use async_trait::async_trait;
-    pub use futures::prelude::*;
-    pub use log::{debug, error, info, trace, warn};
-    pub use serde::{Deserialize, Serialize};
-    pub use smallvec::{smallvec, SmallVec};
-    pub use thiserror::Error;
-    pub use tokio::prelude::*;
-}
-
-use prelude::*;
+use anyhow::{Context, Result};
+use log::info;
+use mesh::node;
+use std::{path::PathBuf, time::Duration};
 use structopt::StructOpt;
 
 // Gossipsub is very noisy, so limit it to warn by default even if
@@ -27,6 +16,18 @@ struct Options {
     #[structopt(short, long, parse(from_occurrences))]
     verbose: usize,
 
+    /// TOML file to read node options from. Options given on the command line
+    /// or in the environment take precedence
+    #[structopt(long, env = "MESH_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the default configuration file and exit
+    #[structopt(long)]
+    generate_config: bool,
+
+    #[structopt(flatten)]
+    node: node::NodeConfig,
+
     #[structopt(subcommand)]
     command: Option<Command>,
 }
@@ -35,10 +36,49 @@ struct Options {
 enum Command {
     /// Show version information
     Test,
+
+    /// Print the agent versions of connected peers
+    NetworkVersions {
+        /// Time to connect to and identify peers before printing
+        #[structopt(long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+        settle: Duration,
+    },
+
+    /// Print the node and peer state as JSON, for support requests
+    Diagnostics {
+        /// Time to connect to and identify peers before printing
+        #[structopt(long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+        settle: Duration,
+
+        /// File to write the JSON to instead of stdout
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
 }
 
-async fn async_main(_options: Options) -> Result<()> {
-    node::run().await
+async fn async_main(options: Options) -> Result<()> {
+    match options.command {
+        Some(Command::NetworkVersions { settle }) => {
+            let histogram = node::network_versions(options.node, settle).await?;
+            for (version, count) in histogram {
+                println!("{:>6} {}", count, version);
+            }
+            Ok(())
+        }
+        Some(Command::Diagnostics { settle, output }) => {
+            let diagnostics = node::diagnostics(options.node, settle).await?;
+            match output {
+                Some(path) => {
+                    let file = std::fs::File::create(&path)
+                        .with_context(|| format!("Creating {}", path.display()))?;
+                    serde_json::to_writer_pretty(file, &diagnostics)?;
+                }
+                None => println!("{}", serde_json::to_string_pretty(&diagnostics)?),
+            }
+            Ok(())
+        }
+        _ => node::run(options.node).await,
+    }
 }
 
 pub fn main() -> Result<()> {
@@ -60,7 +100,15 @@ pub fn main() -> Result<()> {
         build_date  = env!("BUILD_DATE"),
     );
     let matches = Options::clap().long_version(version.as_str()).get_matches();
-    let options = Options::from_clap(&matches);
+    let mut options = Options::from_clap(&matches);
+    if options.generate_config {
+        print!("{}", node::NodeConfig::default().to_toml()?);
+        return Ok(());
+    }
+    if let Some(path) = &options.config {
+        let file = node::NodeConfig::from_file(path)?;
+        options.node = options.node.merge(file)?;
+    }
 
     // Initialize log output (prepend verbosity to RUST_LOG)
     let rust_log = match options.verbose {
@@ -102,22 +150,110 @@ pub fn main() -> Result<()> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test::prelude::{assert_eq, *};
+    use float_eq::assert_float_eq;
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
 
-    pub mod prelude {
-        pub use float_eq::{assert_float_eq, assert_float_ne};
-        pub use pretty_assertions::{assert_eq, assert_ne};
-        pub use proptest::prelude::*;
-    }
+    /// Serializes tests that read or modify `MESH_` environment variables.
+    static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
     #[test]
     fn parse_args() {
+        let _env = ENV_LOCK.lock().unwrap();
         let cmd = "hello -vvv";
         let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
         assert_eq!(options, Options {
-            verbose: 3,
-            command: None,
+            verbose:         3,
+            config:          None,
+            generate_config: false,
+            node:            node::NodeConfig::default(),
+            command:         None,
+        });
+
+        let cmd = "hello --config mesh.toml --generate-config";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(options.config, Some("mesh.toml".into()));
+        assert!(options.generate_config);
+
+        let cmd = "hello network-versions --settle 10s";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(
+            options.command,
+            Some(Command::NetworkVersions {
+                settle: Duration::from_secs(10),
+            })
+        );
+
+        let cmd = "hello diagnostics --settle 5s --output diagnostics.json";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(
+            options.command,
+            Some(Command::Diagnostics {
+                settle: Duration::from_secs(5),
+                output: Some("diagnostics.json".into()),
+            })
+        );
+
+        let cmd = "hello --listen /ip4/127.0.0.1/tcp/60558 --listen /ip6/::1/tcp/60559/ws";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(options.node.listen, vec![
+            "/ip4/127.0.0.1/tcp/60558".parse().unwrap(),
+            "/ip6/::1/tcp/60559/ws".parse().unwrap(),
+        ]);
+        let cmd = "hello --ws-tls-cert cert.der --ws-tls-key key.der";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(options.node.wss_certificate, Some("cert.der".into()));
+        assert_eq!(options.node.wss_private_key, Some("key.der".into()));
+        let cmd = "hello --listen 127.0.0.1:60558";
+        assert!(Options::from_iter_safe(cmd.split(' ')).is_err());
+
+        let cmd = "hello --chain-id 1,4 --order-schema {\"x\":\"???\"}";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        let topics = options
+            .node
+            .gossip_topics()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(topics, vec![
+            "/0x-orders/version/3/chain/1/schema/eyJ4IjoiPz8_In0=",
+            "/0x-orders/version/3/chain/4/schema/eyJ4IjoiPz8_In0=",
+        ]);
+    }
+
+    #[test]
+    fn parse_env() {
+        let _env = ENV_LOCK.lock().unwrap();
+        std::env::set_var("MESH_MAX_DIAL_ADDRESSES", "7");
+        std::env::set_var("MESH_API_ADDRESS", "127.0.0.1:8080");
+        let ban_peers = [
+            "16Uiu2HAmGx8Z6gdq5T5AQE54GMtqDhDFhizywTy1o28NJbAMMumF",
+            "16Uiu2HAkwsDZk4LzXy2rnWANRsyBjB4fhjnsNeJmjgsBqxPGTL32",
+        ];
+        std::env::set_var("MESH_BAN_PEERS", ban_peers.join(","));
+
+        // Environment values are used when the flags are absent
+        let options = Options::from_iter_safe(&["hello"]).unwrap();
+        assert_eq!(options.node, node::NodeConfig {
+            max_dial_addresses: 7,
+            api_address: Some("127.0.0.1:8080".parse().unwrap()),
+            ban_peers: ban_peers.iter().map(|p| p.parse().unwrap()).collect(),
+            ..node::NodeConfig::default()
         });
+
+        // Flags take precedence
+        let cmd = "hello --max-dial-addresses 3";
+        let options = Options::from_iter_safe(cmd.split(' ')).unwrap();
+        assert_eq!(options.node.max_dial_addresses, 3);
+        assert_eq!(options.node.api_address, Some("127.0.0.1:8080".parse().unwrap()));
+
+        // Environment values are validated like flags
+        std::env::set_var("MESH_MAX_DIAL_ADDRESSES", "many");
+        assert!(Options::from_iter_safe(&["hello"]).is_err());
+
+        for name in &["MESH_MAX_DIAL_ADDRESSES", "MESH_API_ADDRESS", "MESH_BAN_PEERS"] {
+            std::env::remove_var(name);
+        }
     }
 
     #[test]
@@ -128,8 +264,3 @@ mod test {
         })
     }
 }
-
-#[cfg(feature = "bench")]
-pub fn bench_main(c: &mut criterion::Criterion) {
-    server::bench::group(c);
-}
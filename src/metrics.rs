@@ -0,0 +1,254 @@
+//! Prometheus metrics, enabled by the `metrics` feature.
+//!
+//! [`MetricsServer`] serves the metrics in the Prometheus text format on
+//! `GET /metrics`. Bandwidth is read from the transport on every scrape, the
+//! other metrics are set by the node from its [`NodeStats`] as it runs.
+//!
+//! Hyper runs on Tokio 0.2, so the server future needs to be run with
+//! `tokio_compat_02::FutureExt::compat`.
+//!
+//! ## To do
+//!
+//! * Gossipsub mesh size. The gossipsub version of libp2p 0.32 does not
+//!   expose its mesh.
+
+use crate::{prelude::*, utils::ShutdownToken};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use libp2p::bandwidth::BandwidthSinks;
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use std::{
+    convert::{Infallible, TryFrom},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+/// Node state exported as metrics. Counts are totals since the node started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NodeStats {
+    pub connected_peers:        usize,
+    /// Number of peers in the Kademlia routing table.
+    pub dht_routing_table_size: usize,
+    pub order_sync_sent:        u64,
+    pub order_sync_received:    u64,
+    pub order_sync_succeeded:   u64,
+    pub order_sync_failed:      u64,
+    /// Orders in the responses to outbound OrderSync requests.
+    pub orders_fetched:         u64,
+    /// Gossip messages received, including rejected ones.
+    pub gossip_received:        u64,
+}
+
+/// The registered node metrics, and the HTTP server exposing them.
+pub struct MetricsServer {
+    registry:               Registry,
+    bandwidth:              Arc<BandwidthSinks>,
+    connected_peers:        IntGauge,
+    dht_routing_table_size: IntGauge,
+    order_sync_sent:        IntCounter,
+    order_sync_received:    IntCounter,
+    order_sync_succeeded:   IntCounter,
+    order_sync_failed:      IntCounter,
+    orders_fetched:         IntCounter,
+    gossip_received:        IntCounter,
+    inbound_bytes:          IntCounter,
+    outbound_bytes:         IntCounter,
+}
+
+impl MetricsServer {
+    /// Register the node metrics, reading the traffic totals from
+    /// `bandwidth`.
+    pub fn new(bandwidth: Arc<BandwidthSinks>) -> Result<Self> {
+        let registry = Registry::new();
+        let gauge = |name: &str, help: &str| -> Result<IntGauge> {
+            let gauge = IntGauge::new(name, help)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+        let counter = |name: &str, help: &str| -> Result<IntCounter> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let connected_peers = gauge("mesh_connected_peers", "Number of connected peers")?;
+        let dht_routing_table_size = gauge(
+            "mesh_dht_routing_table_size",
+            "Number of peers in the Kademlia routing table",
+        )?;
+        let order_sync_sent = counter(
+            "mesh_order_sync_requests_sent_total",
+            "OrderSync requests sent to peers",
+        )?;
+        let order_sync_received = counter(
+            "mesh_order_sync_requests_received_total",
+            "OrderSync requests received from peers",
+        )?;
+        let order_sync_succeeded = counter(
+            "mesh_order_sync_requests_succeeded_total",
+            "OrderSync requests sent that were answered",
+        )?;
+        let order_sync_failed = counter(
+            "mesh_order_sync_requests_failed_total",
+            "OrderSync requests sent that failed without a response",
+        )?;
+        let orders_fetched = counter(
+            "mesh_orders_fetched_total",
+            "Orders received in OrderSync responses",
+        )?;
+        let gossip_received = counter(
+            "mesh_gossip_messages_received_total",
+            "Gossipsub messages received, including rejected ones",
+        )?;
+        let inbound_bytes = counter("mesh_inbound_bytes_total", "Bytes received from peers")?;
+        let outbound_bytes = counter("mesh_outbound_bytes_total", "Bytes sent to peers")?;
+        Ok(Self {
+            registry,
+            bandwidth,
+            connected_peers,
+            dht_routing_table_size,
+            order_sync_sent,
+            order_sync_received,
+            order_sync_succeeded,
+            order_sync_failed,
+            orders_fetched,
+            gossip_received,
+            inbound_bytes,
+            outbound_bytes,
+        })
+    }
+
+    /// Set the metrics to the current node state.
+    pub fn update(&self, stats: &NodeStats) {
+        self.connected_peers.set(gauge_value(stats.connected_peers));
+        self.dht_routing_table_size
+            .set(gauge_value(stats.dht_routing_table_size));
+        set_total(&self.order_sync_sent, stats.order_sync_sent);
+        set_total(&self.order_sync_received, stats.order_sync_received);
+        set_total(&self.order_sync_succeeded, stats.order_sync_succeeded);
+        set_total(&self.order_sync_failed, stats.order_sync_failed);
+        set_total(&self.orders_fetched, stats.orders_fetched);
+        set_total(&self.gossip_received, stats.gossip_received);
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        set_total(&self.inbound_bytes, self.bandwidth.total_inbound());
+        set_total(&self.outbound_bytes, self.bandwidth.total_outbound());
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Encoding metrics")?;
+        Ok(buffer)
+    }
+
+    /// Serve the metrics on the given address until an error occurs or
+    /// `shutdown` fires.
+    pub async fn serve(
+        self: Arc<Self>,
+        address: SocketAddr,
+        shutdown: ShutdownToken,
+    ) -> Result<()> {
+        let make_service = make_service_fn(move |_connection| {
+            let metrics = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(&request, &metrics);
+                    future::ready(Ok::<_, Infallible>(response))
+                }))
+            }
+        });
+        let server = Server::try_bind(&address)
+            .context("Binding metrics address")?
+            .serve(make_service);
+        info!("Metrics listening on http://{}/metrics", server.local_addr());
+        server
+            .with_graceful_shutdown(shutdown)
+            .await
+            .context("Running metrics server")
+    }
+}
+
+fn handle(request: &Request<Body>, metrics: &MetricsServer) -> Response<Body> {
+    let status = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            match metrics.encode() {
+                Ok(body) => {
+                    return Response::builder()
+                        .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+                        .body(Body::from(body))
+                        .unwrap();
+                }
+                Err(err) => {
+                    error!("Error serving metrics: {:?}", err);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }
+        }
+        _ => StatusCode::NOT_FOUND,
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Advance a counter to a total kept elsewhere. Counters never go down, so a
+/// lower total is ignored.
+fn set_total(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+fn gauge_value(value: usize) -> i64 {
+    i64::try_from(value).unwrap_or(i64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::{core::transport::MemoryTransport, TransportExt};
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (_transport, bandwidth) = MemoryTransport::default().with_bandwidth_logging();
+        let metrics = MetricsServer::new(bandwidth).unwrap();
+        metrics.update(&NodeStats {
+            connected_peers: 3,
+            order_sync_sent: 5,
+            ..NodeStats::default()
+        });
+        metrics.update(&NodeStats {
+            connected_peers: 2,
+            dht_routing_table_size: 4,
+            order_sync_sent: 7,
+            order_sync_succeeded: 6,
+            orders_fetched: 100,
+            gossip_received: 12,
+            ..NodeStats::default()
+        });
+
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(&request, &metrics);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        for line in &[
+            "mesh_connected_peers 2",
+            "mesh_dht_routing_table_size 4",
+            "mesh_order_sync_requests_sent_total 7",
+            "mesh_order_sync_requests_failed_total 0",
+            "mesh_order_sync_requests_succeeded_total 6",
+            "mesh_orders_fetched_total 100",
+            "mesh_gossip_messages_received_total 12",
+            "mesh_inbound_bytes_total 0",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(handle(&request, &metrics).status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,218 @@
+//! HTTP API for node operators.
+//!
+//! Endpoints:
+//!
+//! * `GET /validation`: order validation statistics as JSON.
+//! * `GET /orders`: all accepted orders as newline delimited JSON, streamed
+//!   from the orders in the store when the request arrived.
+//! * `GET /orders/{hash}`: an accepted order as JSON, 404 if it is unknown and
+//!   400 if the hash is malformed.
+//!
+//! Hyper runs on Tokio 0.2, so the server future needs to be run with
+//! `tokio_compat_02::FutureExt::compat`.
+
+use super::{
+    behaviour::order_sync::messages::OrderHash, order_store::OrderStore, validation::Validator,
+};
+use crate::{prelude::*, utils::ShutdownToken};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+/// Serve the API on the given address until an error occurs or `shutdown`
+/// fires.
+pub async fn serve(
+    address: SocketAddr,
+    validator: Validator,
+    order_store: Arc<RwLock<OrderStore>>,
+    shutdown: ShutdownToken,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_connection| {
+        let validator = validator.clone();
+        let order_store = order_store.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = handle(&request, &validator, &order_store);
+                future::ready(Ok::<_, Infallible>(response))
+            }))
+        }
+    });
+    let server = Server::try_bind(&address)
+        .context("Binding API address")?
+        .serve(make_service);
+    info!("API listening on http://{}", server.local_addr());
+    server
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Running API server")
+}
+
+fn handle(
+    request: &Request<Body>,
+    validator: &Validator,
+    order_store: &Arc<RwLock<OrderStore>>,
+) -> Response<Body> {
+    let path = request.uri().path();
+    match (request.method(), path) {
+        (&Method::GET, "/validation") => json_response(&validator.stats()),
+        (&Method::GET, "/orders") => orders_response(order_store),
+        (&Method::GET, _) if path.starts_with("/orders/") => {
+            match path["/orders/".len()..].parse::<OrderHash>() {
+                Ok(hash) => {
+                    let store = order_store.read().unwrap();
+                    store.get(&hash).map_or_else(
+                        || status_response(StatusCode::NOT_FOUND),
+                        |stored| json_response(&stored.order),
+                    )
+                }
+                Err(_) => status_response(StatusCode::BAD_REQUEST),
+            }
+        }
+        _ => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Stream the orders in the store, one JSON object per line, sorted by hash.
+///
+/// Only the hashes are collected upfront. Each order is serialized when its
+/// line is sent, so large stores are not buffered in memory.
+fn orders_response(order_store: &Arc<RwLock<OrderStore>>) -> Response<Body> {
+    let mut hashes = order_store
+        .read()
+        .unwrap()
+        .hashes()
+        .copied()
+        .collect::<Vec<_>>();
+    hashes.sort();
+    let order_store = order_store.clone();
+    let lines = stream::iter(hashes).filter_map(move |hash| {
+        let line = order_store.read().unwrap().get(&hash).map(|stored| {
+            let mut line = serde_json::to_vec(&stored.order)?;
+            line.push(b'\n');
+            Ok::<_, serde_json::Error>(line)
+        });
+        future::ready(line)
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::wrap_stream(lines))
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(json) => {
+            Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json))
+                .unwrap()
+        }
+        Err(err) => {
+            error!("Error serializing API response: {}", err);
+            status_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::{
+            behaviour::order_sync::messages::{Order, OrderFilter},
+            validation::ValidationStats,
+        },
+        test::prelude::assert_eq,
+    };
+
+    #[tokio::test]
+    async fn test_get_validation() {
+        let validator = Validator::new(OrderFilter::mainnet_v3());
+        let _ = validator.validate(&Order::default());
+        let request = Request::get("/validation").body(Body::empty()).unwrap();
+        let response = handle(&request, &validator, &Arc::default());
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: ValidationStats = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats.wrong_chain, 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_order() {
+        use libp2p::{identity::Keypair, PeerId};
+
+        let validator = Validator::new(OrderFilter::mainnet_v3());
+        let order_store = Arc::new(RwLock::new(OrderStore::new()));
+        let order = Order {
+            salt: 1.into(),
+            ..Order::default()
+        };
+        let source = PeerId::from(Keypair::generate_ed25519().public());
+        order_store.write().unwrap().insert_merge(order.clone(), source);
+        let get = |path: String| {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            handle(&request, &validator, &order_store)
+        };
+
+        let response = get(format!("/orders/{}", order.hash()));
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<Order>(&body).unwrap(), order);
+
+        let missing = OrderHash([0; 32]);
+        assert_eq!(get(format!("/orders/{}", missing)).status(), StatusCode::NOT_FOUND);
+        assert_eq!(get("/orders/0x1234".into()).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stream_orders() {
+        use libp2p::{identity::Keypair, PeerId};
+
+        let validator = Validator::new(OrderFilter::mainnet_v3());
+        let order_store = Arc::new(RwLock::new(OrderStore::new()));
+        let source = PeerId::from(Keypair::generate_ed25519().public());
+        let order = |salt: u32| {
+            Order {
+                salt: salt.into(),
+                ..Order::default()
+            }
+        };
+        let mut orders = (0..3).map(order).collect::<Vec<_>>();
+        for order in &orders {
+            order_store.write().unwrap().insert_merge(order.clone(), source.clone());
+        }
+        orders.sort_by_key(Order::hash);
+
+        let request = Request::get("/orders").body(Body::empty()).unwrap();
+        let response = handle(&request, &validator, &order_store);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+
+        // Orders added while streaming are not included.
+        order_store.write().unwrap().insert_merge(order(3), source);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let streamed = body
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<Order>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(streamed, orders);
+    }
+}
@@ -0,0 +1,262 @@
+//! Behaviour wrapper that limits the number of addresses dialed per peer.
+//!
+//! libp2p dials all known addresses of a peer at once. For peers advertising
+//! many addresses this is slow and wasteful, so only the most preferred few
+//! are tried per attempt. Subsequent attempts continue with the next
+//! addresses in the list.
+
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ListenerId},
+        ConnectedPoint,
+    },
+    multiaddr::Protocol,
+    swarm::{
+        IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        NetworkBehaviourEventProcess, PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashMap,
+    error,
+    ops::{Deref, DerefMut},
+    task::{Context, Poll},
+};
+
+pub struct AddressLimit<TBehaviour> {
+    inner:         TBehaviour,
+    max_addresses: usize,
+
+    /// Position in the address list to continue from on the next attempt.
+    offsets: HashMap<PeerId, usize>,
+}
+
+impl<TBehaviour> AddressLimit<TBehaviour> {
+    pub fn new(inner: TBehaviour, max_addresses: usize) -> Self {
+        Self {
+            inner,
+            max_addresses,
+            offsets: HashMap::new(),
+        }
+    }
+
+    /// Order addresses by preference and select the ones for this attempt.
+    fn select(&mut self, peer_id: &PeerId, mut addresses: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        addresses.sort_by_key(preference);
+        addresses.dedup();
+        if addresses.len() <= self.max_addresses {
+            return addresses;
+        }
+        let offset = self.offsets.entry(peer_id.clone()).or_default();
+        let start = *offset % addresses.len();
+        *offset = start + self.max_addresses;
+        addresses
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.max_addresses)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Address preference policy, lower is better: direct public addresses, then
+/// DNS, then WebSocket and finally private and loopback addresses.
+fn preference(address: &Multiaddr) -> u8 {
+    let mut rank = 0;
+    for protocol in address {
+        match protocol {
+            Protocol::Ip4(ip) if ip.is_private() || ip.is_loopback() || ip.is_link_local() => {
+                rank = rank.max(3);
+            }
+            Protocol::Ip6(ip) if ip.is_loopback() => rank = rank.max(3),
+            Protocol::Ws(_) | Protocol::Wss(_) => rank = rank.max(2),
+            Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => rank = rank.max(1),
+            _ => {}
+        }
+    }
+    rank
+}
+
+impl<TBehaviour> Deref for AddressLimit<TBehaviour> {
+    type Target = TBehaviour;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<TBehaviour> DerefMut for AddressLimit<TBehaviour> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<TBehaviour> NetworkBehaviour for AddressLimit<TBehaviour>
+where
+    TBehaviour: NetworkBehaviour,
+{
+    type OutEvent = TBehaviour::OutEvent;
+    type ProtocolsHandler = TBehaviour::ProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let addresses = self.inner.addresses_of_peer(peer_id);
+        self.select(peer_id, addresses)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id);
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id);
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        // Start from the most preferred addresses again next time.
+        self.offsets.remove(peer_id);
+        self.inner
+            .inject_connection_established(peer_id, connection, endpoint);
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_closed(peer_id, connection, endpoint);
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_address_change(peer_id, connection, old, new);
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_event(peer_id, connection, event);
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id);
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(addr);
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(addr);
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr);
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn error::Error + 'static)) {
+        self.inner.inject_listener_error(id, err);
+    }
+
+    fn inject_listener_closed(
+        &mut self,
+        id: ListenerId,
+        reason: Result<(), &std::io::Error>,
+    ) {
+        self.inner.inject_listener_closed(id, reason);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent,
+            Self::OutEvent,
+        >,
+    > {
+        self.inner.poll(cx, params)
+    }
+}
+
+impl<TEvent, TBehaviour> NetworkBehaviourEventProcess<TEvent> for AddressLimit<TBehaviour>
+where
+    TBehaviour: NetworkBehaviourEventProcess<TEvent>,
+{
+    fn inject_event(&mut self, event: TEvent) {
+        self.inner.inject_event(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::behaviour::order_sync::OrderSync, test::prelude::assert_eq};
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn test_limit_addresses() {
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let mut behaviour = AddressLimit::new(OrderSync::new(None), 3);
+        let addresses = (0..10)
+            .map(|i| format!("/ip4/1.1.1.{}/tcp/60558", i).parse().unwrap())
+            .collect::<Vec<Multiaddr>>();
+        for address in &addresses {
+            behaviour.add_address(&peer_id, address.clone());
+        }
+
+        // Each attempt dials the next three addresses.
+        assert_eq!(behaviour.addresses_of_peer(&peer_id), addresses[0..3].to_vec());
+        assert_eq!(behaviour.addresses_of_peer(&peer_id), addresses[3..6].to_vec());
+    }
+
+    #[test]
+    fn test_address_preference() {
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let mut behaviour = AddressLimit::new(OrderSync::new(None), 2);
+        for address in &[
+            "/ip4/127.0.0.1/tcp/60558",
+            "/dns4/bootstrap-0.mesh.0x.org/tcp/60558",
+            "/ip4/1.1.1.1/tcp/60559/ws",
+            "/ip4/1.1.1.1/tcp/60558",
+        ] {
+            behaviour.add_address(&peer_id, address.parse().unwrap());
+        }
+        let expected: Vec<Multiaddr> = vec![
+            "/ip4/1.1.1.1/tcp/60558".parse().unwrap(),
+            "/dns4/bootstrap-0.mesh.0x.org/tcp/60558".parse().unwrap(),
+        ];
+        assert_eq!(behaviour.addresses_of_peer(&peer_id), expected);
+    }
+}
@@ -0,0 +1,108 @@
+//! Behaviour that counts the connections to each peer.
+//!
+//! A peer can be connected more than once, for example when both sides dial
+//! at the same time or when it is reachable on several addresses. The events
+//! carry the number of open connections, so peer bookkeeping can treat the
+//! first and last connection as the peer connecting and disconnecting.
+
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint},
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, IntoProtocolsHandler, NetworkBehaviour,
+        NetworkBehaviourAction, PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    task::{Context, Poll},
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ConnectionEvent {
+    /// A connection was established, `connections` includes it.
+    Established { peer_id: PeerId, connections: usize },
+
+    /// A connection was closed, `connections` are still open.
+    Closed { peer_id: PeerId, connections: usize },
+}
+
+#[derive(Default)]
+pub struct ConnectionTracker {
+    connections: HashMap<PeerId, usize>,
+    events:      VecDeque<ConnectionEvent>,
+}
+
+impl NetworkBehaviour for ConnectionTracker {
+    type OutEvent = ConnectionEvent;
+    type ProtocolsHandler = DummyProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        vec![]
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        _endpoint: &ConnectedPoint,
+    ) {
+        let count = self.connections.entry(peer_id.clone()).or_default();
+        *count += 1;
+        self.events.push_back(ConnectionEvent::Established {
+            peer_id:     peer_id.clone(),
+            connections: *count,
+        });
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        _connection: &ConnectionId,
+        _endpoint: &ConnectedPoint,
+    ) {
+        if let Some(count) = self.connections.get_mut(peer_id) {
+            *count -= 1;
+            let connections = *count;
+            if connections == 0 {
+                self.connections.remove(peer_id);
+            }
+            self.events.push_back(ConnectionEvent::Closed {
+                peer_id: peer_id.clone(),
+                connections,
+            });
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection: ConnectionId,
+        event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent,
+            Self::OutEvent,
+        >,
+    > {
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
+}
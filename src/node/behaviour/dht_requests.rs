@@ -0,0 +1,307 @@
+//! Kademlia wrapper that counts the DHT requests we serve.
+//!
+//! `libp2p-kad` 0.26 answers inbound requests internally and has no event for
+//! them (later versions add `KademliaEvent::InboundRequest`). The requests do
+//! pass through `inject_event` as handler events, so they are counted there
+//! before being handed to Kademlia.
+//!
+//! The answers to our own lookups pass through the same way. The addresses
+//! other peers report in them are dropped by Kademlia once the lookup ends, so
+//! for lookups registered with [`CountRequests::watch_lookup`] the addresses
+//! of the target are kept until the result is handled.
+
+use crate::prelude::*;
+use libp2p::{
+    core::{
+        connection::{ConnectionId, ListenerId},
+        ConnectedPoint,
+    },
+    kad::{handler::KademliaHandlerEvent, record::store::MemoryStore, Kademlia, QueryId},
+    swarm::{
+        IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+        ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashMap,
+    error,
+    ops::{Deref, DerefMut},
+    task::{Context, Poll},
+};
+
+/// Number of inbound DHT requests served, by type.
+#[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhtRequestStats {
+    pub find_node:     u64,
+    pub get_providers: u64,
+    pub add_provider:  u64,
+    pub get_record:    u64,
+    pub put_record:    u64,
+}
+
+impl DhtRequestStats {
+    fn record(&mut self, peer_id: &PeerId, event: &KademliaHandlerEvent<QueryId>) {
+        let (counter, kind) = match event {
+            KademliaHandlerEvent::FindNodeReq { .. } => (&mut self.find_node, "FIND_NODE"),
+            KademliaHandlerEvent::GetProvidersReq { .. } => {
+                (&mut self.get_providers, "GET_PROVIDERS")
+            }
+            KademliaHandlerEvent::AddProvider { .. } => (&mut self.add_provider, "ADD_PROVIDER"),
+            KademliaHandlerEvent::GetRecord { .. } => (&mut self.get_record, "GET_VALUE"),
+            KademliaHandlerEvent::PutRecord { .. } => (&mut self.put_record, "PUT_VALUE"),
+            _ => return,
+        };
+        debug!("Serving DHT {} request from {}", kind, peer_id);
+        *counter += 1;
+    }
+}
+
+pub struct CountRequests {
+    inner:   Kademlia<MemoryStore>,
+    stats:   DhtRequestStats,
+    /// Target of each watched lookup and the addresses reported for it.
+    lookups: HashMap<QueryId, (PeerId, Vec<Multiaddr>)>,
+}
+
+impl CountRequests {
+    pub fn new(inner: Kademlia<MemoryStore>) -> Self {
+        Self {
+            inner,
+            stats: DhtRequestStats::default(),
+            lookups: HashMap::new(),
+        }
+    }
+
+    pub fn stats(&self) -> DhtRequestStats {
+        self.stats.clone()
+    }
+
+    /// Keep the addresses peers report for `target` in answers to the lookup
+    /// `query_id`, until they are taken with [`CountRequests::take_reported`].
+    pub fn watch_lookup(&mut self, query_id: QueryId, target: PeerId) {
+        let _ = self.lookups.insert(query_id, (target, Vec::new()));
+    }
+
+    /// Stop watching a lookup and return the addresses reported for its target.
+    pub fn take_reported(&mut self, query_id: QueryId) -> Vec<Multiaddr> {
+        self.lookups
+            .remove(&query_id)
+            .map(|(_, addresses)| addresses)
+            .unwrap_or_default()
+    }
+
+    fn record_reported(&mut self, event: &KademliaHandlerEvent<QueryId>) {
+        if let KademliaHandlerEvent::FindNodeRes {
+            closer_peers,
+            user_data,
+        } = event
+        {
+            if let Some((target, reported)) = self.lookups.get_mut(user_data) {
+                let addresses = closer_peers
+                    .iter()
+                    .filter(|peer| peer.node_id == *target)
+                    .flat_map(|peer| &peer.multiaddrs);
+                for address in addresses {
+                    if !reported.contains(address) {
+                        reported.push(address.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Deref for CountRequests {
+    type Target = Kademlia<MemoryStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for CountRequests {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl NetworkBehaviour for CountRequests {
+    type OutEvent = <Kademlia<MemoryStore> as NetworkBehaviour>::OutEvent;
+    type ProtocolsHandler = <Kademlia<MemoryStore> as NetworkBehaviour>::ProtocolsHandler;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_connected(peer_id);
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.inner.inject_disconnected(peer_id);
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_established(peer_id, connection, endpoint);
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_connection_closed(peer_id, connection, endpoint);
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        connection: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inner
+            .inject_address_change(peer_id, connection, old, new);
+    }
+
+    fn inject_event(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.stats.record(&peer_id, &event);
+        self.record_reported(&event);
+        self.inner.inject_event(peer_id, connection, event);
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        addr: &Multiaddr,
+        error: &dyn error::Error,
+    ) {
+        self.inner.inject_addr_reach_failure(peer_id, addr, error);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.inner.inject_dial_failure(peer_id);
+    }
+
+    fn inject_new_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_listen_addr(addr);
+    }
+
+    fn inject_expired_listen_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_expired_listen_addr(addr);
+    }
+
+    fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
+        self.inner.inject_new_external_addr(addr);
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn error::Error + 'static)) {
+        self.inner.inject_listener_error(id, err);
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        self.inner.inject_listener_closed(id, reason);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent,
+            Self::OutEvent,
+        >,
+    > {
+        self.inner.poll(cx, params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::{
+        identity::Keypair,
+        kad::{protocol::KadPeer, record::Key, KadConnectionType},
+    };
+
+    #[test]
+    fn test_count_inbound_requests() {
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let kademlia = Kademlia::new(peer_id.clone(), MemoryStore::new(peer_id));
+        let mut behaviour = CountRequests::new(kademlia);
+
+        let provider = PeerId::from(Keypair::generate_ed25519().public());
+        behaviour.inject_event(
+            provider.clone(),
+            ConnectionId::new(0),
+            KademliaHandlerEvent::AddProvider {
+                key:      Key::new(&"orders"),
+                provider: KadPeer {
+                    node_id:       provider,
+                    multiaddrs:    vec!["/ip4/127.0.0.1/tcp/60558".parse().unwrap()],
+                    connection_ty: KadConnectionType::Connected,
+                },
+            },
+        );
+        assert_eq!(behaviour.stats(), DhtRequestStats {
+            add_provider: 1,
+            ..DhtRequestStats::default()
+        });
+    }
+
+    #[test]
+    fn test_reported_addresses() {
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let kademlia = Kademlia::new(peer_id.clone(), MemoryStore::new(peer_id));
+        let mut behaviour = CountRequests::new(kademlia);
+        let target = PeerId::from(Keypair::generate_ed25519().public());
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let query_id = behaviour.get_closest_peers(target.clone());
+        behaviour.watch_lookup(query_id, target.clone());
+
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/60558".parse().unwrap();
+        let kad_peer = |node_id: &PeerId, address: &Multiaddr| {
+            KadPeer {
+                node_id:       node_id.clone(),
+                multiaddrs:    vec![address.clone()],
+                connection_ty: KadConnectionType::Connected,
+            }
+        };
+        for _ in 0..2 {
+            behaviour.inject_event(
+                other.clone(),
+                ConnectionId::new(0),
+                KademliaHandlerEvent::FindNodeRes {
+                    closer_peers: vec![
+                        kad_peer(&target, &address),
+                        kad_peer(&other, &"/ip4/10.0.0.2/tcp/60558".parse().unwrap()),
+                    ],
+                    user_data:    query_id,
+                },
+            );
+        }
+        assert_eq!(behaviour.take_reported(query_id), vec![address]);
+        assert_eq!(behaviour.take_reported(query_id), vec![]);
+    }
+}
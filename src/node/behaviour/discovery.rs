@@ -8,31 +8,52 @@
 //!
 //! ## To do
 //!
-//! * Accessor methods for known peers.
-//! * Periodically initiate random Kademlia searches.
-//! * Persistently store known peers for quick restart.
 //! * Distinguish between local and global addresses, only feed global ones to
 //!   DHT.
 //! * Observed addresses protocol: https://docs.rs/libp2p-observed-address/0.12.0/libp2p_observed_address/
 
+use super::{
+    super::state::PeerState,
+    connections::{ConnectionEvent, ConnectionTracker},
+    dht_requests::{CountRequests, DhtRequestStats},
+};
 use crate::prelude::*;
 use humantime::Duration as HumanDuration;
+use once_cell::sync::Lazy;
 use libp2p::{
     identify::{Identify, IdentifyEvent, IdentifyInfo},
     identity::Keypair,
     kad::{
-        record::store::MemoryStore, Kademlia, KademliaBucketInserts, KademliaConfig, KademliaEvent,
-        QueryId, QueryResult,
+        record::store::MemoryStore, BootstrapError, BootstrapResult, Kademlia,
+        GetClosestPeersError, KademliaBucketInserts, KademliaConfig, KademliaEvent, QueryId,
+        QueryResult,
     },
     mdns::{Mdns, MdnsEvent},
     ping::{Ping, PingConfig, PingEvent},
-    swarm::NetworkBehaviourEventProcess,
+    swarm::{
+        toggle::Toggle, DialPeerCondition, NetworkBehaviourAction, NetworkBehaviourEventProcess,
+        PollParameters,
+    },
     Multiaddr, NetworkBehaviour, PeerId,
 };
-use std::{collections::HashMap, time::Duration};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fmt, fs,
+    future::Future,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
 use std::sync::{Arc, RwLock};
+use tokio::time::{sleep, sleep_until, Sleep};
 
-const DHT_PROTOCOL_ID: &[u8] = b"/0x-mesh-dht/version/1";
+/// Kademlia protocol name of 0x Mesh.
+pub const DEFAULT_DHT_PROTOCOL: &str = "/0x-mesh-dht/version/1";
 const BOOTNODES: &[(&str, &str)] = &[
     (
         "16Uiu2HAmGx8Z6gdq5T5AQE54GMtqDhDFhizywTy1o28NJbAMMumF",
@@ -48,11 +69,141 @@ const BOOTNODES: &[(&str, &str)] = &[
     ),
 ];
 
+/// [`BOOTNODES`] parsed on first use. Every entry is checked to parse by
+/// `test_default_bootnodes`.
+static DEFAULT_BOOTNODES: Lazy<Vec<(PeerId, Multiaddr)>> = Lazy::new(|| {
+    BOOTNODES
+        .iter()
+        .map(|(peer_id, multiaddr)| {
+            let peer_id = peer_id.parse().expect("Invalid bootnode peer id");
+            let multiaddr = multiaddr.parse().expect("Invalid bootnode address");
+            (peer_id, multiaddr)
+        })
+        .collect()
+});
+
+/// The default 0x Mesh mainnet bootnodes.
+pub fn default_bootnodes() -> Vec<(PeerId, Multiaddr)> {
+    DEFAULT_BOOTNODES.clone()
+}
+
+/// A bootnode given on the command line as `<PeerId>@<Multiaddr>`, or `none`
+/// to not bootstrap at all.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Bootnode {
+    None,
+    Peer(PeerId, Multiaddr),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum InvalidBootnode {
+    #[error("Expected <PeerId>@<Multiaddr> or none, got {0}")]
+    Format(String),
+    #[error("Invalid bootnode peer id {0}")]
+    PeerId(String),
+    #[error("Invalid bootnode address {0}")]
+    Multiaddr(String),
+}
+
+impl FromStr for Bootnode {
+    type Err = InvalidBootnode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Self::None);
+        }
+        let (peer_id, multiaddr) = s
+            .split_once('@')
+            .ok_or_else(|| InvalidBootnode::Format(s.to_owned()))?;
+        let peer_id = peer_id
+            .parse()
+            .map_err(|_| InvalidBootnode::PeerId(peer_id.to_owned()))?;
+        let multiaddr = multiaddr
+            .parse()
+            .map_err(|_| InvalidBootnode::Multiaddr(multiaddr.to_owned()))?;
+        Ok(Self::Peer(peer_id, multiaddr))
+    }
+}
+
+impl fmt::Display for Bootnode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Peer(peer_id, multiaddr) => write!(f, "{}@{}", peer_id, multiaddr),
+        }
+    }
+}
+
+/// Bootnodes are written as on the command line in configuration files.
+impl Serialize for Bootnode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bootnode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The bootnodes to use given those on the command line: the defaults if
+/// there are none, and no bootnodes at all if any is `none`.
+pub fn resolve_bootnodes(bootnodes: &[Bootnode]) -> Vec<(PeerId, Multiaddr)> {
+    if bootnodes.is_empty() {
+        return default_bootnodes();
+    }
+    bootnodes
+        .iter()
+        .map(|bootnode| {
+            match bootnode {
+                Bootnode::None => None,
+                Bootnode::Peer(peer_id, multiaddr) => Some((peer_id.clone(), multiaddr.clone())),
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default()
+}
+
+/// Discovery settings exposed in the node configuration.
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct DiscoveryConfig {
-    peer_key:          Keypair,
-    dht_protocol_name: String,
-    bootnodes:         Vec<(PeerId, Multiaddr)>,
+    /// Number of peers queried in parallel by iterative Kademlia lookups
+    /// (alpha). Higher values find peers faster at the cost of bandwidth.
+    pub kad_parallelism: NonZeroUsize,
+
+    /// Time between lookups of a random peer id, which keep finding new
+    /// peers after the bootstrap. Zero disables them.
+    pub kad_refresh_interval: Duration,
+
+    /// Discover peers on the LAN through mDNS.
+    pub mdns: bool,
+
+    /// Kademlia protocol name. Nodes with different names form separate
+    /// DHTs, which keeps test networks apart from the 0x mesh.
+    pub dht_protocol_name: String,
+
+    /// Time the info of a disconnected peer is kept. A peer reconnecting
+    /// within it keeps its Identify and ping history.
+    pub eviction_grace: Duration,
 }
+
+impl Default for DiscoveryConfig {
+    /// Alpha of 3 is the Kademlia paper's value and the default in both
+    /// rust-libp2p and the go-libp2p DHT used by Go 0x Mesh, so lookups
+    /// behave the same as on other mesh nodes.
+    fn default() -> Self {
+        Self {
+            kad_parallelism:      NonZeroUsize::new(3).unwrap(),
+            kad_refresh_interval: DEFAULT_KAD_REFRESH_INTERVAL,
+            mdns:                 true,
+            dht_protocol_name:    DEFAULT_DHT_PROTOCOL.into(),
+            eviction_grace:       DEFAULT_EVICTION_GRACE,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PeerInfo {
     pub peer_id: PeerId,
@@ -62,6 +213,19 @@ pub struct PeerInfo {
 
     /// Latest ping time with this node.
     pub ping: Option<Duration>,
+
+    /// Number of open connections to this node. A peer can be connected more
+    /// than once, but has a single entry.
+    pub connections: usize,
+
+    /// When the last connection to this node closed, `None` while connected.
+    /// The info is forgotten once the node stays disconnected for the
+    /// eviction grace period.
+    pub disconnected: Option<Instant>,
+
+    /// How useful the node has been, raised by fast pings and lowered by
+    /// failed OrderSync requests. Starts at zero and is not persisted.
+    pub reputation: f64,
 }
 
 impl PeerInfo {
@@ -70,88 +234,858 @@ impl PeerInfo {
             peer_id,
             identify: None,
             ping: None,
+            connections: 0,
+            disconnected: None,
+            reputation: 0.0,
         }
     }
+
+    /// Update the ping time, rewarding round trips faster than
+    /// [`REFERENCE_RTT`] and penalizing slower ones.
+    pub fn record_ping(&mut self, rtt: Duration) {
+        self.ping = Some(rtt);
+        let score = (1.0 - rtt.as_secs_f64() / REFERENCE_RTT.as_secs_f64()).max(-1.0);
+        self.adjust_reputation(PING_WEIGHT * score);
+    }
+
+    /// Penalize a failed request.
+    pub fn record_failure(&mut self) {
+        self.adjust_reputation(-FAILURE_PENALTY);
+    }
+
+    fn adjust_reputation(&mut self, delta: f64) {
+        self.reputation = (self.reputation + delta).clamp(-MAX_REPUTATION, MAX_REPUTATION);
+    }
+
+    /// Agent version reported by Identify.
+    pub fn agent_version(&self) -> Option<&str> {
+        self.identify
+            .as_ref()
+            .map(|identify| identify.agent_version.as_str())
+    }
+
+    /// Protocols reported by Identify, empty if not identified yet.
+    pub fn protocols(&self) -> &[String] {
+        self.identify
+            .as_ref()
+            .map_or(&[], |identify| identify.protocols.as_slice())
+    }
+
+    /// Listen addresses reported by Identify, empty if not identified yet.
+    pub fn listen_addrs(&self) -> &[Multiaddr] {
+        self.identify
+            .as_ref()
+            .map_or(&[], |identify| identify.listen_addrs.as_slice())
+    }
+
+    pub const fn last_ping(&self) -> Option<Duration> {
+        self.ping
+    }
+
+    /// Whether the peer reported the DHT protocol `dht_protocol`. Kademlia
+    /// does not exchange records with peers running a different DHT protocol
+    /// name.
+    pub fn supports_dht(&self, dht_protocol: &str) -> bool {
+        self.protocols().iter().any(|p| p == dht_protocol)
+    }
+}
+
+/// Up to `n` of `peers` that reported `protocol`, best reputation first.
+pub fn best_peers_for_protocol(
+    peers: &HashMap<PeerId, PeerInfo>,
+    protocol: &str,
+    n: usize,
+) -> Vec<PeerId> {
+    let mut supporting = peers
+        .values()
+        .filter(|info| info.protocols().iter().any(|p| p == protocol))
+        .collect::<Vec<_>>();
+    supporting.sort_by(|a, b| b.reputation.partial_cmp(&a.reputation).unwrap_or(Ordering::Equal));
+    supporting
+        .into_iter()
+        .take(n)
+        .map(|info| info.peer_id.clone())
+        .collect()
+}
+
+/// Identify agent version, the crate version followed by the commit, such as
+/// `mesh-rs/0.1.0+1d4b7270`.
+fn agent_version() -> String {
+    format!(
+        "mesh-rs/{}+{}",
+        env!("CARGO_PKG_VERSION"),
+        &env!("COMMIT_SHA")[..8]
+    )
+}
+
+/// Delay before retrying a failed bootstrap, doubled after every consecutive
+/// failure.
+const BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Upper bound on the bootstrap retry delay.
+const MAX_BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Default time between random peer lookups.
+pub const DEFAULT_KAD_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Ping round trip time that neither raises nor lowers the reputation.
+const REFERENCE_RTT: Duration = Duration::from_millis(500);
+
+/// Reputation gained by an instant ping. With the 15 second ping interval, a
+/// fast peer reaches the maximum reputation in about half an hour.
+const PING_WEIGHT: f64 = 0.1;
+
+/// Reputation lost by a failed request, worth ten fast pings.
+const FAILURE_PENALTY: f64 = 1.0;
+
+/// Bound on the reputation in either direction, so a peer recovers from a
+/// bad spell and a long history does not make a peer unbeatable.
+const MAX_REPUTATION: f64 = 10.0;
+
+/// Default time the info of a disconnected peer is kept.
+pub const DEFAULT_EVICTION_GRACE: Duration = Duration::from_secs(5 * 60);
+
+fn kademlia_config(config: &DiscoveryConfig) -> KademliaConfig {
+    let mut kad_config = KademliaConfig::default();
+    kad_config.set_protocol_name(config.dht_protocol_name.clone().into_bytes());
+    kad_config.set_kbucket_inserts(KademliaBucketInserts::OnConnected);
+    kad_config.set_parallelism(config.kad_parallelism);
+    kad_config
+}
+
+/// Banned peers, and the only peers allowed if there is an allowlist.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PeerAccess {
+    pub banned:  BTreeSet<PeerId>,
+    pub allowed: Option<BTreeSet<PeerId>>,
+}
+
+impl PeerAccess {
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        !self.banned.contains(peer_id)
+            && self
+                .allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(peer_id))
+    }
+}
+
+/// Serializable form of [`PeerAccess`].
+#[derive(Serialize, Deserialize)]
+struct PeerAccessState {
+    banned:  Vec<String>,
+    allowed: Option<Vec<String>>,
+}
+
+/// Persists the peer database across restarts.
+pub trait PeerStore: Send + Sync {
+    fn save(&self, peers: &HashMap<PeerId, PeerInfo>) -> Result<()>;
+
+    /// Load the saved peers, empty if nothing was saved yet.
+    fn load(&self) -> Result<HashMap<PeerId, PeerInfo>>;
+
+    fn save_access(&self, access: &PeerAccess) -> Result<()>;
+
+    /// Load the saved bans and allowlist, empty if nothing was saved yet.
+    fn load_access(&self) -> Result<PeerAccess>;
+}
+
+/// Stores peers in a JSON file, as a list of [`PeerState`]s. The bans and
+/// allowlist are stored next to it, in a file with the `.access.json`
+/// extension.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FilePeerStore {
+    path: PathBuf,
+}
+
+impl FilePeerStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn access_path(&self) -> PathBuf {
+        self.path.with_extension("access.json")
+    }
+}
+
+/// Replace the file at `path`, writing a copy first so a crash does not lose
+/// the previous contents.
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    fs::write(&temporary, contents).with_context(|| format!("Writing {}", path.display()))?;
+    fs::rename(&temporary, path).with_context(|| format!("Replacing {}", path.display()))?;
+    Ok(())
+}
+
+fn parse_peer_ids(peer_ids: &[String]) -> Result<BTreeSet<PeerId>> {
+    peer_ids
+        .iter()
+        .map(|peer_id| {
+            peer_id
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid peer id {}", peer_id))
+        })
+        .collect()
+}
+
+impl PeerStore for FilePeerStore {
+    fn save(&self, peers: &HashMap<PeerId, PeerInfo>) -> Result<()> {
+        let mut peers = peers
+            .values()
+            .cloned()
+            .map(PeerState::from)
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        let json = serde_json::to_vec_pretty(&peers)?;
+        replace_file(&self.path, &json).context("Saving peer store")
+    }
+
+    fn load(&self) -> Result<HashMap<PeerId, PeerInfo>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let json = fs::read(&self.path).context("Reading peer store")?;
+        let peers: Vec<PeerState> = serde_json::from_slice(&json).context("Parsing peer store")?;
+        peers
+            .iter()
+            .map(|peer| PeerInfo::try_from(peer).map(|info| (info.peer_id.clone(), info)))
+            .collect()
+    }
+
+    fn save_access(&self, access: &PeerAccess) -> Result<()> {
+        let to_strings = |peers: &BTreeSet<PeerId>| peers.iter().map(PeerId::to_base58).collect();
+        let json = serde_json::to_vec_pretty(&PeerAccessState {
+            banned:  to_strings(&access.banned),
+            allowed: access.allowed.as_ref().map(to_strings),
+        })?;
+        replace_file(&self.access_path(), &json).context("Saving peer access lists")
+    }
+
+    fn load_access(&self) -> Result<PeerAccess> {
+        let path = self.access_path();
+        if !path.exists() {
+            return Ok(PeerAccess::default());
+        }
+        let json = fs::read(&path).context("Reading peer access lists")?;
+        let state: PeerAccessState =
+            serde_json::from_slice(&json).context("Parsing peer access lists")?;
+        Ok(PeerAccess {
+            banned:  parse_peer_ids(&state.banned)?,
+            allowed: state.allowed.as_deref().map(parse_peer_ids).transpose()?,
+        })
+    }
+}
+
+/// Progress of a bootstrap query after one of its steps finished.
+///
+/// A bootstrap first looks up the local peer id and then refreshes buckets
+/// one at a time, reporting a result after every step. A timed out step with
+/// further buckets remaining does not end the query.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BootstrapOutcome {
+    Complete,
+    InProgress,
+    Failed,
+}
+
+impl From<&BootstrapResult> for BootstrapOutcome {
+    fn from(result: &BootstrapResult) -> Self {
+        match result {
+            Ok(ok) if ok.num_remaining == 0 => Self::Complete,
+            Ok(_) => Self::InProgress,
+            Err(BootstrapError::Timeout {
+                num_remaining: Some(remaining),
+                ..
+            }) if *remaining > 0 => Self::InProgress,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+/// A lookup started by [`Discovery::resolve`].
+struct ResolveQuery {
+    peer_id: PeerId,
+    /// Addresses of the peer known when the lookup started.
+    known:   Vec<Multiaddr>,
+}
+
+impl ResolveQuery {
+    /// The event for the lookup having found `addresses`.
+    fn resolved(&self, addresses: Vec<Multiaddr>) -> DiscoveryEvent {
+        let mut new = Vec::new();
+        for address in addresses {
+            if !self.known.contains(&address) && !new.contains(&address) {
+                new.push(address);
+            }
+        }
+        if new.is_empty() {
+            DiscoveryEvent::Unresolved(self.peer_id.clone())
+        } else {
+            DiscoveryEvent::Resolved(self.peer_id.clone(), new)
+        }
+    }
+}
+
+/// Events emitted by the [`Discovery`] behaviour.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DiscoveryEvent {
+    /// New addresses were found for a peer passed to [`Discovery::resolve`].
+    Resolved(PeerId, Vec<Multiaddr>),
+
+    /// The lookup for a peer passed to [`Discovery::resolve`] finished
+    /// without finding new addresses.
+    Unresolved(PeerId),
+
+    /// The first connection to a peer was established.
+    PeerConnected(PeerId),
+
+    /// The last connection to a peer was closed.
+    PeerDisconnected(PeerId),
 }
 
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "DiscoveryEvent", poll_method = "poll")]
 pub struct Discovery {
-    mdns:     Mdns,
-    kademlia: Kademlia<MemoryStore>,
+    mdns:     Toggle<Mdns>,
+    kademlia: CountRequests,
     identify: Identify,
     ping:     Ping,
 
+    connections: ConnectionTracker,
+
     #[behaviour(ignore)]
     bootstrap_query_id: Option<QueryId>,
 
-    /// Information that we know about all nodes.
+    /// Bootstrap was skipped for lack of known peers and will be started once
+    /// a peer is added to the routing table.
+    #[behaviour(ignore)]
+    bootstrap_deferred: bool,
+
+    /// Consecutive failed bootstraps, reset once a bootstrap completes.
+    #[behaviour(ignore)]
+    bootstrap_failures: u32,
+
+    /// Restarts the bootstrap after a failure.
+    #[behaviour(ignore)]
+    bootstrap_retry: Option<Pin<Box<Sleep>>>,
+
+    #[behaviour(ignore)]
+    refresh_interval: Duration,
+
+    /// Starts the next random peer lookup, once started.
+    #[behaviour(ignore)]
+    refresh_timer: Option<Pin<Box<Sleep>>>,
+
+    /// Number of random peer lookups started.
+    #[behaviour(ignore)]
+    refreshes: u64,
+
+    /// Random peer lookups still running.
+    #[behaviour(ignore)]
+    refresh_queries: HashSet<QueryId>,
+
+    /// Kademlia protocol name, peers without it are not in our DHT.
+    #[behaviour(ignore)]
+    dht_protocol: String,
+
+    #[behaviour(ignore)]
+    eviction_grace: Duration,
+
+    /// Forgets the next disconnected peer once its grace period is over.
+    #[behaviour(ignore)]
+    eviction_timer: Option<Pin<Box<Sleep>>>,
+
+    /// Lookups for peers whose addresses need to be resolved.
+    #[behaviour(ignore)]
+    resolve_queries: HashMap<QueryId, ResolveQuery>,
+
+    /// Dial peers discovered on the LAN.
+    #[behaviour(ignore)]
+    mdns_autodial: bool,
+
+    /// Peers to dial on the next poll.
+    #[behaviour(ignore)]
+    dials: VecDeque<PeerId>,
+
+    #[behaviour(ignore)]
+    events: VecDeque<DiscoveryEvent>,
+
+    /// Information that we know about all nodes. This is the same map that is
+    /// shared through [`Discovery::known_peers`].
     #[behaviour(ignore)]
     peer_info: Arc<RwLock<HashMap<PeerId, PeerInfo>>>,
+
+    /// Where [`Discovery::save_peers`] persists the peer database.
+    #[behaviour(ignore)]
+    peer_store: Option<Box<dyn PeerStore>>,
+
+    /// Bans and allowlist. These are the same lists that are shared through
+    /// [`Discovery::peer_access`].
+    #[behaviour(ignore)]
+    access: Arc<RwLock<PeerAccess>>,
+
+    /// Connected peers outside the allowlist, to be disconnected by the
+    /// swarm owner.
+    #[behaviour(ignore)]
+    refused: Vec<PeerId>,
 }
 
 impl Discovery {
-    pub(crate) async fn new(peer_key: Keypair) -> Result<Self> {
+    /// Create the behaviour, starting with the peers saved in `peer_store`.
+    /// Their DHT addresses are added to the routing table alongside the
+    /// bootnodes.
+    pub(crate) async fn new(
+        peer_key: Keypair,
+        bootnodes: &[(PeerId, Multiaddr)],
+        config: DiscoveryConfig,
+        peer_store: Option<Box<dyn PeerStore>>,
+    ) -> Result<Self> {
         let public_key = peer_key.public();
         let peer_id = PeerId::from_public_key(public_key.clone());
 
-        // Mdns LAN node discovery
-        let mdns = Mdns::new()
-            .await
-            .context("Creating mDNS node discovery behaviour")?;
+        // Mdns LAN node discovery, blocked or noisy on most cloud networks
+        let mdns = if config.mdns {
+            let mdns = Mdns::new()
+                .await
+                .context("Creating mDNS node discovery behaviour")?;
+            Some(mdns)
+        } else {
+            info!("mDNS peer discovery disabled");
+            None
+        };
 
         // Kademlia for 0x Mesh peer discovery
-        let mut kad_config = KademliaConfig::default();
-        kad_config.set_protocol_name(DHT_PROTOCOL_ID);
-        kad_config.set_kbucket_inserts(KademliaBucketInserts::OnConnected);
+        let kad_config = kademlia_config(&config);
         debug!("Kademlia config: {:?}", &kad_config);
         let kad_store = MemoryStore::new(peer_id.clone());
-        let mut kademlia = Kademlia::with_config(peer_id.clone(), kad_store, kad_config);
+        let mut kademlia = CountRequests::new(Kademlia::with_config(
+            peer_id.clone(),
+            kad_store,
+            kad_config,
+        ));
 
         // Add bootnodes
-        for (peer_id, multiaddr) in BOOTNODES {
-            let peer_id = peer_id.parse().context("Parsing bootnode peer id")?;
-            let multiaddr = multiaddr.parse().context("Parsing bootnode address")?;
-            kademlia.add_address(&peer_id, multiaddr);
+        for (peer_id, multiaddr) in bootnodes {
+            kademlia.add_address(peer_id, multiaddr.clone());
+        }
+
+        // Saved bans and allowlist
+        let access = match peer_store.as_ref().map(|store| store.load_access()) {
+            Some(Ok(access)) => access,
+            Some(Err(err)) => {
+                warn!("Could not load saved peer access lists: {:?}", err);
+                PeerAccess::default()
+            }
+            None => PeerAccess::default(),
+        };
+
+        // Add saved peers, a store that fails to load is not fatal
+        let mut peers = match peer_store.as_ref().map(|store| store.load()) {
+            Some(Ok(peers)) => peers,
+            Some(Err(err)) => {
+                warn!("Could not load saved peers: {:?}", err);
+                HashMap::new()
+            }
+            None => HashMap::new(),
+        };
+        peers.retain(|peer_id, _| access.is_allowed(peer_id));
+        if !peers.is_empty() {
+            info!("Loaded {} saved peers", peers.len());
+        }
+        let dht_protocol = config.dht_protocol_name;
+        for peer in peers.values().filter(|peer| peer.supports_dht(&dht_protocol)) {
+            for address in peer.listen_addrs() {
+                kademlia.add_address(&peer.peer_id, address.clone());
+            }
         }
 
-        // Identify protocol
-        let identify = Identify::new("/ipfs/0.1.0".into(), "mesh-rs".into(), public_key);
+        // Identify protocol, without push until the libp2p upgrade
+        let identify = Identify::new("/ipfs/0.1.0".into(), agent_version(), public_key);
 
         // Ping protocol
         let ping = Ping::new(PingConfig::new());
 
         Ok(Self {
-            mdns,
+            mdns: mdns.into(),
             kademlia,
             identify,
             ping,
+            connections: ConnectionTracker::default(),
             bootstrap_query_id: None,
-            peer_info: Arc::new(RwLock::new(HashMap::new())),
+            bootstrap_deferred: false,
+            bootstrap_failures: 0,
+            bootstrap_retry: None,
+            refresh_interval: config.kad_refresh_interval,
+            eviction_grace: config.eviction_grace,
+            eviction_timer: None,
+            refresh_timer: None,
+            refreshes: 0,
+            refresh_queries: HashSet::new(),
+            dht_protocol,
+            resolve_queries: HashMap::new(),
+            mdns_autodial: false,
+            dials: VecDeque::new(),
+            events: VecDeque::new(),
+            peer_info: Arc::new(RwLock::new(peers)),
+            peer_store,
+            access: Arc::new(RwLock::new(access)),
+            refused: Vec::new(),
         })
     }
 
     pub fn start(&mut self) -> Result<()> {
         // Join DHT
-        let query_id = self.kademlia.bootstrap().context("Joining Kademlia DHT")?;
-        info!("Kademlia Bootstrap started {:?}", &query_id);
-        self.bootstrap_query_id = Some(query_id);
+        self.bootstrap();
 
-        // Start searching for random nodes
-        // TODO: self.swarm.search_random_peer();
+        // Keep searching for random nodes
+        if self.refresh_interval > Duration::from_secs(0) {
+            self.refresh_timer = Some(Box::pin(sleep(self.refresh_interval)));
+        }
 
         Ok(())
     }
 
+    /// Look up the peers closest to a random peer id, which adds the peers
+    /// found on the way to the routing table.
+    fn search_random_peer(&mut self) {
+        let query_id = self.kademlia.get_closest_peers(PeerId::random());
+        debug!("Searching random peer with query {:?}", &query_id);
+        let _ = self.refresh_queries.insert(query_id);
+        self.refreshes += 1;
+    }
+
+    /// Start a Kademlia bootstrap, or defer it until a peer is known.
+    fn bootstrap(&mut self) {
+        if let Ok(query_id) = self.kademlia.bootstrap() {
+            info!("Kademlia Bootstrap started {:?}", &query_id);
+            self.bootstrap_query_id = Some(query_id);
+            self.bootstrap_deferred = false;
+            self.bootstrap_retry = None;
+        } else {
+            info!("No known peers to bootstrap from, waiting for a peer to connect");
+            self.bootstrap_deferred = true;
+        }
+    }
+
+    /// Look for more peers with a Kademlia bootstrap, unless one is running.
+    pub fn rediscover(&mut self) {
+        if self.bootstrap_query_id.is_none() {
+            self.bootstrap();
+        }
+    }
+
+    /// Update the bootstrap state with the result of a bootstrap step.
+    fn bootstrap_progress(&mut self, result: &BootstrapResult) {
+        match BootstrapOutcome::from(result) {
+            BootstrapOutcome::Complete => {
+                info!("Bootstrap complete");
+                self.bootstrap_query_id = None;
+                self.bootstrap_failures = 0;
+            }
+            BootstrapOutcome::InProgress => {}
+            BootstrapOutcome::Failed => {
+                self.bootstrap_query_id = None;
+                self.bootstrap_failures += 1;
+                let delay = BOOTSTRAP_RETRY_DELAY
+                    .checked_mul(1 << (self.bootstrap_failures - 1).min(16))
+                    .map_or(MAX_BOOTSTRAP_RETRY_DELAY, |delay| {
+                        delay.min(MAX_BOOTSTRAP_RETRY_DELAY)
+                    });
+                warn!(
+                    "Bootstrap failed {} time(s), retrying in {}",
+                    self.bootstrap_failures,
+                    HumanDuration::from(delay)
+                );
+                self.bootstrap_retry = Some(Box::pin(sleep(delay)));
+            }
+        }
+    }
+
+    /// Shared handle to the live peer map, updated from the Identify, Ping and
+    /// connection events.
+    ///
+    /// The event loop takes the write lock synchronously, so holders of the
+    /// handle must not keep a guard across an `.await`, or the node stalls
+    /// when it runs on the same task.
     pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
         self.peer_info.clone()
     }
+
+    /// Kademlia protocol name of our DHT.
+    pub fn dht_protocol(&self) -> &str {
+        &self.dht_protocol
+    }
+
+    /// Agent versions of connected peers that have identified themselves.
+    pub fn peer_versions(&self) -> HashMap<PeerId, String> {
+        self.peer_info
+            .read()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.connections > 0)
+            .filter_map(|peer| {
+                let version = peer.agent_version()?.trim();
+                if version.is_empty() {
+                    None
+                } else {
+                    Some((peer.peer_id.clone(), version.to_string()))
+                }
+            })
+            .collect()
+    }
+
+    /// Number of connected peers per agent version.
+    pub fn version_histogram(&self) -> BTreeMap<String, usize> {
+        let mut histogram = BTreeMap::new();
+        for (_, version) in self.peer_versions() {
+            *histogram.entry(version).or_default() += 1;
+        }
+        histogram
+    }
+
+    /// Save the peer database to the peer store, if there is one.
+    pub fn save_peers(&self) -> Result<()> {
+        if let Some(store) = &self.peer_store {
+            let peers = self.peer_info.read().unwrap().clone();
+            store.save(&peers)?;
+            store.save_access(&self.access.read().unwrap())?;
+            info!("Saved {} peers", peers.len());
+        }
+        Ok(())
+    }
+
+    /// Forget a peer and keep it out of the routing table. The swarm still
+    /// has to ban it to refuse its connections.
+    pub fn ban_peer(&mut self, peer_id: PeerId) {
+        self.forget_peer(&peer_id);
+        let _ = self.access.write().unwrap().banned.insert(peer_id);
+    }
+
+    pub fn unban_peer(&mut self, peer_id: &PeerId) {
+        let _ = self.access.write().unwrap().banned.remove(peer_id);
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.access.read().unwrap().banned.contains(peer_id)
+    }
+
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.access.read().unwrap().banned.iter().cloned().collect()
+    }
+
+    /// Only accept `peers`. Other peers are forgotten, and connected ones are
+    /// reported by [`Discovery::take_refused`] to be disconnected.
+    pub fn allow_only(&mut self, peers: Vec<PeerId>) {
+        let allowed = peers.into_iter().collect::<BTreeSet<_>>();
+        let refused = self
+            .peer_info
+            .read()
+            .unwrap()
+            .values()
+            .filter(|info| !allowed.contains(&info.peer_id))
+            .map(|info| (info.peer_id.clone(), info.connections > 0))
+            .collect::<Vec<_>>();
+        for (peer_id, connected) in refused {
+            self.forget_peer(&peer_id);
+            if connected {
+                self.refused.push(peer_id);
+            }
+        }
+        self.access.write().unwrap().allowed = Some(allowed);
+    }
+
+    /// Lift the allowlist set by [`Discovery::allow_only`].
+    pub fn allow_all(&mut self) {
+        self.access.write().unwrap().allowed = None;
+    }
+
+    /// Whether the peer is neither banned nor outside the allowlist.
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.access.read().unwrap().is_allowed(peer_id)
+    }
+
+    /// Bans and allowlist, shared with the other behaviours so they refuse
+    /// the same peers.
+    pub fn peer_access(&self) -> Arc<RwLock<PeerAccess>> {
+        self.access.clone()
+    }
+
+    /// Connected peers outside the allowlist since the last call.
+    pub fn take_refused(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.refused)
+    }
+
+    fn forget_peer(&mut self, peer_id: &PeerId) {
+        let _ = self.peer_info.write().unwrap().remove(peer_id);
+        let _ = self.kademlia.remove_peer(peer_id);
+        self.dials.retain(|dial| dial != peer_id);
+    }
+
+    /// Number of DHT requests served to other peers.
+    pub fn dht_request_stats(&self) -> DhtRequestStats {
+        self.kademlia.stats()
+    }
+
+    /// Dial peers discovered through mDNS and add them to the DHT, instead of
+    /// only recording them. Banned peers are not dialed.
+    pub fn set_mdns_autodial(&mut self, enabled: bool) {
+        self.mdns_autodial = enabled;
+    }
+
+    fn mdns_discovered(&mut self, peer_id: PeerId, address: Multiaddr) {
+        debug!("Discovered {} at {} on LAN.", peer_id, address);
+        if self.mdns_autodial && self.is_allowed(&peer_id) {
+            self.kademlia.add_address(&peer_id, address);
+            if !self.dials.contains(&peer_id) {
+                self.dials.push_back(peer_id);
+            }
+        }
+    }
+
+    /// Update the shared info of a peer, holding the write lock only for the
+    /// duration of the update. Refused peers are not recorded.
+    fn update_peer_info(&self, peer_id: PeerId, update: impl FnOnce(&mut PeerInfo)) {
+        if !self.is_allowed(&peer_id) {
+            return;
+        }
+        // Readers hold the lock briefly, see `known_peers`.
+        let mut lock = self.peer_info.write().unwrap();
+        let entry = lock
+            .entry(peer_id.clone())
+            .or_insert_with(|| PeerInfo::new(peer_id));
+        update(entry);
+    }
+
+    /// Lower the reputation of a peer after a failed request.
+    pub fn record_failure(&self, peer_id: PeerId) {
+        self.update_peer_info(peer_id, PeerInfo::record_failure);
+    }
+
+    /// Up to `n` peers that reported `protocol`, best reputation first.
+    #[cfg(test)]
+    pub fn best_peers_for_protocol(&self, protocol: &str, n: usize) -> Vec<PeerId> {
+        best_peers_for_protocol(&self.peer_info.read().unwrap(), protocol, n)
+    }
+
+    /// Forget peers disconnected for longer than the eviction grace period,
+    /// returning when the next of the remaining disconnected peers expires.
+    fn evict_disconnected_at(&self, now: Instant) -> Option<Instant> {
+        let grace = self.eviction_grace;
+        let mut lock = self.peer_info.write().unwrap();
+        lock.retain(|peer_id, info| {
+            match info.disconnected {
+                Some(since) if now.saturating_duration_since(since) >= grace => {
+                    debug!("Forgetting disconnected peer {}", peer_id);
+                    false
+                }
+                _ => true,
+            }
+        });
+        lock.values()
+            .filter_map(|info| info.disconnected)
+            .min()
+            .map(|since| since + grace)
+    }
+
+    /// Peers and their addresses in the Kademlia routing table.
+    pub fn routing_table(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let mut entries = Vec::new();
+        for bucket in self.kademlia.kbuckets() {
+            for entry in bucket.iter() {
+                let peer_id = entry.node.key.preimage().clone();
+                entries.push((peer_id, entry.node.value.iter().cloned().collect()));
+            }
+        }
+        entries
+    }
+
+    /// Number of peers in each non-empty Kademlia bucket, closest bucket
+    /// first.
+    pub fn bucket_sizes(&mut self) -> Vec<usize> {
+        self.kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .collect()
+    }
+
+    /// Add a peer address to the Kademlia routing table.
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.kademlia.add_address(peer_id, address);
+    }
+
+    /// Look up fresh addresses for a peer in the DHT.
+    ///
+    /// Emits [`DiscoveryEvent::Resolved`] with the addresses that were not
+    /// known before, as soon as the peer is added to the routing table with
+    /// them or when the lookup finishes with peers having reported them.
+    /// Emits [`DiscoveryEvent::Unresolved`] if the lookup finishes without
+    /// new addresses.
+    pub fn resolve(&mut self, peer_id: &PeerId) {
+        if self.resolve_queries.values().any(|q| q.peer_id == *peer_id) {
+            return;
+        }
+        let known = self.kademlia_addresses(peer_id);
+        let query_id = self.kademlia.get_closest_peers(peer_id.clone());
+        debug!("Resolving addresses of {} with query {:?}", peer_id, &query_id);
+        self.kademlia.watch_lookup(query_id, peer_id.clone());
+        self.resolve_queries.insert(query_id, ResolveQuery {
+            peer_id: peer_id.clone(),
+            known,
+        });
+    }
+
+    /// Addresses of a peer in the routing table or met by running lookups.
+    fn kademlia_addresses(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        libp2p::swarm::NetworkBehaviour::addresses_of_peer(&mut self.kademlia, peer_id)
+    }
+
+    fn poll<TEv>(
+        &mut self,
+        cx: &mut task::Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, DiscoveryEvent>> {
+        if let Some(retry) = &mut self.bootstrap_retry {
+            if retry.as_mut().poll(cx).is_ready() {
+                self.bootstrap_retry = None;
+                self.bootstrap();
+            }
+        }
+        while let Some(timer) = &mut self.eviction_timer {
+            if timer.as_mut().poll(cx).is_pending() {
+                break;
+            }
+            self.eviction_timer = self
+                .evict_disconnected_at(Instant::now())
+                .map(|next| Box::pin(sleep_until(next.into())));
+        }
+        if let Some(timer) = &mut self.refresh_timer {
+            if timer.as_mut().poll(cx).is_ready() {
+                let next = tokio::time::Instant::now() + self.refresh_interval;
+                timer.as_mut().reset(next);
+                // Register the reset timer with the waker.
+                let _ = timer.as_mut().poll(cx);
+                self.search_random_peer();
+            }
+        }
+        // The swarm refuses dials to banned peers.
+        if let Some(peer_id) = self.dials.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition: DialPeerCondition::Disconnected,
+            });
+        }
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
 }
 
 impl NetworkBehaviourEventProcess<MdnsEvent> for Discovery {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(iter) => for (peer_id, multiaddr) in iter {
-                debug!("Discovered {} at {} on LAN.", peer_id, multiaddr);
+                self.mdns_discovered(peer_id, multiaddr);
             },
             MdnsEvent::Expired(iter) => for (peer_id, multiaddr) in iter {
                 debug!("Expired {} at {} from LAN.", peer_id, multiaddr);
@@ -168,31 +1102,48 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Discovery {
                 info!("Query {:?} finished with {:?}", &id, stats);
                 match result {
                     QueryResult::Bootstrap(result) => {
-                        if Some(id) != self.bootstrap_query_id {
-                            error!("Received bootstrap result for unknown query id.");
+                        match &result {
+                            Ok(ok) => info!("Bootstrap step succeeded with {:?}", ok),
+                            Err(err) => warn!("Bootstrap step failed with {:?}", err),
                         }
-                        let done = match result {
-                            Ok(ok) => {
-                                info!("Bootstrap succeeded with {:?}", ok);
-                                ok.num_remaining == 0
-                            }
-                            Err(err) => {
-                                error!("Bootstrap failed with {:?}", err);
-                                true
-                            }
-                        };
-                        if Some(id) == self.bootstrap_query_id && done {
-                            self.bootstrap_query_id = None;
+                        if Some(id) == self.bootstrap_query_id {
+                            self.bootstrap_progress(&result);
+                        } else {
+                            error!("Received bootstrap result for unknown query id.");
                         }
                     }
                     QueryResult::GetClosestPeers(result) => {
-                        // TODO: track query_id
+                        // Peers resolved during the query are removed on `RoutingUpdated`.
+                        if let Some(query) = self.resolve_queries.remove(&id) {
+                            let mut addresses = self.kademlia.take_reported(id);
+                            addresses.extend(self.kademlia_addresses(&query.peer_id));
+                            let event = query.resolved(addresses);
+                            if let DiscoveryEvent::Unresolved(peer_id) = &event {
+                                debug!("Could not resolve new addresses for {}", peer_id);
+                            }
+                            self.events.push_back(event);
+                            return;
+                        }
+                        if !self.refresh_queries.remove(&id) {
+                            warn!("Received result for unknown peer query {:?}", id);
+                            return;
+                        }
+                        // A lookup that times out still adds the peers it met
+                        // on the way to the routing table.
                         match result {
                             Ok(ok) => {
-                                info!("Peer query succeeded with {:?}", ok);
+                                debug!(
+                                    "Random peer query {:?} found {} peers",
+                                    id,
+                                    ok.peers.len()
+                                );
                             }
-                            Err(err) => {
-                                error!("Peer query failed with {:?}", err);
+                            Err(GetClosestPeersError::Timeout { peers, .. }) => {
+                                debug!(
+                                    "Random peer query {:?} timed out with {} peers",
+                                    id,
+                                    peers.len()
+                                );
                             }
                         }
                     }
@@ -213,6 +1164,23 @@ impl NetworkBehaviourEventProcess<KademliaEvent> for Discovery {
                     debug!("Peer {} evicted from routing table", old_peer);
                 }
                 debug!("Peer {} at {:?} added to routing table", peer, addresses);
+                if self.bootstrap_deferred {
+                    self.bootstrap();
+                }
+                let resolved = self
+                    .resolve_queries
+                    .iter()
+                    .find(|(_, q)| q.peer_id == peer)
+                    .map(|(id, _)| *id);
+                if let Some(query_id) = resolved {
+                    let event = self.resolve_queries[&query_id].resolved(addresses.into_vec());
+                    // Without new addresses, wait for the lookup to finish.
+                    if let DiscoveryEvent::Resolved(..) = event {
+                        self.resolve_queries.remove(&query_id);
+                        let _ = self.kademlia.take_reported(query_id);
+                        self.events.push_back(event);
+                    }
+                }
             }
 
             // A peer has connected for whom no listen address is known.
@@ -253,9 +1221,22 @@ impl NetworkBehaviourEventProcess<IdentifyEvent> for Discovery {
                     "Learned about {}",
                     &peer_id
                 );
-                let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
-                let entry = lock.entry(peer_id.clone()).or_insert(PeerInfo::new(peer_id));
-                entry.identify = Some(info);
+                let supports_dht = info.protocols.contains(&self.dht_protocol);
+                if !supports_dht {
+                    warn!(
+                        "Peer {} does not support DHT protocol {}, it reports {:?}",
+                        peer_id, self.dht_protocol, info.protocols
+                    );
+                }
+                // Without known peers, bootstrap from the first DHT peer that
+                // connects.
+                if self.bootstrap_deferred && supports_dht {
+                    for address in &info.listen_addrs {
+                        self.kademlia.add_address(&peer_id, address.clone());
+                    }
+                    self.bootstrap();
+                }
+                self.update_peer_info(peer_id, |entry| entry.identify = Some(info));
             }
             IdentifyEvent::Sent { peer_id } => {
                 debug!("Sent identify info to {}", peer_id);
@@ -270,6 +1251,46 @@ impl NetworkBehaviourEventProcess<IdentifyEvent> for Discovery {
     }
 }
 
+impl NetworkBehaviourEventProcess<ConnectionEvent> for Discovery {
+    fn inject_event(&mut self, event: ConnectionEvent) {
+        // Connections are still reported, so connect and disconnect events
+        // stay paired, but the peer is not recorded.
+        if let ConnectionEvent::Established { peer_id, .. } = &event {
+            if !self.is_allowed(peer_id) {
+                debug!("Refusing connection to {}", peer_id);
+                self.refused.push(peer_id.clone());
+            }
+        }
+        let (peer_id, connections, event) = match event {
+            ConnectionEvent::Established {
+                peer_id,
+                connections,
+            } => {
+                let event = Some(DiscoveryEvent::PeerConnected(peer_id.clone()))
+                    .filter(|_| connections == 1);
+                (peer_id, connections, event)
+            }
+            ConnectionEvent::Closed {
+                peer_id,
+                connections,
+            } => {
+                let event = Some(DiscoveryEvent::PeerDisconnected(peer_id.clone()))
+                    .filter(|_| connections == 0);
+                (peer_id, connections, event)
+            }
+        };
+        let now = Instant::now();
+        self.update_peer_info(peer_id, |entry| {
+            entry.connections = connections;
+            entry.disconnected = Some(now).filter(|_| connections == 0);
+        });
+        if connections == 0 && self.eviction_timer.is_none() {
+            self.eviction_timer = Some(Box::pin(sleep_until((now + self.eviction_grace).into())));
+        }
+        self.events.extend(event);
+    }
+}
+
 impl NetworkBehaviourEventProcess<PingEvent> for Discovery {
     fn inject_event(&mut self, event: PingEvent) {
         match event.result {
@@ -279,9 +1300,7 @@ impl NetworkBehaviourEventProcess<PingEvent> for Discovery {
                     &event.peer,
                     HumanDuration::from(rtt)
                 );
-                let mut lock = self.peer_info.write().unwrap(); // FIXME: Can block
-                let entry = lock.entry(event.peer.clone()).or_insert(PeerInfo::new(event.peer));
-                entry.ping = Some(rtt);
+                self.update_peer_info(event.peer, |entry| entry.record_ping(rtt));
             }
             Ok(libp2p::ping::PingSuccess::Pong) => {
                 debug!("Sent pong to {}", event.peer);
@@ -292,3 +1311,622 @@ impl NetworkBehaviourEventProcess<PingEvent> for Discovery {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::transport::make_memory_transport, test::prelude::assert_eq};
+    use libp2p::{
+        kad::{BootstrapOk, QueryStats},
+        swarm::SwarmEvent,
+        Swarm,
+    };
+
+    #[tokio::test]
+    async fn test_identify_visible_through_shared_handle() {
+        let mut discovery = Discovery::new(Keypair::generate_ed25519(), &[], DiscoveryConfig::default(), None)
+            .await.unwrap();
+        let known_peers = discovery.known_peers();
+
+        let public_key = Keypair::generate_ed25519().public();
+        let peer_id = PeerId::from(public_key.clone());
+        discovery.inject_event(IdentifyEvent::Received {
+            peer_id:       peer_id.clone(),
+            info:          IdentifyInfo {
+                public_key,
+                protocol_version: "/ipfs/0.1.0".into(),
+                agent_version: "mesh-rs".into(),
+                listen_addrs: vec![],
+                protocols: vec![],
+            },
+            observed_addr: "/ip4/127.0.0.1/tcp/60558".parse().unwrap(),
+        });
+
+        let lock = known_peers.read().unwrap();
+        let agent_version = lock[&peer_id].agent_version();
+        assert_eq!(agent_version, Some("mesh-rs"));
+    }
+
+    #[test]
+    fn test_default_bootnodes() {
+        for (peer_id, multiaddr) in BOOTNODES {
+            assert!(peer_id.parse::<PeerId>().is_ok(), "Invalid peer id {}", peer_id);
+            assert!(multiaddr.parse::<Multiaddr>().is_ok(), "Invalid address {}", multiaddr);
+        }
+        let bootnodes = default_bootnodes();
+        assert_eq!(bootnodes.len(), BOOTNODES.len());
+        assert_eq!(bootnodes[0].0.to_string(), BOOTNODES[0].0);
+    }
+
+    #[test]
+    fn test_parse_bootnode() {
+        let (peer_id, multiaddr) = BOOTNODES[0];
+        let bootnode: Bootnode = format!("{}@{}", peer_id, multiaddr).parse().unwrap();
+        assert_eq!(
+            bootnode,
+            Bootnode::Peer(peer_id.parse().unwrap(), multiaddr.parse().unwrap())
+        );
+        assert_eq!("none".parse(), Ok(Bootnode::None));
+        assert_eq!(bootnode.to_string(), format!("{}@{}", peer_id, multiaddr));
+        assert_eq!(Bootnode::None.to_string(), "none");
+        assert_eq!(
+            multiaddr.parse::<Bootnode>(),
+            Err(InvalidBootnode::Format(multiaddr.into()))
+        );
+        assert_eq!(
+            format!("abc@{}", multiaddr).parse::<Bootnode>(),
+            Err(InvalidBootnode::PeerId("abc".into()))
+        );
+        assert_eq!(
+            format!("{}@localhost", peer_id).parse::<Bootnode>(),
+            Err(InvalidBootnode::Multiaddr("localhost".into()))
+        );
+
+        let bootnode = Bootnode::Peer(PeerId::random(), multiaddr.parse().unwrap());
+        assert_eq!(resolve_bootnodes(&[]), default_bootnodes());
+        assert_eq!(resolve_bootnodes(std::slice::from_ref(&bootnode)).len(), 1);
+        assert_eq!(resolve_bootnodes(&[bootnode, Bootnode::None]), vec![]);
+    }
+
+    #[test]
+    fn test_peer_info_accessors() {
+        let public_key = Keypair::generate_ed25519().public();
+        let mut peer_info = PeerInfo::new(PeerId::from(public_key.clone()));
+        assert_eq!(peer_info.agent_version(), None);
+        assert!(peer_info.protocols().is_empty());
+        assert!(peer_info.listen_addrs().is_empty());
+        assert_eq!(peer_info.last_ping(), None);
+
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/60558".parse().unwrap();
+        peer_info.ping = Some(Duration::from_millis(42));
+        peer_info.identify = Some(IdentifyInfo {
+            public_key,
+            protocol_version: "/ipfs/0.1.0".into(),
+            agent_version: "mesh-rs".into(),
+            listen_addrs: vec![address.clone()],
+            protocols: vec!["/meshsub/1.0.0".into()],
+        });
+        assert_eq!(peer_info.agent_version(), Some("mesh-rs"));
+        assert_eq!(peer_info.protocols(), &["/meshsub/1.0.0".to_string()]);
+        assert_eq!(peer_info.listen_addrs(), &[address]);
+        assert_eq!(peer_info.last_ping(), Some(Duration::from_millis(42)));
+        assert!(!peer_info.supports_dht(DEFAULT_DHT_PROTOCOL));
+    }
+
+    #[tokio::test]
+    async fn test_version_histogram() {
+        let discovery = Discovery::new(Keypair::generate_ed25519(), &[], DiscoveryConfig::default(), None)
+            .await.unwrap();
+        let peers = [
+            ("mesh-rs/0.1.0+1d4b7270", 1),
+            ("mesh-rs/0.1.0+1d4b7270", 2),
+            ("mesh-rs/0.2.0+d342091a", 1),
+            ("github.com/0xProject/0x-mesh", 1),
+            // Disconnected and unidentified peers are not counted.
+            ("mesh-rs/0.0.1+3149056e", 0),
+            ("", 1),
+        ];
+        {
+            let known_peers = discovery.known_peers();
+            let mut lock = known_peers.write().unwrap();
+            for (agent_version, connections) in &peers {
+                let public_key = Keypair::generate_ed25519().public();
+                let peer_id = PeerId::from(public_key.clone());
+                let mut peer_info = PeerInfo::new(peer_id.clone());
+                peer_info.connections = *connections;
+                if !agent_version.is_empty() {
+                    peer_info.identify = Some(IdentifyInfo {
+                        public_key,
+                        protocol_version: "/ipfs/0.1.0".into(),
+                        agent_version: (*agent_version).into(),
+                        listen_addrs: vec![],
+                        protocols: vec![],
+                    });
+                }
+                let _ = lock.insert(peer_id, peer_info);
+            }
+        }
+
+        assert_eq!(discovery.peer_versions().len(), 4);
+        let histogram = discovery.version_histogram();
+        assert_eq!(
+            histogram.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("github.com/0xProject/0x-mesh".to_string(), 1),
+                ("mesh-rs/0.1.0+1d4b7270".to_string(), 2),
+                ("mesh-rs/0.2.0+d342091a".to_string(), 1),
+            ]
+        );
+        assert!(agent_version().starts_with(concat!("mesh-rs/", env!("CARGO_PKG_VERSION"), "+")));
+    }
+
+    async fn discovery_swarm() -> Swarm<Discovery> {
+        let keypair = Keypair::generate_ed25519();
+        let transport = make_memory_transport(&keypair);
+        let discovery = Discovery::new(keypair.clone(), &[], DiscoveryConfig::default(), None).await.unwrap();
+        Swarm::new(transport, discovery, PeerId::from(keypair.public()))
+    }
+
+    #[tokio::test]
+    async fn test_deferred_bootstrap() {
+        let mut node = discovery_swarm().await;
+        let mut peer = discovery_swarm().await;
+
+        // Starting without bootnodes succeeds, but does not bootstrap.
+        node.start().unwrap();
+        assert!(node.bootstrap_deferred);
+        assert!(node.bootstrap_query_id.is_none());
+
+        let address: Multiaddr = "/memory/60224".parse().unwrap();
+        Swarm::listen_on(&mut peer, address.clone()).unwrap();
+        Swarm::dial_addr(&mut node, address).unwrap();
+        let peer_id = Swarm::local_peer_id(&peer).clone();
+        while node.bootstrap_deferred {
+            tokio::select! {
+                _ = node.next_event() => {}
+                _ = peer.next_event() => {}
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+        }
+        assert!(node.routing_table().iter().any(|(p, _)| *p == peer_id));
+    }
+
+    #[tokio::test]
+    async fn test_random_peer_refresh() {
+        let keypair = Keypair::generate_ed25519();
+        let config = DiscoveryConfig {
+            kad_refresh_interval: Duration::from_millis(20),
+            ..DiscoveryConfig::default()
+        };
+        let discovery = Discovery::new(keypair.clone(), &[], config, None).await.unwrap();
+        let transport = make_memory_transport(&keypair);
+        let mut node = Swarm::new(transport, discovery, PeerId::from(keypair.public()));
+
+        // Nothing is searched before the node is started.
+        let _ = tokio::time::timeout(Duration::from_millis(100), node.next_event()).await;
+        assert_eq!(node.refreshes, 0);
+
+        node.start().unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while node.refreshes < 3 && tokio::time::Instant::now() < deadline {
+            let _ = tokio::time::timeout(Duration::from_millis(10), node.next_event()).await;
+        }
+        assert!(node.refreshes >= 3);
+
+        let disabled = DiscoveryConfig {
+            kad_refresh_interval: Duration::from_secs(0),
+            ..DiscoveryConfig::default()
+        };
+        let mut discovery = Discovery::new(keypair.clone(), &[], disabled, None).await.unwrap();
+        discovery.start().unwrap();
+        assert!(discovery.refresh_timer.is_none());
+
+        // A lookup is tracked until its result arrives.
+        discovery.search_random_peer();
+        assert_eq!(discovery.refresh_queries.len(), 1);
+        let transport = make_memory_transport(&keypair);
+        let mut node = Swarm::new(transport, discovery, PeerId::from(keypair.public()));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !node.refresh_queries.is_empty() && tokio::time::Instant::now() < deadline {
+            let _ = tokio::time::timeout(Duration::from_millis(10), node.next_event()).await;
+        }
+        assert!(node.refresh_queries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disable_mdns() {
+        let keypair = Keypair::generate_ed25519();
+        let discovery = Discovery::new(keypair.clone(), &[], DiscoveryConfig::default(), None)
+            .await
+            .unwrap();
+        assert!(discovery.mdns.is_enabled());
+
+        let config = DiscoveryConfig {
+            mdns: false,
+            ..DiscoveryConfig::default()
+        };
+        let discovery = Discovery::new(keypair.clone(), &[], config, None).await.unwrap();
+        assert!(!discovery.mdns.is_enabled());
+
+        // The disabled behaviour still runs in a swarm.
+        let transport = make_memory_transport(&keypair);
+        let mut node = Swarm::new(transport, discovery, PeerId::from(keypair.public()));
+        node.start().unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(50), node.next_event()).await;
+    }
+
+    /// Start a bootstrap from an unreachable bootnode and return its query id.
+    async fn bootstrapping() -> (Discovery, QueryId) {
+        let bootnode = (
+            PeerId::from(Keypair::generate_ed25519().public()),
+            "/memory/60227".parse().unwrap(),
+        );
+        let mut discovery = Discovery::new(Keypair::generate_ed25519(), &[bootnode], DiscoveryConfig::default(), None)
+            .await.unwrap();
+        discovery.start().unwrap();
+        let query_id = discovery.bootstrap_query_id.unwrap();
+        (discovery, query_id)
+    }
+
+    fn bootstrap_result(query_id: QueryId, result: BootstrapResult) -> KademliaEvent {
+        KademliaEvent::QueryResult {
+            id:     query_id,
+            stats:  QueryStats::empty(),
+            result: QueryResult::Bootstrap(result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_complete() {
+        let (mut discovery, query_id) = bootstrapping().await;
+        discovery.bootstrap_failures = 2;
+        let peer = PeerId::random();
+        discovery.inject_event(bootstrap_result(query_id, Ok(BootstrapOk {
+            peer,
+            num_remaining: 0,
+        })));
+        assert_eq!(discovery.bootstrap_query_id, None);
+        assert_eq!(discovery.bootstrap_failures, 0);
+        assert!(discovery.bootstrap_retry.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_in_progress() {
+        let (mut discovery, query_id) = bootstrapping().await;
+        discovery.inject_event(bootstrap_result(query_id, Ok(BootstrapOk {
+            peer:          PeerId::random(),
+            num_remaining: 3,
+        })));
+        assert_eq!(discovery.bootstrap_query_id, Some(query_id));
+
+        // A bucket refresh timing out does not end the query.
+        discovery.inject_event(bootstrap_result(query_id, Err(BootstrapError::Timeout {
+            peer:          PeerId::random(),
+            num_remaining: Some(2),
+        })));
+        assert_eq!(discovery.bootstrap_query_id, Some(query_id));
+        assert_eq!(discovery.bootstrap_failures, 0);
+        assert!(discovery.bootstrap_retry.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_failed() {
+        let (mut discovery, query_id) = bootstrapping().await;
+        discovery.inject_event(bootstrap_result(query_id, Err(BootstrapError::Timeout {
+            peer:          PeerId::random(),
+            num_remaining: None,
+        })));
+        assert_eq!(discovery.bootstrap_query_id, None);
+        assert_eq!(discovery.bootstrap_failures, 1);
+        assert!(discovery.bootstrap_retry.is_some());
+
+        // Restarting clears the pending retry, consecutive failures back off.
+        discovery.bootstrap();
+        assert!(discovery.bootstrap_retry.is_none());
+        let query_id = discovery.bootstrap_query_id.unwrap();
+        discovery.inject_event(bootstrap_result(query_id, Err(BootstrapError::Timeout {
+            peer:          PeerId::random(),
+            num_remaining: Some(0),
+        })));
+        assert_eq!(discovery.bootstrap_failures, 2);
+        let deadline = discovery.bootstrap_retry.as_ref().unwrap().deadline();
+        assert!(deadline > tokio::time::Instant::now() + BOOTSTRAP_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_mdns_autodial() {
+        let keypair = Keypair::generate_ed25519();
+        let transport = make_memory_transport(&keypair);
+        let mut discovery = Discovery::new(keypair.clone(), &[], DiscoveryConfig::default(), None).await.unwrap();
+        discovery.set_mdns_autodial(true);
+        let mut node = Swarm::new(transport, discovery, PeerId::from(keypair.public()));
+
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        node.mdns_discovered(peer_id.clone(), "/memory/60225".parse().unwrap());
+        assert!(node.routing_table().iter().any(|(p, _)| *p == peer_id));
+        loop {
+            if let SwarmEvent::Dialing(dialed) = node.next_event().await {
+                assert_eq!(dialed, peer_id);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eviction_grace() {
+        let config = DiscoveryConfig {
+            eviction_grace: Duration::from_secs(60),
+            ..DiscoveryConfig::default()
+        };
+        let mut discovery = Discovery::new(Keypair::generate_ed25519(), &[], config, None)
+            .await
+            .unwrap();
+        let known_peers = discovery.known_peers();
+        let peer_id = PeerId::random();
+        let connected = |connections| {
+            ConnectionEvent::Established {
+                peer_id: peer_id.clone(),
+                connections,
+            }
+        };
+        let closed = |connections| {
+            ConnectionEvent::Closed {
+                peer_id: peer_id.clone(),
+                connections,
+            }
+        };
+        discovery.inject_event(connected(1));
+        discovery.update_peer_info(peer_id.clone(), |entry| {
+            entry.ping = Some(Duration::from_millis(42));
+        });
+
+        // A quick reconnect keeps the ping history.
+        discovery.inject_event(closed(0));
+        assert!(known_peers.read().unwrap()[&peer_id].disconnected.is_some());
+        discovery.inject_event(connected(1));
+        let later = Instant::now() + Duration::from_secs(120);
+        assert_eq!(discovery.evict_disconnected_at(later), None);
+        let ping = known_peers.read().unwrap()[&peer_id].last_ping();
+        assert_eq!(ping, Some(Duration::from_millis(42)));
+
+        // Staying disconnected past the grace period forgets the peer.
+        discovery.inject_event(closed(0));
+        let now = Instant::now();
+        let next = discovery.evict_disconnected_at(now).unwrap();
+        assert!(next > now && next <= now + Duration::from_secs(60));
+        assert!(known_peers.read().unwrap().contains_key(&peer_id));
+        assert_eq!(discovery.evict_disconnected_at(next), None);
+        assert!(!known_peers.read().unwrap().contains_key(&peer_id));
+    }
+
+    #[test]
+    fn test_reputation() {
+        let mut peer_info = PeerInfo::new(PeerId::random());
+        peer_info.record_ping(Duration::from_millis(50));
+        let fast = peer_info.reputation;
+        assert!(fast > 0.0);
+        peer_info.record_failure();
+        assert!(peer_info.reputation < 0.0);
+        for _ in 0..1000 {
+            peer_info.record_ping(Duration::from_secs(5));
+        }
+        assert_eq!(peer_info.reputation, -MAX_REPUTATION);
+
+        let mut slow = PeerInfo::new(PeerId::random());
+        slow.record_ping(Duration::from_millis(300));
+        assert!(slow.reputation > 0.0 && slow.reputation < fast);
+    }
+
+    #[tokio::test]
+    async fn test_best_peers_for_protocol() {
+        let discovery = Discovery::new(Keypair::generate_ed25519(), &[], DiscoveryConfig::default(), None)
+            .await
+            .unwrap();
+        let order_sync = "/0x-mesh/order-sync/version/0";
+        let add_peer = |protocol: &str, rtt: Duration, failures: usize| {
+            let public_key = Keypair::generate_ed25519().public();
+            let peer_id = PeerId::from(public_key.clone());
+            discovery.update_peer_info(peer_id.clone(), |entry| {
+                entry.identify = Some(IdentifyInfo {
+                    public_key,
+                    protocol_version: "/ipfs/0.1.0".into(),
+                    agent_version: "mesh-rs".into(),
+                    listen_addrs: vec![],
+                    protocols: vec![protocol.into()],
+                });
+                entry.record_ping(rtt);
+            });
+            for _ in 0..failures {
+                discovery.record_failure(peer_id.clone());
+            }
+            peer_id
+        };
+        let slow = add_peer(order_sync, Duration::from_millis(400), 0);
+        let fast = add_peer(order_sync, Duration::from_millis(20), 0);
+        let failing = add_peer(order_sync, Duration::from_millis(20), 1);
+        let _unsupported = add_peer("/meshsub/1.0.0", Duration::from_millis(1), 0);
+
+        assert_eq!(discovery.best_peers_for_protocol(order_sync, 5), vec![
+            fast.clone(),
+            slow,
+            failing
+        ]);
+        assert_eq!(discovery.best_peers_for_protocol(order_sync, 1), vec![fast]);
+        assert!(discovery.best_peers_for_protocol("/unknown", 5).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_connections() {
+        let mut node = discovery_swarm().await;
+        let mut peer = discovery_swarm().await;
+        let peer_id = Swarm::local_peer_id(&peer).clone();
+        let known_peers = node.known_peers();
+        let connections = || known_peers.read().unwrap().get(&peer_id).map(|p| p.connections);
+
+        let address: Multiaddr = "/memory/60226".parse().unwrap();
+        Swarm::listen_on(&mut peer, address.clone()).unwrap();
+        Swarm::dial_addr(&mut node, address.clone()).unwrap();
+        Swarm::dial_addr(&mut node, address).unwrap();
+        let mut connected = 0;
+        let mut closed = 0;
+        while connections() != Some(2) {
+            tokio::select! {
+                event = node.next_event() => match event {
+                    SwarmEvent::Behaviour(DiscoveryEvent::PeerConnected(_)) => connected += 1,
+                    SwarmEvent::ConnectionClosed { .. } => closed += 1,
+                    _ => {}
+                },
+                _ = peer.next_event() => {}
+            }
+        }
+
+        // Both connections close when the peer goes away (or idle out), but it
+        // only disconnects once.
+        drop(peer);
+        loop {
+            match node.next_event().await {
+                SwarmEvent::Behaviour(DiscoveryEvent::PeerConnected(_)) => connected += 1,
+                SwarmEvent::Behaviour(DiscoveryEvent::PeerDisconnected(disconnected)) => {
+                    assert_eq!(disconnected, peer_id);
+                    break;
+                }
+                SwarmEvent::ConnectionClosed { .. } => closed += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(connected, 1);
+        assert_eq!(closed, 2);
+        assert_eq!(connections(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_kad_parallelism() {
+        let config = DiscoveryConfig {
+            kad_parallelism: NonZeroUsize::new(7).unwrap(),
+            ..DiscoveryConfig::default()
+        };
+        let keypair = Keypair::generate_ed25519();
+        let _discovery = Discovery::new(keypair, &[], config.clone(), None).await.unwrap();
+
+        // `KademliaConfig` has no getters, its debug output shows the
+        // query settings.
+        let debug = format!("{:?}", kademlia_config(&config));
+        assert!(debug.contains("parallelism: 7"), "{}", debug);
+        let debug = format!("{:?}", kademlia_config(&DiscoveryConfig::default()));
+        assert!(debug.contains("parallelism: 3"), "{}", debug);
+    }
+
+    #[tokio::test]
+    async fn test_peer_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let store = FilePeerStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+
+        let public_key = Keypair::generate_ed25519().public();
+        let peer_id = PeerId::from(public_key.clone());
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/60558".parse().unwrap();
+        let mut peer_info = PeerInfo::new(peer_id.clone());
+        peer_info.ping = Some(Duration::from_millis(42));
+        peer_info.identify = Some(IdentifyInfo {
+            public_key,
+            protocol_version: "/ipfs/0.1.0".into(),
+            agent_version: "mesh-rs".into(),
+            listen_addrs: vec![address.clone()],
+            protocols: vec![DEFAULT_DHT_PROTOCOL.into()],
+        });
+        let mut peers = HashMap::new();
+        peers.insert(peer_id.clone(), peer_info);
+        store.save(&peers).unwrap();
+
+        // A restarted node knows the peer and can route to it.
+        let mut discovery = Discovery::new(
+            Keypair::generate_ed25519(),
+            &[],
+            DiscoveryConfig::default(),
+            Some(Box::new(store.clone())),
+        )
+        .await
+        .unwrap();
+        let known_peers = discovery.known_peers();
+        let loaded = known_peers.read().unwrap()[&peer_id].clone();
+        assert_eq!(loaded.agent_version(), Some("mesh-rs"));
+        assert_eq!(loaded.last_ping(), Some(Duration::from_millis(42)));
+        assert_eq!(discovery.routing_table(), vec![(peer_id.clone(), vec![address])]);
+
+        // Saving again writes the same peers.
+        discovery.save_peers().unwrap();
+        let reloaded = store.load().unwrap();
+        assert_eq!(reloaded.keys().collect::<Vec<_>>(), vec![&peer_id]);
+
+        // A corrupt store is ignored.
+        fs::write(&path, "not json").unwrap();
+        assert!(store.load().is_err());
+        let discovery = Discovery::new(
+            Keypair::generate_ed25519(),
+            &[],
+            DiscoveryConfig::default(),
+            Some(Box::new(store)),
+        )
+        .await
+        .unwrap();
+        assert!(discovery.known_peers().read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peer_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FilePeerStore::new(dir.path().join("peers.json"));
+        let mut discovery = Discovery::new(
+            Keypair::generate_ed25519(),
+            &[],
+            DiscoveryConfig::default(),
+            Some(Box::new(store.clone())),
+        )
+        .await
+        .unwrap();
+        let known_peers = discovery.known_peers();
+        let connect = |discovery: &mut Discovery, peer_id: &PeerId| {
+            discovery.inject_event(ConnectionEvent::Established {
+                peer_id:     peer_id.clone(),
+                connections: 1,
+            });
+        };
+
+        // Banning forgets the peer.
+        let banned = PeerId::random();
+        connect(&mut discovery, &banned);
+        assert!(known_peers.read().unwrap().contains_key(&banned));
+        discovery.ban_peer(banned.clone());
+        assert!(discovery.is_banned(&banned));
+        assert!(!discovery.is_allowed(&banned));
+        assert!(!known_peers.read().unwrap().contains_key(&banned));
+
+        // Restricting to an allowlist refuses the other connected peers.
+        let allowed = PeerId::random();
+        let other = PeerId::random();
+        connect(&mut discovery, &allowed);
+        connect(&mut discovery, &other);
+        discovery.allow_only(vec![allowed.clone()]);
+        assert_eq!(discovery.take_refused(), vec![other.clone()]);
+        assert!(!known_peers.read().unwrap().contains_key(&other));
+        connect(&mut discovery, &other);
+        assert_eq!(discovery.take_refused(), vec![other.clone()]);
+        assert!(discovery.is_allowed(&allowed));
+        assert!(!discovery.is_allowed(&other));
+
+        // Both survive a restart.
+        discovery.save_peers().unwrap();
+        let discovery = Discovery::new(
+            Keypair::generate_ed25519(),
+            &[],
+            DiscoveryConfig::default(),
+            Some(Box::new(store)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(discovery.banned_peers(), vec![banned]);
+        assert!(discovery.is_allowed(&allowed));
+        assert!(!discovery.is_allowed(&other));
+    }
+}
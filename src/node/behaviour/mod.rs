@@ -11,22 +11,48 @@
 //!
 //! * `/ipfs/id/push/1.0.0`
 //! * `/p2p/id/delta/1.0.0`
-//! * `/libp2p/circuit/relay/0.1.0
+//! * `/libp2p/circuit/relay/0.1.0`
 //! * `/floodsub/1.0.0`
+//!
+//! Circuit relay needs `libp2p-relay`, which is first released with libp2p
+//! 0.35. Until the libp2p dependency is upgraded, nodes behind symmetric NAT
+//! can only be reached through the WebSocket or TCP listeners.
+//!
+//! Identify push is likewise missing from the `libp2p-identify` release that
+//! comes with libp2p 0.32: `Identify` only answers `/ipfs/id/1.0.0` requests
+//! and has no way to send unsolicited updates. Peers learn about changed
+//! listen or external addresses on their next periodic identify, which
+//! updates [`PeerInfo`] as soon as it is received.
 
+pub mod address_limit;
+pub mod connections;
+pub mod dht_requests;
 pub mod discovery;
 pub mod order_sync;
 pub mod pubsub;
 
-use self::{discovery::{Discovery, PeerInfo}, order_sync::OrderSync, pubsub::PubSub};
+use self::{
+    discovery::{Discovery, DiscoveryConfig, DiscoveryEvent, PeerInfo, PeerStore},
+    order_sync::{
+        messages::Order, NegotiationFailures, OrderSource, OrderSync, OrderSyncEvent,
+        OrderSyncHandler, RequestStats, ThrottleConfig,
+    },
+    pubsub::{GossipTopic, PubSub, PubSubConfig, PubSubEvent, PublishError},
+};
 use crate::prelude::*;
 use futures::channel::oneshot;
 use libp2p::{
-    identity::Keypair, request_response, swarm::NetworkBehaviourEventProcess, NetworkBehaviour,
-    PeerId,
+    core::{ConnectedPoint, ProtocolName},
+    identity::Keypair,
+    request_response::ProtocolSupport,
+    swarm::{
+        protocols_handler::UpgradeInfoSend, IntoProtocolsHandler, NetworkBehaviour,
+        NetworkBehaviourEventProcess, ProtocolsHandler,
+    },
+    Multiaddr, NetworkBehaviour, PeerId,
 };
 use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
@@ -36,10 +62,34 @@ pub struct Behaviour {
 }
 
 impl Behaviour {
-    pub async fn new(peer_key: Keypair) -> Result<Self> {
-        let discovery = Discovery::new(peer_key.clone()).await?;
-        let pubsub = PubSub::new(peer_key);
-        let order_sync = OrderSync::new();
+    /// Create the behaviour stack, gossiping orders on `gossip_topics`.
+    /// OrderSync requests are made and accepted
+    /// as set by `order_sync_support`. Inbound requests are answered by
+    /// `order_sync_handler`, or from the order source if it is `None`.
+    /// Requests are retried up to `max_dial_retries` times after a dial
+    /// failure, and response pages hold up to `max_page_bytes` of orders.
+    /// Known peers are loaded from and saved to `peer_store`.
+    #[allow(clippy::too_many_arguments)] // Only called by `Node::create`
+    pub async fn new(
+        peer_key: Keypair,
+        bootnodes: &[(PeerId, Multiaddr)],
+        discovery_config: DiscoveryConfig,
+        pubsub_config: PubSubConfig,
+        gossip_topics: Vec<GossipTopic>,
+        order_sync_support: ProtocolSupport,
+        order_sync_handler: Option<Arc<dyn OrderSyncHandler>>,
+        max_dial_retries: usize,
+        max_page_bytes: usize,
+        peer_store: Option<Box<dyn PeerStore>>,
+    ) -> Result<Self> {
+        let discovery =
+            Discovery::new(peer_key.clone(), bootnodes, discovery_config, peer_store).await?;
+        let pubsub = PubSub::new(peer_key, pubsub_config, gossip_topics)?;
+        let mut order_sync = OrderSync::with_support(order_sync_support, order_sync_handler);
+        let _ = order_sync
+            .set_peer_access(discovery.peer_access())
+            .set_max_dial_retries(max_dial_retries)
+            .set_max_page_bytes(max_page_bytes);
 
         Ok(Self {
             discovery,
@@ -54,6 +104,19 @@ impl Behaviour {
         Ok(())
     }
 
+    /// Stop gossiping orders.
+    pub fn stop(&mut self) {
+        self.pubsub.stop();
+    }
+
+    pub fn gossip_subscribed(&self) -> bool {
+        self.pubsub.is_subscribed()
+    }
+
+    pub fn subscribed_topics(&self) -> Vec<String> {
+        self.pubsub.subscribed_topics()
+    }
+
     pub fn order_sync_send(
         &mut self,
         peer_id: &PeerId,
@@ -63,12 +126,282 @@ impl Behaviour {
         self.order_sync.send(peer_id, request, sender);
     }
 
+    pub fn set_max_pending_requests(&mut self, max: usize) {
+        self.order_sync.set_max_pending_requests(max);
+    }
+
+    pub fn set_order_sync_throttle(&mut self, config: ThrottleConfig) {
+        let _ = self.order_sync.set_throttle(config);
+    }
+
+    pub fn set_order_sync_inbound_throttle(&mut self, config: ThrottleConfig) {
+        let _ = self.order_sync.set_inbound_throttle(config);
+    }
+
+    pub fn order_sync_rejected_inbound(&self) -> u64 {
+        self.order_sync.rejected_inbound()
+    }
+
+    pub const fn order_sync_negotiation_failures(&self) -> NegotiationFailures {
+        self.order_sync.negotiation_failures()
+    }
+
+    pub const fn order_sync_request_stats(&self) -> RequestStats {
+        self.order_sync.request_stats()
+    }
+
+    pub fn set_order_source(&mut self, source: Arc<dyn OrderSource>) {
+        let _ = self.order_sync.set_order_source(source);
+    }
+
+    pub fn pending_order_sync_requests(&self) -> usize {
+        self.order_sync.pending_count()
+    }
+
+    pub fn cancel_order_sync_requests(&mut self) -> usize {
+        self.order_sync.cancel_all()
+    }
+
+    pub fn set_max_publishes_per_sec(&mut self, per_second: u32) {
+        self.pubsub.set_max_publishes_per_sec(per_second);
+    }
+
+    pub fn queue_publish(&mut self, order: &Order) -> Result<(), PublishError> {
+        self.pubsub.queue_publish(order)
+    }
+
+    pub fn take_gossiped_orders(&mut self) -> Vec<(PeerId, Order)> {
+        self.pubsub.take_received()
+    }
+
+    pub fn gossip_received(&self) -> u64 {
+        self.pubsub.received_messages()
+    }
+
+    pub fn gossip_rejected(&self) -> u64 {
+        self.pubsub.rejected_messages()
+    }
+
+    pub fn gossip_resubscriptions(&self) -> u64 {
+        self.pubsub.resubscriptions()
+    }
 
     pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
         self.discovery.known_peers()
     }
+
+    pub fn dht_protocol(&self) -> &str {
+        self.discovery.dht_protocol()
+    }
+
+    pub fn save_peers(&self) -> Result<()> {
+        self.discovery.save_peers()
+    }
+
+    pub fn ban_peer(&mut self, peer_id: PeerId) {
+        self.discovery.ban_peer(peer_id);
+    }
+
+    pub fn unban_peer(&mut self, peer_id: &PeerId) {
+        self.discovery.unban_peer(peer_id);
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.discovery.is_banned(peer_id)
+    }
+
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.discovery.banned_peers()
+    }
+
+    pub fn allow_only(&mut self, peers: Vec<PeerId>) {
+        self.discovery.allow_only(peers);
+    }
+
+    pub fn allow_all(&mut self) {
+        self.discovery.allow_all();
+    }
+
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.discovery.is_allowed(peer_id)
+    }
+
+    pub fn take_refused_peers(&mut self) -> Vec<PeerId> {
+        self.discovery.take_refused()
+    }
+
+    pub fn version_histogram(&self) -> BTreeMap<String, usize> {
+        self.discovery.version_histogram()
+    }
+
+    pub fn dht_request_stats(&self) -> dht_requests::DhtRequestStats {
+        self.discovery.dht_request_stats()
+    }
+
+    pub fn set_mdns_autodial(&mut self, enabled: bool) {
+        self.discovery.set_mdns_autodial(enabled);
+    }
+
+    pub fn routing_table(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.discovery.routing_table()
+    }
+
+    pub fn dht_bucket_sizes(&mut self) -> Vec<usize> {
+        self.discovery.bucket_sizes()
+    }
+
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.discovery.add_address(peer_id, address);
+    }
+}
+
+/// Names of the protocols a behaviour accepts on inbound substreams.
+///
+/// These are taken from the protocols handler the behaviour would create for a
+/// new connection, so they reflect how the behaviour is configured.
+pub fn protocol_names<B: NetworkBehaviour>(behaviour: &mut B, local_peer_id: &PeerId) -> Vec<String> {
+    let connected_point = ConnectedPoint::Listener {
+        local_addr:     Multiaddr::empty(),
+        send_back_addr: Multiaddr::empty(),
+    };
+    let handler = behaviour.new_handler().into_handler(local_peer_id, &connected_point);
+    handler
+        .listen_protocol()
+        .upgrade()
+        .protocol_info()
+        .map(|info| String::from_utf8_lossy(info.protocol_name()).into_owned())
+        .collect()
+}
+
+impl NetworkBehaviourEventProcess<PubSubEvent> for Behaviour {
+    fn inject_event(&mut self, event: PubSubEvent) {
+        match event {
+            // Look for more peers to join the mesh with.
+            PubSubEvent::Resubscribed => self.discovery.rediscover(),
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<DiscoveryEvent> for Behaviour {
+    fn inject_event(&mut self, event: DiscoveryEvent) {
+        match event {
+            DiscoveryEvent::Resolved(peer_id, addresses) => {
+                for address in addresses {
+                    self.order_sync.add_address(&peer_id, address);
+                }
+                self.order_sync.retry(&peer_id);
+            }
+            DiscoveryEvent::Unresolved(peer_id) => self.order_sync.fail_unresolved(&peer_id),
+            DiscoveryEvent::PeerConnected(peer_id) => {
+                debug!("Peer {} connected", peer_id);
+                self.pubsub.peer_connected();
+            }
+            DiscoveryEvent::PeerDisconnected(peer_id) => {
+                debug!("Peer {} disconnected", peer_id);
+                self.pubsub.peer_disconnected();
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<OrderSyncEvent> for Behaviour {
+    fn inject_event(&mut self, event: OrderSyncEvent) {
+        match event {
+            OrderSyncEvent::ResolvePeer(peer_id) => self.discovery.resolve(&peer_id),
+            OrderSyncEvent::RequestFailed(peer_id) => self.discovery.record_failure(peer_id),
+        }
+    }
 }
 
-impl NetworkBehaviourEventProcess<()> for Behaviour {
-    fn inject_event(&mut self, _event: ()) {}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::transport::make_memory_transport, test::prelude::assert_eq};
+    use futures::future::{select, Either};
+    use libp2p::{
+        swarm::{SwarmBuilder, SwarmEvent},
+        Swarm,
+    };
+    use order_sync::messages::{Request, Response};
+    use std::time::Duration;
+
+    async fn behaviour_swarm(bootnodes: &[(PeerId, Multiaddr)]) -> Swarm<Behaviour> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let discovery_config = DiscoveryConfig {
+            mdns: false,
+            ..DiscoveryConfig::default()
+        };
+        let behaviour = Behaviour::new(
+            keypair.clone(),
+            bootnodes,
+            discovery_config,
+            PubSubConfig::default(),
+            Vec::new(),
+            ProtocolSupport::Full,
+            None,
+            order_sync::DEFAULT_DIAL_RETRIES,
+            order_sync::DEFAULT_MAX_PAGE_BYTES,
+            None,
+        )
+        .await
+        .unwrap();
+        SwarmBuilder::new(make_memory_transport(&keypair), behaviour, peer_id).build()
+    }
+
+    async fn listen(swarm: &mut Swarm<Behaviour>) -> Multiaddr {
+        Swarm::listen_on(swarm, "/memory/0".parse().unwrap()).unwrap();
+        loop {
+            if let SwarmEvent::NewListenAddr(address) = swarm.next_event().await {
+                break address;
+            }
+        }
+    }
+
+    fn spawn(mut swarm: Swarm<Behaviour>) {
+        tokio::spawn(async move {
+            loop {
+                let _ = swarm.next_event().await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_resolve_through_dht() {
+        // The target only answers OrderSync requests, the helper knows its
+        // address and answers the lookup for it.
+        let mut target = behaviour_swarm(&[]).await;
+        let target_id = Swarm::local_peer_id(&target).clone();
+        let target_address = listen(&mut target).await;
+        spawn(target);
+        let mut helper = behaviour_swarm(&[]).await;
+        let helper_id = Swarm::local_peer_id(&helper).clone();
+        helper.add_address(&target_id, target_address.clone());
+        let helper_address = listen(&mut helper).await;
+        spawn(helper);
+
+        // The requester only knows a stale address of the target, so the
+        // first dial fails and the target is looked up through the helper.
+        let stale = "/memory/1".parse().unwrap();
+        let bootnodes = [(helper_id, helper_address), (target_id.clone(), stale)];
+        let mut swarm = behaviour_swarm(&bootnodes).await;
+        let (sender, mut receiver) = oneshot::channel();
+        swarm.order_sync_send(&target_id, Request::default(), sender);
+        let exchange = async {
+            loop {
+                match select(swarm.next_event().boxed(), &mut receiver).await {
+                    Either::Left(_) => {}
+                    Either::Right((result, _)) => break result.unwrap(),
+                }
+            }
+        };
+        let result = tokio::time::timeout(Duration::from_secs(30), exchange)
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap(), Response::default());
+        assert!(swarm
+            .order_sync
+            .addresses_of_peer(&target_id)
+            .contains(&target_address));
+    }
 }
@@ -0,0 +1,142 @@
+//! Per peer circuit breaker for outbound OrderSync requests.
+//!
+//! After [`FAILURE_THRESHOLD`] consecutive failures the breaker for a peer
+//! opens and requests to it are refused for a cooldown. After the cooldown it
+//! is half-open: a single probe request is let through, and the breaker closes
+//! again if it succeeds or re-opens if it fails.
+
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Number of consecutive failures after which the breaker opens.
+pub const FAILURE_THRESHOLD: usize = 5;
+
+/// Time the breaker stays open before a probe request is allowed.
+pub const COOLDOWN: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakerState {
+    /// Requests are sent normally.
+    Closed,
+
+    /// Requests are refused until the cooldown has passed.
+    Open,
+
+    /// A probe request is in flight, other requests are refused.
+    HalfOpen,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PeerState {
+    Closed { failures: usize },
+    Open { until: Instant },
+    HalfOpen { probe_sent: Instant },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    cooldown: Duration,
+    peers:    HashMap<PeerId, PeerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            peers: HashMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn state(&self, peer_id: &PeerId) -> BreakerState {
+        match self.peers.get(peer_id) {
+            None | Some(PeerState::Closed { .. }) => BreakerState::Closed,
+            Some(PeerState::Open { .. }) => BreakerState::Open,
+            Some(PeerState::HalfOpen { .. }) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether a request to the peer may be sent at time `now`. Once the
+    /// cooldown has passed this lets a single probe through. A probe that
+    /// never completes is replaced after another cooldown.
+    pub fn allow_at(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        let state = match self.peers.get_mut(peer_id) {
+            None | Some(PeerState::Closed { .. }) => return true,
+            Some(state) => state,
+        };
+        let probe_due = match *state {
+            PeerState::Open { until } => now >= until,
+            PeerState::HalfOpen { probe_sent } => now >= probe_sent + self.cooldown,
+            PeerState::Closed { .. } => unreachable!(),
+        };
+        if probe_due {
+            *state = PeerState::HalfOpen { probe_sent: now };
+        }
+        probe_due
+    }
+
+    pub fn record_success(&mut self, peer_id: &PeerId) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn record_failure_at(&mut self, peer_id: &PeerId, now: Instant) {
+        let state = self
+            .peers
+            .entry(peer_id.clone())
+            .or_insert(PeerState::Closed { failures: 0 });
+        match state {
+            PeerState::Closed { failures } if *failures + 1 < FAILURE_THRESHOLD => {
+                *failures += 1;
+            }
+            _ => {
+                *state = PeerState::Open {
+                    until: now + self.cooldown,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn test_open_until_half_open() {
+        let mut breaker = CircuitBreaker::new(COOLDOWN);
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let now = Instant::now();
+
+        for _ in 1..FAILURE_THRESHOLD {
+            assert!(breaker.allow_at(&peer_id, now));
+            breaker.record_failure_at(&peer_id, now);
+        }
+        assert_eq!(breaker.state(&peer_id), BreakerState::Closed);
+        breaker.record_failure_at(&peer_id, now);
+        assert_eq!(breaker.state(&peer_id), BreakerState::Open);
+
+        // Requests are skipped until the cooldown has passed.
+        assert!(!breaker.allow_at(&peer_id, now + COOLDOWN / 2));
+        assert!(breaker.allow_at(&other, now));
+
+        // Then a single probe is sent.
+        assert!(breaker.allow_at(&peer_id, now + COOLDOWN));
+        assert_eq!(breaker.state(&peer_id), BreakerState::HalfOpen);
+        assert!(!breaker.allow_at(&peer_id, now + COOLDOWN));
+
+        // A failed probe opens the breaker again, a successful one closes it.
+        breaker.record_failure_at(&peer_id, now + COOLDOWN);
+        assert_eq!(breaker.state(&peer_id), BreakerState::Open);
+        assert!(breaker.allow_at(&peer_id, now + COOLDOWN * 2));
+        breaker.record_success(&peer_id);
+        assert_eq!(breaker.state(&peer_id), BreakerState::Closed);
+        assert!(breaker.allow_at(&peer_id, now + COOLDOWN * 2));
+    }
+}
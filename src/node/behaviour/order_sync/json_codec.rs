@@ -1,16 +1,23 @@
-//! Generic RequestResponseCodec for Serde types using raw JSON.
+//! Generic RequestResponseCodec for Serde types using JSON.
 //!
-//! **Note.** Do not use for new protocols.
+//! Messages are framed with a little-endian `u32` length prefix, see
+//! [`read_length_prefixed`](crate::utils::read_length_prefixed). The protocol
+//! name is unchanged, so this does not interoperate with Go 0x Mesh nodes,
+//! which send raw JSON. The `legacy_no_framing` feature restores raw JSON,
+//! which has no framing, so the reader repeatedly tries parsing and reads
+//! more content to the buffer until it succeeds.
 //!
-//! Raw JSON does not include a length prefix, so the solve the framing problem
-//! we repeatedly try parsing and read more content to the buffer until it
-//! succeeds.
+//! Requests and responses have separate maximum sizes, as they can differ
+//! greatly in size.
 //!
-//! ## To do
-//!
-//! * Implement maximum buffer size.
+//! Reads in progress are interrupted once the codec's [`ShutdownToken`]
+//! fires, so a peer that stops sending does not hold up shutdown.
 
-use crate::{prelude::*, utils::read_json};
+#[cfg(feature = "legacy_no_framing")]
+use crate::utils::read_json as read_message;
+#[cfg(not(feature = "legacy_no_framing"))]
+use crate::utils::{read_length_prefixed, write_length_prefixed, ByteOrder};
+use crate::{prelude::*, utils::ShutdownToken};
 use libp2p::{core::ProtocolName, request_response::RequestResponseCodec};
 use std::marker::PhantomData;
 
@@ -21,24 +28,80 @@ where
     Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
     Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
 {
-    protocol: PhantomData<Protocol>,
-    request:  PhantomData<Request>,
-    response: PhantomData<Response>,
+    protocol:          PhantomData<Protocol>,
+    request:           PhantomData<Request>,
+    response:          PhantomData<Response>,
+    max_request_size:  usize,
+    max_response_size: usize,
+    shutdown:          ShutdownToken,
 }
 
-impl<Protocol, Request, Response> Default for JsonCodec<Protocol, Request, Response>
+impl<Protocol, Request, Response> JsonCodec<Protocol, Request, Response>
 where
     Protocol: Clone + Send + Sync + ProtocolName,
     Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
     Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
 {
-    fn default() -> Self {
+    /// Create a codec with maximum message sizes in bytes.
+    pub const fn new(max_request_size: usize, max_response_size: usize) -> Self {
         Self {
             protocol: PhantomData,
-            request:  PhantomData,
+            request: PhantomData,
             response: PhantomData,
+            max_request_size,
+            max_response_size,
+            shutdown: ShutdownToken::never(),
         }
     }
+
+    /// Interrupt reads once `shutdown` fires.
+    pub fn with_shutdown(mut self, shutdown: ShutdownToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+}
+
+impl<Protocol, Request, Response> Default for JsonCodec<Protocol, Request, Response>
+where
+    Protocol: Clone + Send + Sync + ProtocolName,
+    Request: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+    Response: Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    /// Codec without size limits.
+    fn default() -> Self {
+        Self::new(usize::MAX, usize::MAX)
+    }
+}
+
+/// Read one little-endian frame.
+#[cfg(not(feature = "legacy_no_framing"))]
+async fn read_message<R, T>(io: &mut R, max_size: usize, shutdown: ShutdownToken) -> io::Result<T>
+where
+    R: AsyncRead + Unpin + Send,
+    T: for<'a> Deserialize<'a>,
+{
+    read_length_prefixed(io, ByteOrder::LittleEndian, max_size, shutdown).await
+}
+
+/// Write `value` as one little-endian frame.
+#[cfg(not(feature = "legacy_no_framing"))]
+async fn write_message<W, T>(io: &mut W, value: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    T: Serialize + Sync,
+{
+    write_length_prefixed(io, ByteOrder::LittleEndian, value).await
+}
+
+/// Write `value` as raw JSON.
+#[cfg(feature = "legacy_no_framing")]
+async fn write_message<W, T>(io: &mut W, value: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    T: Serialize + Sync,
+{
+    // OPT: Streaming write
+    io.write_all(serde_json::to_vec(value)?.as_slice()).await
 }
 
 #[async_trait]
@@ -60,7 +123,7 @@ where
     where
         T: AsyncRead + Unpin + Send,
     {
-        read_json::<_, Request>(io).await
+        read_message(io, self.max_request_size, self.shutdown.clone()).await
     }
 
     async fn read_response<T>(
@@ -71,7 +134,7 @@ where
     where
         T: AsyncRead + Unpin + Send,
     {
-        read_json::<_, Response>(io).await
+        read_message(io, self.max_response_size, self.shutdown.clone()).await
     }
 
     async fn write_request<T>(
@@ -83,8 +146,7 @@ where
     where
         T: AsyncWrite + Unpin + Send,
     {
-        // OPT: Streaming write
-        io.write_all(serde_json::to_vec(&req)?.as_slice()).await
+        write_message(io, &req).await
     }
 
     async fn write_response<T>(
@@ -96,7 +158,6 @@ where
     where
         T: AsyncWrite + Unpin + Send,
     {
-        // OPT: Streaming write
-        io.write_all(serde_json::to_vec(&res)?.as_slice()).await
+        write_message(io, &res).await
     }
 }
@@ -1,8 +1,32 @@
 //! ## To do
 //!
-//! * De-stringify types such as Hashes, etc.
-
-use crate::prelude::*;
+//! * De-stringify asset data.
+
+/// The EIP-712 hash identifying an [`Order`].
+pub use crate::types::EthHash as OrderHash;
+use super::sketch::{Sketch, DEFAULT_CELLS};
+use crate::{
+    prelude::*,
+    types::{EthAddress, U256},
+};
+use sha3::{Digest, Keccak256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Set reconciliation subprotocol, see [`Sketch`]. Not part of Go 0x Mesh.
+pub const SKETCH_PROTOCOL: &str = "/pagination-with-sketch/version/0";
+
+/// A signature that can not be checked against the maker address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+pub enum SignatureError {
+    #[error("Signature is not hex encoded")]
+    InvalidHex,
+    #[error("Signature of {0} bytes, expected 66")]
+    InvalidLength(usize),
+    #[error("Signature type {0} can not be verified without the chain")]
+    UnsupportedType(u8),
+    #[error("Signature does not recover a public key")]
+    Unrecoverable,
+}
 
 /// The OrderSync protocol uses the same internally tagged JSON object
 /// for request and response.
@@ -41,6 +65,13 @@ pub enum RequestMetadata {
     V1 {
         min_order_hash: String,
 
+        #[serde(rename = "orderfilter")]
+        order_filter: OrderFilter,
+    },
+    /// Hex encoded [`Sketch`] of the hashes of the requester's orders.
+    Sketch {
+        sketch: String,
+
         #[serde(rename = "orderfilter")]
         order_filter: OrderFilter,
     },
@@ -52,6 +83,15 @@ pub struct Response {
 
     pub complete: bool,
 
+    /// Orders the requester is missing, in answer to a sketch.
+    #[serde(rename = "ordersAdded", default, skip_serializing_if = "Vec::is_empty")]
+    pub orders_added: Vec<Order>,
+
+    /// Hashes of the requester's orders that the responder does not have, in
+    /// answer to a sketch.
+    #[serde(rename = "ordersRemoved", default, skip_serializing_if = "Vec::is_empty")]
+    pub orders_removed: Vec<OrderHash>,
+
     #[serde(flatten)]
     pub metadata: ResponseMetadata,
 }
@@ -70,28 +110,31 @@ pub enum ResponseMetadata {
     #[serde(rename = "/pagination-with-filter/version/1")]
     #[serde(rename_all = "camelCase")]
     V1 { next_min_order_hash: String },
+    /// The difference is in `orders_added` and `orders_removed`.
+    #[serde(rename = "/pagination-with-sketch/version/0")]
+    Sketch,
 }
 
 /// See <https://github.com/0xProject/0x-mesh/blob/b2a12fdb186fb56eb7d99dc449b9773d0943ee8e/zeroex/order.go#L538>
 #[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Order {
-    pub chain_id:                i64, // Note: Unlike elsewhere, not renamed to chainID
-    pub exchange_address:        String,
-    pub maker_address:           String,
+    pub chain_id:                u64, // Note: Unlike elsewhere, not renamed to chainID
+    pub exchange_address:        EthAddress,
+    pub maker_address:           EthAddress,
     pub maker_asset_data:        String,
     pub maker_fee_asset_data:    String,
-    pub maker_asset_amount:      String,
-    pub maker_fee:               String,
-    pub taker_address:           String,
+    pub maker_asset_amount:      U256,
+    pub maker_fee:               U256,
+    pub taker_address:           EthAddress,
     pub taker_asset_data:        String,
     pub taker_fee_asset_data:    String,
-    pub taker_asset_amount:      String,
-    pub taker_fee:               String,
-    pub sender_address:          String,
-    pub fee_recipient_address:   String,
-    pub expiration_time_seconds: String,
-    pub salt:                    String,
+    pub taker_asset_amount:      U256,
+    pub taker_fee:               U256,
+    pub sender_address:          EthAddress,
+    pub fee_recipient_address:   EthAddress,
+    pub expiration_time_seconds: U256,
+    pub salt:                    U256,
     pub signature:               String,
 }
 
@@ -102,39 +145,252 @@ pub struct OrderFilter {
     pub custom_order_schema: String,
 
     #[serde(rename = "chainID")]
-    pub chain_id: i64,
+    pub chain_id: u64,
 
-    pub exchange_address: String,
+    pub exchange_address: EthAddress,
 }
 
 impl Default for OrderFilter {
     fn default() -> Self {
         Self {
-            chain_id:            i64::default(),
+            chain_id:            u64::default(),
             custom_order_schema: "{}".into(),
-            exchange_address:    "0x0000000000000000000000000000000000000000".into(),
+            exchange_address:    EthAddress::default(),
         }
     }
 }
 
 impl OrderFilter {
-    #[allow(dead_code)]
     pub fn mainnet_v3() -> Self {
         Self {
             chain_id: 1,
-            exchange_address: "0x61935cbdd02287b511119ddb11aeb42f1593b7ef".into(),
+            exchange_address: "0x61935cbdd02287b511119ddb11aeb42f1593b7ef".parse().unwrap(),
             ..Self::default()
         }
     }
 
-    #[allow(dead_code)]
+    /// Filter for 0x v3 orders on a chain, if the exchange address on that
+    /// chain is known.
+    ///
+    /// See <https://github.com/0xProject/0x-monorepo/blob/development/packages/contract-addresses/addresses.json>
+    pub fn v3(chain_id: u64) -> Option<Self> {
+        let exchange_address = match chain_id {
+            1 => return Some(Self::mainnet_v3()),
+            3 => "0xfb2dd2a1366de37f7241c83d47da58fd503e2c64",
+            4 => "0x198805e9682fceec29413059b68550f92868c129",
+            42 => "0x4eacd0af335451709e1e7b570b8ea68edec8bc97",
+            1337 => "0x48bacb9266a570d521063ef5dd96e61686dbe788",
+            _ => return None,
+        };
+        Some(Self {
+            chain_id,
+            exchange_address: exchange_address.parse().unwrap(),
+            ..Self::default()
+        })
+    }
+
     pub fn mainnet_v2() -> Self {
         Self {
             chain_id: 1,
-            exchange_address: "0x080bf510fcbf18b91105470639e9561022937712".into(),
+            exchange_address: "0x080bf510fcbf18b91105470639e9561022937712".parse().unwrap(),
             ..Self::default()
         }
     }
+
+    /// Whether an order is for this filter's chain and exchange. A zero chain
+    /// id or exchange address matches any, so the default filter matches all
+    /// orders. The custom order schema is not evaluated.
+    pub fn matches(&self, order: &Order) -> bool {
+        (self.chain_id == 0 || order.chain_id == self.chain_id)
+            && (self.exchange_address == EthAddress::default()
+                || order.exchange_address == self.exchange_address)
+    }
+}
+
+/// Reasons an [`OrderFilterBuilder`] refuses to build.
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum InvalidOrderFilter {
+    #[error("Chain id is zero")]
+    ZeroChainId,
+    #[error("Exchange address is zero")]
+    ZeroExchangeAddress,
+    #[error("Custom order schema is not valid JSON: {0}")]
+    InvalidSchema(String),
+}
+
+/// Builds an [`OrderFilter`] for a specific chain and exchange. Unlike the
+/// default filter, which matches any order, built filters always name both.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct OrderFilterBuilder {
+    filter: OrderFilter,
+}
+
+impl OrderFilterBuilder {
+    pub fn chain_id(mut self, chain_id: u64) -> Self {
+        self.filter.chain_id = chain_id;
+        self
+    }
+
+    pub fn exchange_address(mut self, exchange_address: EthAddress) -> Self {
+        self.filter.exchange_address = exchange_address;
+        self
+    }
+
+    /// JSON schema orders must match, `{}` accepts any order.
+    pub fn custom_schema(mut self, schema: String) -> Self {
+        self.filter.custom_order_schema = schema;
+        self
+    }
+
+    pub fn build(self) -> Result<OrderFilter, InvalidOrderFilter> {
+        if self.filter.chain_id == 0 {
+            return Err(InvalidOrderFilter::ZeroChainId);
+        }
+        if self.filter.exchange_address == EthAddress::default() {
+            return Err(InvalidOrderFilter::ZeroExchangeAddress);
+        }
+        serde_json::from_str::<serde_json::Value>(&self.filter.custom_order_schema)
+            .map_err(|err| InvalidOrderFilter::InvalidSchema(err.to_string()))?;
+        Ok(self.filter)
+    }
+}
+
+/// EIP-712 domain of the 0x v3 exchange.
+///
+/// See <https://github.com/0xProject/0x-mesh/blob/b2a12fdb186fb56eb7d99dc449b9773d0943ee8e/zeroex/order.go#L27>
+const EIP712_DOMAIN_SCHEMA: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+const EIP712_DOMAIN_NAME: &str = "0x Protocol";
+const EIP712_DOMAIN_VERSION: &str = "3.0.0";
+const EIP712_ORDER_SCHEMA: &str = "Order(address makerAddress,address takerAddress,\
+                                   address feeRecipientAddress,address senderAddress,\
+                                   uint256 makerAssetAmount,uint256 takerAssetAmount,\
+                                   uint256 makerFee,uint256 takerFee,\
+                                   uint256 expirationTimeSeconds,uint256 salt,\
+                                   bytes makerAssetData,bytes takerAssetData,\
+                                   bytes makerFeeAssetData,bytes takerFeeAssetData)";
+
+impl Order {
+    /// The EIP-712 order hash, as signed by the maker.
+    ///
+    /// Malformed asset data is hashed as empty. Such orders do not match their
+    /// signature and are rejected once signatures are verified.
+    pub fn hash(&self) -> OrderHash {
+        self.eip712_hash(self.chain_id, self.exchange_address)
+    }
+
+    /// The EIP-712 hash of the order in the domain of the exchange contract
+    /// at `exchange_address` on chain `chain_id`, ignoring the chain and
+    /// exchange of the order itself.
+    ///
+    /// See <https://github.com/0xProject/0x-mesh/blob/b2a12fdb186fb56eb7d99dc449b9773d0943ee8e/zeroex/order.go#L27>
+    pub fn eip712_hash(&self, chain_id: u64, exchange_address: EthAddress) -> OrderHash {
+        let domain = keccak(&[
+            &keccak(&[EIP712_DOMAIN_SCHEMA.as_bytes()]),
+            &keccak(&[EIP712_DOMAIN_NAME.as_bytes()]),
+            &keccak(&[EIP712_DOMAIN_VERSION.as_bytes()]),
+            &encode_uint(&U256::from(chain_id)),
+            &encode_address(&exchange_address),
+        ]);
+        let order = keccak(&[
+            &keccak(&[EIP712_ORDER_SCHEMA.as_bytes()]),
+            &encode_address(&self.maker_address),
+            &encode_address(&self.taker_address),
+            &encode_address(&self.fee_recipient_address),
+            &encode_address(&self.sender_address),
+            &encode_uint(&self.maker_asset_amount),
+            &encode_uint(&self.taker_asset_amount),
+            &encode_uint(&self.maker_fee),
+            &encode_uint(&self.taker_fee),
+            &encode_uint(&self.expiration_time_seconds),
+            &encode_uint(&self.salt),
+            &keccak(&[&decode_hex(&self.maker_asset_data)]),
+            &keccak(&[&decode_hex(&self.taker_asset_data)]),
+            &keccak(&[&decode_hex(&self.maker_fee_asset_data)]),
+            &keccak(&[&decode_hex(&self.taker_fee_asset_data)]),
+        ]);
+        OrderHash(keccak(&[b"\x19\x01", &domain, &order]))
+    }
+
+    /// The order hash as `0x` followed by 64 lowercase hex digits.
+    pub fn hash_hex(&self) -> String {
+        self.hash().to_string()
+    }
+
+    /// Whether the signature recovers to the maker address.
+    ///
+    /// Only `EIP712` and `EthSign` signatures can be checked this way, the
+    /// other types are verified by contracts and are an error.
+    pub fn verify_signature(&self) -> Result<bool, SignatureError> {
+        let bytes = hex::decode(self.signature.trim_start_matches("0x"))
+            .map_err(|_| SignatureError::InvalidHex)?;
+        let digest = match bytes.last() {
+            Some(2) => self.hash().0,
+            Some(3) => keccak(&[b"\x19Ethereum Signed Message:\n32", &self.hash().0]),
+            Some(&other) => return Err(SignatureError::UnsupportedType(other)),
+            None => return Err(SignatureError::InvalidLength(0)),
+        };
+        if bytes.len() != 66 {
+            return Err(SignatureError::InvalidLength(bytes.len()));
+        }
+        let recovery_id = secp256k1::RecoveryId::parse_rpc(bytes[0])
+            .map_err(|_| SignatureError::Unrecoverable)?;
+        let signature = secp256k1::Signature::parse_slice(&bytes[1..65])
+            .map_err(|_| SignatureError::Unrecoverable)?;
+        let message = secp256k1::Message::parse(&digest);
+        let public_key = secp256k1::recover(&message, &signature, &recovery_id)
+            .map_err(|_| SignatureError::Unrecoverable)?;
+        let signer = keccak(&[&public_key.serialize()[1..]]);
+        Ok(signer[12..] == self.maker_address.0)
+    }
+
+    /// The expiration as a point in time, or `None` if it is too far in the
+    /// future to represent.
+    pub fn expiration_time(&self) -> Option<SystemTime> {
+        UNIX_EPOCH.checked_add(Duration::from_secs(
+            self.expiration_time_seconds.saturating_u64(),
+        ))
+    }
+
+    /// Whether the order is expired at unix time `now` in seconds. As on
+    /// chain, an order expires at its expiration time, not after it.
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        self.expiration_time_seconds.saturating_u64() <= now
+    }
+
+    /// Whether the order is expired at the current time.
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.is_expired_at(now)
+    }
+}
+
+fn keccak(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn decode_hex(value: &str) -> Vec<u8> {
+    hex::decode(value.trim_start_matches("0x")).unwrap_or_default()
+}
+
+/// ABI encode an address as a 32 byte word.
+fn encode_address(address: &EthAddress) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[12..].copy_from_slice(&address.0);
+    word
+}
+
+/// ABI encode a number as a big endian 256 bit word.
+fn encode_uint(value: &U256) -> [u8; 32] {
+    let mut word = [0_u8; 32];
+    value.to_big_endian(&mut word);
+    word
 }
 
 impl Default for Request {
@@ -146,9 +402,11 @@ impl Default for Request {
 impl Default for Response {
     fn default() -> Self {
         Self {
-            complete: true,
-            orders:   vec![],
-            metadata: ResponseMetadata::V0 {
+            complete:       true,
+            orders:         vec![],
+            orders_added:   vec![],
+            orders_removed: vec![],
+            metadata:       ResponseMetadata::V0 {
                 page:        0,
                 snapshot_id: "".into(),
             },
@@ -157,14 +415,56 @@ impl Default for Response {
 }
 
 impl Response {
+    /// The request for the next page. A sketch is answered in one response,
+    /// unless the difference was too large to decode. The responder then
+    /// starts a `/pagination-with-filter/version/1` sync instead.
     pub fn next_request(&self, order_filter: OrderFilter) -> Option<Request> {
         if self.complete { None } else {
-            Some(self.metadata.next_request_metadata(order_filter).into())
+            self.metadata.next_request_metadata(order_filter).map(Request::from)
+        }
+    }
+
+    /// Whether the server no longer has the V0 snapshot that `request`
+    /// continues. The server then starts over with a new snapshot, so the
+    /// snapshot id or page number differ from what was requested.
+    pub fn snapshot_expired(&self, request: &Request) -> bool {
+        match (request.metadata.metadata.as_slice(), &self.metadata) {
+            (
+                [RequestMetadata::V0 {
+                    snapshot_id, page, ..
+                }],
+                ResponseMetadata::V0 {
+                    snapshot_id: response_snapshot_id,
+                    page: response_page,
+                },
+            ) if !snapshot_id.is_empty() => {
+                snapshot_id != response_snapshot_id || page != response_page
+            }
+            _ => false,
         }
     }
 }
 
 
+impl Request {
+    /// Request the difference between the orders with `hashes` and those of
+    /// the peer, falling back to pagination for peers without the sketch
+    /// subprotocol.
+    pub fn with_sketch<'a>(
+        order_filter: OrderFilter,
+        hashes: impl IntoIterator<Item = &'a OrderHash>,
+    ) -> Self {
+        let sketch = Sketch::from_hashes(DEFAULT_CELLS, hashes);
+        let mut request = Self::from(order_filter.clone());
+        request.subprotocols.insert(0, SKETCH_PROTOCOL.into());
+        request.metadata.metadata.insert(0, RequestMetadata::Sketch {
+            sketch: sketch.to_hex(),
+            order_filter,
+        });
+        request
+    }
+}
+
 impl From<OrderFilter> for Request {
     fn from(order_filter: OrderFilter) -> Self {
         Self {
@@ -196,6 +496,7 @@ impl RequestMetadata {
         match self {
             Self::V0 { .. } => "/pagination-with-filter/version/0",
             Self::V1 { .. } => "/pagination-with-filter/version/1",
+            Self::Sketch { .. } => SKETCH_PROTOCOL,
         }
     }
 
@@ -203,6 +504,7 @@ impl RequestMetadata {
         match self {
             Self::V0 { order_filter, .. } => order_filter,
             Self::V1 { order_filter, .. } => order_filter,
+            Self::Sketch { order_filter, .. } => order_filter,
         }
     }
 
@@ -210,28 +512,30 @@ impl RequestMetadata {
         match self {
             Self::V0 { order_filter, .. } => order_filter,
             Self::V1 { order_filter, .. } => order_filter,
+            Self::Sketch { order_filter, .. } => order_filter,
         }
     }
 }
 
 impl ResponseMetadata {
-    fn next_request_metadata(&self, order_filter:OrderFilter) -> RequestMetadata {
+    fn next_request_metadata(&self, order_filter:OrderFilter) -> Option<RequestMetadata> {
         match self {
             ResponseMetadata::V0 { page, snapshot_id } => {
-                RequestMetadata::V0 {
+                Some(RequestMetadata::V0 {
                     page: page + 1,
                     snapshot_id: snapshot_id.clone(),
                     order_filter,
-                }
+                })
             }
             ResponseMetadata::V1 {
                 next_min_order_hash,
             } => {
-                RequestMetadata::V1 {
+                Some(RequestMetadata::V1 {
                     min_order_hash: next_min_order_hash.clone(),
                     order_filter,
-                }
+                })
             }
+            ResponseMetadata::Sketch => None,
         }
     }
 }
@@ -323,7 +627,7 @@ mod test {
         let order_filter = OrderFilter {
             chain_id:            4,
             custom_order_schema: "{}".into(),
-            exchange_address:    "0x198805e9682fceec29413059b68550f92868c129".into(),
+            exchange_address:    "0x198805e9682fceec29413059b68550f92868c129".parse().unwrap(),
         };
         assert_eq!(
             message,
@@ -361,6 +665,7 @@ mod test {
                     .into(),
             },
             orders:   vec![], // TODO: Add content
+            ..Response::default()
         });
         assert_eq!(
             serde_json::to_value(&message).unwrap(),
@@ -376,9 +681,201 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_sketch_json() {
+        let hash = OrderHash([1; 32]);
+        let request = Message::Request(Request::with_sketch(OrderFilter::mainnet_v3(), &[hash]));
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["subprotocols"][0], SKETCH_PROTOCOL);
+        assert!(json["metadata"]["metadata"][0]["sketch"].as_str().unwrap().starts_with("0x"));
+        assert_eq!(serde_json::from_value::<Message>(json).unwrap(), request);
+
+        let response = Message::Response(Response {
+            orders_removed: vec![hash],
+            metadata: ResponseMetadata::Sketch,
+            ..Response::default()
+        });
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "type": "Response",
+                "subprotocol": SKETCH_PROTOCOL,
+                "orders": [],
+                "ordersRemoved": [hash.to_string()],
+                "complete": true,
+            })
+        );
+        assert_eq!(serde_json::from_value::<Message>(json).unwrap(), response);
+    }
+
     #[test]
     fn test_parse_response() {
         let response = include_str!("../../../../test/response.json");
-        let _message = serde_json::from_str::<Message>(response).unwrap();
+        let message = serde_json::from_str::<Message>(response).unwrap();
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::from_str::<serde_json::Value>(response).unwrap()
+        );
+        let orders = match message {
+            Message::Response(response) => response.orders,
+            Message::Request(_) => panic!("Expected a response"),
+        };
+
+        // Amounts round trip as the same decimal strings.
+        let mut bytes = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+        let mut serializer = serde_json::Serializer::with_formatter(&mut bytes, formatter);
+        orders.serialize(&mut serializer).unwrap();
+        let orders_json = String::from_utf8(bytes).unwrap().replace('\n', "\n    ");
+        assert!(response.contains(&format!("\"orders\": {},", orders_json)));
+
+        let order = &orders[0];
+        assert_eq!(order.maker_asset_amount, U256::from(20_000_000_000_000_000_u64));
+        assert_eq!(
+            order.expiration_time(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_774_301_511))
+        );
+    }
+
+    #[test]
+    fn test_v3_filters() {
+        assert_eq!(OrderFilter::v3(1), Some(OrderFilter::mainnet_v3()));
+        for &chain_id in &[3, 4, 42, 1337] {
+            let order_filter = OrderFilter::v3(chain_id).unwrap();
+            assert_eq!(order_filter.chain_id, chain_id);
+            assert_ne!(
+                order_filter.exchange_address,
+                OrderFilter::mainnet_v3().exchange_address
+            );
+        }
+        assert_eq!(OrderFilter::v3(1234), None);
+    }
+
+    #[test]
+    fn test_order_filter_builder() {
+        let exchange_address = OrderFilter::mainnet_v3().exchange_address;
+        let builder = OrderFilterBuilder::default()
+            .chain_id(1)
+            .exchange_address(exchange_address);
+        assert_eq!(builder.clone().build(), Ok(OrderFilter::mainnet_v3()));
+        let schema = r#"{"properties":{"makerAddress":{"const":"0x0"}}}"#;
+        assert_eq!(
+            builder.clone().custom_schema(schema.into()).build(),
+            Ok(OrderFilter {
+                custom_order_schema: schema.into(),
+                ..OrderFilter::mainnet_v3()
+            })
+        );
+
+        assert_eq!(
+            OrderFilterBuilder::default().build(),
+            Err(InvalidOrderFilter::ZeroChainId)
+        );
+        assert_eq!(
+            builder.clone().chain_id(0).build(),
+            Err(InvalidOrderFilter::ZeroChainId)
+        );
+        assert_eq!(
+            builder.clone().exchange_address(EthAddress::default()).build(),
+            Err(InvalidOrderFilter::ZeroExchangeAddress)
+        );
+        assert!(matches!(
+            builder.custom_schema("{".into()).build(),
+            Err(InvalidOrderFilter::InvalidSchema(_))
+        ));
+    }
+
+    #[test]
+    fn test_order_hash() {
+        let response = include_str!("../../../../test/response.json");
+        let orders = match serde_json::from_str::<Message>(response).unwrap() {
+            Message::Response(response) => response.orders,
+            Message::Request(_) => panic!("Expected a response"),
+        };
+
+        // Verified by recovering the maker address from the EIP-712 signatures.
+        let expected = [
+            (0, "0x0028d86dbbff462dcb1bf2fdea815bc905307a03a0d40436cc7c15a6f605b0f5"),
+            (1, "0x0037ff3a998efa64f5262cac55e1fcc2d625fe287f02ff1b7673eda678287360"),
+            (3, "0x16a6aabe68abda7f91644f3e1e535bf62a6132933d049eb6120d10488694c0af"),
+        ];
+        for &(index, hash) in &expected {
+            assert_eq!(orders[index].hash_hex(), hash);
+            assert_eq!(orders[index].hash(), hash.parse().unwrap());
+        }
+
+        // The domain separator commits to the chain and exchange.
+        let order = &orders[0];
+        let other_chain = Order {
+            chain_id: 3,
+            ..order.clone()
+        };
+        let other_exchange = Order {
+            exchange_address: OrderFilter::mainnet_v2().exchange_address,
+            ..order.clone()
+        };
+        assert_ne!(other_chain.hash(), order.hash());
+        assert_ne!(other_exchange.hash(), order.hash());
+        assert_eq!(order.eip712_hash(3, order.exchange_address), other_chain.hash());
+        assert_eq!(
+            order.eip712_hash(order.chain_id, other_exchange.exchange_address),
+            other_exchange.hash()
+        );
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let order = Order {
+            expiration_time_seconds: 1_600_000_000.into(),
+            ..Order::default()
+        };
+        assert!(!order.is_expired_at(1_599_999_999));
+        assert!(order.is_expired_at(1_600_000_000));
+        assert!(order.is_expired_at(1_600_000_001));
+        assert!(order.is_expired());
+
+        let far_future = Order {
+            expiration_time_seconds: U256::MAX,
+            ..Order::default()
+        };
+        assert!(!far_future.is_expired());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let response = include_str!("../../../../test/response.json");
+        let orders = match serde_json::from_str::<Message>(response).unwrap() {
+            Message::Response(response) => response.orders,
+            Message::Request(_) => panic!("Expected a response"),
+        };
+
+        // Orders 2 and 4 are `EthSign`, the others `EIP712`.
+        for order in &orders {
+            assert_eq!(order.verify_signature(), Ok(true));
+        }
+        let tampered = Order {
+            salt: orders[0].salt + 1,
+            ..orders[0].clone()
+        };
+        assert_eq!(tampered.verify_signature(), Ok(false));
+
+        let with_signature = |signature: String| {
+            Order {
+                signature,
+                ..orders[0].clone()
+            }
+        };
+        let cases = [
+            ("0xzz".into(), SignatureError::InvalidHex),
+            ("0x".into(), SignatureError::InvalidLength(0)),
+            ("0x1b02".into(), SignatureError::InvalidLength(2)),
+            (format!("0x{}04", "1b".repeat(65)), SignatureError::UnsupportedType(4)),
+            (format!("0x00{}02", "1b".repeat(64)), SignatureError::Unrecoverable),
+            (format!("0x1b{}02", "00".repeat(64)), SignatureError::Unrecoverable),
+        ];
+        for (signature, error) in cases.iter().cloned() {
+            assert_eq!(with_signature(signature).verify_signature(), Err(error));
+        }
     }
 }
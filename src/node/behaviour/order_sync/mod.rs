@@ -6,43 +6,83 @@
 //!
 //! [sub]: https://github.com/paritytech/substrate/blob/6b600cdeb4043e512bc5f342eb02a5a17d26797a/client/network/src/request_responses.rs#L59
 //!
-//! TODO: Add Throttling: https://docs.rs/libp2p/0.32.2/libp2p/request_response/struct.Throttled.html
+//! Outbound requests are rate limited per peer and in total by a
+//! [`ThrottleConfig`], requests over the limits are queued. Inbound requests
+//! are limited the same way with a separate [`ThrottleConfig`], defaulting to
+//! 10 requests per peer per second and 32 concurrently handled requests.
+//! Requests over those limits are not answered, so the remote sees its stream
+//! closed. libp2p's `Throttled` wrapper is not used, because it adds a header
+//! to every message that Go 0x Mesh peers do not understand.
 //!
-//! TODO:
-//!
-//! This protocol implements set reconciliation, but does so in a rather
-//! inefficient way (bulk transfer of all the orders). There more efficient
-//! reconciliation algorithms out there that efficiently compute the set
-//! difference first. For an academic overview see
+//! The pagination subprotocols implement set reconciliation rather
+//! inefficiently, by bulk transfer of all the orders. Requests made with
+//! [`Request::with_sketch`] also offer `/pagination-with-sketch/version/0`,
+//! which computes the set difference first and transfers only that. For an
+//! academic overview see
 //!
 //! * Ivo Kubjas (2014). "Set Reconciliation Master Thesis". [pdf](https://comserv.cs.ut.ee/home/files/kubjas_cybersecurity_2014.pdf?study=ATILoputoo&reference=E731444824814AE27FE0D91FA073B5F3FE61038D)
-//!
-//! There is a crate for Minisketch that should allow prototyping something:
-//!
-//! <https://docs.rs/minisketch-rs/0.1.9/minisketch_rs/>
 
+mod circuit_breaker;
 mod json_codec;
 pub mod messages;
+mod pages;
+mod responder;
+mod sketch;
+mod throttle;
 
+#[cfg(test)]
+pub use self::circuit_breaker::BreakerState;
+pub use self::{
+    pages::DEFAULT_MAX_PAGE_BYTES,
+    responder::{OrderSource, OrderSyncHandler},
+    throttle::ThrottleConfig,
+};
+use super::discovery::PeerAccess;
 use self::{
+    circuit_breaker::{CircuitBreaker, COOLDOWN},
     json_codec::JsonCodec,
     messages::{Message, Request, Response},
+    responder::respond,
+    throttle::Throttle,
+};
+use crate::{prelude::*, utils::ShutdownTrigger};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    stream::FuturesUnordered,
 };
-use crate::prelude::*;
-use futures::channel::{mpsc, oneshot};
 use libp2p::{
     core::ProtocolName,
     request_response::{
-        OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
-        RequestResponseEvent, RequestResponseMessage,
+        InboundFailure, OutboundFailure, ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage, ResponseChannel,
     },
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour, PeerId,
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use std::{collections::HashMap, iter, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep_until, Sleep};
+
+/// Maximum request size in bytes. Fits the largest accepted sketch.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Maximum response size in bytes. Responses contain a page of orders and are
+/// much larger than requests.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Default number of times a request is re-sent after a dial failure, once
+/// the peer's addresses have been resolved again.
+pub const DEFAULT_DIAL_RETRIES: usize = 1;
 
-/// Maximum message size
-const MAX_SIZE: usize = 1024;
+/// Default maximum number of outbound requests awaiting a response.
+const MAX_PENDING_REQUESTS: usize = 64;
 
 #[derive(Clone, Debug)]
 pub struct Version();
@@ -63,6 +103,27 @@ pub enum Error {
     #[error("OrderSync send queue is full.")]
     QueueFull,
 
+    #[error("Peer is banned.")]
+    Banned,
+
+    #[error("Peer is not in the allowed peers.")]
+    NotAllowed,
+
+    #[error("Read replicas do not send OrderSync requests.")]
+    ReadReplica,
+
+    #[error("Peer failed too many requests, skipping it for now.")]
+    CircuitOpen,
+
+    #[error("OrderSync snapshot kept expiring during pagination.")]
+    SnapshotExpired,
+
+    #[error("Fetch was cancelled.")]
+    Cancelled,
+
+    #[error("No response received in time.")]
+    Timeout,
+
     #[error("Failure during request: {0:?}")]
     OutboundFailure(OutboundFailure),
 
@@ -86,34 +147,493 @@ impl From<oneshot::Canceled> for Error {
     }
 }
 
+/// Failed requests, split by whether the protocol could be negotiated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NegotiationFailures {
+    /// Outbound requests to peers that do not support OrderSync.
+    pub unsupported_outbound: u64,
+    /// Inbound streams for a protocol we do not support.
+    pub unsupported_inbound:  u64,
+    /// Outbound requests that failed after the protocol was negotiated, by
+    /// timing out or losing the connection.
+    pub failed_outbound:      u64,
+}
+
+/// Counts of OrderSync requests and the orders they returned.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RequestStats {
+    /// Outbound requests accepted for sending, retries are not counted.
+    pub sent:           u64,
+    /// Inbound requests, including those rejected by the rate limits.
+    pub received:       u64,
+    /// Outbound requests answered with a response.
+    pub succeeded:      u64,
+    /// Outbound requests that failed without a response.
+    pub failed:         u64,
+    /// Orders in the responses to outbound requests.
+    pub orders_fetched: u64,
+}
+
+/// Events emitted by the [`OrderSync`] behaviour.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum OrderSyncEvent {
+    /// Dialing the peer failed. Requests to it are held until its addresses
+    /// are resolved again, after which [`OrderSync::retry`] should be called.
+    ResolvePeer(PeerId),
+
+    /// A request to the peer failed without a response.
+    RequestFailed(PeerId),
+}
+
+/// An inbound request being answered by the [`OrderSyncHandler`].
+type Handling = BoxFuture<
+    'static,
+    (
+        PeerId,
+        RequestId,
+        ResponseChannel<Message>,
+        anyhow::Result<Response>,
+    ),
+>;
+
+/// An outbound request awaiting its response.
+struct PendingRequest {
+    request:      Request,
+    sender:       oneshot::Sender<Result>,
+    dial_retries: usize,
+}
+
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "OrderSyncEvent", poll_method = "poll")]
 pub struct OrderSync {
     request_response: RequestResponse<Codec>,
 
     #[behaviour(ignore)]
-    pending_requests: HashMap<RequestId, oneshot::Sender<Result>>,
+    pending_requests: HashMap<RequestId, PendingRequest>,
+
+    /// Requests that failed to dial, waiting for the peer to be resolved.
+    #[behaviour(ignore)]
+    unresolved_requests: HashMap<PeerId, Vec<PendingRequest>>,
+
+    /// Requests over the rate limits, sent in order as the limits allow.
+    #[behaviour(ignore)]
+    throttled_requests: VecDeque<(PeerId, PendingRequest)>,
+
+    #[behaviour(ignore)]
+    throttle: Throttle,
+
+    /// Rate limits for inbound requests.
+    #[behaviour(ignore)]
+    inbound_throttle: Throttle,
+
+    /// Number of inbound requests rejected by the rate limits.
+    #[behaviour(ignore)]
+    rejected_inbound: u64,
+
+    #[behaviour(ignore)]
+    negotiation_failures: NegotiationFailures,
+
+    #[behaviour(ignore)]
+    request_stats: RequestStats,
+
+    /// Wakes the behaviour when a throttled request may be sent.
+    #[behaviour(ignore)]
+    throttle_timer: Option<Pin<Box<Sleep>>>,
+
+    #[behaviour(ignore)]
+    max_dial_retries: usize,
+
+    #[behaviour(ignore)]
+    max_pending_requests: usize,
+
+    /// Byte budget for the orders in a response page.
+    #[behaviour(ignore)]
+    max_page_bytes: usize,
+
+    /// Orders served to peers, they get empty responses without one.
+    #[behaviour(ignore)]
+    order_source: Option<Arc<dyn OrderSource>>,
+
+    /// Answers inbound requests instead of the order source.
+    #[behaviour(ignore)]
+    handler: Option<Arc<dyn OrderSyncHandler>>,
+
+    #[behaviour(ignore)]
+    handling: FuturesUnordered<Handling>,
+
+    /// Skips peers that keep failing requests.
+    #[behaviour(ignore)]
+    breaker: CircuitBreaker,
+
+    /// Bans and allowlist, requests to peers they refuse fail.
+    #[behaviour(ignore)]
+    peer_access: Arc<RwLock<PeerAccess>>,
+
+    /// Interrupts reads in connection handlers when the behaviour is dropped.
+    #[behaviour(ignore)]
+    _shutdown: ShutdownTrigger,
+
+    #[behaviour(ignore)]
+    events: VecDeque<OrderSyncEvent>,
+}
+
+/// Create a codec with the message size limits of this protocol.
+pub const fn codec() -> Codec {
+    JsonCodec::new(MAX_REQUEST_SIZE, MAX_RESPONSE_SIZE)
 }
 
 impl OrderSync {
-    pub fn new() -> Self {
-        let protocols = iter::once((Version(), ProtocolSupport::Full));
-        let codec = JsonCodec::default();
+    /// Create an OrderSync behaviour that answers inbound requests with
+    /// `handler`, or from the order source if there is none.
+    #[cfg(test)]
+    pub fn new(handler: Option<Arc<dyn OrderSyncHandler>>) -> Self {
+        Self::with_support(ProtocolSupport::Full, handler)
+    }
+
+    /// Create an OrderSync behaviour for the given directions. Observers use
+    /// [`ProtocolSupport::Outbound`] to request orders without serving them,
+    /// seed nodes use [`ProtocolSupport::Inbound`] to serve orders without
+    /// requesting any. Only protocols accepting inbound requests are
+    /// advertised through Identify.
+    pub fn with_support(
+        support: ProtocolSupport,
+        handler: Option<Arc<dyn OrderSyncHandler>>,
+    ) -> Self {
+        let protocols = iter::once((Version(), support));
+        let (shutdown, token) = ShutdownTrigger::new();
+        let codec = codec().with_shutdown(token);
         let mut config = Config::default();
         config.set_request_timeout(Duration::from_secs(30)); // Same as Go 0x-mesh
         Self {
-            request_response: RequestResponse::new(codec, protocols, config),
-            pending_requests: HashMap::new(),
+            request_response:     RequestResponse::new(codec, protocols, config),
+            pending_requests:     HashMap::new(),
+            unresolved_requests:  HashMap::new(),
+            throttled_requests:   VecDeque::new(),
+            throttle:             Throttle::new(ThrottleConfig::default()),
+            inbound_throttle:     Throttle::new(ThrottleConfig::default()),
+            rejected_inbound:     0,
+            negotiation_failures: NegotiationFailures::default(),
+            request_stats:        RequestStats::default(),
+            throttle_timer:       None,
+            max_dial_retries:     DEFAULT_DIAL_RETRIES,
+            max_pending_requests: MAX_PENDING_REQUESTS,
+            max_page_bytes:       DEFAULT_MAX_PAGE_BYTES,
+            order_source:         None,
+            handler,
+            handling:             FuturesUnordered::new(),
+            breaker:              CircuitBreaker::new(COOLDOWN),
+            peer_access:          Arc::default(),
+            _shutdown:            shutdown,
+            events:               VecDeque::new(),
         }
     }
 
+    /// Set the number of times a request is retried after a dial failure.
+    pub const fn set_max_dial_retries(&mut self, retries: usize) -> &mut Self {
+        self.max_dial_retries = retries;
+        self
+    }
+
+    /// Set the maximum number of requests awaiting a response. Requests beyond
+    /// this fail immediately with [`Error::QueueFull`].
+    pub const fn set_max_pending_requests(&mut self, max: usize) -> &mut Self {
+        self.max_pending_requests = max;
+        self
+    }
+
+    /// Set the rate limits for outbound requests.
+    pub fn set_throttle(&mut self, config: ThrottleConfig) -> &mut Self {
+        self.throttle.set_config(config);
+        self
+    }
+
+    /// Set the rate limits for inbound requests. The concurrency limit counts
+    /// requests being handled by the [`OrderSyncHandler`].
+    pub fn set_inbound_throttle(&mut self, config: ThrottleConfig) -> &mut Self {
+        self.inbound_throttle.set_config(config);
+        self
+    }
+
+    /// Number of inbound requests rejected by the rate limits.
+    pub const fn rejected_inbound(&self) -> u64 {
+        self.rejected_inbound
+    }
+
+    /// Number of failed requests, by whether the peer supports OrderSync.
+    pub const fn negotiation_failures(&self) -> NegotiationFailures {
+        self.negotiation_failures
+    }
+
+    /// Number of requests sent, received and failed so far.
+    pub const fn request_stats(&self) -> RequestStats {
+        self.request_stats
+    }
+
+    /// Set the byte budget for the orders in a response page. It should stay
+    /// below the transport limits of the peers we serve.
+    pub const fn set_max_page_bytes(&mut self, bytes: usize) -> &mut Self {
+        self.max_page_bytes = bytes;
+        self
+    }
+
+    /// Set the orders served to peers that sync from us.
+    pub fn set_order_source(&mut self, source: Arc<dyn OrderSource>) -> &mut Self {
+        self.order_source = Some(source);
+        self
+    }
+
+    /// Share the bans and allowlist of discovery. Requests to peers they refuse
+    /// fail with [`Error::Banned`] or [`Error::NotAllowed`], including retries
+    /// of requests made before the peer was refused.
+    pub fn set_peer_access(&mut self, access: Arc<RwLock<PeerAccess>>) -> &mut Self {
+        self.peer_access = access;
+        self
+    }
+
+    /// Number of requests awaiting a response, including those waiting for
+    /// their peer to be resolved or for the rate limits.
+    pub fn pending_count(&self) -> usize {
+        let unresolved: usize = self.unresolved_requests.values().map(Vec::len).sum();
+        self.pending_requests.len() + unresolved + self.throttled_requests.len()
+    }
+
+    /// Number of requests waiting for the rate limits.
+    #[cfg(test)]
+    pub fn throttled_count(&self) -> usize {
+        self.throttled_requests.len()
+    }
+
+    /// State of the circuit breaker for requests to a peer.
+    #[cfg(test)]
+    pub fn breaker_state(&self, peer_id: &PeerId) -> BreakerState {
+        self.breaker.state(peer_id)
+    }
+
+    /// Add a known address for a peer, used when dialing it.
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) {
+        self.request_response.add_address(peer_id, address);
+    }
+
     pub fn send(&mut self, peer_id: &PeerId, request: Request, sender: oneshot::Sender<Result>) {
-        let message = Message::Request(request);
+        if let Some(error) = self.refusal(peer_id) {
+            debug!("Refusing request to {}: {}", peer_id, error);
+            let _ = sender.send(Err(error));
+            return;
+        }
+        if self.pending_count() >= self.max_pending_requests {
+            warn!("Too many pending OrderSync requests, refusing request to {}", peer_id);
+            let _ = sender.send(Err(Error::QueueFull));
+            return;
+        }
+        if !self.breaker.allow_at(peer_id, Instant::now()) {
+            debug!("Circuit breaker for {} is open, refusing request", peer_id);
+            let _ = sender.send(Err(Error::CircuitOpen));
+            return;
+        }
+        self.request_stats.sent += 1;
+        self.throttled_requests.push_back((peer_id.clone(), PendingRequest {
+            request,
+            sender,
+            dial_retries: self.max_dial_retries,
+        }));
+        let _ = self.send_throttled(Instant::now());
+    }
+
+    /// Send the queued requests that the rate limits allow at `now`, keeping
+    /// the others in order. Returns whether any were sent.
+    fn send_throttled(&mut self, now: Instant) -> bool {
+        let mut sent = false;
+        for (peer_id, pending) in std::mem::take(&mut self.throttled_requests) {
+            if self.throttle.allow_at(&peer_id, self.pending_requests.len(), now) {
+                self.throttle.record_at(&peer_id, now);
+                self.send_pending(&peer_id, pending);
+                sent = true;
+            } else {
+                self.throttled_requests.push_back((peer_id, pending));
+            }
+        }
+        self.throttle.prune_at(now);
+        sent
+    }
+
+    /// Re-send the requests that failed to dial the peer, now that its
+    /// addresses have been resolved again.
+    pub fn retry(&mut self, peer_id: &PeerId) {
+        for pending in self.unresolved_requests.remove(peer_id).unwrap_or_default() {
+            debug!("Retrying request to {} after address resolution", peer_id);
+            self.send_pending(peer_id, pending);
+        }
+    }
+
+    /// Fail the requests that are waiting for the peer to be resolved.
+    pub fn fail_unresolved(&mut self, peer_id: &PeerId) {
+        for pending in self.unresolved_requests.remove(peer_id).unwrap_or_default() {
+            self.breaker.record_failure_at(peer_id, Instant::now());
+            self.request_stats.failed += 1;
+            self.events.push_back(OrderSyncEvent::RequestFailed(peer_id.clone()));
+            let result = Err(Error::OutboundFailure(OutboundFailure::DialFailure));
+            if let Err(_result) = pending.sender.send(result) {
+                warn!("Received outbound failure for dropped handler");
+            }
+        }
+    }
+
+    /// Fail every request awaiting a response with [`Error::Dropped`].
+    /// Returns the number of requests failed.
+    pub fn cancel_all(&mut self) -> usize {
+        let pending = self.pending_requests.drain().map(|(_, pending)| pending);
+        let unresolved = self.unresolved_requests.drain().flat_map(|(_, pending)| pending);
+        let throttled = self.throttled_requests.drain(..).map(|(_, pending)| pending);
+        let mut count = 0;
+        for pending in pending.chain(unresolved).chain(throttled) {
+            let _ = pending.sender.send(Err(Error::Dropped));
+            count += 1;
+        }
+        count
+    }
+
+    /// Answer an inbound request, or start the handler on it.
+    fn answer(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        request: Request,
+        channel: ResponseChannel<Message>,
+    ) {
+        let now = Instant::now();
+        if !self.inbound_throttle.allow_at(&peer, self.handling.len(), now) {
+            // Dropping the channel closes the stream without a response.
+            debug!("Rejecting request {} from {} over the rate limits", request_id, peer);
+            self.rejected_inbound += 1;
+            return;
+        }
+        self.inbound_throttle.record_at(&peer, now);
+        self.inbound_throttle.prune_at(now);
+
+        if let Some(handler) = &self.handler {
+            let handler = handler.clone();
+            self.handling.push(
+                async move {
+                    let result = handler.handle(request).await;
+                    (peer, request_id, channel, result)
+                }
+                .boxed(),
+            );
+            return;
+        }
+        let response = match &self.order_source {
+            Some(source) => respond(source.as_ref(), &request, self.max_page_bytes),
+            None => Response::default(),
+        };
+        self.send_response(&peer, request_id, channel, response);
+    }
+
+    /// Send the response to an inbound request.
+    fn send_response(
+        &mut self,
+        peer: &PeerId,
+        request_id: RequestId,
+        channel: ResponseChannel<Message>,
+        response: Response,
+    ) {
+        debug!(
+            "Answering request {} from {} with {} orders",
+            request_id,
+            peer,
+            response.orders.len()
+        );
+        let message = Message::Response(response);
+        if let Err(_message) = self.request_response.send_response(channel, message) {
+            warn!("Connection to {} closed before responding", peer);
+        }
+    }
+
+    /// The error for requests to a banned peer or one outside the allowlist.
+    fn refusal(&self, peer_id: &PeerId) -> Option<Error> {
+        let access = self.peer_access.read().unwrap();
+        if access.banned.contains(peer_id) {
+            Some(Error::Banned)
+        } else if !access.is_allowed(peer_id) {
+            Some(Error::NotAllowed)
+        } else {
+            None
+        }
+    }
+
+    fn send_pending(&mut self, peer_id: &PeerId, pending: PendingRequest) {
+        // The peer may have been refused while the request was queued.
+        if let Some(error) = self.refusal(peer_id) {
+            debug!("Dropping queued request to {}: {}", peer_id, error);
+            self.request_stats.failed += 1;
+            let _ = pending.sender.send(Err(error));
+            return;
+        }
+        let message = Message::Request(pending.request.clone());
         let request_id = self.request_response.send_request(peer_id, message);
-        let existing = self.pending_requests.insert(request_id, sender);
+        let existing = self.pending_requests.insert(request_id, pending);
         if let Some(_existing) = existing {
             error!("Pending request with same id already exists, dropping.");
         }
     }
+
+    fn poll<TEv>(
+        &mut self,
+        cx: &mut task::Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, OrderSyncEvent>> {
+        while let Poll::Ready(Some((peer, request_id, channel, result))) =
+            self.handling.poll_next_unpin(cx)
+        {
+            match result {
+                Ok(response) => {
+                    self.send_response(&peer, request_id, channel, response);
+                    // The response is sent on the next poll of the protocol.
+                    cx.waker().wake_by_ref();
+                }
+                Err(err) => {
+                    warn!("Handling request {} from {} failed: {:?}", request_id, peer, err);
+                }
+            }
+        }
+
+        // Forget requests whose caller is no longer waiting for the response.
+        self.pending_requests
+            .retain(|_, pending| !pending.sender.is_canceled());
+        for pending in self.unresolved_requests.values_mut() {
+            pending.retain(|pending| !pending.sender.is_canceled());
+        }
+        self.unresolved_requests
+            .retain(|_, pending| !pending.is_empty());
+        self.throttled_requests
+            .retain(|(_, pending)| !pending.sender.is_canceled());
+
+        // Responses free up concurrency, time frees up the peer windows. The
+        // requests are sent on the next poll of the protocol.
+        if self.send_throttled(Instant::now()) {
+            cx.waker().wake_by_ref();
+        }
+        let next_slot = self
+            .throttled_requests
+            .iter()
+            .filter_map(|(peer_id, _)| self.throttle.next_slot(peer_id))
+            .min();
+        match next_slot {
+            Some(next) => {
+                let timer = self
+                    .throttle_timer
+                    .get_or_insert_with(|| Box::pin(sleep_until(next.into())));
+                timer.as_mut().reset(next.into());
+                if timer.as_mut().poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            None => self.throttle_timer = None,
+        }
+
+        self.events.pop_front().map_or(Poll::Pending, |event| {
+            Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
+        })
+    }
 }
 
 impl ProtocolName for Version {
@@ -132,7 +652,7 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                     RequestResponseMessage::Request {
                         request_id,
                         request,
-                        channel: _,
+                        channel,
                     },
             } => {
                 let request = match request {
@@ -145,10 +665,8 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                         return;
                     }
                 };
-                error!(
-                    "Incoming request {} from {} not handled (unimplemented): {:#?}",
-                    request_id, peer, request
-                );
+                self.request_stats.received += 1;
+                self.answer(peer, request_id, request, channel);
             }
 
             // Receive incoming response.
@@ -160,8 +678,8 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                         response,
                     },
             } => {
-                let sender = match self.pending_requests.remove(&request_id) {
-                    Some(sender) => sender,
+                let pending = match self.pending_requests.remove(&request_id) {
+                    Some(pending) => pending,
                     None => {
                         error!(
                             "Received response for unexpected request id {} from peer {}",
@@ -174,7 +692,18 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                     Message::Request(_) => Err(Error::UnexpectedRequest),
                     Message::Response(response) => Ok(response),
                 };
-                if let Err(_result) = sender.send(result) {
+                match &result {
+                    Ok(response) => {
+                        self.request_stats.succeeded += 1;
+                        self.request_stats.orders_fetched += response.orders.len() as u64;
+                        self.breaker.record_success(&peer);
+                    }
+                    Err(_) => {
+                        self.request_stats.failed += 1;
+                        self.breaker.record_failure_at(&peer, Instant::now());
+                    }
+                }
+                if let Err(_result) = pending.sender.send(result) {
                     warn!("Received response for dropped handler, dropping response");
                 }
             }
@@ -185,8 +714,8 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                 request_id,
                 error,
             } => {
-                let sender = match self.pending_requests.remove(&request_id) {
-                    Some(sender) => sender,
+                let mut pending = match self.pending_requests.remove(&request_id) {
+                    Some(pending) => pending,
                     None => {
                         error!(
                             "Failure for unexpected outbound request id {} from peer {}: {:?}",
@@ -195,14 +724,59 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
                         return;
                     }
                 };
+
+                // The peer's addresses may be stale, resolve them again before
+                // retrying. Other failures (e.g. timeouts) are not retried.
+                if matches!(error, OutboundFailure::DialFailure) && pending.dial_retries > 0 {
+                    debug!("Dialing {} failed, resolving addresses", peer);
+                    pending.dial_retries -= 1;
+                    let requests = self.unresolved_requests.entry(peer.clone()).or_default();
+                    if requests.is_empty() {
+                        self.events.push_back(OrderSyncEvent::ResolvePeer(peer));
+                    }
+                    requests.push(pending);
+                    return;
+                }
+
+                match error {
+                    OutboundFailure::UnsupportedProtocols => {
+                        info!(
+                            "Peer {} does not support {}",
+                            peer,
+                            String::from_utf8_lossy(Version().protocol_name())
+                        );
+                        self.negotiation_failures.unsupported_outbound += 1;
+                    }
+                    OutboundFailure::Timeout | OutboundFailure::ConnectionClosed => {
+                        self.negotiation_failures.failed_outbound += 1;
+                    }
+                    // Not connected, so nothing was negotiated.
+                    OutboundFailure::DialFailure => {}
+                }
+                self.breaker.record_failure_at(&peer, Instant::now());
+                self.request_stats.failed += 1;
+                self.events.push_back(OrderSyncEvent::RequestFailed(peer));
                 let result = Err(Error::OutboundFailure(error));
-                if let Err(_result) = sender.send(result) {
+                if let Err(_result) = pending.sender.send(result) {
                     warn!("Received outbound failure for dropped handler");
                 }
             }
 
             // A request remote initiated failed. (Either during reading the request or sending the
             // response).
+            // Requests over the inbound rate limits are omitted on purpose.
+            RequestResponseEvent::InboundFailure {
+                error: InboundFailure::ResponseOmission,
+                ..
+            } => {}
+            RequestResponseEvent::InboundFailure {
+                peer,
+                error: InboundFailure::UnsupportedProtocols,
+                ..
+            } => {
+                debug!("Peer {} requested an unsupported protocol", peer);
+                self.negotiation_failures.unsupported_inbound += 1;
+            }
             RequestResponseEvent::InboundFailure {
                 peer,
                 request_id,
@@ -222,3 +796,525 @@ impl NetworkBehaviourEventProcess<Event> for OrderSync {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::transport::make_memory_transport, test::prelude::assert_eq};
+    use futures::future::{select, Either};
+    use libp2p::{identity::Keypair, swarm::SwarmEvent, Swarm};
+
+    fn order_sync_swarm() -> Swarm<OrderSync> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        Swarm::new(make_memory_transport(&keypair), OrderSync::new(None), peer_id)
+    }
+
+    /// Spawn a peer that answers every request with a default response.
+    async fn spawn_responder() -> (PeerId, Multiaddr) {
+        let protocols = iter::once((Version(), ProtocolSupport::Full));
+        let behaviour = RequestResponse::new(Codec::default(), protocols, Config::default());
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut swarm = Swarm::new(make_memory_transport(&keypair), behaviour, peer_id.clone());
+        Swarm::listen_on(&mut swarm, "/memory/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = swarm.next_event().await {
+                break address;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                if let RequestResponseEvent::Message {
+                    message: RequestResponseMessage::Request { channel, .. },
+                    ..
+                } = swarm.next().await
+                {
+                    let response = Message::Response(Response::default());
+                    swarm.send_response(channel, response).unwrap();
+                }
+            }
+        });
+        (peer_id, address)
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_dial_failure() {
+        let (peer_id, address) = spawn_responder().await;
+        let mut swarm = order_sync_swarm();
+
+        // Only a stale address is known, so the first dial fails.
+        swarm.add_address(&peer_id, "/memory/1".parse().unwrap());
+        let (sender, mut receiver) = oneshot::channel();
+        swarm.send(&peer_id, Request::default(), sender);
+        assert_eq!(swarm.next().await, OrderSyncEvent::ResolvePeer(peer_id.clone()));
+
+        // Resolution finds the new address and the request is retried.
+        swarm.add_address(&peer_id, address);
+        swarm.retry(&peer_id);
+        let result = loop {
+            match select(swarm.next().boxed(), &mut receiver).await {
+                Either::Left(_) => {}
+                Either::Right((result, _)) => break result.unwrap(),
+            }
+        };
+        assert_eq!(result.unwrap(), Response::default());
+        assert_eq!(swarm.request_stats(), RequestStats {
+            sent: 1,
+            succeeded: 1,
+            ..RequestStats::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_fail_unresolved() {
+        let (peer_id, _address) = spawn_responder().await;
+        let mut swarm = order_sync_swarm();
+
+        let (sender, receiver) = oneshot::channel();
+        swarm.send(&peer_id, Request::default(), sender);
+        assert_eq!(swarm.next().await, OrderSyncEvent::ResolvePeer(peer_id.clone()));
+
+        swarm.fail_unresolved(&peer_id);
+        assert!(matches!(
+            receiver.await.unwrap(),
+            Err(Error::OutboundFailure(OutboundFailure::DialFailure))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refused_peers() {
+        let (peer_id, _address) = spawn_responder().await;
+        let mut swarm = order_sync_swarm();
+        let access = Arc::new(RwLock::new(PeerAccess::default()));
+        let _ = swarm.set_peer_access(access.clone());
+        let send = |swarm: &mut Swarm<OrderSync>, peer_id: &PeerId| {
+            let (sender, receiver) = oneshot::channel();
+            swarm.send(peer_id, Request::default(), sender);
+            receiver
+        };
+
+        // Requests made before the ban fail when they are retried.
+        let mut queued = send(&mut swarm, &peer_id);
+        assert_eq!(swarm.next().await, OrderSyncEvent::ResolvePeer(peer_id.clone()));
+        let _ = access.write().unwrap().banned.insert(peer_id.clone());
+        swarm.retry(&peer_id);
+        assert!(matches!(queued.try_recv(), Ok(Some(Err(Error::Banned)))));
+        assert!(matches!(
+            send(&mut swarm, &peer_id).await.unwrap(),
+            Err(Error::Banned)
+        ));
+
+        let other = PeerId::random();
+        access.write().unwrap().allowed = Some(iter::once(peer_id.clone()).collect());
+        assert!(matches!(
+            send(&mut swarm, &other).await.unwrap(),
+            Err(Error::NotAllowed)
+        ));
+        assert_eq!(swarm.request_stats(), RequestStats {
+            sent: 1,
+            failed: 1,
+            ..RequestStats::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_pending_request_cap() {
+        let (peer_id, _address) = spawn_responder().await;
+        let mut swarm = order_sync_swarm();
+        swarm.set_max_pending_requests(2);
+
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (sender, receiver) = oneshot::channel();
+            swarm.send(&peer_id, Request::default(), sender);
+            receivers.push(receiver);
+        }
+        assert_eq!(swarm.pending_count(), 2);
+
+        let (sender, receiver) = oneshot::channel();
+        swarm.send(&peer_id, Request::default(), sender);
+        assert!(matches!(receiver.await.unwrap(), Err(Error::QueueFull)));
+        assert_eq!(swarm.pending_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_throttle_burst() {
+        let (peer_id, address) = spawn_responder().await;
+        let mut swarm = order_sync_swarm();
+        swarm.add_address(&peer_id, address);
+        swarm.set_throttle(ThrottleConfig {
+            max_requests_per_peer_per_second: 2,
+            global_max_concurrent:            10,
+        });
+
+        // Requests over the per peer rate wait for the next window.
+        let start = Instant::now();
+        let mut receivers = Vec::new();
+        for _ in 0..4 {
+            let (sender, receiver) = oneshot::channel();
+            swarm.send(&peer_id, Request::default(), sender);
+            receivers.push(receiver);
+        }
+        assert_eq!(swarm.throttled_count(), 2);
+        assert_eq!(swarm.pending_count(), 4);
+        let mut responses = future::join_all(receivers);
+        let results = loop {
+            match select(swarm.next().boxed(), &mut responses).await {
+                Either::Left(_) => {}
+                Either::Right((results, _)) => break results,
+            }
+        };
+        assert!(results.into_iter().all(|result| result.unwrap().is_ok()));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        // Requests over the global concurrency wait for responses.
+        swarm.set_throttle(ThrottleConfig {
+            max_requests_per_peer_per_second: 100,
+            global_max_concurrent:            1,
+        });
+        let mut receivers = Vec::new();
+        for _ in 0..3 {
+            let (sender, receiver) = oneshot::channel();
+            swarm.send(&peer_id, Request::default(), sender);
+            receivers.push(receiver);
+        }
+        assert_eq!(swarm.throttled_count(), 2);
+
+        // The queue counts towards the pending request cap.
+        swarm.set_max_pending_requests(3);
+        let (sender, receiver) = oneshot::channel();
+        swarm.send(&peer_id, Request::default(), sender);
+        assert!(matches!(receiver.await.unwrap(), Err(Error::QueueFull)));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        use self::circuit_breaker::FAILURE_THRESHOLD;
+
+        // The peer has no known addresses, so every request fails to dial.
+        let unreachable = PeerId::from(Keypair::generate_ed25519().public());
+        let mut swarm = order_sync_swarm();
+        swarm.set_max_dial_retries(0);
+
+        for _ in 0..FAILURE_THRESHOLD {
+            let (sender, mut receiver) = oneshot::channel();
+            swarm.send(&unreachable, Request::default(), sender);
+            let result = loop {
+                match select(swarm.next().boxed(), &mut receiver).await {
+                    Either::Left(_) => {}
+                    Either::Right((result, _)) => break result.unwrap(),
+                }
+            };
+            assert!(matches!(result, Err(Error::OutboundFailure(_))));
+        }
+        assert_eq!(swarm.breaker_state(&unreachable), BreakerState::Open);
+
+        let (sender, receiver) = oneshot::channel();
+        swarm.send(&unreachable, Request::default(), sender);
+        assert!(matches!(receiver.await.unwrap(), Err(Error::CircuitOpen)));
+    }
+
+    #[tokio::test]
+    async fn test_message_size_limits() {
+        use self::messages::{Order, OrderFilter};
+        use futures::io::Cursor;
+        use libp2p::request_response::RequestResponseCodec;
+
+        let mut codec = codec();
+
+        // A request with a large custom order schema exceeds the request limit.
+        let mut request = Request::from(OrderFilter {
+            custom_order_schema: "x".repeat(MAX_REQUEST_SIZE),
+            ..OrderFilter::default()
+        });
+        request.subprotocols.clear();
+        let mut wire = Cursor::new(Vec::new());
+        codec
+            .write_request(&Version(), &mut wire, Message::Request(request))
+            .await
+            .unwrap();
+        let error = codec
+            .read_request(&Version(), &mut Cursor::new(wire.into_inner()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // A full page of orders is well over the request limit, but fine as a
+        // response.
+        let response = Response {
+            orders: vec![Order::default(); 500],
+            ..Response::default()
+        };
+        let mut wire = Cursor::new(Vec::new());
+        codec
+            .write_response(&Version(), &mut wire, Message::Response(response.clone()))
+            .await
+            .unwrap();
+        assert!(wire.get_ref().len() > MAX_REQUEST_SIZE);
+        let message = codec
+            .read_response(&Version(), &mut Cursor::new(wire.into_inner()))
+            .await
+            .unwrap();
+        assert_eq!(message, Message::Response(response));
+    }
+
+    /// Spawn `server` and send it `request` from a new client.
+    async fn request_from(server: Swarm<OrderSync>, request: Request) -> Response {
+        try_request_from(server, request).await.unwrap()
+    }
+
+    /// Spawn `server` and send it `request` from a new client.
+    async fn try_request_from(server: Swarm<OrderSync>, request: Request) -> Result {
+        let (server_id, address) = spawn_server(server).await;
+        let mut client = order_sync_swarm();
+        client.add_address(&server_id, address);
+        let (sender, mut receiver) = oneshot::channel();
+        client.send(&server_id, request, sender);
+        loop {
+            match select(client.next().boxed(), &mut receiver).await {
+                Either::Left(_) => {}
+                Either::Right((result, _)) => break result.unwrap(),
+            }
+        }
+    }
+
+    /// Run `server` in the background, returning its id and address.
+    async fn spawn_server(mut server: Swarm<OrderSync>) -> (PeerId, Multiaddr) {
+        Swarm::listen_on(&mut server, "/memory/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = server.next_event().await {
+                break address;
+            }
+        };
+        let server_id = Swarm::local_peer_id(&server).clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = server.next_event().await;
+            }
+        });
+        (server_id, address)
+    }
+
+    #[tokio::test]
+    async fn test_inbound_throttle() {
+        let mut server = order_sync_swarm();
+        server.set_inbound_throttle(ThrottleConfig {
+            max_requests_per_peer_per_second: 2,
+            global_max_concurrent:            10,
+        });
+        let (server_id, address) = spawn_server(server).await;
+
+        let mut client = order_sync_swarm();
+        client.add_address(&server_id, address);
+        client.set_throttle(ThrottleConfig {
+            max_requests_per_peer_per_second: 100,
+            global_max_concurrent:            100,
+        });
+        let mut receivers = Vec::new();
+        for _ in 0..4 {
+            let (sender, receiver) = oneshot::channel();
+            client.send(&server_id, Request::default(), sender);
+            receivers.push(receiver);
+        }
+        let mut responses = future::join_all(receivers);
+        let results = loop {
+            match select(client.next().boxed(), &mut responses).await {
+                Either::Left(_) => {}
+                Either::Right((results, _)) => break results,
+            }
+        };
+
+        // The requests over the limit are rejected instead of served.
+        let served = results
+            .into_iter()
+            .filter(|result| matches!(result, Ok(Ok(_))))
+            .count();
+        assert_eq!(served, 2);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_only() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let behaviour = OrderSync::with_support(ProtocolSupport::Outbound, None);
+        let server = Swarm::new(make_memory_transport(&keypair), behaviour, peer_id);
+        let result = try_request_from(server, Request::default()).await;
+        assert!(matches!(
+            result,
+            Err(Error::OutboundFailure(OutboundFailure::UnsupportedProtocols))
+        ));
+
+        // Inbound only peers are still served.
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let behaviour = OrderSync::with_support(ProtocolSupport::Inbound, None);
+        let server = Swarm::new(make_memory_transport(&keypair), behaviour, peer_id);
+        let response = request_from(server, Request::default()).await;
+        assert_eq!(response, Response::default());
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_failure() {
+        #[derive(Clone)]
+        struct Other;
+
+        impl ProtocolName for Other {
+            fn protocol_name(&self) -> &[u8] {
+                b"/0x-mesh/other/version/0"
+            }
+        }
+
+        // The server only speaks another request response protocol.
+        let keypair = Keypair::generate_ed25519();
+        let server_id = PeerId::from(keypair.public());
+        let protocols = iter::once((Other, ProtocolSupport::Full));
+        let codec = JsonCodec::<Other, Message, Message>::default();
+        let behaviour = RequestResponse::new(codec, protocols, Config::default());
+        let mut server = Swarm::new(make_memory_transport(&keypair), behaviour, server_id.clone());
+        Swarm::listen_on(&mut server, "/memory/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = server.next_event().await {
+                break address;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                let _ = server.next_event().await;
+            }
+        });
+
+        let mut client = order_sync_swarm();
+        client.add_address(&server_id, address);
+        let (sender, mut receiver) = oneshot::channel();
+        client.send(&server_id, Request::default(), sender);
+        let result = loop {
+            match select(client.next().boxed(), &mut receiver).await {
+                Either::Left(_) => {}
+                Either::Right((result, _)) => break result.unwrap(),
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(Error::OutboundFailure(OutboundFailure::UnsupportedProtocols))
+        ));
+        assert_eq!(client.negotiation_failures(), NegotiationFailures {
+            unsupported_outbound: 1,
+            ..NegotiationFailures::default()
+        });
+        assert_eq!(client.request_stats(), RequestStats {
+            sent: 1,
+            failed: 1,
+            ..RequestStats::default()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_serve_orders() {
+        use self::messages::{Order, OrderFilter};
+
+        struct Orders(Vec<Order>);
+
+        impl OrderSource for Orders {
+            fn orders(&self, filter: &OrderFilter) -> Vec<Order> {
+                self.0.iter().filter(|order| filter.matches(order)).cloned().collect()
+            }
+        }
+
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            ..Order::default()
+        };
+        let mut server = order_sync_swarm();
+        let _ = server.set_order_source(Arc::new(Orders(vec![order.clone()])));
+        let response = request_from(server, Request::from(order_filter)).await;
+        assert_eq!(response.orders, vec![order.clone()]);
+        assert!(response.complete);
+        assert_eq!(response.metadata, messages::ResponseMetadata::V1 {
+            next_min_order_hash: order.hash().to_string(),
+        });
+    }
+
+    #[tokio::test]
+    async fn test_sketch_converges() {
+        use self::messages::{Order, OrderFilter, OrderHash};
+        use std::collections::BTreeSet;
+
+        struct Orders(Vec<Order>);
+
+        impl OrderSource for Orders {
+            fn orders(&self, filter: &OrderFilter) -> Vec<Order> {
+                self.0.iter().filter(|order| filter.matches(order)).cloned().collect()
+            }
+        }
+
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = |salt: u64| {
+            Order {
+                chain_id: order_filter.chain_id,
+                exchange_address: order_filter.exchange_address,
+                salt: salt.into(),
+                ..Order::default()
+            }
+        };
+        let server_orders = (0..40).map(order).collect::<Vec<_>>();
+        let client_orders = (20..60).map(order).collect::<Vec<_>>();
+        let mut server = order_sync_swarm();
+        let _ = server.set_order_source(Arc::new(Orders(server_orders.clone())));
+
+        let mut client_hashes = client_orders.iter().map(Order::hash).collect::<BTreeSet<_>>();
+        let request = Request::with_sketch(order_filter.clone(), &client_hashes);
+        let response = request_from(server, request).await;
+        assert!(response.complete);
+        assert_eq!(response.next_request(order_filter), None);
+        assert_eq!(response.orders_added.len(), 20);
+        assert_eq!(response.orders_removed.len(), 20);
+
+        // Applying the difference gives the server's orders.
+        for hash in &response.orders_removed {
+            assert!(client_hashes.remove(hash));
+        }
+        client_hashes.extend(response.orders_added.iter().map(Order::hash));
+        let server_hashes = server_orders.iter().map(Order::hash).collect::<BTreeSet<OrderHash>>();
+        assert_eq!(client_hashes, server_hashes);
+    }
+
+    #[tokio::test]
+    async fn test_order_sync_handler() {
+        use self::messages::{Order, OrderFilter, ResponseMetadata};
+
+        /// Answers with a single order after a short delay.
+        struct Handler;
+
+        #[async_trait]
+        impl OrderSyncHandler for Handler {
+            async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                let order_filter = request.metadata.metadata[0].order_filter_ref();
+                Ok(Response {
+                    orders:   vec![Order {
+                        chain_id: order_filter.chain_id,
+                        salt: 42.into(),
+                        ..Order::default()
+                    }],
+                    complete: true,
+                    metadata: ResponseMetadata::V1 {
+                        next_min_order_hash: String::new(),
+                    },
+                    ..Response::default()
+                })
+            }
+        }
+
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let order_sync = OrderSync::new(Some(Arc::new(Handler)));
+        let server = Swarm::new(make_memory_transport(&keypair), order_sync, peer_id);
+        let response = request_from(server, Request::from(OrderFilter::mainnet_v3())).await;
+        assert_eq!(response.orders.len(), 1);
+        assert_eq!(response.orders[0].salt, 42.into());
+        assert!(response.complete);
+    }
+}
@@ -0,0 +1,97 @@
+//! Splitting orders into response pages that fit a byte budget.
+//!
+//! Orders vary a lot in size (asset data can be long), so a fixed number of
+//! orders per page does not bound the size of a response. Pages are instead
+//! filled until the serialized orders reach the budget.
+
+use super::messages::Order;
+use crate::prelude::*;
+
+/// Default byte budget of a response page. Same as the gossipsub maximum
+/// transmit size, and well under the OrderSync response limit.
+pub const DEFAULT_MAX_PAGE_BYTES: usize = 256 * 1024;
+
+/// Bytes reserved for the response envelope around the orders: message type,
+/// subprotocol and pagination metadata.
+const PAGE_OVERHEAD: usize = 512;
+
+/// Split `orders` into pages whose serialized orders stay under `max_bytes`.
+///
+/// Orders that do not fit in a page on their own are skipped.
+pub fn paginate(orders: impl IntoIterator<Item = Order>, max_bytes: usize) -> Vec<Vec<Order>> {
+    let budget = max_bytes.saturating_sub(PAGE_OVERHEAD);
+    let mut pages = Vec::new();
+    let mut page = Vec::new();
+    let mut page_bytes = 0;
+    for order in orders {
+        // Orders are separated by a comma in the JSON array.
+        let size = match serde_json::to_vec(&order) {
+            Ok(json) => json.len() + 1,
+            Err(err) => {
+                error!("Error serializing order, skipping: {}", err);
+                continue;
+            }
+        };
+        if size > budget {
+            warn!(
+                "Order of {} bytes exceeds page budget of {} bytes, skipping",
+                size, budget
+            );
+            continue;
+        }
+        if page_bytes + size > budget {
+            pages.push(std::mem::take(&mut page));
+            page_bytes = 0;
+        }
+        page.push(order);
+        page_bytes += size;
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::behaviour::order_sync::messages::{Message, Response},
+        test::prelude::assert_eq,
+    };
+
+    #[test]
+    fn test_paginate_by_size() {
+        let max_bytes = 64 * 1024;
+        let order = |size: usize| {
+            Order {
+                maker_asset_data: format!("0x{}", "ab".repeat(size / 2)),
+                ..Order::default()
+            }
+        };
+        let orders = vec![
+            order(10_000),
+            order(30_000),
+            order(30_000),
+            order(100),
+            order(max_bytes),
+            order(50_000),
+        ];
+
+        let pages = paginate(orders.clone(), max_bytes);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 1]);
+        for page in &pages {
+            let response = Response {
+                orders: page.clone(),
+                ..Response::default()
+            };
+            let json = serde_json::to_vec(&Message::Response(response)).unwrap();
+            assert!(json.len() < max_bytes);
+        }
+
+        // The order larger than the budget is left out.
+        let mut expected = orders;
+        let _ = expected.remove(4);
+        assert_eq!(pages.concat(), expected);
+    }
+}
@@ -0,0 +1,312 @@
+//! Answering OrderSync requests from peers.
+//!
+//! Orders come from a pluggable [`OrderSource`]. Both pagination subprotocols
+//! are served statelessly by sorting the matching orders by hash:
+//!
+//! * `/pagination-with-filter/version/1` returns the orders after the
+//!   requested minimum hash. Preferred when the peer offers it.
+//! * `/pagination-with-filter/version/0` returns the requested page. There
+//!   are no server side snapshots, the snapshot id is echoed back, so pages
+//!   can shift if orders are added during a sync.
+//! * `/pagination-with-sketch/version/0` returns the difference with the
+//!   requester's [`Sketch`], in a single response. Preferred over both.
+//!   Differences that do not decode or fit a page start a version 1 sync.
+//!
+//! Only orders matching the requester's [`OrderFilter`] are served, whatever
+//! the source returns. Pages are split by [`paginate`] to stay within the
+//! byte budget.
+//!
+//! An [`OrderSyncHandler`] replaces this entirely, for nodes that answer
+//! requests asynchronously or from elsewhere.
+
+use super::{
+    messages::{Order, OrderFilter, OrderHash, Request, RequestMetadata, Response, ResponseMetadata},
+    pages::paginate,
+    sketch::Sketch,
+};
+use crate::prelude::*;
+use std::{collections::HashSet, convert::TryFrom};
+
+/// Orders served to peers that sync from us.
+pub trait OrderSource: Send + Sync {
+    /// Orders matching `filter`, in any order.
+    fn orders(&self, filter: &OrderFilter) -> Vec<Order>;
+}
+
+/// Answers inbound OrderSync requests.
+#[async_trait]
+pub trait OrderSyncHandler: Send + Sync {
+    /// Build the response to `request`. On error the request is dropped
+    /// without a response.
+    async fn handle(&self, request: Request) -> Result<Response>;
+}
+
+/// Build the response to `request` from the orders in `source`.
+pub fn respond(source: &dyn OrderSource, request: &Request, max_page_bytes: usize) -> Response {
+    let metadata = &request.metadata.metadata;
+    let metadata = metadata
+        .iter()
+        .find(|metadata| matches!(metadata, RequestMetadata::Sketch { .. }))
+        .or_else(|| {
+            metadata
+                .iter()
+                .find(|metadata| matches!(metadata, RequestMetadata::V1 { .. }))
+        })
+        .or_else(|| metadata.first());
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return Response::default(),
+    };
+    let order_filter = metadata.order_filter_ref();
+    let mut orders = source
+        .orders(order_filter)
+        .into_iter()
+        .filter(|order| order_filter.matches(order))
+        .map(|order| (order.hash(), order))
+        .collect::<Vec<_>>();
+    // Without orders, the difference is everything the requester has.
+    if orders.is_empty() && !matches!(metadata, RequestMetadata::Sketch { .. }) {
+        return Response::default();
+    }
+    orders.sort_by_key(|(hash, _)| *hash);
+
+    match metadata {
+        RequestMetadata::V1 { min_order_hash, .. } => {
+            let min_order_hash = min_order_hash.parse().unwrap_or(OrderHash([0; 32]));
+            let orders = orders
+                .into_iter()
+                .filter(|(hash, _)| *hash > min_order_hash)
+                .map(|(_, order)| order);
+            let mut pages = paginate(orders, max_page_bytes).into_iter();
+            let page = pages.next().unwrap_or_default();
+            let next_min_order_hash = page.last().map_or(min_order_hash, Order::hash);
+            Response {
+                orders: page,
+                complete: pages.next().is_none(),
+                metadata: ResponseMetadata::V1 {
+                    next_min_order_hash: next_min_order_hash.to_string(),
+                },
+                ..Response::default()
+            }
+        }
+        RequestMetadata::V0 {
+            snapshot_id, page, ..
+        } => {
+            let pages = paginate(orders.into_iter().map(|(_, order)| order), max_page_bytes);
+            let index = usize::try_from(*page).unwrap_or_default();
+            Response {
+                orders: pages.get(index).cloned().unwrap_or_default(),
+                complete: index + 1 >= pages.len(),
+                metadata: ResponseMetadata::V0 {
+                    snapshot_id: snapshot_id.clone(),
+                    page:        *page,
+                },
+                ..Response::default()
+            }
+        }
+        RequestMetadata::Sketch { sketch, .. } => reconcile(orders, sketch, max_page_bytes),
+    }
+}
+
+/// Answer a sketch with the difference to `orders`.
+fn reconcile(orders: Vec<(OrderHash, Order)>, sketch: &str, max_page_bytes: usize) -> Response {
+    let difference = Sketch::from_hex(sketch).and_then(|remote| {
+        let local = Sketch::from_hashes(remote.len(), orders.iter().map(|(hash, _)| hash));
+        local.subtract(&remote)?.decode()
+    });
+    let (added, removed) = match difference {
+        Ok(difference) => difference,
+        Err(err) => {
+            debug!("Falling back to pagination: {}", err);
+            return full_sync();
+        }
+    };
+    let added = added.into_iter().collect::<HashSet<_>>();
+    let orders_added = orders
+        .into_iter()
+        .filter(|(hash, _)| added.contains(hash))
+        .map(|(_, order)| order);
+    let mut pages = paginate(orders_added, max_page_bytes).into_iter();
+    let orders_added = pages.next().unwrap_or_default();
+    if pages.next().is_some() {
+        debug!("Falling back to pagination: difference does not fit a page");
+        return full_sync();
+    }
+    Response {
+        orders: vec![],
+        complete: true,
+        orders_added,
+        orders_removed: removed,
+        metadata: ResponseMetadata::Sketch,
+    }
+}
+
+/// An empty page that starts a `/pagination-with-filter/version/1` sync.
+fn full_sync() -> Response {
+    Response {
+        complete: false,
+        metadata: ResponseMetadata::V1 {
+            next_min_order_hash: OrderHash([0; 32]).to_string(),
+        },
+        ..Response::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    impl OrderSource for Vec<Order> {
+        fn orders(&self, filter: &OrderFilter) -> Vec<Order> {
+            self.iter().filter(|order| filter.matches(order)).cloned().collect()
+        }
+    }
+
+    fn orders() -> Vec<Order> {
+        let order_filter = OrderFilter::mainnet_v3();
+        (0..10)
+            .map(|salt| {
+                Order {
+                    chain_id: order_filter.chain_id,
+                    exchange_address: order_filter.exchange_address,
+                    salt: salt.into(),
+                    maker_asset_data: "0x".to_string() + &"ab".repeat(1000),
+                    ..Order::default()
+                }
+            })
+            .collect()
+    }
+
+    /// Follow the responses until complete, checking each page is bounded.
+    fn sync(source: &dyn OrderSource, mut request: Request) -> Vec<Order> {
+        let mut received = Vec::new();
+        loop {
+            let response = respond(source, &request, 5000);
+            assert!(response.orders.len() <= 2);
+            received.extend(response.orders.iter().cloned());
+            match response.next_request(OrderFilter::mainnet_v3()) {
+                Some(next) => request = next,
+                None => return received,
+            }
+        }
+    }
+
+    #[test]
+    fn test_respond_v1() {
+        let orders = orders();
+        let request = Request::from(OrderFilter::mainnet_v3());
+        let response = respond(&orders, &request, 5000);
+        assert!(matches!(response.metadata, ResponseMetadata::V1 { .. }));
+        assert!(!response.complete);
+
+        let mut received = sync(&orders, request);
+        let mut expected = orders;
+        received.sort_by_key(Order::hash);
+        expected.sort_by_key(Order::hash);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_respond_v0() {
+        let orders = orders();
+        let mut request = Request::from(OrderFilter::mainnet_v3());
+        let _ = request.metadata.metadata.remove(0);
+        let response = respond(&orders, &request, 5000);
+        assert_eq!(response.metadata, ResponseMetadata::V0 {
+            snapshot_id: String::new(),
+            page:        0,
+        });
+
+        let mut received = sync(&orders, request);
+        let mut expected = orders;
+        received.sort_by_key(Order::hash);
+        expected.sort_by_key(Order::hash);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_respond_empty() {
+        let request = Request::from(OrderFilter::mainnet_v3());
+        assert_eq!(respond(&Vec::new(), &request, 5000), Response::default());
+
+        // No orders for the filter.
+        let request = Request::from(OrderFilter::mainnet_v2());
+        assert_eq!(respond(&orders(), &request, 5000), Response::default());
+    }
+
+    #[test]
+    fn test_respond_filtered() {
+        /// Returns every order, whatever the filter.
+        struct Unfiltered(Vec<Order>);
+
+        impl OrderSource for Unfiltered {
+            fn orders(&self, _filter: &OrderFilter) -> Vec<Order> {
+                self.0.clone()
+            }
+        }
+
+        let mainnet = orders();
+        let chain_42 = mainnet
+            .iter()
+            .take(3)
+            .map(|order| {
+                Order {
+                    chain_id: 42,
+                    ..order.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        let source = Unfiltered(mainnet.iter().chain(&chain_42).cloned().collect());
+        let filter = OrderFilter {
+            chain_id: 42,
+            ..OrderFilter::mainnet_v3()
+        };
+        let response = respond(&source, &Request::from(filter), 100_000);
+        assert!(response.complete);
+        let mut expected = chain_42;
+        expected.sort_by_key(Order::hash);
+        assert_eq!(response.orders, expected);
+
+        // The default filter matches all orders.
+        let response = respond(&source, &Request::from(OrderFilter::default()), 100_000);
+        assert_eq!(response.orders.len(), mainnet.len() + expected.len());
+    }
+
+    #[test]
+    fn test_respond_sketch() {
+        let orders = orders();
+        let unknown = OrderHash([1; 32]);
+        let known = orders[5..].iter().map(Order::hash).chain(Some(unknown)).collect::<Vec<_>>();
+        let request = Request::with_sketch(OrderFilter::mainnet_v3(), &known);
+        let response = respond(&orders, &request, 100_000);
+        assert_eq!(response.metadata, ResponseMetadata::Sketch);
+        assert!(response.complete);
+        assert!(response.orders.is_empty());
+        let mut added = response.orders_added;
+        let mut expected = orders[..5].to_vec();
+        added.sort_by_key(Order::hash);
+        expected.sort_by_key(Order::hash);
+        assert_eq!(added, expected);
+        assert_eq!(response.orders_removed, vec![unknown]);
+
+        // A difference over the page budget is paginated instead.
+        let response = respond(&orders, &request, 5000);
+        assert_eq!(response.metadata, ResponseMetadata::V1 {
+            next_min_order_hash: OrderHash([0; 32]).to_string(),
+        });
+        assert_eq!(sync(&orders, request).len(), orders.len());
+
+        // As is an invalid sketch.
+        let mut request = Request::with_sketch(OrderFilter::mainnet_v3(), &known);
+        if let RequestMetadata::Sketch { sketch, .. } = &mut request.metadata.metadata[0] {
+            sketch.truncate(10);
+        }
+        assert!(!respond(&orders, &request, 100_000).complete);
+
+        // Without orders, everything the requester has is removed.
+        let response = respond(&Vec::new(), &Request::with_sketch(OrderFilter::mainnet_v3(), &known), 5000);
+        assert!(response.complete);
+        assert_eq!(response.orders_removed.len(), known.len());
+    }
+}
@@ -0,0 +1,286 @@
+//! Invertible Bloom lookup table over order hashes, for set reconciliation.
+//!
+//! Each side adds the hashes of its orders to a fixed number of cells.
+//! Subtracting two sketches cancels the orders both sides have. If the
+//! difference is small enough, it is listed by repeatedly peeling off cells
+//! that hold a single hash. See Eppstein et al. (2011), "What's the
+//! Difference? Efficient Set Reconciliation without Prior Context".
+//!
+//! Stands in for Minisketch, which is a C++ library. The sketch is larger,
+//! about 1.5 cells of 44 bytes per differing order, but needs no native build.
+
+use super::messages::OrderHash;
+use crate::prelude::*;
+use sha3::{Digest, Keccak256};
+use std::convert::TryInto;
+
+/// Number of cells each hash is added to, one in each subtable.
+const HASH_COUNT: usize = 3;
+
+/// Count, hash sum and checksum sum.
+const CELL_BYTES: usize = 4 + 32 + 8;
+
+/// Cells in the sketch sent with a request. Decodes differences of up to
+/// about 150 orders.
+pub const DEFAULT_CELLS: usize = 240;
+
+/// Largest sketch accepted from a peer, about 42 kB hex encoded.
+pub const MAX_CELLS: usize = 480;
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum SketchError {
+    #[error("Sketch is not hex encoded")]
+    InvalidHex,
+    #[error("Sketch of {0} bytes is not a whole number of subtables")]
+    InvalidLength(usize),
+    #[error("Sketch of {0} cells is over the maximum of {}", MAX_CELLS)]
+    TooLarge(usize),
+    #[error("Sketches of {0} and {1} cells can not be subtracted")]
+    SizeMismatch(usize, usize),
+    #[error("Difference is too large to decode")]
+    Undecodable,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct Cell {
+    count: i32,
+    hash:  [u8; 32],
+    check: u64,
+}
+
+impl Cell {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The hash in this cell, if it holds exactly one.
+    fn pure(&self) -> Option<([u8; 32], i32)> {
+        if (self.count == 1 || self.count == -1) && self.check == checksum(&self.hash) {
+            Some((self.hash, self.count))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Sketch {
+    cells: Vec<Cell>,
+}
+
+/// Differences of a subtracted sketch: hashes only in the first sketch, and
+/// hashes only in the second.
+pub type Difference = (Vec<OrderHash>, Vec<OrderHash>);
+
+impl Sketch {
+    /// An empty sketch of at least `cells` cells.
+    pub fn new(cells: usize) -> Self {
+        let subtable = (cells.max(1) + HASH_COUNT - 1) / HASH_COUNT;
+        Self {
+            cells: vec![Cell::default(); subtable * HASH_COUNT],
+        }
+    }
+
+    pub fn from_hashes<'a>(cells: usize, hashes: impl IntoIterator<Item = &'a OrderHash>) -> Self {
+        let mut sketch = Self::new(cells);
+        for hash in hashes {
+            sketch.insert(hash);
+        }
+        sketch
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn insert(&mut self, hash: &OrderHash) {
+        self.add(&hash.0, 1);
+    }
+
+    /// The sketch of the hashes in `self` but not in `other`, and the other
+    /// way around.
+    pub fn subtract(&self, other: &Self) -> Result<Self, SketchError> {
+        if self.len() != other.len() {
+            return Err(SketchError::SizeMismatch(self.len(), other.len()));
+        }
+        let cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(a, b)| {
+                let mut hash = a.hash;
+                hash.iter_mut().zip(&b.hash).for_each(|(x, y)| *x ^= y);
+                Cell {
+                    count: a.count.wrapping_sub(b.count),
+                    hash,
+                    check: a.check ^ b.check,
+                }
+            })
+            .collect();
+        Ok(Self { cells })
+    }
+
+    /// List the hashes of a subtracted sketch.
+    pub fn decode(mut self) -> Result<Difference, SketchError> {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut candidates = (0..self.len()).collect::<Vec<_>>();
+        while let Some(index) = candidates.pop() {
+            let (hash, count) = match self.cells[index].pure() {
+                Some(pure) => pure,
+                None => continue,
+            };
+            // More hashes than cells means a checksum collision.
+            if added.len() + removed.len() >= self.len() {
+                return Err(SketchError::Undecodable);
+            }
+            if count > 0 {
+                added.push(OrderHash(hash));
+            } else {
+                removed.push(OrderHash(hash));
+            }
+            self.add(&hash, -count);
+            candidates.extend(self.indices(&hash).iter());
+        }
+        if self.cells.iter().all(Cell::is_empty) {
+            Ok((added, removed))
+        } else {
+            Err(SketchError::Undecodable)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() * CELL_BYTES);
+        for cell in &self.cells {
+            bytes.extend_from_slice(&cell.count.to_be_bytes());
+            bytes.extend_from_slice(&cell.hash);
+            bytes.extend_from_slice(&cell.check.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SketchError> {
+        if bytes.is_empty() || bytes.len() % (CELL_BYTES * HASH_COUNT) != 0 {
+            return Err(SketchError::InvalidLength(bytes.len()));
+        }
+        let cells = bytes.len() / CELL_BYTES;
+        if cells > MAX_CELLS {
+            return Err(SketchError::TooLarge(cells));
+        }
+        let cells = bytes
+            .chunks_exact(CELL_BYTES)
+            .map(|cell| {
+                Cell {
+                    count: i32::from_be_bytes(cell[..4].try_into().unwrap()),
+                    hash:  cell[4..36].try_into().unwrap(),
+                    check: u64::from_be_bytes(cell[36..].try_into().unwrap()),
+                }
+            })
+            .collect();
+        Ok(Self { cells })
+    }
+
+    /// Encode as `0x` followed by hex digits, as sent in requests.
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.to_bytes()))
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, SketchError> {
+        let digits = s.strip_prefix("0x").ok_or(SketchError::InvalidHex)?;
+        if digits.len() > MAX_CELLS * CELL_BYTES * 2 {
+            return Err(SketchError::TooLarge(digits.len() / (CELL_BYTES * 2)));
+        }
+        let bytes = hex::decode(digits).map_err(|_| SketchError::InvalidHex)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Cell of `hash` in each subtable. Order hashes are uniformly
+    /// distributed, so their bytes are used directly.
+    fn indices(&self, hash: &[u8; 32]) -> [usize; HASH_COUNT] {
+        let subtable = self.len() / HASH_COUNT;
+        let mut indices = [0; HASH_COUNT];
+        for (i, index) in indices.iter_mut().enumerate() {
+            let word = u64::from_le_bytes(hash[i * 8..(i + 1) * 8].try_into().unwrap());
+            *index = i * subtable + (word % subtable as u64) as usize;
+        }
+        indices
+    }
+
+    fn add(&mut self, hash: &[u8; 32], count: i32) {
+        let check = checksum(hash);
+        for &index in &self.indices(hash) {
+            let cell = &mut self.cells[index];
+            cell.count = cell.count.wrapping_add(count);
+            cell.hash.iter_mut().zip(hash).for_each(|(x, y)| *x ^= y);
+            cell.check ^= check;
+        }
+    }
+}
+
+/// Independent of the cell indices, so a cell holding several hashes is
+/// unlikely to look pure.
+fn checksum(hash: &[u8; 32]) -> u64 {
+    let mut hasher = Keccak256::new();
+    hasher.update(b"sketch");
+    hasher.update(hash);
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn hashes(range: std::ops::Range<u8>) -> Vec<OrderHash> {
+        range.map(|i| OrderHash(Keccak256::digest(&[i]).into())).collect()
+    }
+
+    #[test]
+    fn test_sketch_decode() {
+        let local = hashes(0..100);
+        let remote = hashes(60..130);
+        let local_sketch = Sketch::from_hashes(DEFAULT_CELLS, &local);
+        let remote_sketch = Sketch::from_hashes(DEFAULT_CELLS, &remote);
+        let (mut added, mut removed) = local_sketch.subtract(&remote_sketch).unwrap().decode().unwrap();
+        added.sort();
+        removed.sort();
+        let mut expected_added = hashes(0..60);
+        let mut expected_removed = hashes(100..130);
+        expected_added.sort();
+        expected_removed.sort();
+        assert_eq!(added, expected_added);
+        assert_eq!(removed, expected_removed);
+
+        // Identical sets have no difference.
+        let difference = local_sketch.subtract(&local_sketch).unwrap().decode().unwrap();
+        assert_eq!(difference, (vec![], vec![]));
+
+        // Too small for the difference.
+        let small = Sketch::from_hashes(12, &local).subtract(&Sketch::from_hashes(12, &remote));
+        assert_eq!(small.unwrap().decode(), Err(SketchError::Undecodable));
+        assert_eq!(
+            local_sketch.subtract(&Sketch::new(12)),
+            Err(SketchError::SizeMismatch(240, 12))
+        );
+    }
+
+    #[test]
+    fn test_sketch_encoding() {
+        let sketch = Sketch::from_hashes(10, &hashes(0..5));
+        assert_eq!(sketch.len(), 12);
+        assert_eq!(Sketch::from_hex(&sketch.to_hex()), Ok(sketch.clone()));
+
+        let bytes = sketch.to_bytes();
+        assert_eq!(
+            Sketch::from_bytes(&bytes[..CELL_BYTES]),
+            Err(SketchError::InvalidLength(CELL_BYTES))
+        );
+        assert_eq!(Sketch::from_hex("abcd"), Err(SketchError::InvalidHex));
+        let too_large = Sketch::new(MAX_CELLS + 1).to_bytes();
+        assert_eq!(
+            Sketch::from_bytes(&too_large),
+            Err(SketchError::TooLarge(MAX_CELLS + 3))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Rate limits for outbound OrderSync requests.
+//!
+//! Each peer gets at most [`ThrottleConfig::max_requests_per_peer_per_second`]
+//! requests in any one second window, and at most
+//! [`ThrottleConfig::global_max_concurrent`] requests await a response across
+//! all peers. Requests over either limit wait in a queue instead of failing.
+
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Length of the per peer rate window.
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ThrottleConfig {
+    pub max_requests_per_peer_per_second: u32,
+    pub global_max_concurrent:            usize,
+}
+
+impl Default for ThrottleConfig {
+    /// A full sync pages through a peer one request at a time, so these only
+    /// bind when many fetches run at once.
+    fn default() -> Self {
+        Self {
+            max_requests_per_peer_per_second: 10,
+            global_max_concurrent:            32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    config: ThrottleConfig,
+
+    /// Send times of the requests to each peer within the last window.
+    sent: HashMap<PeerId, VecDeque<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            sent: HashMap::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+    }
+
+    /// Whether a request to `peer_id` may be sent at `now`, given the number
+    /// of requests awaiting a response.
+    pub fn allow_at(&mut self, peer_id: &PeerId, in_flight: usize, now: Instant) -> bool {
+        if in_flight >= self.config.global_max_concurrent.max(1) {
+            return false;
+        }
+        let max_per_peer = self.config.max_requests_per_peer_per_second.max(1) as usize;
+        match self.sent.get_mut(peer_id) {
+            Some(sent) => {
+                while sent.front().map_or(false, |&time| time + WINDOW <= now) {
+                    let _ = sent.pop_front();
+                }
+                sent.len() < max_per_peer
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_at(&mut self, peer_id: &PeerId, now: Instant) {
+        self.sent.entry(peer_id.clone()).or_default().push_back(now);
+    }
+
+    /// Time at which the window of `peer_id` has room again, if it is full.
+    pub fn next_slot(&self, peer_id: &PeerId) -> Option<Instant> {
+        let max_per_peer = self.config.max_requests_per_peer_per_second.max(1) as usize;
+        let sent = self.sent.get(peer_id)?;
+        if sent.len() < max_per_peer {
+            return None;
+        }
+        sent.get(sent.len() - max_per_peer).map(|&time| time + WINDOW)
+    }
+
+    /// Forget peers without requests in the last window.
+    pub fn prune_at(&mut self, now: Instant) {
+        self.sent
+            .retain(|_, sent| sent.back().map_or(false, |&time| time + WINDOW > now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn test_throttle_window() {
+        let mut throttle = Throttle::new(ThrottleConfig {
+            max_requests_per_peer_per_second: 2,
+            global_max_concurrent:            3,
+        });
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(throttle.allow_at(&peer_id, 0, now));
+            throttle.record_at(&peer_id, now);
+        }
+        assert!(!throttle.allow_at(&peer_id, 0, now));
+        assert_eq!(throttle.next_slot(&peer_id), Some(now + WINDOW));
+        assert!(throttle.allow_at(&other, 2, now));
+
+        // The global limit applies to all peers.
+        assert!(!throttle.allow_at(&other, 3, now));
+
+        // The window slides.
+        assert!(!throttle.allow_at(&peer_id, 0, now + WINDOW / 2));
+        assert!(throttle.allow_at(&peer_id, 0, now + WINDOW));
+        assert_eq!(throttle.next_slot(&peer_id), None);
+        throttle.prune_at(now + WINDOW);
+        assert!(throttle.sent.is_empty());
+    }
+}
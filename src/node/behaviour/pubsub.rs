@@ -1,38 +1,870 @@
 //! Pub sub behaviour for order sharing.
+//!
+//! Outgoing orders go through a bounded queue that publishes at most a
+//! configured number of messages per second, so a burst of submitted orders
+//! does not flood gossipsub. Orders already waiting in the queue are not
+//! queued again, and submitters get [`PublishError::QueueFull`] when the queue
+//! is full. Publishing a queued order can still fail, for example when no peer
+//! is subscribed to the topic, which is only logged.
+//!
+//! The behaviour subscribes to one [`GossipTopic`] per configured chain.
+//! Incoming messages are only forwarded once they decode to an order of the
+//! topic's chain. Messages over [`PubSubConfig::max_message_size`] are
+//! rejected before decoding. Rejected messages are not forwarded, but otherwise go
+//! unpunished: gossipsub v1.0 has no peer scoring.
+//!
+//! With [`PubSubConfig::forward`] off, decoded orders are still delivered
+//! locally but never validated, so gossipsub does not relay them. Leaf nodes
+//! use this to receive orders without spending bandwidth on forwarding, while
+//! still taking part in the mesh.
+//!
+//! A node can end up subscribed but outside the topic mesh, receiving nothing
+//! without any error. If no message arrives on any topic for
+//! [`PubSubConfig::stall_timeout`] while peers are connected, the behaviour
+//! resubscribes to all topics to rejoin the mesh and emits [`PubSubEvent::Resubscribed`] so
+//! more peers are looked for.
+//!
+//! ## To do
+//!
+//! * Peer exchange on PRUNE. This is a gossipsub v1.1 feature, but the
+//!   `libp2p-gossipsub` version we use only implements v1.0, so there is no
+//!   PRUNE peer information to act on. Enable `do_px` and dial the suggested
+//!   peers once we upgrade to a v1.1 implementation (libp2p 0.34+).
+//! * Report rejected messages with `MessageAcceptance::Reject` after that
+//!   upgrade, so peer scoring penalizes the sender.
 
+use super::order_sync::messages::Order;
+use crate::prelude::*;
+use anyhow::ensure;
 use libp2p::{
-    gossipsub::{Gossipsub, GossipsubConfigBuilder, GossipsubEvent, MessageAuthenticity, Topic},
+    gossipsub::{
+        Gossipsub, GossipsubConfig, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
+        MessageAuthenticity, Topic,
+    },
     identity::Keypair,
-    swarm::NetworkBehaviourEventProcess,
-    NetworkBehaviour,
+    swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters},
+    NetworkBehaviour, PeerId,
 };
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep_until, Sleep};
+
+/// JSON schema matching all orders.
+pub const UNFILTERED_SCHEMA: &str = "{}";
+
+/// `messageType` of order messages.
+const ORDER_MESSAGE_TYPE: &str = "order";
+
+/// Default number of messages published per second.
+pub const DEFAULT_MAX_PUBLISHES_PER_SEC: u32 = 20;
+
+/// Number of messages waiting to be published above which new messages are
+/// refused.
+const MAX_QUEUED_PUBLISHES: usize = 1024;
+
+/// Largest message gossipsub sends or receives, in bytes.
+const MAX_TRANSMIT_SIZE: usize = 262_144;
+
+/// Default size in bytes above which received messages are rejected. Orders
+/// with multi asset data are a few kilobytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Number of received orders not yet taken, above which the oldest are
+/// dropped.
+const MAX_RECEIVED: usize = 1024;
+
+/// Default time without messages after which the topic is resubscribed.
+/// Mainnet orders are gossiped every few seconds.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Gossipsub settings exposed in the node configuration.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PubSubConfig {
+    /// Number of heartbeats for which published and forwarded messages are
+    /// kept to answer IWANT requests.
+    pub history_length: usize,
+
+    /// Number of most recent heartbeats whose messages are gossiped about,
+    /// at most `history_length`.
+    pub history_gossip: usize,
+
+    /// Size in bytes above which received messages are rejected without
+    /// decoding them.
+    pub max_message_size: usize,
+
+    /// Time without messages while peers are connected after which the
+    /// topic is resubscribed, never if `None`.
+    pub stall_timeout: Option<Duration>,
+
+    /// Relay received orders to other peers in the mesh.
+    pub forward: bool,
+}
+
+impl Default for PubSubConfig {
+    /// Gossipsub keeps 5 heartbeats of history by default. Orders are
+    /// re-shared by many peers and keep arriving for longer than that, so we
+    /// keep them for 10 heartbeats to answer IWANTs instead of having them
+    /// forwarded again.
+    fn default() -> Self {
+        Self {
+            history_length:   10,
+            history_gossip:   3,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            stall_timeout:    Some(DEFAULT_STALL_TIMEOUT),
+            forward:          true,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("Too many messages waiting to be published.")]
+    QueueFull,
+    #[error("Not gossiping orders of chain {0}.")]
+    UnknownChain(u64),
+    #[error("Encoding order message: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// A 0x Mesh orders topic, named
+/// `/0x-orders/version/{version}/chain/{chain_id}/schema/{schema}`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct GossipTopic {
+    pub chain_id: u64,
+    pub version:  u32,
+    /// Base64 encoded JSON schema the orders on the topic match.
+    pub schema:   String,
+}
+
+impl GossipTopic {
+    /// Topic for all v3 orders on `chain_id`.
+    #[allow(dead_code)]
+    pub fn v3(chain_id: u64) -> Self {
+        Self::with_schema(chain_id, UNFILTERED_SCHEMA)
+    }
+
+    /// Topic for the v3 orders on `chain_id` matching the JSON schema
+    /// `schema_json`. The schema is encoded like Go 0x Mesh does, as URL safe
+    /// base64 with padding, so it does not add `/` to the topic name.
+    pub fn with_schema(chain_id: u64, schema_json: &str) -> Self {
+        Self {
+            chain_id,
+            version: 3,
+            schema: base64::encode_config(schema_json, base64::URL_SAFE),
+        }
+    }
+
+    fn topic(&self) -> Topic {
+        Topic::new(self.to_string())
+    }
+}
+
+impl fmt::Display for GossipTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "/0x-orders/version/{}/chain/{}/schema/{}",
+            self.version, self.chain_id, self.schema
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PubSubEvent {
+    /// No messages arrived for the stall timeout, and the topic was
+    /// resubscribed.
+    Resubscribed,
+}
+
+#[derive(Debug, Error)]
+enum InvalidMessage {
+    #[error("Message of {0} bytes exceeds the maximum of {1} bytes")]
+    TooLarge(usize, usize),
+    #[error("Malformed order message: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Message is not on a subscribed topic")]
+    UnknownTopic,
+    #[error("Order for chain {0} on a topic of chain {1}")]
+    WrongChain(u64, u64),
+}
 
-/// Topic for all mainnet v3 orders (unfiltered)
-const TOPIC: &str = "/0x-orders/version/3/chain/1/schema/e30=";
+/// An order shared over gossipsub, as encoded by 0x Mesh.
+///
+/// See <https://github.com/0xProject/0x-mesh/blob/b2a12fdb186fb56eb7d99dc449b9773d0943ee8e/encoding/encoding.go>
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderMessage {
+    message_type: String,
+    order:        Order,
+}
+
+/// Messages waiting to be published, released at a fixed rate.
+#[derive(Debug)]
+struct PublishQueue<T = Vec<u8>> {
+    messages:     VecDeque<T>,
+    interval:     Duration,
+    next_publish: Option<Instant>,
+}
+
+impl<T: PartialEq> PublishQueue<T> {
+    fn new(per_second: u32) -> Self {
+        Self {
+            messages:     VecDeque::new(),
+            interval:     Duration::from_secs(1) / per_second.max(1),
+            next_publish: None,
+        }
+    }
+
+    fn push(&mut self, message: T) -> Result<(), PublishError> {
+        if self.messages.contains(&message) {
+            return Ok(());
+        }
+        if self.messages.len() >= MAX_QUEUED_PUBLISHES {
+            return Err(PublishError::QueueFull);
+        }
+        self.messages.push_back(message);
+        Ok(())
+    }
+
+    /// Take the next message if one may be published at time `now`.
+    fn pop_at(&mut self, now: Instant) -> Option<T> {
+        if self.next_publish.map_or(false, |next| now < next) {
+            return None;
+        }
+        let message = self.messages.pop_front()?;
+        self.next_publish = Some(now + self.interval);
+        Some(message)
+    }
+}
+
+/// Tracks the time since the last received message.
+#[derive(Debug)]
+struct Watchdog {
+    timeout:         Duration,
+    last_message:    Instant,
+    connected_peers: usize,
+    timer:           Option<Pin<Box<Sleep>>>,
+}
+
+impl Watchdog {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_message: Instant::now(),
+            connected_peers: 0,
+            timer: None,
+        }
+    }
+
+    /// When the topic counts as stalled, if peers are connected.
+    fn deadline(&self) -> Option<Instant> {
+        if self.connected_peers == 0 {
+            return None;
+        }
+        Some(self.last_message + self.timeout)
+    }
+}
+
+/// The gossipsub configuration for `config`.
+fn gossipsub_config(config: &PubSubConfig) -> GossipsubConfig {
+    GossipsubConfigBuilder::new()
+        .max_transmit_size(MAX_TRANSMIT_SIZE)
+        .history_length(config.history_length)
+        .history_gossip(config.history_gossip)
+        .validate_messages()
+        .build()
+}
 
 #[derive(NetworkBehaviour)]
+#[behaviour(out_event = "PubSubEvent", poll_method = "poll")]
 pub struct PubSub {
     gossipsub: Gossipsub,
+
+    #[behaviour(ignore)]
+    topics: Vec<GossipTopic>,
+
+    #[behaviour(ignore)]
+    queue: PublishQueue<(Topic, Vec<u8>)>,
+
+    /// Wakes the behaviour when the next queued message may be published.
+    #[behaviour(ignore)]
+    timer: Option<Pin<Box<Sleep>>>,
+
+    #[behaviour(ignore)]
+    max_message_size: usize,
+
+    /// Validate decoded messages, which makes gossipsub forward them.
+    #[behaviour(ignore)]
+    forward: bool,
+
+    /// Decoded orders and the peers they were received from.
+    #[behaviour(ignore)]
+    received: VecDeque<(PeerId, Order)>,
+
+    /// Number of received messages, including rejected ones.
+    #[behaviour(ignore)]
+    messages: u64,
+
+    /// Number of received messages that were rejected.
+    #[behaviour(ignore)]
+    rejected: u64,
+
+    #[behaviour(ignore)]
+    watchdog: Option<Watchdog>,
+
+    /// Number of times the topic was resubscribed by the watchdog.
+    #[behaviour(ignore)]
+    resubscriptions: u64,
 }
 
 impl PubSub {
-    pub(crate) fn new(peer_key: Keypair) -> Self {
-        // GossipSub
-        let gossipsub_config = GossipsubConfigBuilder::new()
-            .max_transmit_size(262_144)
-            .build();
-        let gossipsub = Gossipsub::new(MessageAuthenticity::Signed(peer_key), gossipsub_config);
+    /// Create the behaviour, subscribing to `topics` once started.
+    pub(crate) fn new(
+        peer_key: Keypair,
+        config: PubSubConfig,
+        topics: Vec<GossipTopic>,
+    ) -> Result<Self> {
+        ensure!(
+            config.history_gossip <= config.history_length,
+            "Gossip history ({}) can not be longer than the history length ({})",
+            config.history_gossip,
+            config.history_length
+        );
+
+        let gossipsub = Gossipsub::new(
+            MessageAuthenticity::Signed(peer_key),
+            gossipsub_config(&config),
+        );
 
-        Self { gossipsub }
+        Ok(Self {
+            gossipsub,
+            topics,
+            queue: PublishQueue::new(DEFAULT_MAX_PUBLISHES_PER_SEC),
+            timer: None,
+            max_message_size: config.max_message_size,
+            forward: config.forward,
+            received: VecDeque::new(),
+            messages: 0,
+            rejected: 0,
+            watchdog: config.stall_timeout.map(Watchdog::new),
+            resubscriptions: 0,
+        })
     }
 
     pub fn start(&mut self) {
         // Subscribe to orders
-        let topic = Topic::new(TOPIC.into());
-        self.gossipsub.subscribe(topic);
+        for topic in &self.topics {
+            let _ = self.gossipsub.subscribe(topic.topic());
+        }
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.last_message = Instant::now();
+        }
+    }
+
+    /// Unsubscribe from all topics.
+    pub fn stop(&mut self) {
+        for topic in &self.topics {
+            let _ = self.gossipsub.unsubscribe(topic.topic());
+        }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.gossipsub.topics().next().is_some()
+    }
+
+    /// Names of the topics currently subscribed to.
+    pub fn subscribed_topics(&self) -> Vec<String> {
+        self.topics
+            .iter()
+            .filter(|topic| {
+                let hash = topic.topic().no_hash();
+                self.gossipsub.topics().any(|subscribed| *subscribed == hash)
+            })
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    /// Count a newly connected peer for the watchdog.
+    pub fn peer_connected(&mut self) {
+        if let Some(watchdog) = &mut self.watchdog {
+            // Nothing could be received without peers.
+            if watchdog.connected_peers == 0 {
+                watchdog.last_message = Instant::now();
+            }
+            watchdog.connected_peers += 1;
+        }
+    }
+
+    pub fn peer_disconnected(&mut self) {
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.connected_peers = watchdog.connected_peers.saturating_sub(1);
+        }
+    }
+
+    /// Number of times the topic was resubscribed after a stall.
+    pub const fn resubscriptions(&self) -> u64 {
+        self.resubscriptions
+    }
+
+    /// Limit the rate at which queued messages are published.
+    pub fn set_max_publishes_per_sec(&mut self, per_second: u32) {
+        self.queue.interval = Duration::from_secs(1) / per_second.max(1);
+    }
+
+    /// The subscribed topic of `chain_id`.
+    pub fn topic(&self, chain_id: u64) -> Option<&GossipTopic> {
+        self.topics.iter().find(|topic| topic.chain_id == chain_id)
+    }
+
+    /// Queue `order` for publishing on the topic of its chain, encoded like 0x
+    /// Mesh does.
+    pub fn queue_publish(&mut self, order: &Order) -> Result<(), PublishError> {
+        let topic = self
+            .topic(order.chain_id)
+            .ok_or(PublishError::UnknownChain(order.chain_id))?
+            .topic();
+        let message = serde_json::to_vec(&OrderMessage {
+            message_type: ORDER_MESSAGE_TYPE.into(),
+            order:        order.clone(),
+        })?;
+        self.queue.push((topic, message))
+    }
+
+    /// Take the orders received since the last call.
+    pub fn take_received(&mut self) -> Vec<(PeerId, Order)> {
+        self.received.drain(..).collect()
+    }
+
+    /// Number of received messages, including rejected ones.
+    pub const fn received_messages(&self) -> u64 {
+        self.messages
+    }
+
+    /// Number of received messages rejected for their size or content.
+    pub const fn rejected_messages(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Decode an order message, checking its size first so oversized messages
+    /// cost no parsing.
+    fn decode(&self, message: &GossipsubMessage) -> Result<Order, InvalidMessage> {
+        let topic = self
+            .topics
+            .iter()
+            .find(|topic| message.topics.contains(&topic.topic().no_hash()))
+            .ok_or(InvalidMessage::UnknownTopic)?;
+        let data = &message.data;
+        if data.len() > self.max_message_size {
+            return Err(InvalidMessage::TooLarge(data.len(), self.max_message_size));
+        }
+        let message: OrderMessage = serde_json::from_slice(data)?;
+        if message.order.chain_id != topic.chain_id {
+            return Err(InvalidMessage::WrongChain(message.order.chain_id, topic.chain_id));
+        }
+        Ok(message.order)
+    }
+
+    /// Resubscribe if the topics stalled at time `now`. Returns whether it
+    /// did.
+    fn check_stalled_at(&mut self, now: Instant) -> bool {
+        let subscribed = self.is_subscribed();
+        let watchdog = match &mut self.watchdog {
+            Some(watchdog) if subscribed => watchdog,
+            _ => return false,
+        };
+        match watchdog.deadline() {
+            Some(deadline) if now >= deadline => {}
+            _ => return false,
+        }
+        warn!(
+            "No gossip received for {:?} with {} peers connected, resubscribing",
+            watchdog.timeout, watchdog.connected_peers
+        );
+        watchdog.last_message = now;
+        for topic in &self.topics {
+            let _ = self.gossipsub.unsubscribe(topic.topic());
+            let _ = self.gossipsub.subscribe(topic.topic());
+        }
+        self.resubscriptions += 1;
+        true
+    }
+
+    fn poll<TEv>(
+        &mut self,
+        cx: &mut task::Context,
+        _params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, PubSubEvent>> {
+        if let Some((topic, message)) = self.queue.pop_at(Instant::now()) {
+            if let Err(err) = self.gossipsub.publish(&topic, message) {
+                warn!("Failed to publish message: {:?}", err);
+            }
+            // Gossipsub has messages to send now.
+            cx.waker().wake_by_ref();
+        }
+        match self.queue.next_publish {
+            Some(next) if !self.queue.messages.is_empty() => {
+                let timer = self
+                    .timer
+                    .get_or_insert_with(|| Box::pin(sleep_until(next.into())));
+                timer.as_mut().reset(next.into());
+                if timer.as_mut().poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            _ => self.timer = None,
+        }
+
+        if self.check_stalled_at(Instant::now()) {
+            let event = PubSubEvent::Resubscribed;
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        if let Some(watchdog) = &mut self.watchdog {
+            match watchdog.deadline() {
+                Some(deadline) => {
+                    let timer = watchdog
+                        .timer
+                        .get_or_insert_with(|| Box::pin(sleep_until(deadline.into())));
+                    timer.as_mut().reset(deadline.into());
+                    if timer.as_mut().poll(cx).is_ready() {
+                        cx.waker().wake_by_ref();
+                    }
+                }
+                None => watchdog.timer = None,
+            }
+        }
+        Poll::Pending
     }
 }
 
 impl NetworkBehaviourEventProcess<GossipsubEvent> for PubSub {
-    fn inject_event(&mut self, _event: GossipsubEvent) {}
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message(source, message_id, message) = event {
+            // Even rejected messages show we are in the mesh.
+            if let Some(watchdog) = &mut self.watchdog {
+                watchdog.last_message = Instant::now();
+            }
+            self.messages += 1;
+            match self.decode(&message) {
+                Ok(order) => {
+                    if self.forward {
+                        let _ = self.gossipsub.validate_message(&message_id, &source);
+                    }
+                    if self.received.len() >= MAX_RECEIVED {
+                        let _ = self.received.pop_front();
+                    }
+                    self.received.push_back((source, order));
+                }
+                Err(err) => {
+                    // Never validated, so gossipsub does not forward it.
+                    self.rejected += 1;
+                    debug!("Rejecting message {} from {}: {}", message_id, source, err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::transport::make_memory_transport, test::prelude::assert_eq};
+    use libp2p::{
+        gossipsub::MessageId,
+        swarm::{Swarm, SwarmEvent},
+        Multiaddr,
+    };
+
+    fn mainnet() -> Vec<GossipTopic> {
+        vec![GossipTopic::v3(1)]
+    }
+
+    fn order_message(source: &PeerId, topic: &GossipTopic, order: Order) -> GossipsubEvent {
+        let data = serde_json::to_vec(&OrderMessage {
+            message_type: ORDER_MESSAGE_TYPE.into(),
+            order,
+        })
+        .unwrap();
+        GossipsubEvent::Message(source.clone(), MessageId::new(&data), GossipsubMessage {
+            source: None,
+            data,
+            sequence_number: None,
+            topics: vec![topic.topic().no_hash()],
+            signature: None,
+            key: None,
+            validated: false,
+        })
+    }
+
+    fn pubsub_swarm(forward: bool) -> Swarm<PubSub> {
+        let keypair = Keypair::generate_ed25519();
+        let config = PubSubConfig {
+            forward,
+            ..PubSubConfig::default()
+        };
+        let pubsub = PubSub::new(keypair.clone(), config, mainnet()).unwrap();
+        let transport = make_memory_transport(&keypair);
+        Swarm::new(transport, pubsub, PeerId::from(keypair.public()))
+    }
+
+    async fn listen(swarm: &mut Swarm<PubSub>) -> Multiaddr {
+        Swarm::listen_on(swarm, "/memory/0".parse().unwrap()).unwrap();
+        loop {
+            if let SwarmEvent::NewListenAddr(address) = swarm.next_event().await {
+                break address;
+            }
+        }
+    }
+
+    /// Drive all swarms for `duration`.
+    async fn run_for(swarms: &mut [&mut Swarm<PubSub>], duration: Duration) {
+        let deadline = tokio::time::Instant::now() + duration;
+        let _ = future::join_all(swarms.iter_mut().map(|swarm| {
+            async move {
+                while tokio::time::timeout_at(deadline, swarm.next_event()).await.is_ok() {}
+            }
+        }))
+        .await;
+    }
+
+    /// Publish `order` to a relay that is connected to one other subscriber.
+    /// Returns the orders received by the relay and by the subscriber.
+    async fn relay_order(forward: bool, order: &Order) -> (Vec<Order>, Vec<Order>) {
+        let step = Duration::from_millis(500);
+        let mut subscriber = pubsub_swarm(true);
+        let mut relay = pubsub_swarm(forward);
+        let mut publisher = pubsub_swarm(true);
+        subscriber.start();
+        let subscriber_address = listen(&mut subscriber).await;
+        let relay_address = listen(&mut relay).await;
+
+        // The relay grafts the subscriber when it joins the topic, without
+        // waiting for a heartbeat.
+        Swarm::dial_addr(&mut relay, subscriber_address).unwrap();
+        run_for(&mut [&mut subscriber, &mut relay], step).await;
+        relay.start();
+
+        // The publisher is not subscribed, so it sends to the relay as fanout.
+        Swarm::dial_addr(&mut publisher, relay_address).unwrap();
+        run_for(&mut [&mut subscriber, &mut relay, &mut publisher], step).await;
+        publisher.queue_publish(order).unwrap();
+        run_for(&mut [&mut subscriber, &mut relay, &mut publisher], step).await;
+
+        let orders = |swarm: &mut Swarm<PubSub>| {
+            swarm
+                .take_received()
+                .into_iter()
+                .map(|(_, order)| order)
+                .collect::<Vec<_>>()
+        };
+        (orders(&mut relay), orders(&mut subscriber))
+    }
+
+    #[tokio::test]
+    async fn test_no_forward() {
+        let order = Order {
+            chain_id: 1,
+            ..Order::default()
+        };
+        let (relayed, received) = relay_order(true, &order).await;
+        assert_eq!(relayed, vec![order.clone()]);
+        assert_eq!(received, vec![order.clone()]);
+
+        // Delivered locally, but not relayed.
+        let (relayed, received) = relay_order(false, &order).await;
+        assert_eq!(relayed, vec![order]);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn test_publish_rate() {
+        let mut queue = PublishQueue::new(5);
+        for salt in 0..10_u8 {
+            queue.push(vec![salt]).unwrap();
+        }
+        // Coalesced with the queued copy
+        queue.push(vec![0]).unwrap();
+        assert_eq!(queue.messages.len(), 10);
+
+        // Over two seconds the burst is released five messages per second.
+        let start = Instant::now();
+        let mut published = Vec::new();
+        for millis in (0..2000).step_by(10) {
+            let now = start + Duration::from_millis(millis);
+            while let Some(message) = queue.pop_at(now) {
+                published.push((millis, message));
+            }
+        }
+        let times = published.iter().map(|(millis, _)| *millis).collect::<Vec<_>>();
+        assert_eq!(times, vec![0, 200, 400, 600, 800, 1000, 1200, 1400, 1600, 1800]);
+        assert_eq!(published[9].1, vec![9]);
+    }
+
+    #[test]
+    fn test_history_config() {
+        let config = PubSubConfig {
+            history_length: 20,
+            history_gossip: 4,
+            ..PubSubConfig::default()
+        };
+        assert!(PubSub::new(Keypair::generate_ed25519(), config, mainnet()).is_ok());
+        let gossipsub_config = gossipsub_config(&config);
+        assert_eq!(gossipsub_config.history_length, 20);
+        assert_eq!(gossipsub_config.history_gossip, 4);
+
+        let config = PubSubConfig {
+            history_length: 2,
+            history_gossip: 3,
+            ..PubSubConfig::default()
+        };
+        assert!(PubSub::new(Keypair::generate_ed25519(), config, mainnet()).is_err());
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut queue = PublishQueue::new(5);
+        for i in 0..MAX_QUEUED_PUBLISHES {
+            queue.push(i.to_le_bytes().to_vec()).unwrap();
+        }
+        assert!(matches!(queue.push(vec![]), Err(PublishError::QueueFull)));
+        let _ = queue.pop_at(Instant::now());
+        assert!(queue.push(vec![]).is_ok());
+    }
+
+    #[test]
+    fn test_stall_watchdog() {
+        let config = PubSubConfig {
+            stall_timeout: Some(Duration::from_secs(60)),
+            ..PubSubConfig::default()
+        };
+        let mut pubsub = PubSub::new(Keypair::generate_ed25519(), config, mainnet()).unwrap();
+        pubsub.peer_connected();
+        // Not subscribed yet.
+        assert!(!pubsub.check_stalled_at(Instant::now() + Duration::from_secs(120)));
+
+        pubsub.start();
+        let start = Instant::now();
+        assert!(!pubsub.check_stalled_at(start + Duration::from_secs(30)));
+
+        // Without peers there is nothing to receive.
+        pubsub.peer_disconnected();
+        assert!(!pubsub.check_stalled_at(start + Duration::from_secs(120)));
+
+        pubsub.peer_connected();
+        assert!(pubsub.check_stalled_at(start + Duration::from_secs(120)));
+        assert_eq!(pubsub.resubscriptions(), 1);
+        assert_eq!(pubsub.gossipsub.topics().count(), 1);
+
+        // The timeout starts over after resubscribing.
+        assert!(!pubsub.check_stalled_at(start + Duration::from_secs(150)));
+        assert!(pubsub.check_stalled_at(start + Duration::from_secs(180)));
+        assert_eq!(pubsub.resubscriptions(), 2);
+
+        let disabled = PubSubConfig {
+            stall_timeout: None,
+            ..PubSubConfig::default()
+        };
+        let mut pubsub = PubSub::new(Keypair::generate_ed25519(), disabled, mainnet()).unwrap();
+        pubsub.start();
+        pubsub.peer_connected();
+        assert!(!pubsub.check_stalled_at(Instant::now() + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_reject_oversized() {
+        let config = PubSubConfig {
+            max_message_size: 1024,
+            ..PubSubConfig::default()
+        };
+        let mut pubsub = PubSub::new(Keypair::generate_ed25519(), config, mainnet()).unwrap();
+        let source = PeerId::from(Keypair::generate_ed25519().public());
+        let topic = GossipTopic::v3(1);
+        let order = Order {
+            chain_id: 1,
+            ..Order::default()
+        };
+
+        pubsub.inject_event(order_message(&source, &topic, order.clone()));
+        assert_eq!(pubsub.rejected_messages(), 0);
+        assert_eq!(pubsub.take_received(), vec![(source.clone(), order.clone())]);
+
+        // A well formed order over the limit is not decoded.
+        let oversized = Order {
+            maker_asset_data: format!("0x{}", "ab".repeat(1024)),
+            ..order
+        };
+        pubsub.inject_event(order_message(&source, &topic, oversized));
+        assert_eq!(pubsub.rejected_messages(), 1);
+        assert_eq!(pubsub.received_messages(), 2);
+        assert!(pubsub.take_received().is_empty());
+    }
+
+    #[test]
+    fn test_gossip_topics() {
+        assert_eq!(
+            GossipTopic::v3(1).to_string(),
+            "/0x-orders/version/3/chain/1/schema/e30="
+        );
+        assert_eq!(
+            GossipTopic::with_schema(4, r#"{"x":"???"}"#).to_string(),
+            "/0x-orders/version/3/chain/4/schema/eyJ4IjoiPz8_In0="
+        );
+
+        let topics = vec![GossipTopic::v3(1), GossipTopic::v3(4)];
+        let config = PubSubConfig::default();
+        let mut pubsub = PubSub::new(Keypair::generate_ed25519(), config, topics).unwrap();
+        pubsub.start();
+        assert_eq!(pubsub.gossipsub.topics().count(), 2);
+
+        // Orders are accepted on the topic of their chain only.
+        let source = PeerId::from(Keypair::generate_ed25519().public());
+        let rinkeby = Order {
+            chain_id: 4,
+            ..Order::default()
+        };
+        pubsub.inject_event(order_message(&source, &GossipTopic::v3(4), rinkeby.clone()));
+        pubsub.inject_event(order_message(&source, &GossipTopic::v3(1), rinkeby.clone()));
+        pubsub.inject_event(order_message(&source, &GossipTopic::v3(3), rinkeby.clone()));
+        assert_eq!(pubsub.take_received(), vec![(source, rinkeby)]);
+        assert_eq!(pubsub.rejected_messages(), 2);
+    }
+
+    #[test]
+    fn test_queue_publish() {
+        let mut pubsub = PubSub::new(Keypair::generate_ed25519(), PubSubConfig::default(), mainnet())
+            .unwrap();
+        pubsub.start();
+        let order = Order {
+            chain_id: 1,
+            ..Order::default()
+        };
+        pubsub.queue_publish(&order).unwrap();
+        // Coalesced with the queued copy
+        pubsub.queue_publish(&order).unwrap();
+        let rinkeby = Order {
+            chain_id: 4,
+            ..Order::default()
+        };
+        assert!(matches!(
+            pubsub.queue_publish(&rinkeby),
+            Err(PublishError::UnknownChain(4))
+        ));
+        assert_eq!(pubsub.queue.messages.len(), 1);
+
+        // The message is on the chain's topic and encoded like 0x Mesh does.
+        let (topic, message) = pubsub.queue.messages.pop_front().unwrap();
+        assert_eq!(topic, GossipTopic::v3(1).topic());
+        let message: serde_json::Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "messageType": "order",
+                "order": order,
+            })
+        );
+    }
 }
@@ -0,0 +1,128 @@
+//! Builder for nodes that are listening as soon as they exist.
+
+use super::{
+    behaviour::{
+        discovery::{Bootnode, PeerStore},
+        order_sync::OrderSyncHandler,
+    },
+    Node, NodeConfig, OrderSink, Result,
+};
+use libp2p::{identity, Multiaddr};
+use std::sync::Arc;
+
+/// Creates a [`Node`] and starts it in one step, so a node can not be used
+/// before it listens. Anything not set on the builder is taken from the
+/// [`NodeConfig`].
+pub struct NodeBuilder {
+    config:             NodeConfig,
+    keypair:            Option<identity::Keypair>,
+    order_sync_handler: Option<Arc<dyn OrderSyncHandler>>,
+    peer_store:         Option<Box<dyn PeerStore>>,
+    order_sinks:        Vec<Arc<dyn OrderSink>>,
+}
+
+impl NodeBuilder {
+    pub fn new(config: NodeConfig) -> Self {
+        Self {
+            config,
+            keypair: None,
+            order_sync_handler: None,
+            peer_store: None,
+            order_sinks: Vec::new(),
+        }
+    }
+
+    /// Identity of the node, a new one is generated if not set.
+    pub fn keypair(mut self, keypair: identity::Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Addresses to listen for peers on.
+    pub fn listen_addrs(mut self, addresses: Vec<Multiaddr>) -> Self {
+        self.config.listen = addresses;
+        self
+    }
+
+    /// Bootnodes to use instead of the configured ones. An empty list falls
+    /// back to the default bootnodes, [`Bootnode::None`] disables
+    /// bootstrapping.
+    pub fn bootnode_overrides(mut self, bootnodes: Vec<Bootnode>) -> Self {
+        self.config.bootnodes = bootnodes;
+        self
+    }
+
+    /// Do not discover peers on the LAN through mDNS.
+    pub fn disable_mdns(mut self, disable: bool) -> Self {
+        self.config.disable_mdns = disable;
+        self
+    }
+
+    /// Answer inbound OrderSync requests with `handler` instead of from the
+    /// order store.
+    pub fn order_sync_handler(mut self, handler: Arc<dyn OrderSyncHandler>) -> Self {
+        self.order_sync_handler = Some(handler);
+        self
+    }
+
+    /// Load and save known peers in `peer_store` instead of the configured
+    /// file.
+    pub fn peer_store(mut self, peer_store: Box<dyn PeerStore>) -> Self {
+        self.peer_store = Some(peer_store);
+        self
+    }
+
+    /// Forward trusted orders to `sink`, see [`Node::spawn_order_sink`]. Can
+    /// be called more than once to add several sinks.
+    pub fn order_sink(mut self, sink: Arc<dyn OrderSink>) -> Self {
+        self.order_sinks.push(sink);
+        self
+    }
+
+    /// Create the node, start its behaviours and listen on its addresses.
+    pub async fn build(self) -> Result<Node> {
+        let keypair = self
+            .keypair
+            .unwrap_or_else(identity::Keypair::generate_ed25519);
+        let mut node =
+            Node::create(keypair, self.config, self.order_sync_handler, self.peer_store).await?;
+        node.start()?;
+        for sink in self.order_sinks {
+            node.spawn_order_sink(sink);
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::PeerId;
+
+    #[tokio::test]
+    async fn test_build() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let node = NodeBuilder::new(NodeConfig::default())
+            .keypair(keypair.clone())
+            .listen_addrs(vec![
+                "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+                "/ip4/127.0.0.1/tcp/0/ws".parse().unwrap(),
+            ])
+            .bootnode_overrides(vec![Bootnode::None])
+            .disable_mdns(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(node.local_peer_id(), &PeerId::from(keypair.public()));
+        assert_eq!(node.listener_ids.len(), 2);
+
+        // Listening fails the build instead of leaving a node behind.
+        let result = NodeBuilder::new(NodeConfig::default())
+            .listen_addrs(vec!["/dns4/example.com/udp/1".parse().unwrap()])
+            .bootnode_overrides(vec![Bootnode::None])
+            .build()
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,650 @@
+use super::{
+    behaviour::{
+        discovery::{self, Bootnode, DiscoveryConfig},
+        order_sync::{self, messages::OrderFilter, ThrottleConfig},
+        pubsub::{GossipTopic, PubSubConfig, DEFAULT_MAX_MESSAGE_SIZE, UNFILTERED_SCHEMA},
+    },
+    provider,
+    transport::{read_psk_file, TcpConfig, WssConfig},
+};
+use crate::types::EthAddress;
+use anyhow::Context as _;
+use libp2p::{request_response::ProtocolSupport, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+/// Default TCP port of 0x Mesh on all interfaces.
+const DEFAULT_LISTEN_ADDRESS: &str = "/ip4/0.0.0.0/tcp/60558";
+
+/// Node configuration, also exposed as command line options.
+///
+/// Options that take a value fall back to a `MESH_` prefixed environment
+/// variable, for example `MESH_API_ADDRESS`, when they are not given on the
+/// command line. Lists in environment variables are comma separated. Flags
+/// have no environment variable, since any value would enable them.
+///
+/// All options can also be set in a TOML configuration file, with the field
+/// names as keys, see [`NodeConfig::from_file`].
+#[derive(Clone, PartialEq, Debug, StructOpt, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Maximum number of addresses dialed per peer per attempt
+    #[structopt(long, env = "MESH_MAX_DIAL_ADDRESSES", default_value = "5")]
+    pub max_dial_addresses: usize,
+
+    /// Address to listen for peers on, can be repeated or comma separated to
+    /// listen on several interfaces or transports
+    #[structopt(
+        long,
+        env = "MESH_LISTEN",
+        default_value = DEFAULT_LISTEN_ADDRESS,
+        use_delimiter = true
+    )]
+    pub listen: Vec<Multiaddr>,
+
+    /// Address to serve the HTTP API on, disabled if not set
+    #[structopt(long, env = "MESH_API_ADDRESS")]
+    pub api_address: Option<SocketAddr>,
+
+    /// Address to serve the gRPC API on, disabled if not set. Requires the
+    /// grpc feature
+    #[structopt(long, env = "MESH_GRPC_ADDRESS")]
+    pub grpc_address: Option<SocketAddr>,
+
+    /// Serve Prometheus metrics on /metrics, requires the metrics feature
+    #[structopt(long)]
+    pub metrics: bool,
+
+    /// Address to serve the Prometheus metrics on
+    #[structopt(long, env = "MESH_METRICS_ADDRESS", default_value = "127.0.0.1:9090")]
+    pub metrics_address: SocketAddr,
+
+    /// Set SO_REUSEADDR on TCP sockets
+    #[structopt(
+        long,
+        env = "MESH_TCP_REUSE_ADDRESS",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    pub tcp_reuse_address: bool,
+
+    /// Set SO_REUSEPORT on TCP sockets
+    #[structopt(long)]
+    pub tcp_reuse_port: bool,
+
+    /// TCP send buffer size in bytes, system default if not set
+    #[structopt(long, env = "MESH_TCP_SEND_BUFFER_SIZE")]
+    pub tcp_send_buffer_size: Option<u32>,
+
+    /// TCP receive buffer size in bytes, system default if not set
+    #[structopt(long, env = "MESH_TCP_RECV_BUFFER_SIZE")]
+    pub tcp_recv_buffer_size: Option<u32>,
+
+    /// Maximum number of inbound connections per source IP per minute
+    #[structopt(long, env = "MESH_MAX_INBOUND_PER_IP", default_value = "60")]
+    pub max_inbound_per_ip: u32,
+
+    /// Also listen on secure WebSockets on a random port, requires the tls
+    /// feature and a certificate
+    #[structopt(long)]
+    pub enable_wss: bool,
+
+    /// DER encoded certificate for secure WebSockets, may be self-signed.
+    /// Enables TLS for `/wss` listen addresses, requires the tls feature
+    #[structopt(long, alias = "ws-tls-cert", env = "MESH_WSS_CERTIFICATE")]
+    #[serde(alias = "ws_tls_cert")]
+    pub wss_certificate: Option<PathBuf>,
+
+    /// DER encoded PKCS#8 private key of the secure WebSocket certificate
+    #[structopt(long, alias = "ws-tls-key", env = "MESH_WSS_PRIVATE_KEY")]
+    #[serde(alias = "ws_tls_key")]
+    pub wss_private_key: Option<PathBuf>,
+
+    /// Swarm key file with the pre-shared key of a private network. Only
+    /// peers with the same key can connect, so nodes never reach the public
+    /// mesh. In configuration files the key is given as 64 hex digits
+    #[structopt(long = "pnet-psk", env = "MESH_PNET_PSK", parse(try_from_str = read_psk_file))]
+    #[serde(with = "psk")]
+    pub pnet_psk: Option<[u8; 32]>,
+
+    /// Seconds for which DNS resolutions of dialed addresses are reused
+    #[structopt(long, env = "MESH_DNS_CACHE_TTL", default_value = "300")]
+    pub dns_cache_ttl: u64,
+
+    /// Peer id to refuse connections to and from, can be repeated or comma
+    /// separated
+    #[structopt(long = "ban-peer", env = "MESH_BAN_PEERS", use_delimiter = true)]
+    #[serde(with = "peer_ids")]
+    pub ban_peers: Vec<PeerId>,
+
+    /// Only accept connections to and from these peers, can be repeated or
+    /// comma separated. All peers are accepted if none are given
+    #[structopt(long = "allow-peer", env = "MESH_ALLOW_PEERS", use_delimiter = true)]
+    #[serde(with = "peer_ids")]
+    pub allow_peers: Vec<PeerId>,
+
+    /// Do not connect to the default 0x Mesh bootnodes
+    #[structopt(long)]
+    pub no_default_bootnodes: bool,
+
+    /// Bootnode as <PeerId>@<Multiaddr> to use instead of the default 0x Mesh
+    /// bootnodes, can be repeated or comma separated. `none` disables
+    /// bootstrapping
+    #[structopt(long = "bootnode", env = "MESH_BOOTNODES", use_delimiter = true)]
+    pub bootnodes: Vec<Bootnode>,
+
+    /// Do not discover peers on the LAN through mDNS
+    #[structopt(long = "no-mdns")]
+    pub disable_mdns: bool,
+
+    /// Dial peers discovered on the LAN through mDNS instead of only logging them
+    #[structopt(long)]
+    pub mdns_autodial: bool,
+
+    /// Chain to gossip and validate orders for, can be repeated or comma
+    /// separated. Only chains with a known 0x v3 exchange are supported
+    #[structopt(
+        long = "chain-id",
+        env = "MESH_CHAIN_IDS",
+        default_value = "1",
+        use_delimiter = true
+    )]
+    pub chain_ids: Vec<u64>,
+
+    /// JSON schema of the gossiped orders, which names the topic of every
+    /// chain. Peers only share orders on topics with the same schema.
+    #[structopt(long, env = "MESH_ORDER_SCHEMA", default_value = "{}")]
+    pub order_schema: String,
+
+    /// Exchange contract address to accept orders for, can be repeated or
+    /// comma separated. Defaults to the 0x v3 exchange of the chain
+    #[structopt(long = "allow-exchange", env = "MESH_ALLOWED_EXCHANGES", use_delimiter = true)]
+    pub allowed_exchanges: Vec<EthAddress>,
+
+    /// Number of distinct peers that must send an order before it is trusted
+    #[structopt(long, env = "MESH_MIN_CONFIRMATIONS", default_value = "1")]
+    pub min_confirmations: usize,
+
+    /// Seconds past their expiration time during which orders are still
+    /// accepted, to tolerate clock skew
+    #[structopt(long, env = "MESH_EXPIRATION_GRACE", default_value = "60")]
+    pub expiration_grace: u64,
+
+    /// Maximum number of outbound OrderSync requests awaiting a response
+    #[structopt(long, env = "MESH_MAX_PENDING_REQUESTS", default_value = "64")]
+    pub max_pending_requests: usize,
+
+    /// Number of times an OrderSync request is re-sent after failing to dial
+    /// the peer, once its addresses have been resolved again
+    #[structopt(long, env = "MESH_MAX_DIAL_RETRIES", default_value = "1")]
+    pub max_dial_retries: usize,
+
+    /// Byte budget for the orders in an OrderSync response page, keep it
+    /// below the message limits of the peers served
+    #[structopt(long, env = "MESH_MAX_PAGE_BYTES", default_value = "262144")]
+    pub max_page_bytes: usize,
+
+    /// Maximum number of OrderSync requests sent to a peer per second, further
+    /// requests are queued
+    #[structopt(long, env = "MESH_ORDER_SYNC_RATE_PER_PEER", default_value = "10")]
+    pub order_sync_rate_per_peer: u32,
+
+    /// Maximum number of outbound OrderSync requests in flight across all
+    /// peers, further requests are queued
+    #[structopt(long, env = "MESH_ORDER_SYNC_MAX_CONCURRENT", default_value = "32")]
+    pub order_sync_max_concurrent: usize,
+
+    /// Maximum number of OrderSync requests served to a peer per second,
+    /// further requests are rejected
+    #[structopt(long, env = "MESH_ORDER_SYNC_SERVE_RATE", default_value = "10")]
+    pub order_sync_serve_rate: u32,
+
+    /// Maximum number of OrderSync requests served at once, further requests
+    /// are rejected
+    #[structopt(long, env = "MESH_ORDER_SYNC_SERVE_LIMIT", default_value = "32")]
+    pub order_sync_serve_limit: usize,
+
+    /// Softmax temperature for picking the peer to fetch orders from, higher
+    /// values spread fetches more evenly across peers, 0 always picks the
+    /// best scoring one
+    #[structopt(long, env = "MESH_PROVIDER_TEMPERATURE", default_value = "1.0")]
+    pub provider_temperature: f64,
+
+    /// Maximum number of gossip messages published per second, further
+    /// messages are queued
+    #[structopt(long, env = "MESH_MAX_PUBLISHES_PER_SEC", default_value = "20")]
+    pub max_publishes_per_sec: u32,
+
+    /// Number of gossipsub heartbeats for which messages are kept to answer
+    /// IWANT requests
+    #[structopt(long, env = "MESH_GOSSIP_HISTORY_LENGTH", default_value = "10")]
+    pub gossip_history_length: usize,
+
+    /// Number of recent gossipsub heartbeats whose messages are gossiped
+    /// about, at most the history length
+    #[structopt(long, env = "MESH_GOSSIP_HISTORY_GOSSIP", default_value = "3")]
+    pub gossip_history_gossip: usize,
+
+    /// Size in bytes above which received gossip messages are rejected
+    /// without decoding
+    #[structopt(long, env = "MESH_GOSSIP_MAX_MESSAGE_SIZE", default_value = "65536")]
+    pub gossip_max_message_size: usize,
+
+    /// Seconds without gossip messages while peers are connected after which
+    /// the orders topic is resubscribed, 0 to never resubscribe
+    #[structopt(long, env = "MESH_GOSSIP_STALL_TIMEOUT", default_value = "600")]
+    pub gossip_stall_timeout: u64,
+
+    /// Receive gossiped orders without relaying them to other peers
+    #[structopt(long)]
+    pub no_gossip_forward: bool,
+
+    /// Number of peers queried in parallel by Kademlia lookups
+    #[structopt(long, env = "MESH_KAD_PARALLELISM", default_value = "3")]
+    pub kad_parallelism: NonZeroUsize,
+
+    /// Seconds between lookups of random peers to find new ones, 0 disables
+    /// them
+    #[structopt(long, env = "MESH_KAD_REFRESH_SECS", default_value = "60")]
+    pub kad_refresh_secs: u64,
+
+    /// Kademlia protocol name, change it to run a DHT separate from the 0x
+    /// mesh
+    #[structopt(long, env = "MESH_DHT_PROTOCOL_NAME", default_value = "/0x-mesh-dht/version/1")]
+    pub dht_protocol_name: String,
+
+    /// Seconds the info of a disconnected peer is kept, so it survives quick
+    /// reconnects
+    #[structopt(long, env = "MESH_PEER_EVICTION_GRACE", default_value = "300")]
+    pub peer_eviction_grace: u64,
+
+    /// Fetch orders from peers without serving them over OrderSync
+    #[structopt(long)]
+    pub observer: bool,
+
+    /// Serve orders over OrderSync without fetching from peers
+    #[structopt(long, conflicts_with = "observer")]
+    pub seed: bool,
+
+    /// Serve orders over OrderSync and the API from the order store another
+    /// node writes, without gossiping or fetching orders. Needs an order store
+    #[structopt(long, conflicts_with_all = &["observer", "seed"])]
+    pub read_replica: bool,
+
+    /// Seconds between reads of the orders added to the shared store, for
+    /// read replicas
+    #[structopt(long, env = "MESH_REPLICA_REFRESH_SECS", default_value = "5")]
+    pub replica_refresh_secs: u64,
+
+    /// Reject orders whose signature does not recover to the maker,
+    /// including contract signatures that can not be checked offline
+    #[structopt(long)]
+    pub verify_signatures: bool,
+
+    /// Directory to persist received orders in, kept in memory only if not set
+    #[structopt(long, env = "MESH_ORDER_STORE")]
+    pub order_store: Option<PathBuf>,
+
+    /// File holding the node's private key, created if missing. A new peer id
+    /// is used on every start if not set
+    #[structopt(long, env = "MESH_IDENTITY")]
+    pub identity: Option<PathBuf>,
+
+    /// File to save known peers in while running and on shutdown, and load
+    /// them from on startup, not persisted if not set
+    #[structopt(long, env = "MESH_PEER_STORE")]
+    pub peer_store: Option<PathBuf>,
+
+    /// Seconds between saves of the peer store while running
+    #[structopt(long, env = "MESH_PEER_STORE_INTERVAL", default_value = "300")]
+    pub peer_store_interval: u64,
+
+    /// Seconds outstanding OrderSync requests get to finish on shutdown
+    /// before they are dropped
+    #[structopt(long, env = "MESH_SHUTDOWN_TIMEOUT", default_value = "5")]
+    pub shutdown_timeout: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            max_dial_addresses:        5,
+            listen:                    vec![DEFAULT_LISTEN_ADDRESS.parse().unwrap()],
+            api_address:               None,
+            grpc_address:              None,
+            metrics:                   false,
+            metrics_address:           "127.0.0.1:9090".parse().unwrap(),
+            tcp_reuse_address:         true,
+            tcp_reuse_port:            false,
+            tcp_send_buffer_size:      None,
+            tcp_recv_buffer_size:      None,
+            max_inbound_per_ip:        60,
+            enable_wss:                false,
+            wss_certificate:           None,
+            wss_private_key:           None,
+            pnet_psk:                  None,
+            dns_cache_ttl:             300,
+            ban_peers:                 Vec::new(),
+            allow_peers:               Vec::new(),
+            no_default_bootnodes:      false,
+            bootnodes:                 Vec::new(),
+            disable_mdns:              false,
+            mdns_autodial:             false,
+            chain_ids:                 vec![1],
+            order_schema:              UNFILTERED_SCHEMA.into(),
+            allowed_exchanges:         Vec::new(),
+            min_confirmations:         1,
+            expiration_grace:          60,
+            max_pending_requests:      64,
+            max_dial_retries:          order_sync::DEFAULT_DIAL_RETRIES,
+            max_page_bytes:            order_sync::DEFAULT_MAX_PAGE_BYTES,
+            order_sync_rate_per_peer:  10,
+            order_sync_max_concurrent: 32,
+            order_sync_serve_rate:     10,
+            order_sync_serve_limit:    32,
+            provider_temperature:      provider::DEFAULT_TEMPERATURE,
+            max_publishes_per_sec:     20,
+            gossip_history_length:     10,
+            gossip_history_gossip:     3,
+            gossip_max_message_size:   DEFAULT_MAX_MESSAGE_SIZE,
+            gossip_stall_timeout:      600,
+            no_gossip_forward:         false,
+            kad_parallelism:           NonZeroUsize::new(3).unwrap(),
+            kad_refresh_secs:          60,
+            dht_protocol_name:         discovery::DEFAULT_DHT_PROTOCOL.into(),
+            peer_eviction_grace:       300,
+            observer:                  false,
+            seed:                      false,
+            read_replica:              false,
+            replica_refresh_secs:      5,
+            verify_signatures:         false,
+            order_store:               None,
+            identity:                  None,
+            peer_store:                None,
+            peer_store_interval:       300,
+            shutdown_timeout:          5,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Read a TOML configuration file. Options missing from the file keep
+    /// their default, unknown options are an error.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let toml = fs::read_to_string(path)
+            .with_context(|| format!("Reading configuration file {}", path.display()))?;
+        toml::from_str(&toml)
+            .with_context(|| format!("Parsing configuration file {}", path.display()))
+    }
+
+    /// The configuration as a TOML file. Options that are not set, such as
+    /// the API address by default, are left out.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Take the options left at their default from `file`, so options given
+    /// on the command line or in the environment override the configuration
+    /// file. An option set in the file can not be reset to its default this
+    /// way.
+    pub fn merge(self, file: Self) -> anyhow::Result<Self> {
+        let default = serde_json::to_value(Self::default())?;
+        let mut merged = serde_json::to_value(file)?;
+        if let (Some(merged), serde_json::Value::Object(given)) =
+            (merged.as_object_mut(), serde_json::to_value(self)?)
+        {
+            for (key, value) in given {
+                if default.get(&key) != Some(&value) {
+                    let _ = merged.insert(key, value);
+                }
+            }
+        }
+        Ok(serde_json::from_value(merged)?)
+    }
+
+    pub fn tcp_config(&self) -> TcpConfig {
+        TcpConfig {
+            reuse_address: self.tcp_reuse_address,
+            reuse_port: self.tcp_reuse_port,
+            send_buffer_size: self.tcp_send_buffer_size,
+            recv_buffer_size: self.tcp_recv_buffer_size,
+            ..TcpConfig::default()
+        }
+    }
+
+    pub fn wss_config(&self) -> WssConfig {
+        WssConfig {
+            // Without a certificate WebSockets stay plain `/ws`.
+            enabled:     self.enable_wss || self.wss_certificate.is_some(),
+            certificate: self.wss_certificate.clone(),
+            private_key: self.wss_private_key.clone(),
+        }
+    }
+
+    /// OrderSync directions: outbound only for observers, inbound only for
+    /// seeds and read replicas.
+    pub const fn order_sync_support(&self) -> ProtocolSupport {
+        match (self.observer, self.seed || self.read_replica) {
+            (true, _) => ProtocolSupport::Outbound,
+            (false, true) => ProtocolSupport::Inbound,
+            (false, false) => ProtocolSupport::Full,
+        }
+    }
+
+    /// Bootnodes given with `--bootnode`, falling back to the default 0x Mesh
+    /// bootnodes unless `--no-default-bootnodes` is set.
+    pub fn bootnodes(&self) -> Vec<(PeerId, Multiaddr)> {
+        if self.bootnodes.is_empty() && self.no_default_bootnodes {
+            Vec::new()
+        } else {
+            discovery::resolve_bootnodes(&self.bootnodes)
+        }
+    }
+
+    pub fn discovery_config(&self) -> DiscoveryConfig {
+        DiscoveryConfig {
+            kad_parallelism:      self.kad_parallelism,
+            kad_refresh_interval: Duration::from_secs(self.kad_refresh_secs),
+            mdns:                 !self.disable_mdns,
+            dht_protocol_name:    self.dht_protocol_name.clone(),
+            eviction_grace:       Duration::from_secs(self.peer_eviction_grace),
+        }
+    }
+
+    pub const fn throttle_config(&self) -> ThrottleConfig {
+        ThrottleConfig {
+            max_requests_per_peer_per_second: self.order_sync_rate_per_peer,
+            global_max_concurrent:            self.order_sync_max_concurrent,
+        }
+    }
+
+    pub const fn inbound_throttle_config(&self) -> ThrottleConfig {
+        ThrottleConfig {
+            max_requests_per_peer_per_second: self.order_sync_serve_rate,
+            global_max_concurrent:            self.order_sync_serve_limit,
+        }
+    }
+
+    /// The v3 orders topic of every configured chain, none for read replicas.
+    pub fn gossip_topics(&self) -> Vec<GossipTopic> {
+        if self.read_replica {
+            return Vec::new();
+        }
+        self.chain_ids
+            .iter()
+            .map(|&chain_id| GossipTopic::with_schema(chain_id, &self.order_schema))
+            .collect()
+    }
+
+    /// The v3 order filter of every configured chain. Fails if no chain is
+    /// configured or the exchange of a chain is not known.
+    pub fn order_filters(&self) -> anyhow::Result<Vec<OrderFilter>> {
+        if self.chain_ids.is_empty() {
+            return Err(anyhow::anyhow!("No chain ids configured"));
+        }
+        self.chain_ids
+            .iter()
+            .map(|&chain_id| {
+                OrderFilter::v3(chain_id)
+                    .with_context(|| format!("No known 0x v3 exchange on chain {}", chain_id))
+            })
+            .collect()
+    }
+
+    pub const fn pubsub_config(&self) -> PubSubConfig {
+        PubSubConfig {
+            history_length:   self.gossip_history_length,
+            history_gossip:   self.gossip_history_gossip,
+            max_message_size: self.gossip_max_message_size,
+            // Read replicas do not subscribe, so they never receive gossip.
+            stall_timeout:    if self.gossip_stall_timeout == 0 || self.read_replica {
+                None
+            } else {
+                Some(Duration::from_secs(self.gossip_stall_timeout))
+            },
+            forward:          !self.no_gossip_forward,
+        }
+    }
+}
+
+/// Peer ids in configuration files, as base58 strings.
+mod peer_ids {
+    use libp2p::PeerId;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peer_ids: &[PeerId], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(peer_ids.iter().map(PeerId::to_base58))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<PeerId>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|peer_id| peer_id.parse().map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+/// Pre-shared keys in configuration files, as hex strings.
+mod psk {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(key: &Option<[u8; 32]>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match key {
+            Some(key) => serializer.serialize_some(&hex::encode(key)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|digits| {
+                let mut key = [0; 32];
+                hex::decode_to_slice(&digits, &mut key).map_err(de::Error::custom)?;
+                Ok(key)
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_wss_config() {
+        assert!(!NodeConfig::default().wss_config().enabled);
+        let config = NodeConfig {
+            wss_certificate: Some("cert.der".into()),
+            wss_private_key: Some("key.der".into()),
+            ..NodeConfig::default()
+        };
+        assert_eq!(config.wss_config(), WssConfig {
+            enabled:     true,
+            certificate: Some("cert.der".into()),
+            private_key: Some("key.der".into()),
+        });
+    }
+
+    #[test]
+    fn test_order_filters() {
+        let config = NodeConfig {
+            chain_ids: vec![1, 3],
+            ..NodeConfig::default()
+        };
+        assert_eq!(config.order_filters().unwrap(), vec![
+            OrderFilter::mainnet_v3(),
+            OrderFilter::v3(3).unwrap(),
+        ]);
+        for chain_ids in &[vec![], vec![1, 1234]] {
+            let config = NodeConfig {
+                chain_ids: chain_ids.clone(),
+                ..NodeConfig::default()
+            };
+            assert!(config.order_filters().is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_file() {
+        // The default configuration survives a round trip.
+        let config = NodeConfig::default();
+        let toml = config.to_toml().unwrap();
+        assert_eq!(toml::from_str::<NodeConfig>(&toml).unwrap(), config);
+
+        let peer_id = "16Uiu2HAmGx8Z6gdq5T5AQE54GMtqDhDFhizywTy1o28NJbAMMumF";
+        let file: NodeConfig = toml::from_str(&format!(
+            r#"
+            max_dial_addresses = 7
+            api_address = "127.0.0.1:8080"
+            ban_peers = ["{}"]
+            bootnodes = ["none"]
+            "#,
+            peer_id
+        ))
+        .unwrap();
+        assert_eq!(file, NodeConfig {
+            max_dial_addresses: 7,
+            api_address: Some("127.0.0.1:8080".parse().unwrap()),
+            ban_peers: vec![peer_id.parse().unwrap()],
+            bootnodes: vec![Bootnode::None],
+            ..NodeConfig::default()
+        });
+        assert!(toml::from_str::<NodeConfig>("max_dial_adresses = 7").is_err());
+        let wss: NodeConfig = toml::from_str("ws_tls_cert = \"cert.der\"").unwrap();
+        assert_eq!(wss.wss_certificate, Some("cert.der".into()));
+        let pnet = NodeConfig {
+            pnet_psk: Some([0xab; 32]),
+            ..NodeConfig::default()
+        };
+        assert_eq!(toml::from_str::<NodeConfig>(&pnet.to_toml().unwrap()).unwrap(), pnet);
+
+        // Options given on the command line take precedence.
+        let given = NodeConfig {
+            max_dial_addresses: 3,
+            seed: true,
+            ..NodeConfig::default()
+        };
+        assert_eq!(given.merge(file.clone()).unwrap(), NodeConfig {
+            max_dial_addresses: 3,
+            seed: true,
+            ..file
+        });
+    }
+}
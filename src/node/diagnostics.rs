@@ -0,0 +1,118 @@
+//! Dump of the node state for support requests and bug reports.
+//!
+//! ## To do
+//!
+//! * Gossip mesh peers per topic. The gossipsub version of libp2p 0.32 does
+//!   not expose its mesh, so only the subscribed topics are listed.
+//! * NAT status once AutoNAT is available. Until then the external addresses
+//!   peers observed us on show whether the node is reachable.
+
+use super::{
+    behaviour::dht_requests::DhtRequestStats, state::PeerState, validation::ValidationStats,
+};
+use crate::prelude::*;
+use libp2p::Multiaddr;
+
+/// Everything the node knows about itself and its peers.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    pub peer_id:                String,
+    pub supported_protocols:    Vec<String>,
+    pub listen_addresses:       Vec<Multiaddr>,
+    /// Addresses peers observed us on, empty if none could reach us.
+    pub external_addresses:     Vec<Multiaddr>,
+    pub connected_peers:        usize,
+    /// Known peers, sorted by peer id.
+    pub peers:                  Vec<PeerDiagnostics>,
+    /// Number of peers in each non-empty Kademlia bucket, closest first.
+    pub dht_buckets:            Vec<usize>,
+    pub dht_requests:           DhtRequestStats,
+    pub gossip_topics:          Vec<String>,
+    pub gossip_rejected:        u64,
+    pub gossip_resubscriptions: u64,
+    pub orders:                 usize,
+    pub validation:             ValidationStats,
+    pub bandwidth_inbound:      u64,
+    pub bandwidth_outbound:     u64,
+    /// The node configuration in Rust debug notation, with the private
+    /// network key zeroed.
+    pub config:                 String,
+}
+
+/// A known peer, with what is only kept while running.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerDiagnostics {
+    #[serde(flatten)]
+    pub state:       PeerState,
+    pub connections: usize,
+    pub reputation:  f64,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        node::{
+            behaviour::{
+                discovery::PeerInfo,
+                order_sync::messages::{Order, OrderFilter},
+            },
+            Node, NodeConfig,
+        },
+        test::prelude::assert_eq,
+    };
+    use libp2p::{identity::Keypair, PeerId};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(Keypair::generate_ed25519(), config).await.unwrap();
+        node.start().unwrap();
+
+        let peer_id = PeerId::from(Keypair::generate_ed25519().public());
+        let mut peer_info = PeerInfo::new(peer_id.clone());
+        peer_info.ping = Some(Duration::from_millis(42));
+        peer_info.connections = 1;
+        node.known_peers()
+            .write()
+            .unwrap()
+            .insert(peer_id.clone(), peer_info);
+        node.swarm
+            .add_address(&peer_id, "/ip4/10.0.0.1/tcp/60558".parse().unwrap());
+
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: u32::MAX.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..Order::default()
+        };
+        node.ingest_order(order, peer_id.clone()).unwrap();
+
+        let diagnostics = node.diagnostics();
+        assert_eq!(diagnostics.peer_id, node.local_peer_id().to_base58());
+        assert_eq!(diagnostics.peers.len(), 1);
+        assert_eq!(diagnostics.peers[0].state.peer_id, peer_id.to_base58());
+        assert_eq!(diagnostics.peers[0].state.ping, Some(Duration::from_millis(42)));
+        assert_eq!(diagnostics.peers[0].connections, 1);
+        assert_eq!(diagnostics.dht_buckets.iter().sum::<usize>(), 1);
+        assert_eq!(diagnostics.gossip_topics.len(), 1);
+        assert_eq!(diagnostics.orders, 1);
+        assert_eq!(diagnostics.validation.accepted, 1);
+        assert!(diagnostics.config.contains("no_default_bootnodes: true"));
+
+        // Peer fields are flattened into the peer entry.
+        let json = serde_json::to_value(&diagnostics).unwrap();
+        assert_eq!(json["peers"][0]["peerId"], peer_id.to_base58());
+        assert_eq!(json["peers"][0]["connections"], 1);
+        assert_eq!(json["orders"], 1);
+    }
+}
@@ -0,0 +1,44 @@
+//! Errors returned by the [`Node`](super::Node) API.
+//!
+//! Internally the node uses `anyhow`. Errors callers may want to act on get
+//! their own variant, anything else ends up in [`NodeError::Other`].
+
+use super::{
+    behaviour::{
+        order_sync,
+        pubsub::PublishError,
+    },
+    validation::Rejection,
+};
+use crate::prelude::*;
+use libp2p::{core::transport::TransportError, swarm::DialError, PeerId};
+use std::io;
+
+pub type Result<T> = std::result::Result<T, NodeError>;
+
+#[derive(Error, Debug)]
+pub enum NodeError {
+    #[error("Peer {0} is not connected and has no known addresses.")]
+    PeerNotConnected(PeerId),
+
+    #[error("Peer {0} is banned.")]
+    PeerBanned(PeerId),
+
+    #[error("Dial failed: {0}")]
+    Dial(#[from] DialError),
+
+    #[error("Transport error: {0}")]
+    Transport(#[from] TransportError<io::Error>),
+
+    #[error("Publishing failed: {0}")]
+    Publish(#[from] PublishError),
+
+    #[error("Order rejected: {0}")]
+    Rejected(#[from] Rejection),
+
+    #[error("OrderSync error: {0}")]
+    OrderSync(#[from] order_sync::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
@@ -0,0 +1,135 @@
+//! Background OrderSync fetches that can be listed and cancelled.
+
+use super::behaviour::order_sync::{self, messages::Order};
+use crate::prelude::*;
+use futures::future::AbortHandle;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use tokio::task::JoinHandle;
+
+/// Pages and orders received so far by a fetch.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct FetchProgress {
+    pub pages:  usize,
+    pub orders: usize,
+}
+
+/// Description of an active fetch, as listed by
+/// [`Node::active_fetches`](super::Node::active_fetches).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FetchSessionInfo {
+    pub id:       u64,
+    pub peer_id:  PeerId,
+    pub progress: FetchProgress,
+}
+
+struct ActiveFetch {
+    peer_id:  PeerId,
+    progress: Arc<RwLock<FetchProgress>>,
+    abort:    AbortHandle,
+}
+
+/// Registry of active fetches, shared between the node and its RPC handles.
+#[derive(Default)]
+pub struct FetchRegistry {
+    next_id:  AtomicU64,
+    sessions: Mutex<HashMap<u64, ActiveFetch>>,
+}
+
+impl FetchRegistry {
+    pub fn list(&self) -> Vec<FetchSessionInfo> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, fetch)| {
+                FetchSessionInfo {
+                    id:       *id,
+                    peer_id:  fetch.peer_id.clone(),
+                    progress: *fetch.progress.read().unwrap(),
+                }
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|info| info.id);
+        sessions
+    }
+
+    /// Cancel an active fetch. Returns `false` if there is no such fetch.
+    pub fn cancel(&self, id: u64) -> bool {
+        let lock = self.sessions.lock().unwrap();
+        lock.get(&id).map(|fetch| fetch.abort.abort()).is_some()
+    }
+
+    /// Cancel all active fetches, returning how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let lock = self.sessions.lock().unwrap();
+        lock.values().for_each(|fetch| fetch.abort.abort());
+        lock.len()
+    }
+
+    /// Spawn `fetch` as a session. The future reports its progress through the
+    /// shared [`FetchProgress`] it is created with.
+    pub fn spawn<F, Fut>(self: &Arc<Self>, peer_id: PeerId, fetch: F) -> FetchSession
+    where
+        F: FnOnce(Arc<RwLock<FetchProgress>>) -> Fut,
+        Fut: Future<Output = Result<Vec<Order>, order_sync::Error>> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(RwLock::new(FetchProgress::default()));
+        let (future, abort) = futures::future::abortable(fetch(progress.clone()));
+        self.sessions.lock().unwrap().insert(id, ActiveFetch {
+            peer_id,
+            progress: progress.clone(),
+            abort: abort.clone(),
+        });
+        let registry = self.clone();
+        let task = tokio::spawn(future.map(move |result| {
+            registry.sessions.lock().unwrap().remove(&id);
+            result.unwrap_or(Err(order_sync::Error::Cancelled))
+        }));
+        FetchSession {
+            id,
+            progress,
+            abort,
+            task,
+        }
+    }
+}
+
+/// Handle to a fetch running in the background.
+///
+/// Dropping the handle does not cancel the fetch.
+pub struct FetchSession {
+    id:       u64,
+    progress: Arc<RwLock<FetchProgress>>,
+    abort:    AbortHandle,
+    task:     JoinHandle<Result<Vec<Order>, order_sync::Error>>,
+}
+
+impl FetchSession {
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn progress(&self) -> FetchProgress {
+        *self.progress.read().unwrap()
+    }
+
+    /// Stop the fetch. Its outstanding OrderSync request is abandoned and
+    /// [`FetchSession::join`] returns [`order_sync::Error::Cancelled`].
+    pub fn cancel(&self) {
+        self.abort.abort();
+    }
+
+    /// Wait for the fetch to finish and return the orders.
+    pub async fn join(self) -> Result<Vec<Order>, order_sync::Error> {
+        self.task.await.unwrap_or(Err(order_sync::Error::Dropped))
+    }
+}
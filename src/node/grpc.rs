@@ -0,0 +1,273 @@
+//! gRPC API for order consumers, enabled by the `grpc` feature.
+//!
+//! The messages and the `Mesh` service are generated from `proto/mesh.proto`
+//! by `tonic-build`. [`MeshService`] implements the service on top of a node:
+//! `SubscribeOrders` streams the orders the node accepts from then on, and
+//! `GetOrders` answers from the order store. Filters only match on chain and
+//! exchange, custom order schemas are not supported over gRPC.
+//!
+//! Tonic runs on Tokio 0.2, so the server future needs to be run with
+//! `tokio_compat_02::FutureExt::compat`.
+
+use super::{
+    behaviour::order_sync::messages, order_store::OrderStore, subscribe_chains, ChainStreams,
+};
+use crate::{
+    prelude::*,
+    types::{InvalidDecimal, InvalidHex, U256},
+    utils::ShutdownToken,
+};
+use std::{
+    convert::TryFrom,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
+use tonic::{transport::Server, Request, Response, Status};
+
+#[allow(clippy::all, clippy::pedantic, clippy::nursery)]
+mod proto {
+    tonic::include_proto!("mesh");
+}
+
+pub use proto::{
+    mesh_server::{Mesh, MeshServer},
+    GetOrdersRequest, GetOrdersResponse, Order, OrderFilter, SubscribeOrdersRequest,
+};
+
+/// A protobuf order with a malformed address or amount.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+pub enum InvalidOrder {
+    #[error(transparent)]
+    Hex(#[from] InvalidHex),
+    #[error(transparent)]
+    Decimal(#[from] InvalidDecimal),
+}
+
+impl From<messages::Order> for Order {
+    fn from(order: messages::Order) -> Self {
+        Self {
+            chain_id:                order.chain_id,
+            exchange_address:        order.exchange_address.to_string(),
+            maker_address:           order.maker_address.to_string(),
+            maker_asset_data:        order.maker_asset_data,
+            maker_fee_asset_data:    order.maker_fee_asset_data,
+            maker_asset_amount:      order.maker_asset_amount.to_string(),
+            maker_fee:               order.maker_fee.to_string(),
+            taker_address:           order.taker_address.to_string(),
+            taker_asset_data:        order.taker_asset_data,
+            taker_fee_asset_data:    order.taker_fee_asset_data,
+            taker_asset_amount:      order.taker_asset_amount.to_string(),
+            taker_fee:               order.taker_fee.to_string(),
+            sender_address:          order.sender_address.to_string(),
+            fee_recipient_address:   order.fee_recipient_address.to_string(),
+            expiration_time_seconds: order.expiration_time_seconds.to_string(),
+            salt:                    order.salt.to_string(),
+            signature:               order.signature,
+        }
+    }
+}
+
+impl TryFrom<Order> for messages::Order {
+    type Error = InvalidOrder;
+
+    fn try_from(order: Order) -> Result<Self, Self::Error> {
+        Ok(Self {
+            chain_id:                order.chain_id,
+            exchange_address:        order.exchange_address.parse()?,
+            maker_address:           order.maker_address.parse()?,
+            maker_asset_data:        order.maker_asset_data,
+            maker_fee_asset_data:    order.maker_fee_asset_data,
+            maker_asset_amount:      U256::from_decimal(&order.maker_asset_amount)?,
+            maker_fee:               U256::from_decimal(&order.maker_fee)?,
+            taker_address:           order.taker_address.parse()?,
+            taker_asset_data:        order.taker_asset_data,
+            taker_fee_asset_data:    order.taker_fee_asset_data,
+            taker_asset_amount:      U256::from_decimal(&order.taker_asset_amount)?,
+            taker_fee:               U256::from_decimal(&order.taker_fee)?,
+            sender_address:          order.sender_address.parse()?,
+            fee_recipient_address:   order.fee_recipient_address.parse()?,
+            expiration_time_seconds: U256::from_decimal(&order.expiration_time_seconds)?,
+            salt:                    U256::from_decimal(&order.salt)?,
+            signature:               order.signature,
+        })
+    }
+}
+
+impl OrderFilter {
+    /// Whether an order matches the filter. Empty fields match any value.
+    pub fn matches(&self, order: &messages::Order) -> bool {
+        (self.chain_id == 0 || self.chain_id == order.chain_id)
+            && (self.exchange_address.is_empty()
+                || self.exchange_address.parse() == Ok(order.exchange_address))
+    }
+}
+
+/// The `Mesh` service, serving the orders of a node.
+#[derive(Clone)]
+pub struct MeshService {
+    order_store:   Arc<RwLock<OrderStore>>,
+    chain_streams: ChainStreams,
+    /// Chains the node accepts orders of.
+    chain_ids:     Vec<u64>,
+}
+
+impl MeshService {
+    pub(super) const fn new(
+        order_store: Arc<RwLock<OrderStore>>,
+        chain_streams: ChainStreams,
+        chain_ids: Vec<u64>,
+    ) -> Self {
+        Self {
+            order_store,
+            chain_streams,
+            chain_ids,
+        }
+    }
+}
+
+// The signatures are set by the generated trait.
+#[allow(clippy::unused_async, clippy::result_large_err)]
+#[tonic::async_trait]
+impl Mesh for MeshService {
+    type SubscribeOrdersStream =
+        Pin<Box<dyn Stream<Item = Result<Order, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_orders(
+        &self,
+        request: Request<SubscribeOrdersRequest>,
+    ) -> Result<Response<Self::SubscribeOrdersStream>, Status> {
+        let filter = request.into_inner().filter.unwrap_or_default();
+        let chain_ids = self
+            .chain_ids
+            .iter()
+            .copied()
+            .filter(|&chain_id| filter.chain_id == 0 || filter.chain_id == chain_id)
+            .collect::<Vec<_>>();
+        if chain_ids.is_empty() {
+            let message = format!("Chain {} is not served", filter.chain_id);
+            return Err(Status::invalid_argument(message));
+        }
+        let orders = subscribe_chains(&self.chain_streams, &chain_ids)
+            .filter(move |order| future::ready(filter.matches(order)))
+            .map(|order| Ok(Order::from(order)));
+        Ok(Response::new(Box::pin(orders)))
+    }
+
+    async fn get_orders(
+        &self,
+        request: Request<GetOrdersRequest>,
+    ) -> Result<Response<GetOrdersResponse>, Status> {
+        let filter = request.into_inner().filter.unwrap_or_default();
+        let orders = self
+            .order_store
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|stored| filter.matches(&stored.order))
+            .map(|stored| Order::from(stored.order.clone()))
+            .collect();
+        Ok(Response::new(GetOrdersResponse { orders }))
+    }
+}
+
+/// Serve the `Mesh` service on the given address until an error occurs or
+/// `shutdown` fires.
+pub async fn serve(
+    address: SocketAddr,
+    service: MeshService,
+    shutdown: ShutdownToken,
+) -> Result<()> {
+    info!("gRPC API listening on {}", address);
+    Server::builder()
+        .add_service(MeshServer::new(service))
+        .serve_with_shutdown(address, shutdown)
+        .await
+        .context("Running gRPC server")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::{test::valid_order, Node, NodeConfig},
+        test::prelude::assert_eq,
+    };
+    use libp2p::{identity::Keypair, PeerId};
+    use proto::mesh_client::MeshClient;
+    use prost::Message;
+    use tonic::Code;
+
+    #[test]
+    fn test_protobuf_roundtrip() {
+        let order = messages::Order {
+            chain_id: 1,
+            exchange_address: "0x61935cbdd02287b511119ddb11aeb42f1593b7ef".parse().unwrap(),
+            salt: 1601501515.into(),
+            ..messages::Order::default()
+        };
+        let mut bytes = Vec::new();
+        Order::from(order.clone()).encode(&mut bytes).unwrap();
+        let decoded = Order::decode(bytes.as_slice()).unwrap();
+        assert_eq!(messages::Order::try_from(decoded.clone()).unwrap(), order);
+
+        let malformed = Order {
+            maker_fee: "1e18".into(),
+            ..decoded
+        };
+        assert_eq!(
+            messages::Order::try_from(malformed),
+            Err(InvalidOrder::Decimal(InvalidDecimal))
+        );
+
+        let filter = OrderFilter {
+            chain_id:         1,
+            exchange_address: "0x61935CBDD02287B511119DDB11AEB42F1593B7EF".into(),
+        };
+        assert!(filter.matches(&order));
+        assert!(!OrderFilter {
+            chain_id: 3,
+            ..filter
+        }
+        .matches(&order));
+    }
+
+    #[tokio::test]
+    async fn test_mesh_service() {
+        let config = NodeConfig {
+            chain_ids: vec![1, 3],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let mut client = MeshClient::new(MeshServer::new(node.grpc_service()));
+        let filter = |chain_id| {
+            Some(OrderFilter {
+                chain_id,
+                ..OrderFilter::default()
+            })
+        };
+
+        // Orders accepted after subscribing are streamed if they match.
+        let request = SubscribeOrdersRequest { filter: filter(1) };
+        let mut orders = client.subscribe_orders(request).await.unwrap().into_inner();
+        let source = PeerId::random();
+        node.ingest_order(valid_order(3), source.clone()).unwrap();
+        node.ingest_order(valid_order(1), source).unwrap();
+        let order = orders.message().await.unwrap().unwrap();
+        assert_eq!(messages::Order::try_from(order).unwrap(), valid_order(1));
+
+        let request = GetOrdersRequest { filter: filter(3) };
+        let response = client.get_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.orders, vec![Order::from(valid_order(3))]);
+        let request = GetOrdersRequest { filter: None };
+        let response = client.get_orders(request).await.unwrap().into_inner();
+        assert_eq!(response.orders.len(), 2);
+
+        // Chains the node does not accept can not be subscribed to.
+        let request = SubscribeOrdersRequest { filter: filter(42) };
+        let status = client.subscribe_orders(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
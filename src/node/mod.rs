@@ -9,25 +9,100 @@
 // See https://github.com/libp2p/rust-libp2p/issues/983
 // See https://github.com/libp2p/rust-libp2p/issues/1021
 
+mod api;
 mod behaviour;
+mod builder;
+mod config;
+mod diagnostics;
+mod error;
+mod fetch;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod order_sink;
+mod order_snapshot;
+mod order_store;
+mod provider;
+#[cfg(test)]
+mod replay;
+mod state;
 mod transport;
+mod validation;
 
+pub use self::{
+    behaviour::order_sync::messages::{
+        InvalidOrderFilter, Order, OrderFilter, OrderFilterBuilder, OrderHash,
+    },
+    behaviour::pubsub::PublishError,
+    builder::NodeBuilder,
+    config::NodeConfig,
+    diagnostics::{Diagnostics, PeerDiagnostics},
+    error::{NodeError, Result},
+    fetch::{FetchProgress, FetchSession, FetchSessionInfo},
+    order_sink::{OrderSink, SinkForwarder, SinkStats},
+    order_snapshot::SnapshotImport,
+    state::NodeState,
+    validation::{Rejection, ValidationStats, Validator},
+};
 use self::{
-    behaviour::{order_sync, Behaviour, discovery::PeerInfo},
+    behaviour::{
+        address_limit::AddressLimit,
+        dht_requests::DhtRequestStats,
+        discovery::{self, FilePeerStore, PeerInfo, PeerStore},
+        order_sync::{self, OrderSource, OrderSyncHandler},
+        protocol_names, Behaviour,
+    },
+    fetch::FetchRegistry,
+    order_snapshot::OrderSnapshot,
+    order_store::OrderStore,
+    state::PeerState,
     transport::make_transport,
 };
-use crate::prelude::*;
+#[cfg(feature = "metrics")]
+use crate::metrics::{MetricsServer, NodeStats};
+use crate::{
+    prelude::*,
+    utils::{ShutdownToken, ShutdownTrigger},
+};
 use futures::channel::{mpsc, oneshot};
 use libp2p::{
-    bandwidth::BandwidthSinks, core::network::NetworkInfo, gossipsub::Topic, identity,
-    swarm::SwarmBuilder, Multiaddr, PeerId, Swarm,
+    bandwidth::BandwidthSinks,
+    core::{connection::ListenerId, network::NetworkInfo},
+    identity,
+    swarm::{AddressScore, DialError, SwarmBuilder}, Multiaddr, PeerId, Swarm,
 };
 use ubyte::ToByteUnit;
-use tokio::time::sleep;
-use std::time::Duration;
-use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use tokio::time::{interval_at, sleep, Interval};
+use tokio_compat_02::FutureExt as _;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, path::Path};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+
+
+/// Number of times a fetch starts over after the peer expired its snapshot.
+const MAX_SNAPSHOT_RESTARTS: usize = 3;
+
+/// Time [`OrderSyncRpc::call`] waits for a response.
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Time between updates of the Prometheus metrics.
+const METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of undelivered node events before new ones are dropped.
+const EVENT_BUFFER_SIZE: usize = 1024;
 
+/// Start of a protobuf encoded ed25519 private key, the libp2p `PrivateKey`
+/// message with `Type` 1 (ed25519) followed by 64 bytes of `Data`.
+const ED25519_KEY_HEADER: [u8; 4] = [0x08, 0x01, 0x12, 0x40];
+
+/// Events emitted by the node to its consumer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum NodeEvent {
+    /// A valid order was received from at least the configured number of
+    /// distinct peers.
+    OrderReceived(order_sync::messages::Order),
+}
 
 type OrderSyncRequest = (
     PeerId,
@@ -35,18 +110,72 @@ type OrderSyncRequest = (
     oneshot::Sender<order_sync::Result>,
 );
 
+/// Subscribers to trusted orders, by chain id.
+type ChainStreams = Arc<Mutex<HashMap<u64, Vec<mpsc::Sender<order_sync::messages::Order>>>>>;
+
+/// Subscribe to the trusted orders of the given chains.
+fn subscribe_chains(
+    streams: &ChainStreams,
+    chain_ids: &[u64],
+) -> mpsc::Receiver<order_sync::messages::Order> {
+    let (sender, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+    let mut lock = streams.lock().unwrap();
+    for &chain_id in chain_ids {
+        lock.entry(chain_id).or_default().push(sender.clone());
+    }
+    receiver
+}
+
 /// TODO: Impl Debug
 pub struct Node {
-    bandwidth_monitor: Arc<BandwidthSinks>,
-    swarm:             Swarm<Behaviour>,
+    config:              NodeConfig,
+    peer_key:            identity::Keypair,
+    bandwidth_monitor:   Arc<BandwidthSinks>,
+    swarm:               Swarm<AddressLimit<Behaviour>>,
+    /// Validator of every configured chain, sharing their statistics.
+    validators:          BTreeMap<u64, Validator>,
+    order_store:         Arc<RwLock<OrderStore>>,
+    min_confirmations:   usize,
+    supported_protocols: Vec<String>,
+    listen_addresses:    Vec<Multiaddr>,
+    listen_wss:          bool,
+    listener_ids:        Vec<ListenerId>,
+    shutdown_timeout:    Duration,
+    shut_down:           bool,
+
+    /// Rereads the shared order store, for read replicas.
+    store_refresh: Option<Interval>,
+
+    /// Updates the metrics, if they are served.
+    metrics_refresh: Option<Interval>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<MetricsServer>>,
+    /// Stops the metrics server.
+    #[cfg(feature = "metrics")]
+    metrics_shutdown: Option<ShutdownTrigger>,
+
+    event_sender:   mpsc::Sender<NodeEvent>,
+    event_receiver: Option<mpsc::Receiver<NodeEvent>>,
+
+    /// Subscribers to trusted orders, shared with the gRPC service.
+    chain_streams: ChainStreams,
+
+    /// Subscribers to gossiped orders.
+    gossip_streams: Mutex<Vec<mpsc::Sender<order_sync::messages::Order>>>,
 
     order_sync_sender:   mpsc::Sender<OrderSyncRequest>,
     order_sync_receiver: mpsc::Receiver<OrderSyncRequest>,
+    fetches:             Arc<FetchRegistry>,
+    /// Delivery statistics of the order sinks.
+    sink_stats:          Vec<Arc<RwLock<SinkStats>>>,
 }
 
 #[derive(Clone)]
 pub struct OrderSyncRpc {
-    sender: mpsc::Sender<OrderSyncRequest>,
+    sender:      mpsc::Sender<OrderSyncRequest>,
+    fetches:     Arc<FetchRegistry>,
+    /// Orders we already have, which fetches do not need to transfer.
+    order_store: Arc<RwLock<OrderStore>>,
 }
 
 impl OrderSyncRpc {
@@ -55,26 +184,198 @@ impl OrderSyncRpc {
         peer_id: PeerId,
         request: order_sync::messages::Request,
     ) -> order_sync::Result {
-        let (sender, receiver) = oneshot::channel();
-        self.sender.send((peer_id, request, sender)).await?;
-        receiver.await?
+        self.call_with_timeout(peer_id, request, DEFAULT_CALL_TIMEOUT)
+            .await
+    }
+
+    /// Send a request, failing with [`order_sync::Error::Timeout`] if there is
+    /// no response within `timeout`. The timeout includes waiting for the
+    /// request to be sent.
+    pub async fn call_with_timeout(
+        &mut self,
+        peer_id: PeerId,
+        request: order_sync::messages::Request,
+        timeout: Duration,
+    ) -> order_sync::Result {
+        let call = async {
+            let (sender, receiver) = oneshot::channel();
+            self.sender.send((peer_id, request, sender)).await?;
+            receiver.await?
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or(Err(order_sync::Error::Timeout))
+    }
+
+    /// Fetch all orders matching the filter from a peer, following pagination.
+    ///
+    /// The first request carries a sketch of the matching orders in the order
+    /// store, so peers supporting set reconciliation only send the orders we
+    /// do not have yet. Other peers send all orders.
+    ///
+    /// If the peer expires its V0 snapshot while we are paginating, the pages
+    /// fetched so far are discarded and the fetch starts over.
+    pub async fn fetch_all(
+        &mut self,
+        peer_id: PeerId,
+        order_filter: order_sync::messages::OrderFilter,
+    ) -> std::result::Result<Vec<order_sync::messages::Order>, order_sync::Error> {
+        let progress = RwLock::new(FetchProgress::default());
+        self.fetch_with_progress(peer_id, order_filter, &progress)
+            .await
+    }
+
+    /// Run [`OrderSyncRpc::fetch_all`] in the background as a session that is
+    /// listed by [`Node::active_fetches`] and can be cancelled.
+    pub fn spawn_fetch(
+        &self,
+        peer_id: PeerId,
+        order_filter: order_sync::messages::OrderFilter,
+    ) -> FetchSession {
+        let mut rpc = self.clone();
+        self.fetches.spawn(peer_id.clone(), move |progress| {
+            async move {
+                rpc.fetch_with_progress(peer_id, order_filter, &progress)
+                    .await
+            }
+        })
+    }
+
+    async fn fetch_with_progress(
+        &mut self,
+        peer_id: PeerId,
+        order_filter: order_sync::messages::OrderFilter,
+        progress: &RwLock<FetchProgress>,
+    ) -> std::result::Result<Vec<order_sync::messages::Order>, order_sync::Error> {
+        let mut orders = Vec::new();
+        let mut restarts = 0;
+        let mut maybe_request = Some(self.first_request(&order_filter));
+        while let Some(request) = maybe_request {
+            info!("Request: {:#?}", &request);
+            let response = self.call(peer_id.clone(), request.clone()).await?;
+            info!(
+                "Received response {} orders complete: {:?}, metadata: {:#?}",
+                response.orders.len(),
+                response.complete,
+                response.metadata
+            );
+            if response.snapshot_expired(&request) {
+                if restarts == MAX_SNAPSHOT_RESTARTS {
+                    return Err(order_sync::Error::SnapshotExpired);
+                }
+                restarts += 1;
+                warn!("OrderSync snapshot expired, restarting from the first page");
+                orders.clear();
+                *progress.write().unwrap() = FetchProgress::default();
+                maybe_request = Some(self.first_request(&order_filter));
+                continue;
+            }
+            maybe_request = response.next_request(order_filter.clone());
+            orders.extend(response.orders);
+            orders.extend(response.orders_added);
+            let mut progress = progress.write().unwrap();
+            progress.pages += 1;
+            progress.orders = orders.len();
+        }
+        Ok(orders)
+    }
+
+    /// Request the orders matching `order_filter`, with a sketch of the
+    /// matching orders we already have.
+    fn first_request(
+        &self,
+        order_filter: &order_sync::messages::OrderFilter,
+    ) -> order_sync::messages::Request {
+        let hashes = self
+            .order_store
+            .orders(order_filter)
+            .iter()
+            .map(order_sync::messages::Order::hash)
+            .collect::<Vec<_>>();
+        order_sync::messages::Request::with_sketch(order_filter.clone(), &hashes)
     }
 }
 
 impl Node {
-    pub async fn new(peer_id_keys: identity::Keypair) -> Result<Self> {
+    /// Create a node that does nothing until [`Node::start`] is called.
+    /// [`NodeBuilder`] does both at once and is preferred.
+    pub async fn new(peer_id_keys: identity::Keypair, config: NodeConfig) -> Result<Self> {
+        Self::create(peer_id_keys, config, None, None).await
+    }
+
+    /// Create a node, answering OrderSync from `order_sync_handler` and
+    /// keeping peers in `peer_store` if given, or else from the order store
+    /// and in the configured peer store.
+    pub(crate) async fn create(
+        peer_id_keys: identity::Keypair,
+        config: NodeConfig,
+        order_sync_handler: Option<Arc<dyn OrderSyncHandler>>,
+        peer_store: Option<Box<dyn PeerStore>>,
+    ) -> Result<Self> {
+        let node_config = config.clone();
+
         // Generate peer id
         let peer_id = PeerId::from(peer_id_keys.public());
         info!("Peer Id: {}", peer_id.clone());
 
+        // Fail early on chains we can not validate orders for
+        let order_filters = config.order_filters()?;
+
         // Create a transport
-        let (transport, bandwidth_monitor) =
-            make_transport(peer_id_keys.clone()).context("Creating libp2p transport")?;
+        let (transport, bandwidth_monitor) = make_transport(
+            peer_id_keys.clone(),
+            config.tcp_config(),
+            config.max_inbound_per_ip,
+            Duration::from_secs(config.dns_cache_ttl),
+            &config.wss_config(),
+            config.pnet_psk,
+        )
+        .context("Creating libp2p transport")?;
+
+        // Open the order store
+        let order_store = match &config.order_store {
+            Some(dir) if config.read_replica => {
+                OrderStore::open_replica(dir).context("Opening shared order store")?
+            }
+            Some(dir) => OrderStore::open(dir).context("Opening order store")?,
+            None if config.read_replica => {
+                return Err(anyhow::anyhow!("A read replica needs an order store").into());
+            }
+            None => OrderStore::new(),
+        };
+        let order_store = Arc::new(RwLock::new(order_store));
 
         // Create node behaviour
-        let behaviour = Behaviour::new(peer_id_keys)
-            .await
-            .context("Creating node behaviour")?;
+        let bootnodes = config.bootnodes();
+        let peer_store = peer_store.or_else(|| {
+            config
+                .peer_store
+                .as_ref()
+                .map(|path| Box::new(FilePeerStore::new(path)) as Box<dyn PeerStore>)
+        });
+        let behaviour = Behaviour::new(
+            peer_id_keys.clone(),
+            &bootnodes,
+            config.discovery_config(),
+            config.pubsub_config(),
+            config.gossip_topics(),
+            config.order_sync_support(),
+            order_sync_handler,
+            config.max_dial_retries,
+            config.max_page_bytes,
+            peer_store,
+        )
+        .await
+        .context("Creating node behaviour")?;
+        let mut behaviour = AddressLimit::new(behaviour, config.max_dial_addresses);
+        behaviour.set_mdns_autodial(config.mdns_autodial);
+        behaviour.set_max_pending_requests(config.max_pending_requests);
+        behaviour.set_order_sync_throttle(config.throttle_config());
+        behaviour.set_order_sync_inbound_throttle(config.inbound_throttle_config());
+        behaviour.set_max_publishes_per_sec(config.max_publishes_per_sec);
+        behaviour.set_order_source(order_store.clone());
+        let supported_protocols = protocol_names(&mut behaviour, &peer_id);
+        debug!("Supported protocols: {:?}", supported_protocols);
 
         // Executor for connection background tasks.
         let executor = Box::new(|future| {
@@ -83,56 +384,398 @@ impl Node {
         });
 
         // Create a Swarm to manage peers and events.
-        let swarm: Swarm<Behaviour> = SwarmBuilder::new(transport, behaviour, peer_id)
+        let mut swarm: Swarm<AddressLimit<Behaviour>> = SwarmBuilder::new(transport, behaviour, peer_id)
             .executor(executor)
             .build();
 
+        // Apply the saved bans and those from the configuration
+        for peer_id in config.ban_peers {
+            swarm.ban_peer(peer_id);
+        }
+        for peer_id in swarm.banned_peers() {
+            Swarm::ban_peer_id(&mut swarm, peer_id);
+        }
+        if !config.allow_peers.is_empty() {
+            swarm.allow_only(config.allow_peers);
+        }
+
         // Create a channel for OrderSync requests
         let request_buffer_size = 16;
         let (order_sync_sender, order_sync_receiver) = mpsc::channel(request_buffer_size);
+        let (event_sender, event_receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+
+        let mut validator = Validator::new(order_sync::messages::OrderFilter::default());
+        validator.set_expiration_grace(Duration::from_secs(config.expiration_grace));
+        validator.set_allowed_exchanges(config.allowed_exchanges.clone());
+        validator.set_verify_signatures(config.verify_signatures);
+        let validators = order_filters
+            .into_iter()
+            .map(|order_filter| (order_filter.chain_id, validator.for_order_filter(order_filter)))
+            .collect();
+
+        #[cfg(feature = "metrics")]
+        let metrics = if config.metrics {
+            let metrics = MetricsServer::new(bandwidth_monitor.clone())
+                .context("Registering metrics")?;
+            Some(Arc::new(metrics))
+        } else {
+            None
+        };
+        #[cfg(not(feature = "metrics"))]
+        if config.metrics {
+            return Err(anyhow::anyhow!("Metrics require building with the metrics feature").into());
+        }
+        #[cfg(not(feature = "grpc"))]
+        if config.grpc_address.is_some() {
+            return Err(anyhow::anyhow!("The gRPC API requires building with the grpc feature").into());
+        }
+        let metrics_refresh = if config.metrics {
+            Some(interval_at(tokio::time::Instant::now(), METRICS_INTERVAL))
+        } else {
+            None
+        };
+
+        let store_refresh = if config.read_replica {
+            let period = Duration::from_secs(config.replica_refresh_secs.max(1));
+            Some(interval_at(tokio::time::Instant::now() + period, period))
+        } else {
+            None
+        };
 
         Ok(Self {
+            config: node_config,
+            peer_key: peer_id_keys,
             bandwidth_monitor,
             swarm,
+            validators,
+            order_store,
+            min_confirmations: config.min_confirmations,
+            supported_protocols,
+            listen_addresses: config.listen,
+            listen_wss: config.enable_wss,
+            listener_ids: Vec::new(),
+            shutdown_timeout: Duration::from_secs(config.shutdown_timeout),
+            shut_down: false,
+            store_refresh,
+            metrics_refresh,
+            #[cfg(feature = "metrics")]
+            metrics,
+            #[cfg(feature = "metrics")]
+            metrics_shutdown: None,
+            event_sender,
+            event_receiver: Some(event_receiver),
+            chain_streams: Arc::default(),
+            gossip_streams: Mutex::default(),
             order_sync_sender,
             order_sync_receiver,
+            fetches: Arc::default(),
+            sink_stats: Vec::new(),
         })
     }
 
+    /// Start the behaviours and listen on the configured addresses.
     pub fn start(&mut self) -> Result<()> {
         // Start behaviours
         self.swarm.start()?;
 
-        // Listen on all interfaces and whatever port the OS assigns
-        Swarm::listen_on(
-            &mut self.swarm,
-            "/ip4/0.0.0.0/tcp/0"
-                .parse()
-                .context("Parsing listening address")?,
-        )
-        .context("Starting to listen")?;
+        for address in self.listen_addresses.clone() {
+            let listener = Swarm::listen_on(&mut self.swarm, address.clone())
+                .with_context(|| format!("Listening on {}", address))?;
+            self.listener_ids.push(listener);
+        }
+        if self.listen_wss {
+            let listener = Swarm::listen_on(
+                &mut self.swarm,
+                "/ip4/0.0.0.0/tcp/0/wss"
+                    .parse()
+                    .context("Parsing listening address")?,
+            )?;
+            self.listener_ids.push(listener);
+        }
+
+        // Serve the metrics in the background
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            let address = self.config.metrics_address;
+            let (trigger, shutdown) = ShutdownTrigger::new();
+            let server = metrics.clone().serve(address, shutdown);
+            tokio::spawn(async move {
+                if let Err(err) = server.compat().await {
+                    error!("Metrics server failed: {:?}", err);
+                }
+            });
+            self.metrics_shutdown = Some(trigger);
+        }
 
         Ok(())
     }
 
+    /// Shut the node down. Stops listening and gossiping, cancels background
+    /// fetches, gives outstanding OrderSync requests up to `timeout` to finish
+    /// and fails the rest with [`order_sync::Error::Dropped`], closes all
+    /// connections and flushes the order store. Calling it again does nothing.
+    pub async fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+        info!("Shutting down node");
+        for listener in self.listener_ids.drain(..) {
+            let _ = Swarm::remove_listener(&mut self.swarm, listener);
+        }
+        self.swarm.stop();
+        #[cfg(feature = "metrics")]
+        if let Some(trigger) = self.metrics_shutdown.take() {
+            trigger.trigger();
+        }
+        let cancelled = self.fetches.cancel_all();
+        if cancelled > 0 {
+            info!("Cancelled {} fetches", cancelled);
+        }
+
+        // Refuse new requests, and drop the ones not yet sent.
+        self.order_sync_receiver.close();
+        while let Ok(Some((_, _, sender))) = self.order_sync_receiver.try_next() {
+            let _ = sender.send(Err(order_sync::Error::Dropped));
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.pending_order_sync_requests() > 0 && tokio::time::Instant::now() < deadline {
+            // Responses are handled inside the behaviour without a swarm
+            // event, so check the count regularly.
+            let _ = tokio::time::timeout(Duration::from_millis(100), self.swarm.next()).await;
+        }
+        let cancelled = self.swarm.cancel_order_sync_requests();
+        if cancelled > 0 {
+            info!("Dropped {} outstanding OrderSync requests", cancelled);
+        }
+
+        let connected = self
+            .known_peers()
+            .read()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.connections > 0)
+            .map(|peer| peer.peer_id.clone())
+            .collect::<Vec<_>>();
+        for peer_id in connected {
+            self.disconnect(peer_id);
+        }
+
+        Ok(self.order_store.write().unwrap().flush()?)
+    }
+
     /// Create a Send + Sync handle to the OrderSync RPC interface.
     pub fn order_sync_rpc(&self) -> OrderSyncRpc {
         OrderSyncRpc {
-            sender: self.order_sync_sender.clone(),
+            sender:      self.order_sync_sender.clone(),
+            fetches:     self.fetches.clone(),
+            order_store: self.order_store.clone(),
         }
     }
 
+    /// Fetches started with [`OrderSyncRpc::spawn_fetch`] that are still
+    /// running.
+    pub fn active_fetches(&self) -> Vec<FetchSessionInfo> {
+        self.fetches.list()
+    }
+
+    /// Cancel a running fetch. Returns `false` if there is no such fetch.
+    pub fn cancel_fetch(&self, id: u64) -> bool {
+        self.fetches.cancel(id)
+    }
+
+    /// Take the receiving end of the node event channel. Returns `None` if it
+    /// was already taken.
+    pub fn events(&mut self) -> Option<mpsc::Receiver<NodeEvent>> {
+        self.event_receiver.take()
+    }
+
+    /// Validate and store an order received from `source`.
+    ///
+    /// [`NodeEvent::OrderReceived`] is emitted once the order has been
+    /// received from `min_confirmations` distinct peers.
+    pub fn ingest_order(
+        &mut self,
+        order: order_sync::messages::Order,
+        source: PeerId,
+    ) -> std::result::Result<(), Rejection> {
+        self.validator_for(order.chain_id).validate(&order)?;
+        let confirmations = self
+            .order_store
+            .write()
+            .unwrap()
+            .insert_merge(order.clone(), source);
+        if confirmations == Some(self.min_confirmations) {
+            self.dispatch_order(&order);
+            if let Err(err) = self.event_sender.try_send(NodeEvent::OrderReceived(order)) {
+                warn!("Dropping node event: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stream of all orders received over gossip, as they arrive and before
+    /// they are validated. Messages that do not decode to an order are
+    /// counted in [`Node::gossip_rejected`] instead.
+    pub fn order_stream(&self) -> mpsc::Receiver<order_sync::messages::Order> {
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER_SIZE);
+        self.gossip_streams.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Stream of trusted orders for a single chain.
+    ///
+    /// Orders are only buffered for chains that have a stream, and streams
+    /// that are dropped are removed on the next order for their chain.
+    pub fn order_stream_for_chain(
+        &self,
+        chain_id: u64,
+    ) -> mpsc::Receiver<order_sync::messages::Order> {
+        subscribe_chains(&self.chain_streams, &[chain_id])
+    }
+
+    /// The gRPC `Mesh` service for this node, serving the orders of the
+    /// configured chains.
+    #[cfg(feature = "grpc")]
+    pub fn grpc_service(&self) -> grpc::MeshService {
+        grpc::MeshService::new(
+            self.order_store.clone(),
+            self.chain_streams.clone(),
+            self.validators.keys().copied().collect(),
+        )
+    }
+
+    /// Forward the trusted orders of every configured chain to `sink` in the
+    /// background, see [`SinkForwarder`]. Forwarding stops when the node is
+    /// dropped.
+    pub fn spawn_order_sink(&mut self, sink: Arc<dyn OrderSink>) {
+        let chain_ids = self.validators.keys().copied().collect::<Vec<_>>();
+        let orders = subscribe_chains(&self.chain_streams, &chain_ids);
+        let forwarder = SinkForwarder::new(sink);
+        self.sink_stats.push(forwarder.stats());
+        tokio::spawn(forwarder.run(orders));
+    }
+
+    /// Delivery statistics of each sink added with
+    /// [`Node::spawn_order_sink`], in the order they were added.
+    pub fn order_sink_stats(&self) -> Vec<SinkStats> {
+        self.sink_stats
+            .iter()
+            .map(|stats| stats.read().unwrap().clone())
+            .collect()
+    }
+
+    /// Send an order to the streams for its chain.
+    fn dispatch_order(&self, order: &order_sync::messages::Order) {
+        let chain_id = order.chain_id;
+        let mut lock = self.chain_streams.lock().unwrap();
+        if let Some(senders) = lock.get_mut(&chain_id) {
+            senders.retain(|sender| !sender.is_closed());
+            for sender in senders.iter_mut() {
+                if let Err(err) = sender.try_send(order.clone()) {
+                    warn!("Dropping order for chain {}: {}", chain_id, err);
+                }
+            }
+            if senders.is_empty() {
+                lock.remove(&chain_id);
+            }
+        }
+    }
+
+    /// Run the node until `shutdown` fires, then shut it down with the
+    /// configured shutdown timeout.
+    pub async fn run_until(&mut self, mut shutdown: ShutdownToken) -> Result<()> {
+        loop {
+            tokio::select! {
+                result = self.run() => result?,
+                _ = &mut shutdown => break,
+            }
+        }
+        self.shutdown(self.shutdown_timeout).await
+    }
+
     /// Drive the event loop forward
     pub async fn run(&mut self) -> Result<()> {
         let order_sync_request = tokio::select! {
             _ = self.swarm.next() => None,
             r = self.order_sync_receiver.next() => r,
+            _ = tick(&mut self.store_refresh) => {
+                if let Err(err) = self.refresh_store() {
+                    error!("Reading the shared order store failed: {:?}", err);
+                }
+                None
+            }
+            _ = tick(&mut self.metrics_refresh) => {
+                #[cfg(feature = "metrics")]
+                self.update_metrics();
+                None
+            }
         };
         if let Some((peer_id, request, sender)) = order_sync_request {
-            self.swarm.order_sync_send(&peer_id, request, sender);
+            if self.is_read_replica() {
+                let _ = sender.send(Err(order_sync::Error::ReadReplica));
+            } else {
+                self.swarm.order_sync_send(&peer_id, request, sender);
+            }
+        }
+        for peer_id in self.swarm.take_refused_peers() {
+            self.disconnect(peer_id);
+        }
+        for (peer_id, order) in self.swarm.take_gossiped_orders() {
+            self.handle_gossiped_order(order, peer_id);
         }
         Ok(())
     }
+
+    /// Whether the node serves orders from a store written by another node.
+    pub fn is_read_replica(&self) -> bool {
+        self.store_refresh.is_some()
+    }
+
+    /// Read the orders another node added to the shared store. Returns the
+    /// number of entries read, zero unless the node is a read replica.
+    pub fn refresh_store(&self) -> Result<usize> {
+        let entries = self.order_store.write().unwrap().refresh()?;
+        if entries > 0 {
+            debug!("Read {} entries from the shared order store", entries);
+        }
+        Ok(entries)
+    }
+
+    /// Set the metrics to the current node state.
+    #[cfg(feature = "metrics")]
+    fn update_metrics(&mut self) {
+        let metrics = match &self.metrics {
+            Some(metrics) => metrics.clone(),
+            None => return,
+        };
+        let requests = self.order_sync_request_stats();
+        metrics.update(&NodeStats {
+            connected_peers:        self.network_info().num_peers(),
+            dht_routing_table_size: self.swarm.dht_bucket_sizes().iter().sum(),
+            order_sync_sent:        requests.sent,
+            order_sync_received:    requests.received,
+            order_sync_succeeded:   requests.succeeded,
+            order_sync_failed:      requests.failed,
+            orders_fetched:         requests.orders_fetched,
+            gossip_received:        self.gossip_received(),
+        });
+    }
+
+    fn handle_gossiped_order(&mut self, order: order_sync::messages::Order, source: PeerId) {
+        {
+            let mut senders = self.gossip_streams.lock().unwrap();
+            senders.retain(|sender| !sender.is_closed());
+            for sender in senders.iter_mut() {
+                if let Err(err) = sender.try_send(order.clone()) {
+                    warn!("Dropping gossiped order: {}", err);
+                }
+            }
+        }
+        if let Err(err) = self.ingest_order(order, source.clone()) {
+            debug!("Rejecting gossiped order from {}: {}", source, err);
+        }
+    }
 }
 
 // Pass-through accessors
@@ -157,65 +800,565 @@ impl Node {
         self.bandwidth_monitor.total_outbound()
     }
 
+    /// Protocols this node accepts from peers, as negotiated by multistream
+    /// select.
+    pub fn supported_protocols(&self) -> Vec<String> {
+        self.supported_protocols.clone()
+    }
+
     /// Return a handle to the peer database
     pub fn known_peers(&self) -> Arc<RwLock<HashMap<PeerId, PeerInfo>>> {
         self.swarm.known_peers()
     }
+
+    /// Save the peer database to the configured peer store, so the next run
+    /// starts with these peers.
+    pub fn save_peers(&self) -> Result<()> {
+        Ok(self.swarm.save_peers()?)
+    }
+
+    /// Whether the node is subscribed to its order topics.
+    pub fn gossip_subscribed(&self) -> bool {
+        self.swarm.gossip_subscribed()
+    }
+
+    /// Number of received gossip messages, including rejected ones.
+    pub fn gossip_received(&self) -> u64 {
+        self.swarm.gossip_received()
+    }
+
+    /// Number of received gossip messages rejected for their size or content.
+    pub fn gossip_rejected(&self) -> u64 {
+        self.swarm.gossip_rejected()
+    }
+
+    /// Number of times the orders topic was resubscribed after receiving no
+    /// gossip for the stall timeout.
+    pub fn gossip_resubscriptions(&self) -> u64 {
+        self.swarm.gossip_resubscriptions()
+    }
+
+    /// Number of connected peers per Identify agent version.
+    pub fn version_histogram(&self) -> BTreeMap<String, usize> {
+        self.swarm.version_histogram()
+    }
+
+    /// Number of connected peers that reported our DHT protocol through
+    /// Identify.
+    pub fn dht_compatible_peers(&self) -> usize {
+        let dht_protocol = self.swarm.dht_protocol();
+        self.count_connected_peers(|info| info.supports_dht(dht_protocol))
+    }
+
+    /// Number of connected peers that identified without our DHT protocol.
+    /// Kademlia silently ignores these peers.
+    pub fn dht_incompatible_peers(&self) -> usize {
+        let dht_protocol = self.swarm.dht_protocol();
+        self.count_connected_peers(|info| info.identify.is_some() && !info.supports_dht(dht_protocol))
+    }
+
+    fn count_connected_peers(&self, predicate: impl Fn(&PeerInfo) -> bool) -> usize {
+        let known_peers = self.swarm.known_peers();
+        let lock = known_peers.read().unwrap();
+        lock.values()
+            .filter(|info| Swarm::is_connected(&self.swarm, &info.peer_id) && predicate(info))
+            .count()
+    }
+
+    /// Return a handle to the validator of the first configured chain. The
+    /// validators of all chains share their statistics.
+    pub fn validator(&self) -> Validator {
+        self.validators
+            .values()
+            .next()
+            .expect("Nodes are created with at least one chain")
+            .clone()
+    }
+
+    /// The order filter of every configured chain, for fetching their orders.
+    pub fn order_filters(&self) -> Vec<order_sync::messages::OrderFilter> {
+        self.validators
+            .values()
+            .map(|validator| validator.order_filter().clone())
+            .collect()
+    }
+
+    /// The validator for orders of `chain_id`. Orders of other chains go to
+    /// the first validator, which rejects them as [`Rejection::WrongChain`].
+    fn validator_for(&self, chain_id: u64) -> &Validator {
+        self.validators
+            .get(&chain_id)
+            .or_else(|| self.validators.values().next())
+            .expect("Nodes are created with at least one chain")
+    }
+
+    /// Return a handle to the store of accepted orders.
+    pub fn order_store(&self) -> Arc<RwLock<OrderStore>> {
+        self.order_store.clone()
+    }
+
+    /// Look up an accepted order by its hash.
+    pub fn get_order(&self, hash: &OrderHash) -> Option<order_sync::messages::Order> {
+        let store = self.order_store.read().unwrap();
+        store.get(hash).map(|stored| stored.order.clone())
+    }
+
+    /// Number of inbound OrderSync requests rejected by the rate limits.
+    pub fn order_sync_rejected_inbound(&self) -> u64 {
+        self.swarm.order_sync_rejected_inbound()
+    }
+
+    /// OrderSync requests that failed, split into peers not supporting the
+    /// protocol and failures after it was negotiated.
+    pub fn order_sync_negotiation_failures(&self) -> order_sync::NegotiationFailures {
+        self.swarm.order_sync_negotiation_failures()
+    }
+
+    /// Number of OrderSync requests sent, received and failed so far.
+    pub fn order_sync_request_stats(&self) -> order_sync::RequestStats {
+        self.swarm.order_sync_request_stats()
+    }
+
+    /// Number of outbound OrderSync requests awaiting a response.
+    pub fn pending_order_sync_requests(&self) -> usize {
+        self.swarm.pending_order_sync_requests()
+    }
+
+    /// Counts of DHT requests served to other peers, by type.
+    pub fn dht_request_stats(&self) -> DhtRequestStats {
+        self.swarm.dht_request_stats()
+    }
+
+    /// Counts of accepted and rejected orders so far.
+    pub fn validation_stats(&self) -> ValidationStats {
+        self.validator().stats()
+    }
 }
 
-pub async fn run() -> Result<()> {
-    let peer_id_keys = identity::Keypair::generate_ed25519();
-    let mut node = Node::new(peer_id_keys).await.context("Creating node")?;
-    node.start()?;
+// Node identity
+impl Node {
+    /// Load the node's keypair from `path`, or generate one and save it there
+    /// readable by the owner only, so the peer id survives restarts. A file
+    /// that does not hold a protobuf encoded ed25519 key is an error.
+    pub fn load_or_generate_key(path: &Path) -> Result<identity::Keypair> {
+        if path.exists() {
+            let mut encoded =
+                fs::read(path).with_context(|| format!("Reading identity {}", path.display()))?;
+            if encoded.len() != ED25519_KEY_HEADER.len() + 64
+                || !encoded.starts_with(&ED25519_KEY_HEADER)
+            {
+                return Err(anyhow::anyhow!(
+                    "Identity {} is not a protobuf encoded ed25519 key",
+                    path.display()
+                )
+                .into());
+            }
+            let keypair = identity::ed25519::Keypair::decode(&mut encoded[ED25519_KEY_HEADER.len()..])
+                .with_context(|| format!("Decoding identity {}", path.display()))?;
+            return Ok(identity::Keypair::Ed25519(keypair));
+        }
+
+        let keypair = identity::ed25519::Keypair::generate();
+        let mut encoded = ED25519_KEY_HEADER.to_vec();
+        encoded.extend_from_slice(&keypair.encode());
+        let mut options = fs::OpenOptions::new();
+        let _ = options.write(true).create_new(true);
+        #[cfg(unix)]
+        let _ = std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, &encoded))
+            .with_context(|| format!("Writing identity {}", path.display()))?;
+        info!("Generated new identity in {}", path.display());
+        Ok(identity::Keypair::Ed25519(keypair))
+    }
+}
+
+// Peer bans
+impl Node {
+    /// Ban a peer. Existing connections are closed and new connections to or
+    /// from the peer are refused. The peer is forgotten, and the ban is saved
+    /// with the peer store.
+    pub fn ban_peer(&mut self, peer_id: PeerId) {
+        info!("Banning peer {}", peer_id);
+        Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+        self.swarm.ban_peer(peer_id);
+    }
+
+    pub fn unban_peer(&mut self, peer_id: PeerId) {
+        info!("Unbanning peer {}", peer_id);
+        self.swarm.unban_peer(&peer_id);
+        Swarm::unban_peer_id(&mut self.swarm, peer_id);
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.swarm.is_banned(peer_id)
+    }
+
+    pub fn banned_peers(&self) -> Vec<PeerId> {
+        self.swarm.banned_peers()
+    }
+
+    /// Only accept connections to and from `peers`. Connections to other
+    /// peers are closed and OrderSync requests to them fail.
+    pub fn allow_only(&mut self, peers: Vec<PeerId>) {
+        info!("Only allowing {} peers", peers.len());
+        self.swarm.allow_only(peers);
+        for peer_id in self.swarm.take_refused_peers() {
+            self.disconnect(peer_id);
+        }
+    }
+
+    /// Accept all peers that are not banned again.
+    pub fn allow_all(&mut self) {
+        self.swarm.allow_all();
+    }
+
+    /// Whether the peer is neither banned nor outside the allowed peers.
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.swarm.is_allowed(peer_id)
+    }
+
+    /// Close the connections to a peer. libp2p 0.32 has no way to disconnect
+    /// a peer other than banning it, so ban it and lift the ban again right
+    /// away.
+    fn disconnect(&mut self, peer_id: PeerId) {
+        if !self.is_banned(&peer_id) {
+            Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+            Swarm::unban_peer_id(&mut self.swarm, peer_id);
+        }
+    }
+
+    /// Queue an order for publishing on the orders topic of its chain.
+    /// Orders are published at the configured rate.
+    ///
+    /// Orders this node would reject from peers fail with
+    /// [`NodeError::Rejected`], including orders for chains it is not
+    /// configured for. Fails with [`NodeError::Publish`] when too many orders
+    /// are waiting.
+    pub fn queue_publish(&mut self, order: &order_sync::messages::Order) -> Result<()> {
+        self.validate_for_publish(order)?;
+        Ok(self.swarm.queue_publish(order)?)
+    }
+
+    /// Publish an order through the publish queue, see
+    /// [`Node::queue_publish`].
+    pub fn publish_order(&mut self, order: &order_sync::messages::Order) -> Result<()> {
+        self.queue_publish(order)
+    }
+
+    /// Validate an order we are about to publish with the rules of the
+    /// configured chain it is for.
+    fn validate_for_publish(
+        &self,
+        order: &order_sync::messages::Order,
+    ) -> std::result::Result<(), Rejection> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.validators
+            .get(&order.chain_id)
+            .ok_or(Rejection::WrongChain)?
+            .validate_for_publish(order, now)
+    }
+
+    /// Dial a peer using the addresses known for it.
+    pub fn dial(&mut self, peer_id: &PeerId) -> Result<()> {
+        Swarm::dial(&mut self.swarm, peer_id).map_err(|err| {
+            match err {
+                DialError::NoAddresses => NodeError::PeerNotConnected(peer_id.clone()),
+                DialError::Banned => NodeError::PeerBanned(peer_id.clone()),
+                err @ DialError::ConnectionLimit(_) => err.into(),
+            }
+        })
+    }
+}
+
+// State snapshots
+impl Node {
+    /// Collect the state of the node and all its behaviours, for support
+    /// requests and bug reports.
+    pub fn diagnostics(&mut self) -> Diagnostics {
+        let mut peers = self
+            .known_peers()
+            .read()
+            .unwrap()
+            .values()
+            .map(|peer| {
+                PeerDiagnostics {
+                    state:       PeerState::from(peer.clone()),
+                    connections: peer.connections,
+                    reputation:  peer.reputation,
+                }
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.state.peer_id.cmp(&b.state.peer_id));
+        // Diagnostics are shared, so the private network key is left out.
+        let redacted = NodeConfig {
+            pnet_psk: self.config.pnet_psk.map(|_| [0; 32]),
+            ..self.config.clone()
+        };
+        Diagnostics {
+            peer_id: self.local_peer_id().to_base58(),
+            supported_protocols: self.supported_protocols(),
+            listen_addresses: self.listeners().cloned().collect(),
+            external_addresses: Swarm::external_addresses(&self.swarm)
+                .map(|record| record.addr.clone())
+                .collect(),
+            connected_peers: self.network_info().num_peers(),
+            peers,
+            dht_buckets: self.swarm.dht_bucket_sizes(),
+            dht_requests: self.dht_request_stats(),
+            gossip_topics: self.swarm.subscribed_topics(),
+            gossip_rejected: self.gossip_rejected(),
+            gossip_resubscriptions: self.gossip_resubscriptions(),
+            orders: self.order_store.read().unwrap().len(),
+            validation: self.validation_stats(),
+            bandwidth_inbound: self.total_inbound(),
+            bandwidth_outbound: self.total_outbound(),
+            config: format!("{:?}", redacted),
+        }
+    }
+
+    /// Export a snapshot of the peer database, DHT routing table and external
+    /// addresses.
+    pub fn export_state(&mut self) -> NodeState {
+        let peers = self.known_peers().read().unwrap().values().cloned().collect::<Vec<_>>();
+        let external_addresses = Swarm::external_addresses(&self.swarm)
+            .map(|record| record.addr.clone())
+            .collect();
+        let orders = self
+            .order_store
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        NodeState::new(peers, self.swarm.routing_table(), external_addresses, orders)
+    }
+
+    /// Import a snapshot created by [`Node::export_state`], merging it into the
+    /// current state.
+    pub fn import_state(&mut self, state: NodeState) -> Result<()> {
+        state.check_version()?;
+        let peers = state.peer_infos().context("Decoding peer database")?;
+        let routing_table = state.routing_entries().context("Decoding routing table")?;
+        let orders = state.stored_orders().context("Decoding orders")?;
+
+        let known_peers = self.known_peers();
+        let mut lock = known_peers.write().unwrap();
+        for mut peer_info in peers {
+            // Connections are live state, keep the current count.
+            if let Some(current) = lock.get(&peer_info.peer_id) {
+                peer_info.connections = current.connections;
+            }
+            lock.insert(peer_info.peer_id.clone(), peer_info);
+        }
+        drop(lock);
+        for (peer_id, addresses) in routing_table {
+            for address in addresses {
+                self.swarm.add_address(&peer_id, address);
+            }
+        }
+        for address in state.external_addresses {
+            Swarm::add_external_address(&mut self.swarm, address, AddressScore::Finite(1));
+        }
+        let mut order_store = self.order_store.write().unwrap();
+        for stored in orders {
+            for source in stored.sources {
+                order_store.insert_merge_at(stored.order.clone(), source, stored.received_at);
+            }
+        }
+        Ok(())
+    }
+
+    /// Export the accepted orders as a gzipped snapshot signed with the node
+    /// key, for other nodes to warm up from.
+    pub fn export_order_snapshot(&self) -> Result<Vec<u8>> {
+        let orders = self
+            .order_store
+            .read()
+            .unwrap()
+            .iter()
+            .map(|stored| stored.order.clone())
+            .collect();
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        let snapshot = OrderSnapshot::new(orders, &self.peer_key, created_at)?;
+        Ok(snapshot.encode()?)
+    }
+
+    /// Import a snapshot created by [`Node::export_order_snapshot`].
+    ///
+    /// Orders are validated and stored as if received from the signer, so
+    /// they count as a single confirmation. Callers that only trust certain
+    /// peers should check [`SnapshotImport::signer`].
+    pub fn import_order_snapshot(&mut self, bytes: &[u8]) -> Result<SnapshotImport> {
+        let snapshot = OrderSnapshot::decode(bytes)?;
+        let signer = snapshot.verify()?;
+        let mut import = SnapshotImport {
+            signer:   signer.clone(),
+            accepted: 0,
+            rejected: 0,
+        };
+        for order in snapshot.orders {
+            match self.ingest_order(order, signer.clone()) {
+                Ok(()) => import.accepted += 1,
+                Err(_) => import.rejected += 1,
+            }
+        }
+        info!(
+            "Imported {} orders from snapshot by {}, rejected {}",
+            import.accepted, import.signer, import.rejected
+        );
+        Ok(import)
+    }
+}
+
+/// Wait for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            let _ = interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// The configured identity, or a new one for this run only.
+fn identity_key(config: &NodeConfig) -> anyhow::Result<identity::Keypair> {
+    match &config.identity {
+        Some(path) => Ok(Node::load_or_generate_key(path)?),
+        None => Ok(identity::Keypair::generate_ed25519()),
+    }
+}
+
+/// Run a node until SIGINT or SIGTERM.
+pub async fn run(config: NodeConfig) -> anyhow::Result<()> {
+    // Catch SIGTERM so the container can shutdown without an init process.
+    let (trigger, shutdown) = ShutdownTrigger::new();
+    let _signals = tokio::spawn(async move {
+        if let Some(signal) = shutdown_signal().await {
+            info!("{} received, shutting down", signal);
+            trigger.trigger();
+        }
+    });
+    run_until(config, shutdown).await
+}
+
+/// Wait for SIGINT or, on Unix, SIGTERM and return its name. Returns `None`
+/// if no signal can be listened for.
+async fn shutdown_signal() -> Option<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                return tokio::select! {
+                    result = tokio::signal::ctrl_c() => result.ok().map(|()| "SIGINT"),
+                    _ = sigterm.recv() => Some("SIGTERM"),
+                };
+            }
+            Err(err) => warn!("Can not listen for SIGTERM: {}", err),
+        }
+    }
+    tokio::signal::ctrl_c().await.ok().map(|()| "SIGINT")
+}
+
+/// Run a node until `shutdown` fires, which stops the API server, the
+/// initial fetch and the node itself.
+pub async fn run_until(config: NodeConfig, mut shutdown: ShutdownToken) -> anyhow::Result<()> {
+    let peer_id_keys = identity_key(&config)?;
+    let api_address = config.api_address;
+    #[cfg(feature = "grpc")]
+    let grpc_address = config.grpc_address;
+    // Seeds and read replicas only serve orders.
+    let serve_only = config.seed || config.read_replica;
+    let provider_temperature = config.provider_temperature;
+    let peer_store_interval = Duration::from_secs(config.peer_store_interval.max(1));
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    let mut node = NodeBuilder::new(config)
+        .keypair(peer_id_keys)
+        .build()
+        .await
+        .context("Creating node")?;
 
     let known_peers = node.known_peers();
     let mut order_sync_rpc = node.order_sync_rpc();
+    let order_filters = node.order_filters();
 
-    // Catch SIGTERM so the container can shutdown without an init process.
-    let sigterm = tokio::signal::ctrl_c();
-    tokio::pin!(sigterm);
+    // Serve the APIs in the background
+    if let Some(address) = api_address {
+        let api = api::serve(address, node.validator(), node.order_store(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(err) = api.compat().await {
+                error!("API server failed: {:?}", err);
+            }
+        });
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(address) = grpc_address {
+        let server = grpc::serve(address, node.grpc_service(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(err) = server.compat().await {
+                error!("gRPC server failed: {:?}", err);
+            }
+        });
+    }
 
     // Fetch orders from node
     // 16Uiu2HAkzQUGvnR21snR3HSsfCgYFkUJn4LzSSSkNbBwefwfdtT8
     let fetch = async {
+        if serve_only {
+            future::pending::<()>().await;
+        }
+
         // Find a peer that supports the order_sync protocol
         let protocol: String = "/0x-mesh/order-sync/version/0".into();
-        let peer_id = 'outer: loop {
+        let peer_id = loop {
             info!("Looking for peer to fetch from");
-            let lock = known_peers.read().unwrap();
-            for (peer_id, peer_info) in lock.iter() {
-                if let Some(identify_info) = &peer_info.identify {
-                    if identify_info.protocols.contains(&protocol) {
-                        break 'outer peer_id.clone();
-                    };
-                }
+            // The guard is dropped before waiting, so the event loop can
+            // update peers in the meantime.
+            let providers = {
+                let peers = known_peers.read().unwrap();
+                discovery::best_peers_for_protocol(&peers, &protocol, provider::CANDIDATES)
+                    .into_iter()
+                    .map(|peer_id| {
+                        let reputation = peers[&peer_id].reputation;
+                        (peer_id, reputation)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let selected =
+                provider::select_provider(&providers, provider_temperature, &mut rand::thread_rng());
+            if let Some(peer_id) = selected {
+                break peer_id.clone();
             }
-            drop(lock);
             info!("No peers found, wait and retry.");
             sleep(Duration::from_secs(20)).await;
         };
         info!("Inquiring peer {}", &peer_id);
 
-        // First fetch
+        // First fetch, of every configured chain
         let mut orders = Vec::new();
-        let order_filter = order_sync::messages::OrderFilter::mainnet_v3();
-        if true {
-            let mut maybe_request = Some(order_filter.clone().into());
-            while let Some(request) = maybe_request {
-                info!("Request: {:#?}", &request);
-                let response = order_sync_rpc.call(peer_id.clone(), request).await?;
-                info!("Received response {} orders complete: {:?}, metadata: {:#?}", response.orders.len(), response.complete, response.metadata);
-                maybe_request = response.next_request(order_filter.clone());
-                orders.extend(response.orders);
-                info!("Last order: {}", orders.last().unwrap().signature);
-            }
+        for order_filter in order_filters {
+            let chain_id = order_filter.chain_id;
+            let fetched = order_sync_rpc.fetch_all(peer_id.clone(), order_filter).await?;
+            info!("Fetched {} orders of chain {}", fetched.len(), chain_id);
+            orders.extend(fetched);
         }
-        info!("Fetched {} orders", orders.len());
-        anyhow::Result::<_>::Ok(orders)
+        anyhow::Result::<_>::Ok((peer_id, orders))
     }
     .fuse();
     tokio::pin!(fetch);
 
+    // Save peers periodically, so a crash does not lose them all
+    let mut save_peers = tokio::time::interval_at(
+        tokio::time::Instant::now() + peer_store_interval,
+        peer_store_interval,
+    );
+
     // Kick it off
     loop {
         tokio::select! {
@@ -223,16 +1366,23 @@ pub async fn run() -> Result<()> {
             },
             result = &mut fetch  => match result {
                 Err(err) => error!("OrderSync fetch failed: {}", err),
-                Ok(orders) => {
+                Ok((peer_id, mut orders)) => {
+                    orders.retain(|order| node.ingest_order(order.clone(), peer_id.clone()).is_ok());
                     info!("OrderSync fetch finished successfully with {} orders.", orders.len());
                     
                     let mut file = std::fs::File::create("order.json").unwrap();
                     serde_json::to_writer_pretty(file, &orders).unwrap();
                 }
             },
-            _ = &mut sigterm => {
-                info!("SIGTERM received, shutting down");
-                // TODO: Shut down swarm?
+            _ = save_peers.tick() => {
+                if let Err(err) = node.save_peers() {
+                    error!("Saving peers failed: {}", err);
+                }
+            },
+            _ = &mut shutdown => {
+                if let Err(err) = node.shutdown(shutdown_timeout).await {
+                    error!("Shutdown failed: {}", err);
+                }
                 break;
             }
         }
@@ -247,7 +1397,936 @@ pub async fn run() -> Result<()> {
         node.total_outbound().bytes()
     );
     info!("Peers discovered: {:?}", known_peers.read().unwrap().len());
-    // TODO: Store and load peer info
+    if let Err(err) = node.save_peers() {
+        error!("Saving peers failed: {}", err);
+    }
 
     Ok(())
 }
+
+/// Run a node for `settle` to connect to and identify peers, then return the
+/// number of connected peers per agent version.
+pub async fn network_versions(
+    config: NodeConfig,
+    settle: Duration,
+) -> anyhow::Result<BTreeMap<String, usize>> {
+    let peer_id_keys = identity_key(&config)?;
+    let mut node = NodeBuilder::new(config)
+        .keypair(peer_id_keys)
+        .build()
+        .await
+        .context("Creating node")?;
+
+    info!("Waiting {:?} for peers to identify", settle);
+    let deadline = tokio::time::Instant::now() + settle;
+    while let Ok(result) = tokio::time::timeout_at(deadline, node.run()).await {
+        result?;
+    }
+    if let Err(err) = node.save_peers() {
+        error!("Saving peers failed: {}", err);
+    }
+    Ok(node.version_histogram())
+}
+
+/// Run a node for `settle` to connect to and identify peers, then return its
+/// diagnostics.
+pub async fn diagnostics(config: NodeConfig, settle: Duration) -> anyhow::Result<Diagnostics> {
+    let peer_id_keys = identity_key(&config)?;
+    let mut node = NodeBuilder::new(config)
+        .keypair(peer_id_keys)
+        .build()
+        .await
+        .context("Creating node")?;
+
+    info!("Waiting {:?} for peers to identify", settle);
+    let deadline = tokio::time::Instant::now() + settle;
+    while let Ok(result) = tokio::time::timeout_at(deadline, node.run()).await {
+        result?;
+    }
+    Ok(node.diagnostics())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::swarm::SwarmEvent;
+
+    #[tokio::test]
+    async fn test_fetch_all_restarts_on_snapshot_expiry() {
+        use order_sync::messages::{Order, OrderFilter, Response, ResponseMetadata};
+
+        let page = |page, snapshot_id: &str, salt: u64, complete| {
+            Response {
+                orders: vec![Order {
+                    salt: salt.into(),
+                    ..Order::default()
+                }],
+                complete,
+                metadata: ResponseMetadata::V0 {
+                    page,
+                    snapshot_id: snapshot_id.into(),
+                },
+                ..Response::default()
+            }
+        };
+        let mut responses = vec![
+            page(0, "a", 1, false),
+            // Snapshot `a` expired when requesting the second page
+            page(0, "b", 1, false),
+            page(0, "b", 1, false),
+            page(1, "b", 2, true),
+        ]
+        .into_iter();
+
+        let (sender, mut receiver) = mpsc::channel::<OrderSyncRequest>(1);
+        tokio::spawn(async move {
+            while let Some((_, _, sender)) = receiver.next().await {
+                let _ = sender.send(Ok(responses.next().unwrap()));
+            }
+        });
+        let mut rpc = OrderSyncRpc {
+            sender,
+            fetches: Arc::default(),
+            order_store: Arc::default(),
+        };
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let orders = rpc.fetch_all(peer_id, OrderFilter::default()).await.unwrap();
+        let salts = orders.iter().map(|order| order.salt).collect::<Vec<_>>();
+        assert_eq!(salts, vec![1.into(), 2.into()]);
+    }
+
+    #[tokio::test]
+    async fn test_call_timeout() {
+        use order_sync::messages::Request;
+
+        // Keeps the response senders without answering, until told to drop
+        // them.
+        let (sender, mut receiver) = mpsc::channel::<OrderSyncRequest>(1);
+        let (drop_sender, drop_receiver) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let first = receiver.next().await.unwrap();
+            drop_receiver.await.unwrap();
+            drop(first);
+            while let Some(request) = receiver.next().await {
+                drop(request);
+            }
+        });
+        let mut rpc = OrderSyncRpc {
+            sender,
+            fetches: Arc::default(),
+            order_store: Arc::default(),
+        };
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let timeout = Duration::from_millis(50);
+        let result = rpc
+            .call_with_timeout(peer_id.clone(), Request::default(), timeout)
+            .await;
+        assert!(matches!(result, Err(order_sync::Error::Timeout)));
+
+        // A dropped sender fails right away instead.
+        drop_sender.send(()).unwrap();
+        let result = rpc
+            .call_with_timeout(peer_id, Request::default(), Duration::from_secs(10))
+            .await;
+        assert!(matches!(result, Err(order_sync::Error::Dropped)));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_fetch_session() {
+        use order_sync::messages::{Order, OrderFilter, Response, ResponseMetadata};
+
+        // A slow peer that serves an endless list of pages, and never answers
+        // the fourth request.
+        let (sender, mut receiver) = mpsc::channel::<OrderSyncRequest>(1);
+        let (stalled_sender, stalled_receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut page = 0;
+            while let Some((_, _, sender)) = receiver.next().await {
+                if page == 3 {
+                    let _ = stalled_sender.send(sender);
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+                let _ = sender.send(Ok(Response {
+                    orders:   vec![Order::default()],
+                    complete: false,
+                    metadata: ResponseMetadata::V0 {
+                        page,
+                        snapshot_id: "a".into(),
+                    },
+                    ..Response::default()
+                }));
+                page += 1;
+            }
+        });
+
+        let fetches = Arc::new(FetchRegistry::default());
+        let rpc = OrderSyncRpc {
+            sender,
+            fetches: fetches.clone(),
+            order_store: Arc::default(),
+        };
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let session = rpc.spawn_fetch(peer_id.clone(), OrderFilter::default());
+        let stalled = stalled_receiver.await.unwrap();
+        assert_eq!(session.progress(), FetchProgress {
+            pages:  3,
+            orders: 3,
+        });
+        assert_eq!(fetches.list(), vec![FetchSessionInfo {
+            id: session.id(),
+            peer_id,
+            progress: session.progress(),
+        }]);
+
+        session.cancel();
+        assert!(matches!(session.join().await, Err(order_sync::Error::Cancelled)));
+        assert!(fetches.list().is_empty());
+        // The outstanding request is abandoned, so OrderSync can drop it.
+        assert!(stalled.is_canceled());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        use order_sync::messages::Request;
+
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            listen: vec![
+                "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+                "/ip4/127.0.0.1/tcp/0/ws".parse().unwrap(),
+            ],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        node.start().unwrap();
+        assert_eq!(node.listener_ids.len(), 2);
+
+        // A request not yet handed to OrderSync is dropped.
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let (sender, receiver) = oneshot::channel();
+        node.order_sync_sender
+            .clone()
+            .send((peer_id.clone(), Request::default(), sender))
+            .await
+            .unwrap();
+        node.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert!(matches!(receiver.await.unwrap(), Err(order_sync::Error::Dropped)));
+        assert!(node.listener_ids.is_empty());
+
+        // New requests are refused, and shutting down again does nothing.
+        let result = node.order_sync_rpc().call(peer_id, Request::default()).await;
+        assert!(matches!(result, Err(order_sync::Error::Dropped)));
+        node.shutdown(Duration::from_secs(5)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_in_flight_request() {
+        use order_sync::messages::Request;
+
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            ..NodeConfig::default()
+        };
+        let mut server = Node::new(identity::Keypair::generate_ed25519(), config.clone())
+            .await
+            .unwrap();
+        server.start().unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = server.swarm.next_event().await {
+                break address;
+            }
+        };
+        let mut client = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let server_id = server.local_peer_id().clone();
+        client.swarm.add_address(&server_id, address);
+
+        // The server is not polled from here on, so the request stays in
+        // flight.
+        let (sender, receiver) = oneshot::channel();
+        client
+            .order_sync_sender
+            .clone()
+            .send((server_id, Request::default(), sender))
+            .await
+            .unwrap();
+        while client.pending_order_sync_requests() == 0 {
+            client.run().await.unwrap();
+        }
+
+        let timeout = Duration::from_millis(200);
+        let started = tokio::time::Instant::now();
+        client.shutdown(timeout).await.unwrap();
+        assert!(started.elapsed() < timeout + Duration::from_secs(1));
+        assert_eq!(client.pending_order_sync_requests(), 0);
+        match receiver.await.unwrap() {
+            Ok(_) | Err(order_sync::Error::Dropped) => {}
+            Err(err) => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_until() {
+        use order_sync::messages::Request;
+
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        node.start().unwrap();
+        assert!(node.gossip_subscribed());
+        let peer_id = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let session = node.fetches.spawn(peer_id.clone(), |_| future::pending());
+
+        let (trigger, shutdown) = ShutdownTrigger::new();
+        let cancel = async {
+            sleep(Duration::from_millis(100)).await;
+            trigger.trigger();
+        };
+        let (result, ()) = tokio::time::timeout(
+            Duration::from_secs(1),
+            future::join(node.run_until(shutdown), cancel),
+        )
+        .await
+        .unwrap();
+        result.unwrap();
+
+        assert!(matches!(session.join().await, Err(order_sync::Error::Cancelled)));
+        assert!(node.active_fetches().is_empty());
+        assert!(node.listener_ids.is_empty());
+        assert!(!node.gossip_subscribed());
+        let result = node.order_sync_rpc().call(peer_id, Request::default()).await;
+        assert!(matches!(result, Err(order_sync::Error::Dropped)));
+    }
+
+    #[tokio::test]
+    async fn test_read_replica() {
+        use order_sync::messages::{Order, OrderFilter, Request};
+
+        let order_filter = OrderFilter::mainnet_v3();
+        let valid = |salt: u64| {
+            Order {
+                chain_id: order_filter.chain_id,
+                exchange_address: order_filter.exchange_address,
+                maker_asset_amount: 1000.into(),
+                taker_asset_amount: 2000.into(),
+                expiration_time_seconds: u32::MAX.into(),
+                signature: format!("0x{}02", "1b".repeat(65)),
+                salt: salt.into(),
+                ..Order::default()
+            }
+        };
+        let source = PeerId::from(identity::Keypair::generate_ed25519().public());
+
+        // The ingester writes the shared store.
+        let dir = tempfile::tempdir().unwrap();
+        let mut ingester = OrderStore::open(dir.path()).unwrap();
+        let _ = ingester.insert_merge(valid(1), source.clone());
+
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            read_replica: true,
+            order_store: Some(dir.path().to_path_buf()),
+            ..NodeConfig::default()
+        };
+        let mut replica = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        replica.start().unwrap();
+        // Running the node consumes the swarm events, so take the listen
+        // address first.
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = replica.swarm.next_event().await {
+                break address;
+            }
+        };
+        assert!(replica.is_read_replica());
+        assert!(!replica.gossip_subscribed());
+        let _ = ingester.insert_merge(valid(2), source.clone());
+        assert_eq!(replica.refresh_store().unwrap(), 1);
+
+        // No outbound fetch is made.
+        let mut rpc = replica.order_sync_rpc();
+        let call = rpc.call(source, Request::default());
+        tokio::pin!(call);
+        let result = loop {
+            tokio::select! {
+                result = &mut call => break result,
+                result = replica.run() => result.unwrap(),
+            }
+        };
+        assert!(matches!(result, Err(order_sync::Error::ReadReplica)));
+        assert_eq!(replica.pending_order_sync_requests(), 0);
+
+        // Inbound OrderSync is served from the shared store.
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let mut client = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let replica_id = replica.local_peer_id().clone();
+        client.swarm.add_address(&replica_id, address);
+        let mut rpc = client.order_sync_rpc();
+        let fetch = rpc.fetch_all(replica_id, order_filter.clone());
+        tokio::pin!(fetch);
+        let mut orders = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    result = &mut fetch => break result.unwrap(),
+                    result = client.run() => result.unwrap(),
+                    result = replica.run() => result.unwrap(),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut expected = vec![valid(1), valid(2)];
+        orders.sort_by_key(Order::hash);
+        expected.sort_by_key(Order::hash);
+        assert_eq!(orders, expected);
+
+        // A read replica can not run without a store.
+        let config = NodeConfig {
+            read_replica: true,
+            ..NodeConfig::default()
+        };
+        assert!(Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_sketch() {
+        use order_sync::messages::{Order, OrderFilter};
+
+        let order = |salt: u64| {
+            Order {
+                salt: salt.into(),
+                ..valid_order(1)
+            }
+        };
+        let source = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            listen: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            ..NodeConfig::default()
+        };
+        let mut server = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        for salt in 1..=3 {
+            let _ = server
+                .order_store
+                .write()
+                .unwrap()
+                .insert_merge(order(salt), source.clone());
+        }
+        server.start().unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = server.swarm.next_event().await {
+                break address;
+            }
+        };
+
+        // The client only gets the orders it does not have yet.
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let mut client = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let _ = client
+            .order_store
+            .write()
+            .unwrap()
+            .insert_merge(order(1), source);
+        let server_id = server.local_peer_id().clone();
+        client.swarm.add_address(&server_id, address);
+        let mut rpc = client.order_sync_rpc();
+        let fetch = rpc.fetch_all(server_id, OrderFilter::mainnet_v3());
+        tokio::pin!(fetch);
+        let mut orders = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    result = &mut fetch => break result.unwrap(),
+                    result = client.run() => result.unwrap(),
+                    result = server.run() => result.unwrap(),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut expected = vec![order(2), order(3)];
+        orders.sort_by_key(Order::hash);
+        expected.sort_by_key(Order::hash);
+        assert_eq!(orders, expected);
+    }
+
+    #[tokio::test]
+    async fn test_banned_peer_refused() {
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let mut banned = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let banned_id = banned.local_peer_id().clone();
+        node.ban_peer(banned_id.clone());
+        assert!(node.is_banned(&banned_id));
+
+        Swarm::listen_on(&mut node.swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = node.swarm.next_event().await {
+                break address;
+            }
+        };
+        Swarm::dial_addr(&mut banned.swarm, address).unwrap();
+
+        // The connection is closed as soon as it is established.
+        let peer_id = loop {
+            tokio::select! {
+                event = node.swarm.next_event() => match event {
+                    SwarmEvent::BannedPeer { peer_id, .. } => break peer_id,
+                    SwarmEvent::ConnectionEstablished { .. } => panic!("Banned peer connected"),
+                    _ => {}
+                },
+                _ = banned.swarm.next_event() => {}
+            }
+        };
+        assert_eq!(peer_id, banned_id);
+        assert!(!Swarm::is_connected(&node.swarm, &banned_id));
+
+        node.unban_peer(banned_id.clone());
+        assert!(!node.is_banned(&banned_id));
+    }
+
+    #[tokio::test]
+    async fn test_dht_compatible_peers() {
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config.clone())
+            .await
+            .unwrap();
+        let mut peer = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let peer_id = peer.local_peer_id().clone();
+
+        Swarm::listen_on(&mut node.swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr(address) = node.swarm.next_event().await {
+                break address;
+            }
+        };
+        Swarm::dial_addr(&mut peer.swarm, address).unwrap();
+        let known_peers = node.known_peers();
+        let identified = || {
+            known_peers
+                .read()
+                .unwrap()
+                .get(&peer_id)
+                .map_or(false, |info| info.identify.is_some())
+        };
+        while !identified() {
+            tokio::select! {
+                _ = node.swarm.next_event() => {}
+                _ = peer.swarm.next_event() => {}
+                _ = sleep(Duration::from_millis(10)) => {}
+            }
+        }
+        assert_eq!(node.dht_compatible_peers(), 1);
+        assert_eq!(node.dht_incompatible_peers(), 0);
+
+        // The peer runs a different DHT protocol name.
+        known_peers
+            .write()
+            .unwrap()
+            .get_mut(&peer_id)
+            .unwrap()
+            .identify
+            .as_mut()
+            .unwrap()
+            .protocols = vec!["/ipfs/kad/1.0.0".into()];
+        assert_eq!(node.dht_compatible_peers(), 0);
+        assert_eq!(node.dht_incompatible_peers(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_supported_protocols() {
+        let order_sync = "/0x-mesh/order-sync/version/0".to_string();
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let node = Node::new(identity::Keypair::generate_ed25519(), config.clone())
+            .await
+            .unwrap();
+        let protocols = node.supported_protocols();
+        assert!(protocols.contains(&order_sync));
+        assert!(protocols.contains(&"/ipfs/id/1.0.0".to_string()));
+        assert!(protocols.contains(&"/meshsub/1.0.0".to_string()));
+        assert!(protocols.contains(&"/0x-mesh-dht/version/1".to_string()));
+
+        // A test mesh runs its own DHT.
+        let test_mesh = Node::new(identity::Keypair::generate_ed25519(), NodeConfig {
+            dht_protocol_name: "/test-mesh-dht/version/1".into(),
+            ..config.clone()
+        })
+        .await
+        .unwrap();
+        let protocols = test_mesh.supported_protocols();
+        assert!(protocols.contains(&"/test-mesh-dht/version/1".to_string()));
+        assert!(!protocols.contains(&"/0x-mesh-dht/version/1".to_string()));
+
+        let observer = Node::new(identity::Keypair::generate_ed25519(), NodeConfig {
+            observer: true,
+            ..config.clone()
+        })
+        .await
+        .unwrap();
+        assert!(!observer.supported_protocols().contains(&order_sync));
+
+        let seed = Node::new(identity::Keypair::generate_ed25519(), NodeConfig {
+            seed: true,
+            ..config
+        })
+        .await
+        .unwrap();
+        assert!(seed.supported_protocols().contains(&order_sync));
+    }
+
+    #[tokio::test]
+    async fn test_dial_unknown_peer() {
+        let config = NodeConfig {
+            no_default_bootnodes: true,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let unknown = PeerId::from(identity::Keypair::generate_ed25519().public());
+        match node.dial(&unknown) {
+            Err(NodeError::PeerNotConnected(peer_id)) => assert_eq!(peer_id, unknown),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        node.ban_peer(unknown.clone());
+        assert!(matches!(node.dial(&unknown), Err(NodeError::PeerBanned(_))));
+    }
+
+    /// An order that passes validation on a chain with a known v3 exchange.
+    pub(super) fn valid_order(chain_id: u64) -> order_sync::messages::Order {
+        let order_filter = order_sync::messages::OrderFilter::v3(chain_id).unwrap();
+        order_sync::messages::Order {
+            chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: u32::MAX.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..order_sync::messages::Order::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_validators() {
+        use order_sync::messages::OrderFilter;
+
+        let config = NodeConfig {
+            chain_ids: vec![1, 3],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        assert_eq!(node.order_filters(), vec![
+            OrderFilter::mainnet_v3(),
+            OrderFilter::v3(3).unwrap(),
+        ]);
+        let source = PeerId::random();
+        assert_eq!(node.ingest_order(valid_order(1), source.clone()), Ok(()));
+        assert_eq!(node.ingest_order(valid_order(3), source.clone()), Ok(()));
+        assert_eq!(
+            node.ingest_order(valid_order(42), source),
+            Err(Rejection::WrongChain)
+        );
+        assert_eq!(node.validation_stats().accepted, 2);
+        assert_eq!(node.validation_stats().wrong_chain, 1);
+
+        // Chains without a known exchange fail at startup.
+        let config = NodeConfig {
+            chain_ids: vec![1, 1234],
+            ..NodeConfig::default()
+        };
+        assert!(Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_order_stream_for_chain() {
+        let config = NodeConfig {
+            chain_ids: vec![1, 3],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let mut mainnet = node.order_stream_for_chain(1);
+        let mut ropsten = node.order_stream_for_chain(3);
+        let mut kovan = node.order_stream_for_chain(42);
+        let source = PeerId::random();
+        for &chain_id in &[1, 3, 42] {
+            let _ = node.ingest_order(valid_order(chain_id), source.clone());
+        }
+
+        assert_eq!(mainnet.try_next().unwrap(), Some(valid_order(1)));
+        assert!(mainnet.try_next().is_err());
+        assert_eq!(ropsten.try_next().unwrap(), Some(valid_order(3)));
+        assert!(ropsten.try_next().is_err());
+
+        // Orders of chains that are not configured are rejected, not streamed.
+        assert!(kovan.try_next().is_err());
+        assert_eq!(node.validation_stats().wrong_chain, 1);
+    }
+
+    #[tokio::test]
+    async fn test_order_stream() {
+        use order_sync::messages::Order;
+
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let mut stream = node.order_stream();
+        let dropped = node.order_stream();
+        drop(dropped);
+        let source = PeerId::from(identity::Keypair::generate_ed25519().public());
+
+        // Invalid orders are streamed too, but not stored.
+        let order = Order {
+            chain_id: 1,
+            ..Order::default()
+        };
+        node.handle_gossiped_order(order.clone(), source);
+        assert_eq!(stream.try_next().unwrap(), Some(order.clone()));
+        assert!(stream.try_next().is_err());
+        assert_eq!(node.get_order(&order.hash()), None);
+        assert_eq!(node.gossip_streams.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_publish_order_validated() {
+        use order_sync::messages::{Order, OrderFilter};
+
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: u32::MAX.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..Order::default()
+        };
+        let expired = Order {
+            expiration_time_seconds: 1000.into(),
+            ..order.clone()
+        };
+        let wrong_exchange = Order {
+            exchange_address: OrderFilter::mainnet_v2().exchange_address,
+            ..order.clone()
+        };
+        assert!(matches!(
+            node.publish_order(&expired),
+            Err(NodeError::Rejected(Rejection::Expired))
+        ));
+        assert!(matches!(
+            node.publish_order(&wrong_exchange),
+            Err(NodeError::Rejected(Rejection::InconsistentExchange))
+        ));
+
+        // Only the configured chains are published on, even with a known
+        // exchange.
+        let ropsten_filter = OrderFilter::v3(3).unwrap();
+        let ropsten = Order {
+            chain_id: ropsten_filter.chain_id,
+            exchange_address: ropsten_filter.exchange_address,
+            ..order.clone()
+        };
+        assert!(matches!(
+            node.publish_order(&ropsten),
+            Err(NodeError::Rejected(Rejection::WrongChain))
+        ));
+
+        // Valid orders are queued, even without peers to publish to.
+        node.publish_order(&order).unwrap();
+
+        // The configured exchanges apply to publishing too.
+        let config = NodeConfig {
+            allowed_exchanges: vec![wrong_exchange.exchange_address],
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        node.publish_order(&wrong_exchange).unwrap();
+        assert!(matches!(
+            node.publish_order(&order),
+            Err(NodeError::Rejected(Rejection::InconsistentExchange))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_order() {
+        use order_sync::messages::{Order, OrderFilter};
+
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: u32::MAX.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..Order::default()
+        };
+        let source = PeerId::from(identity::Keypair::generate_ed25519().public());
+        node.ingest_order(order.clone(), source).unwrap();
+
+        assert_eq!(node.get_order(&order.hash()), Some(order));
+        assert_eq!(node.get_order(&OrderHash([0; 32])), None);
+    }
+
+    #[tokio::test]
+    async fn test_min_confirmations() {
+        use order_sync::messages::{Order, OrderFilter};
+
+        let config = NodeConfig {
+            min_confirmations: 2,
+            ..NodeConfig::default()
+        };
+        let mut node = Node::new(identity::Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let mut events = node.events().unwrap();
+        let order_filter = OrderFilter::mainnet_v3();
+        let order = Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: u32::MAX.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..Order::default()
+        };
+        let first = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let second = PeerId::from(identity::Keypair::generate_ed25519().public());
+
+        // Repeats from the same peer do not count.
+        node.ingest_order(order.clone(), first.clone()).unwrap();
+        node.ingest_order(order.clone(), first).unwrap();
+        assert!(events.try_next().is_err());
+
+        node.ingest_order(order.clone(), second.clone()).unwrap();
+        match events.try_next() {
+            Ok(Some(NodeEvent::OrderReceived(received))) => assert_eq!(received, order),
+            _ => panic!("Order not surfaced after second confirmation"),
+        }
+
+        // Further confirmations do not surface the order again.
+        node.ingest_order(order, second).unwrap();
+        assert!(events.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_order_snapshot() {
+        use order_sync::messages::{Order, OrderFilter};
+
+        let source_key = identity::Keypair::generate_ed25519();
+        let source = Node::new(source_key.clone(), NodeConfig::default())
+            .await
+            .unwrap();
+        let order_filter = OrderFilter::mainnet_v3();
+        let valid = |salt: u64| {
+            Order {
+                chain_id: order_filter.chain_id,
+                exchange_address: order_filter.exchange_address,
+                maker_asset_amount: 1000.into(),
+                taker_asset_amount: 2000.into(),
+                expiration_time_seconds: u32::MAX.into(),
+                signature: format!("0x{}02", "1b".repeat(65)),
+                salt: salt.into(),
+                ..Order::default()
+            }
+        };
+        let expired = Order {
+            expiration_time_seconds: 1.into(),
+            ..valid(3)
+        };
+        let peer = PeerId::from(identity::Keypair::generate_ed25519().public());
+        {
+            // Bypass validation to get an invalid order into the store.
+            let mut store = source.order_store.write().unwrap();
+            for order in [valid(1), valid(2), expired] {
+                let _ = store.insert_merge(order, peer.clone());
+            }
+        }
+        let snapshot = source.export_order_snapshot().unwrap();
+
+        let mut target = Node::new(identity::Keypair::generate_ed25519(), NodeConfig::default())
+            .await
+            .unwrap();
+        let import = target.import_order_snapshot(&snapshot).unwrap();
+        assert_eq!(import, SnapshotImport {
+            signer:   PeerId::from(source_key.public()),
+            accepted: 2,
+            rejected: 1,
+        });
+        assert_eq!(target.order_store.read().unwrap().len(), 2);
+        assert_eq!(target.get_order(&valid(1).hash()), Some(valid(1)));
+        assert_eq!(target.get_order(&valid(2).hash()), Some(valid(2)));
+    }
+
+    #[test]
+    fn test_load_or_generate_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        let generated = Node::load_or_generate_key(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The same identity is loaded on restart.
+        let loaded = Node::load_or_generate_key(&path).unwrap();
+        assert_eq!(PeerId::from(loaded.public()), PeerId::from(generated.public()));
+
+        // A corrupt key is an error and is left alone.
+        fs::write(&path, b"not a key").unwrap();
+        assert!(Node::load_or_generate_key(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a key");
+    }
+}
@@ -0,0 +1,190 @@
+//! Forward trusted orders to external systems.
+//!
+//! Implement [`OrderSink`] for a message bus or database and add it with
+//! [`NodeBuilder::order_sink`](super::NodeBuilder::order_sink), which runs a
+//! [`SinkForwarder`] on the trusted orders of every configured chain. A
+//! forwarder can also be run on any other stream of orders. Failed sends are retried with exponential backoff. Orders that still fail
+//! are counted and dropped, so a broken sink does not stop the node.
+//!
+//! ## To do
+//!
+//! * Kafka and NATS sinks behind `kafka` and `nats` features, once their client
+//!   crates are added as dependencies.
+
+use super::behaviour::order_sync::messages::Order;
+use crate::prelude::*;
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Number of times an order is sent before it is dropped.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Delay before the first retry, doubled on every following retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+#[async_trait]
+pub trait OrderSink: Send + Sync {
+    async fn send(&self, order: &Order) -> Result<()>;
+}
+
+#[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SinkStats {
+    pub delivered: u64,
+    pub retries:   u64,
+    pub dropped:   u64,
+}
+
+pub struct SinkForwarder {
+    sink:            Arc<dyn OrderSink>,
+    initial_backoff: Duration,
+    stats:           Arc<RwLock<SinkStats>>,
+}
+
+impl SinkForwarder {
+    pub fn new(sink: Arc<dyn OrderSink>) -> Self {
+        Self {
+            sink,
+            initial_backoff: INITIAL_BACKOFF,
+            stats: Arc::new(RwLock::new(SinkStats::default())),
+        }
+    }
+
+    pub const fn set_initial_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Return a handle to the delivery statistics.
+    pub fn stats(&self) -> Arc<RwLock<SinkStats>> {
+        self.stats.clone()
+    }
+
+    /// Send all orders from the stream to the sink. Completes when the stream
+    /// ends.
+    pub async fn run(self, mut orders: impl Stream<Item = Order> + Unpin) {
+        while let Some(order) = orders.next().await {
+            self.deliver(&order).await;
+        }
+    }
+
+    async fn deliver(&self, order: &Order) {
+        let mut backoff = self.initial_backoff;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match self.sink.send(order).await {
+                Ok(()) => {
+                    self.stats.write().unwrap().delivered += 1;
+                    return;
+                }
+                Err(err) if attempt < MAX_ATTEMPTS => {
+                    warn!("Order sink failed, retrying in {:?}: {:?}", backoff, err);
+                    self.stats.write().unwrap().retries += 1;
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => {
+                    error!("Order sink failed, dropping order {}: {:?}", order.signature, err);
+                    self.stats.write().unwrap().dropped += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::{behaviour::discovery::Bootnode, test::valid_order, NodeBuilder, NodeConfig},
+        test::prelude::assert_eq,
+    };
+    use anyhow::anyhow;
+    use libp2p::{identity, PeerId};
+    use std::sync::Mutex;
+
+    /// Sink that fails every other send.
+    #[derive(Default)]
+    struct MockSink {
+        calls:  Mutex<usize>,
+        orders: Mutex<Vec<Order>>,
+    }
+
+    #[async_trait]
+    impl OrderSink for MockSink {
+        async fn send(&self, order: &Order) -> Result<()> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            if *calls % 2 == 1 {
+                return Err(anyhow!("Connection reset"));
+            }
+            self.orders.lock().unwrap().push(order.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver_with_retries() {
+        let sink = Arc::new(MockSink::default());
+        let mut forwarder = SinkForwarder::new(sink.clone());
+        forwarder.set_initial_backoff(Duration::from_millis(1));
+        let stats = forwarder.stats();
+
+        let orders = (0..3)
+            .map(|salt| {
+                Order {
+                    salt: salt.into(),
+                    ..Order::default()
+                }
+            })
+            .collect::<Vec<_>>();
+        forwarder.run(stream::iter(orders.clone())).await;
+
+        assert_eq!(*sink.orders.lock().unwrap(), orders);
+        assert_eq!(*stats.read().unwrap(), SinkStats {
+            delivered: 3,
+            retries:   3,
+            dropped:   0,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_node_order_sink() {
+        let sink = Arc::new(MockSink::default());
+        let mut node = NodeBuilder::new(NodeConfig::default())
+            .listen_addrs(vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()])
+            .bootnode_overrides(vec![Bootnode::None])
+            .disable_mdns(true)
+            .order_sink(sink.clone())
+            .build()
+            .await
+            .unwrap();
+        let source = PeerId::from(identity::Keypair::generate_ed25519().public());
+        let order = valid_order(1);
+        node.ingest_order(order.clone(), source.clone()).unwrap();
+
+        // Rejected orders are not forwarded.
+        let invalid = Order {
+            chain_id: 1,
+            ..Order::default()
+        };
+        assert!(node.ingest_order(invalid, source).is_err());
+
+        // The first send fails and is retried after the backoff.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.order_sink_stats()[0].delivered == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*sink.orders.lock().unwrap(), vec![order]);
+        assert_eq!(node.order_sink_stats(), vec![SinkStats {
+            delivered: 1,
+            retries:   1,
+            dropped:   0,
+        }]);
+    }
+}
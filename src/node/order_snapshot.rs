@@ -0,0 +1,174 @@
+//! Signed bulk snapshots of the order store.
+//!
+//! A new node can warm up from a peer it trusts by importing a snapshot of
+//! that peer's orders, for example downloaded over HTTP, instead of paging
+//! through OrderSync. The snapshot is gzipped JSON: the orders plus a manifest
+//! signed with the exporting node's identity key. The signature covers the
+//! EIP-712 hashes of the orders, so it does not depend on JSON formatting.
+//!
+//! Imported orders still go through validation like orders from OrderSync.
+
+use super::behaviour::order_sync::messages::Order;
+use crate::prelude::*;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use std::io::{Read, Write};
+
+/// Current version of the [`OrderSnapshot`] format.
+pub const VERSION: u32 = 1;
+
+/// Maximum decompressed size of a snapshot in bytes.
+const MAX_SNAPSHOT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// First bytes of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotManifest {
+    pub version:     u32,
+    /// Unix timestamp in seconds.
+    pub created_at:  u64,
+    pub order_count: usize,
+    /// Hex encoded protobuf encoding of the signer's public key.
+    pub public_key:  String,
+    /// Hex encoded signature over the concatenated order hashes.
+    pub signature:   String,
+}
+
+/// Outcome of [`Node::import_order_snapshot`](super::Node::import_order_snapshot).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SnapshotImport {
+    /// Peer that signed the snapshot, its orders are counted as received from
+    /// it.
+    pub signer:   PeerId,
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderSnapshot {
+    pub manifest: SnapshotManifest,
+    pub orders:   Vec<Order>,
+}
+
+impl OrderSnapshot {
+    /// Create a snapshot signed with `key`. Orders are sorted by hash so that
+    /// snapshots of the same orders compare equal.
+    pub fn new(mut orders: Vec<Order>, key: &Keypair, created_at: u64) -> Result<Self> {
+        orders.sort_by_cached_key(|order| order.hash().0);
+        let signature = key
+            .sign(&signed_message(&orders))
+            .context("Signing order snapshot")?;
+        Ok(Self {
+            manifest: SnapshotManifest {
+                version: VERSION,
+                created_at,
+                order_count: orders.len(),
+                public_key: hex::encode(key.public().into_protobuf_encoding()),
+                signature: hex::encode(signature),
+            },
+            orders,
+        })
+    }
+
+    /// Serialize to gzipped JSON.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        serde_json::to_writer(&mut encoder, self).context("Serializing order snapshot")?;
+        encoder.flush()?;
+        Ok(encoder.finish()?)
+    }
+
+    /// Parse a snapshot from gzipped or plain JSON.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut json = Vec::new();
+        if bytes.starts_with(&GZIP_MAGIC) {
+            GzDecoder::new(bytes)
+                .take(MAX_SNAPSHOT_SIZE + 1)
+                .read_to_end(&mut json)
+                .context("Decompressing order snapshot")?;
+        } else {
+            json.extend_from_slice(bytes);
+        }
+        if json.len() as u64 > MAX_SNAPSHOT_SIZE {
+            return Err(anyhow::anyhow!(
+                "Order snapshot exceeds {} bytes",
+                MAX_SNAPSHOT_SIZE
+            ));
+        }
+        serde_json::from_slice(&json).context("Parsing order snapshot")
+    }
+
+    /// Check the version and signature, and return the signer.
+    pub fn verify(&self) -> Result<PeerId> {
+        let manifest = &self.manifest;
+        if manifest.version != VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported order snapshot version {}, expected {}",
+                manifest.version,
+                VERSION
+            ));
+        }
+        if manifest.order_count != self.orders.len() {
+            return Err(anyhow::anyhow!(
+                "Order snapshot has {} orders, manifest lists {}",
+                self.orders.len(),
+                manifest.order_count
+            ));
+        }
+        let public_key = hex::decode(&manifest.public_key).context("Decoding public key")?;
+        let public_key =
+            PublicKey::from_protobuf_encoding(&public_key).context("Decoding public key")?;
+        let signature = hex::decode(&manifest.signature).context("Decoding signature")?;
+        if !public_key.verify(&signed_message(&self.orders), &signature) {
+            return Err(anyhow::anyhow!("Invalid order snapshot signature"));
+        }
+        Ok(PeerId::from(public_key))
+    }
+}
+
+fn signed_message(orders: &[Order]) -> Vec<u8> {
+    orders.iter().flat_map(|order| order.hash().0.to_vec()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_tampered_snapshot() {
+        let key = Keypair::generate_ed25519();
+        let orders = vec![
+            Order {
+                salt: 1.into(),
+                ..Order::default()
+            },
+            Order {
+                salt: 2.into(),
+                ..Order::default()
+            },
+        ];
+        let snapshot = OrderSnapshot::new(orders, &key, 1_600_000_000).unwrap();
+        let decoded = OrderSnapshot::decode(&snapshot.encode().unwrap()).unwrap();
+        assert_eq!(decoded, snapshot);
+        assert_eq!(decoded.verify().unwrap(), PeerId::from(key.public()));
+
+        // Plain JSON is accepted too.
+        let json = serde_json::to_vec(&snapshot).unwrap();
+        assert_eq!(OrderSnapshot::decode(&json).unwrap(), snapshot);
+
+        let mut tampered = snapshot.clone();
+        tampered.orders[0].salt = 3.into();
+        assert!(tampered.verify().is_err());
+
+        let mut truncated = snapshot;
+        let _ = truncated.orders.pop();
+        assert!(truncated.verify().is_err());
+    }
+}
@@ -0,0 +1,525 @@
+//! Store of accepted orders.
+//!
+//! The store is kept in memory and can optionally be persisted to a directory.
+//! Every newly seen `(order, source)` pair is appended to a write-ahead log, so
+//! a crash loses at most the entry being written. Once the log grows past
+//! [`COMPACTION_THRESHOLD`] entries the store is written out as a snapshot and
+//! the log is truncated. On open the snapshot is loaded and the log replayed
+//! on top of it.
+//!
+//! Both files are newline delimited JSON with one entry per line:
+//!
+//! ```json
+//! { "source": "16Uiu2HAm...", "received_at": 1607000000000, "order": { "chainId": 1, ... } }
+//! ```
+//!
+//! Orders are keyed by their EIP-712 [`OrderHash`]. The same order received
+//! from several peers is merged into one [`StoredOrder`] that collects the
+//! sources and keeps the earliest receipt time. The store is the
+//! [`OrderSource`] for peers syncing from us.
+//!
+//! A read replica opens the directory of a store written by another node and
+//! only reads it, see [`OrderStore::open_replica`]. Readers share the store
+//! through a `RwLock`, so OrderSync responses and API requests are served
+//! concurrently.
+
+use super::behaviour::order_sync::{
+    messages::{Order, OrderFilter, OrderHash},
+    OrderSource,
+};
+use crate::prelude::*;
+use anyhow::anyhow;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Number of log entries after which the store is compacted into a snapshot.
+const COMPACTION_THRESHOLD: usize = 10_000;
+
+const SNAPSHOT_FILE: &str = "snapshot.ndjson";
+const LOG_FILE: &str = "wal.ndjson";
+
+/// An order together with the peers it was received from.
+#[derive(Clone, Debug)]
+pub struct StoredOrder {
+    pub order:       Order,
+    pub sources:     HashSet<PeerId>,
+    /// When the order was first received, from any source.
+    pub received_at: SystemTime,
+}
+
+impl StoredOrder {
+    /// Number of distinct peers the order was received from.
+    pub fn confirmations(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Entry {
+    source:      String,
+    /// Milliseconds since the Unix epoch, missing in older entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    received_at: Option<u64>,
+    order:       Order,
+}
+
+#[derive(Debug)]
+struct Wal {
+    dir:     PathBuf,
+    log:     File,
+    entries: usize,
+}
+
+/// How far a read replica has read the files of the store it follows.
+#[derive(Debug)]
+struct Replica {
+    dir:        PathBuf,
+    /// Modification time and length of the snapshot when it was last read.
+    snapshot:   Option<(SystemTime, u64)>,
+    /// Length of the complete log entries read so far.
+    log_offset: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct OrderStore {
+    orders:  HashMap<OrderHash, StoredOrder>,
+    wal:     Option<Wal>,
+    replica: Option<Replica>,
+}
+
+impl OrderStore {
+    /// Create an in-memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a store persisted in `dir`, creating the directory if needed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).context("Creating order store directory")?;
+        let mut store = Self::new();
+        let snapshot = dir.join(SNAPSHOT_FILE);
+        let log = dir.join(LOG_FILE);
+        store
+            .replay(&snapshot, 0)
+            .with_context(|| format!("Reading {}", snapshot.display()))?;
+        let (entries, length) = store
+            .replay(&log, 0)
+            .with_context(|| format!("Reading {}", log.display()))?;
+        info!(
+            "Loaded {} orders from {} ({} log entries)",
+            store.len(),
+            dir.display(),
+            entries
+        );
+
+        // Cut off any incomplete entry so new entries start on a fresh line.
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .with_context(|| format!("Opening {}", log.display()))?;
+        log.set_len(length)?;
+        store.wal = Some(Wal {
+            dir: dir.to_path_buf(),
+            log,
+            entries,
+        });
+        Ok(store)
+    }
+
+    /// Open the store another node persists in `dir` for reading only. Orders
+    /// that node writes later are picked up by [`OrderStore::refresh`], orders
+    /// inserted here are kept in memory only.
+    pub fn open_replica(dir: &Path) -> Result<Self> {
+        let mut store = Self::new();
+        store.replica = Some(Replica {
+            dir:        dir.to_path_buf(),
+            snapshot:   None,
+            log_offset: 0,
+        });
+        let entries = store.refresh()?;
+        info!(
+            "Loaded {} orders from {} as a read replica ({} entries)",
+            store.len(),
+            dir.display(),
+            entries
+        );
+        Ok(store)
+    }
+
+    /// Read the entries the writing node added since the last refresh, or
+    /// reload the whole store if it was compacted in the meantime. Returns the
+    /// number of entries read. Does nothing unless the store was opened with
+    /// [`OrderStore::open_replica`].
+    pub fn refresh(&mut self) -> Result<usize> {
+        let (dir, read_snapshot, log_offset) = match &self.replica {
+            Some(replica) => (replica.dir.clone(), replica.snapshot, replica.log_offset),
+            None => return Ok(0),
+        };
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let log_path = dir.join(LOG_FILE);
+        let snapshot = file_version(&snapshot_path)?;
+        let log_length = file_version(&log_path)?.map_or(0, |(_, length)| length);
+
+        // Compaction replaces the snapshot and truncates the log, after which
+        // the offset into the log is meaningless.
+        let (mut entries, mut log_offset) = (0, log_offset);
+        if snapshot != read_snapshot || log_length < log_offset {
+            self.orders.clear();
+            entries = self
+                .replay(&snapshot_path, 0)
+                .with_context(|| format!("Reading {}", snapshot_path.display()))?
+                .0;
+            log_offset = 0;
+        }
+        let (log_entries, length) = self
+            .replay(&log_path, log_offset)
+            .with_context(|| format!("Reading {}", log_path.display()))?;
+        self.replica = Some(Replica {
+            dir,
+            snapshot,
+            log_offset: log_offset + length,
+        });
+        Ok(entries + log_entries)
+    }
+
+    /// Whether the store was opened with [`OrderStore::open_replica`].
+    pub fn is_replica(&self) -> bool {
+        self.replica.is_some()
+    }
+
+    /// Insert an order received from `source` now, see
+    /// [`OrderStore::insert_merge_at`].
+    pub fn insert_merge(&mut self, order: Order, source: PeerId) -> Option<usize> {
+        self.insert_merge_at(order, source, SystemTime::now())
+    }
+
+    /// Insert an order received from `source` at `received_at`, merging it
+    /// into an earlier receipt of the same order: `source` is added to its
+    /// sources and the earliest receipt time is kept. Returns the number of
+    /// distinct peers it has now been received from, or `None` if `source` had
+    /// already sent it.
+    pub fn insert_merge_at(
+        &mut self,
+        order: Order,
+        source: PeerId,
+        received_at: SystemTime,
+    ) -> Option<usize> {
+        let confirmations = self.insert_memory(order.clone(), source.clone(), received_at)?;
+        if let Err(err) = self.append(&order, &source, received_at) {
+            error!("Writing order store log: {:?}", err);
+        }
+        Some(confirmations)
+    }
+
+    pub fn get(&self, hash: &OrderHash) -> Option<&StoredOrder> {
+        self.orders.get(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StoredOrder> {
+        self.orders.values()
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = &OrderHash> {
+        self.orders.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// Write the store to a new snapshot and truncate the log. Does nothing for
+    /// an in-memory store.
+    pub fn compact(&mut self) -> Result<()> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        let snapshot = wal.dir.join(SNAPSHOT_FILE);
+        let temporary = wal.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        for stored in self.orders.values() {
+            for source in &stored.sources {
+                write_entry(&mut writer, &stored.order, source, stored.received_at)?;
+            }
+        }
+        let file = writer.into_inner().map_err(std::io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &snapshot).context("Replacing order store snapshot")?;
+
+        // Entries are now in the snapshot, so a crash before truncating only
+        // causes them to be replayed twice, which is harmless.
+        wal.log.set_len(0)?;
+        wal.entries = 0;
+        debug!("Compacted order store into {}", snapshot.display());
+        Ok(())
+    }
+
+    /// Sync the log to disk. Does nothing for an in-memory store.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(wal) = &mut self.wal {
+            wal.log.sync_data().context("Syncing order store log")?;
+        }
+        Ok(())
+    }
+
+    fn insert_memory(
+        &mut self,
+        order: Order,
+        source: PeerId,
+        received_at: SystemTime,
+    ) -> Option<usize> {
+        let entry = self
+            .orders
+            .entry(order.hash())
+            .or_insert_with(|| {
+                StoredOrder {
+                    order,
+                    sources: HashSet::new(),
+                    received_at,
+                }
+            });
+        entry.received_at = entry.received_at.min(received_at);
+        if entry.sources.insert(source) {
+            Some(entry.sources.len())
+        } else {
+            None
+        }
+    }
+
+    fn append(&mut self, order: &Order, source: &PeerId, received_at: SystemTime) -> Result<()> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+        write_entry(&mut wal.log, order, source, received_at)?;
+        wal.entries += 1;
+        if wal.entries >= COMPACTION_THRESHOLD {
+            self.compact().context("Compacting order store")?;
+        }
+        Ok(())
+    }
+
+    /// Insert all entries from a log or snapshot file, starting `offset`
+    /// bytes in. Returns the number of entries and the length in bytes of the
+    /// complete entries. A truncated last entry, as left by a crash or a write
+    /// in progress, is skipped. Entries without a receipt time count as
+    /// received now.
+    fn replay(&mut self, path: &Path, offset: u64) -> Result<(usize, u64)> {
+        let now = SystemTime::now();
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok((0, 0)),
+            Err(err) => return Err(err.into()),
+        };
+        let _ = file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        let (mut count, mut length) = (0, 0);
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            // Only the last line can lack a newline.
+            let complete = line.ends_with('\n');
+            let entry = match serde_json::from_str::<Entry>(&line) {
+                Ok(entry) if complete => entry,
+                Err(err) if complete => return Err(err.into()),
+                _ => {
+                    warn!("Skipping incomplete order store entry in {}", path.display());
+                    break;
+                }
+            };
+            let source = entry
+                .source
+                .parse::<PeerId>()
+                .map_err(|_| anyhow!("Invalid peer id {}", entry.source))?;
+            let received_at = entry
+                .received_at
+                .map_or(now, |millis| UNIX_EPOCH + Duration::from_millis(millis));
+            self.insert_memory(entry.order, source, received_at);
+            count += 1;
+            length += read as u64;
+        }
+        Ok((count, length))
+    }
+}
+
+impl OrderSource for RwLock<OrderStore> {
+    fn orders(&self, filter: &OrderFilter) -> Vec<Order> {
+        let store = self.read().unwrap();
+        store
+            .iter()
+            .filter(|stored| filter.matches(&stored.order))
+            .map(|stored| stored.order.clone())
+            .collect()
+    }
+}
+
+/// Modification time and length of a file, `None` if it does not exist.
+fn file_version(path: &Path) -> Result<Option<(SystemTime, u64)>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some((metadata.modified()?, metadata.len()))),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write an entry as a single line in a single write call.
+fn write_entry<W: Write>(
+    writer: &mut W,
+    order: &Order,
+    source: &PeerId,
+    received_at: SystemTime,
+) -> Result<()> {
+    let received_at = received_at
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|since_epoch| since_epoch.as_millis() as u64);
+    let mut line = serde_json::to_vec(&Entry {
+        source: source.to_base58(),
+        received_at,
+        order: order.clone(),
+    })?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use libp2p::identity::Keypair;
+
+    fn peer() -> PeerId {
+        PeerId::from(Keypair::generate_ed25519().public())
+    }
+
+    fn order(salt: usize) -> Order {
+        Order {
+            salt: salt.into(),
+            signature: format!("0x{:04x}02", salt),
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (peer(), peer());
+
+        let mut store = OrderStore::open(dir.path()).unwrap();
+        for salt in 0..5 {
+            store.insert_merge(order(salt), first.clone());
+        }
+        store.compact().unwrap();
+        for salt in 3..8 {
+            store.insert_merge(order(salt), second.clone());
+        }
+        // Crash without closing, leaving a partially written entry behind.
+        drop(store);
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        log.write_all(b"{\"source\":\"16U").unwrap();
+        drop(log);
+
+        let mut store = OrderStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 8);
+        for salt in 0..8 {
+            let stored = store.get(&order(salt).hash()).unwrap();
+            assert_eq!(stored.order, order(salt));
+            assert_eq!(stored.sources.contains(&first), salt < 5);
+            assert_eq!(stored.sources.contains(&second), salt >= 3);
+        }
+
+        // Entries written after recovery are not corrupted by the partial one.
+        assert_eq!(store.insert_merge(order(8), first), Some(1));
+        drop(store);
+        let store = OrderStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 9);
+    }
+
+    #[test]
+    fn test_insert_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = OrderStore::open(dir.path()).unwrap();
+        let first_seen = UNIX_EPOCH + Duration::from_secs(1_607_000_000);
+        let sources = [peer(), peer(), peer()];
+        let received = [
+            first_seen + Duration::from_secs(10),
+            first_seen,
+            first_seen + Duration::from_secs(5),
+        ];
+        for (count, (source, received_at)) in sources.iter().zip(&received).enumerate() {
+            let confirmations = store.insert_merge_at(order(1), source.clone(), *received_at);
+            assert_eq!(confirmations, Some(count + 1));
+        }
+        assert_eq!(store.insert_merge(order(1), sources[0].clone()), None);
+
+        let check = |store: &OrderStore| {
+            assert_eq!(store.len(), 1);
+            let stored = store.get(&order(1).hash()).unwrap();
+            assert_eq!(stored.confirmations(), 3);
+            assert!(sources.iter().all(|source| stored.sources.contains(source)));
+            assert_eq!(stored.received_at, first_seen);
+        };
+        check(&store);
+
+        // The merged entry survives both log replay and compaction.
+        drop(store);
+        let mut store = OrderStore::open(dir.path()).unwrap();
+        check(&store);
+        store.compact().unwrap();
+        drop(store);
+        check(&OrderStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_replica_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = peer();
+        let mut writer = OrderStore::open(dir.path()).unwrap();
+        for salt in 0..3 {
+            writer.insert_merge(order(salt), source.clone());
+        }
+
+        let mut replica = OrderStore::open_replica(dir.path()).unwrap();
+        assert!(replica.is_replica());
+        assert_eq!(replica.len(), 3);
+        assert_eq!(replica.refresh().unwrap(), 0);
+
+        // New entries are read from where the last refresh stopped.
+        writer.insert_merge(order(3), source.clone());
+        assert_eq!(replica.refresh().unwrap(), 1);
+        assert_eq!(replica.len(), 4);
+
+        // A compaction makes the replica reload the snapshot.
+        writer.compact().unwrap();
+        writer.insert_merge(order(4), source.clone());
+        assert_eq!(replica.refresh().unwrap(), 5);
+        assert_eq!(replica.len(), 5);
+        assert_eq!(replica.get(&order(4).hash()).unwrap().order, order(4));
+
+        // Orders inserted in the replica are not written to the shared files.
+        assert_eq!(replica.insert_merge(order(5), peer()), Some(1));
+        drop(replica);
+        drop(writer);
+        assert_eq!(OrderStore::open(dir.path()).unwrap().len(), 5);
+    }
+}
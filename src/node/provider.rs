@@ -0,0 +1,105 @@
+//! Choosing the peer to fetch orders from.
+//!
+//! Always asking the best scoring provider concentrates load on it. Instead a
+//! provider is picked at random with softmax weights `exp(score /
+//! temperature)`, which spreads requests over providers of similar score while
+//! still favoring better ones. A high temperature spreads them evenly, at zero
+//! the best provider is always picked.
+//!
+//! Providers are scored by their peer reputation, and only the
+//! [`CANDIDATES`] best reputed ones are considered.
+
+use rand::Rng;
+
+/// Number of best reputed providers to pick from.
+pub const CANDIDATES: usize = 8;
+
+/// Temperature under which a provider scoring one point higher is picked
+/// about 2.7 times as often.
+pub const DEFAULT_TEMPERATURE: f64 = 1.0;
+
+/// Selection probability of each score.
+pub fn softmax_weights(scores: &[f64], temperature: f64) -> Vec<f64> {
+    let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if temperature <= 0.0 {
+        // Split evenly between the best providers.
+        let best = scores.iter().filter(|&&score| score >= max).count();
+        return scores
+            .iter()
+            .map(|&score| if score >= max { 1.0 / best as f64 } else { 0.0 })
+            .collect();
+    }
+    // Subtracting the maximum keeps `exp` from overflowing.
+    let exps = scores
+        .iter()
+        .map(|score| ((score - max) / temperature).exp())
+        .collect::<Vec<_>>();
+    let sum: f64 = exps.iter().sum();
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// Pick one of the scored `providers`, or `None` if there are none.
+pub fn select_provider<'a, T>(
+    providers: &'a [(T, f64)],
+    temperature: f64,
+    rng: &mut impl Rng,
+) -> Option<&'a T> {
+    let scores = providers.iter().map(|(_, score)| *score).collect::<Vec<_>>();
+    let weights = softmax_weights(&scores, temperature);
+    let mut remaining = rng.gen::<f64>();
+    let mut selected = None;
+    for (index, weight) in weights.into_iter().enumerate() {
+        // Rounding may leave a tiny remainder at the end, which goes to the
+        // last provider that can be picked.
+        if weight > 0.0 {
+            selected = Some(index);
+        }
+        if remaining < weight {
+            break;
+        }
+        remaining -= weight;
+    }
+    selected.map(|index| &providers[index].0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use float_eq::assert_float_eq;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_select_provider_distribution() {
+        let providers = [("a", 2.0), ("b", 1.8), ("c", 1.5)];
+        let weights = softmax_weights(&[2.0, 1.8, 1.5], DEFAULT_TEMPERATURE);
+        assert_float_eq!(weights.iter().sum::<f64>(), 1.0, abs <= 1e-12);
+
+        let mut rng = StdRng::seed_from_u64(260);
+        let draws = 30_000;
+        let mut counts = [0_usize; 3];
+        for _ in 0..draws {
+            match select_provider(&providers, DEFAULT_TEMPERATURE, &mut rng) {
+                Some(&"a") => counts[0] += 1,
+                Some(&"b") => counts[1] += 1,
+                Some(&"c") => counts[2] += 1,
+                other => panic!("Unexpected selection {:?}", other),
+            }
+        }
+        for (count, weight) in counts.iter().zip(&weights) {
+            assert_float_eq!(*count as f64 / draws as f64, *weight, abs <= 0.02);
+        }
+    }
+
+    #[test]
+    fn test_select_provider_temperature() {
+        let providers = [("a", 1.0), ("b", 3.0), ("c", 2.0)];
+        let mut rng = StdRng::seed_from_u64(260);
+        for _ in 0..100 {
+            assert_eq!(select_provider(&providers, 0.0, &mut rng), Some(&"b"));
+        }
+        let weights = softmax_weights(&[1.0, 3.0, 2.0], 1e6);
+        assert!(weights.iter().all(|weight| (weight - 1.0 / 3.0).abs() < 1e-3));
+        assert_eq!(select_provider::<&str>(&[], 1.0, &mut rng), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! Replay captured OrderSync sessions for regression testing.
+//!
+//! A session directory contains a `session.json` manifest and the captured
+//! messages as `<sequence>-request.json` and `<sequence>-response.json` files.
+//! The manifest holds the capture time and the order filter in effect, so
+//! orders are validated as they would have been when they were captured:
+//!
+//! ```json
+//! { "capturedAt": 1608400000, "orderFilter": { "chainID": 1, ... } }
+//! ```
+//!
+//! Every message is decoded with the OrderSync codec, re-encoded and compared
+//! to the capture, and the orders in responses are run through validation.
+//! Captures hold the JSON payload only, the codec's framing is added before
+//! decoding and removed after encoding.
+
+use super::{
+    behaviour::order_sync::{
+        codec,
+        messages::{Message, OrderFilter},
+        Version,
+    },
+    validation::{Rejection, Validator},
+};
+use crate::prelude::*;
+use futures::io::Cursor;
+use libp2p::request_response::RequestResponseCodec;
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    captured_at:  u64,
+    order_filter: OrderFilter,
+}
+
+/// Outcome of a replay.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct ReplayReport {
+    pub messages:      usize,
+    pub orders:        usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Discrepancy {
+    pub file: PathBuf,
+    pub kind: DiscrepancyKind,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DiscrepancyKind {
+    /// The codec failed to decode the message.
+    Parse(String),
+    /// A request file contained a response or vice versa.
+    UnexpectedType,
+    /// Re-encoding the message does not reproduce the capture.
+    RoundTrip,
+    /// An order in a response failed validation.
+    Rejected {
+        signature: String,
+        rejection: Rejection,
+    },
+}
+
+/// Replay the session captured in `dir`.
+pub async fn replay_session(dir: &Path) -> Result<ReplayReport> {
+    let manifest = fs::read(dir.join("session.json")).context("Reading session manifest")?;
+    let manifest: Manifest =
+        serde_json::from_slice(&manifest).context("Parsing session manifest")?;
+    let validator = Validator::new(manifest.order_filter);
+
+    let mut files = fs::read_dir(dir)
+        .context("Reading session directory")?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("Reading session directory")?;
+    files.sort();
+
+    let mut report = ReplayReport::default();
+    for file in files {
+        let name = file.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let is_request = if name.ends_with("-request.json") {
+            true
+        } else if name.ends_with("-response.json") {
+            false
+        } else {
+            continue;
+        };
+        let capture = fs::read(&file).with_context(|| format!("Reading {}", file.display()))?;
+        report.messages += 1;
+        let mut kinds = Vec::new();
+
+        let mut codec = codec();
+        let mut io = Cursor::new(frame(&capture));
+        let decoded = if is_request {
+            codec.read_request(&Version(), &mut io).await
+        } else {
+            codec.read_response(&Version(), &mut io).await
+        };
+        let message = match decoded {
+            Ok(message) => message,
+            Err(err) => {
+                report.discrepancies.push(Discrepancy {
+                    file,
+                    kind: DiscrepancyKind::Parse(err.to_string()),
+                });
+                continue;
+            }
+        };
+
+        // Compare as JSON values so formatting and key order do not matter.
+        let mut encoded = Cursor::new(Vec::new());
+        codec
+            .write_response(&Version(), &mut encoded, message.clone())
+            .await?;
+        let original: serde_json::Value = serde_json::from_slice(&capture)?;
+        let reencoded: serde_json::Value = serde_json::from_slice(unframe(encoded.get_ref()))?;
+        if original != reencoded {
+            kinds.push(DiscrepancyKind::RoundTrip);
+        }
+
+        match message {
+            Message::Request(_) if is_request => {}
+            Message::Response(response) if !is_request => {
+                for order in &response.orders {
+                    report.orders += 1;
+                    if let Err(rejection) = validator.validate_at(order, manifest.captured_at) {
+                        kinds.push(DiscrepancyKind::Rejected {
+                            signature: order.signature.clone(),
+                            rejection,
+                        });
+                    }
+                }
+            }
+            _ => kinds.push(DiscrepancyKind::UnexpectedType),
+        }
+        report
+            .discrepancies
+            .extend(kinds.into_iter().map(|kind| {
+                Discrepancy {
+                    file: file.clone(),
+                    kind,
+                }
+            }));
+    }
+    Ok(report)
+}
+
+/// The bytes the codec reads for the payload `json`.
+#[cfg(not(feature = "legacy_no_framing"))]
+fn frame(json: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(json.len()).unwrap_or(u32::MAX);
+    [&length.to_le_bytes()[..], json].concat()
+}
+
+#[cfg(feature = "legacy_no_framing")]
+fn frame(json: &[u8]) -> Vec<u8> {
+    json.to_vec()
+}
+
+/// The payload of a message written by the codec.
+#[cfg(not(feature = "legacy_no_framing"))]
+fn unframe(wire: &[u8]) -> &[u8] {
+    wire.get(4..).unwrap_or_default()
+}
+
+#[cfg(feature = "legacy_no_framing")]
+const fn unframe(wire: &[u8]) -> &[u8] {
+    wire
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[tokio::test]
+    async fn test_replay_fixture() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/session");
+        let report = replay_session(&dir).await.unwrap();
+        assert_eq!(report, ReplayReport {
+            messages:      2,
+            orders:        5,
+            discrepancies: vec![],
+        });
+    }
+}
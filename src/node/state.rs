@@ -0,0 +1,336 @@
+//! Snapshot of the node state for deterministic tests and migration.
+//!
+//! The snapshot is versioned so that older snapshots can be migrated when they
+//! are imported. Version 1 snapshots have no orders and import as having an
+//! empty order store.
+
+use super::{
+    behaviour::{discovery::PeerInfo, order_sync::messages::Order},
+    order_store::StoredOrder,
+};
+use crate::prelude::*;
+use libp2p::{
+    identify::IdentifyInfo,
+    identity::PublicKey,
+    Multiaddr, PeerId,
+};
+use std::{
+    convert::TryFrom,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Current version of the [`NodeState`] format.
+pub const VERSION: u32 = 2;
+
+/// Oldest version of the [`NodeState`] format that can be imported.
+const MIN_VERSION: u32 = 1;
+
+/// A complete snapshot of the node state.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeState {
+    pub version:            u32,
+    pub peers:              Vec<PeerState>,
+    pub routing_table:      Vec<RoutingEntry>,
+    pub external_addresses: Vec<Multiaddr>,
+    #[serde(default)]
+    pub orders:             Vec<OrderState>,
+}
+
+/// Serializable form of [`PeerInfo`].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerState {
+    pub peer_id:  String,
+    pub identify: Option<IdentifyState>,
+    pub ping:     Option<Duration>,
+}
+
+/// Serializable form of [`IdentifyInfo`].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentifyState {
+    /// Hex encoded protobuf encoding of the public key.
+    pub public_key:       String,
+    pub protocol_version: String,
+    pub agent_version:    String,
+    pub listen_addrs:     Vec<Multiaddr>,
+    pub protocols:        Vec<String>,
+}
+
+/// Serializable form of [`StoredOrder`].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderState {
+    pub order:       Order,
+    pub sources:     Vec<String>,
+    /// Milliseconds since the Unix epoch.
+    pub received_at: u64,
+}
+
+/// A peer in the Kademlia routing table.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingEntry {
+    pub peer_id:   String,
+    pub addresses: Vec<Multiaddr>,
+}
+
+impl NodeState {
+    /// Create a snapshot in the current format. Entries are sorted by peer id
+    /// or order hash so that snapshots of the same state compare equal.
+    pub fn new(
+        peers: impl IntoIterator<Item = PeerInfo>,
+        routing_table: impl IntoIterator<Item = (PeerId, Vec<Multiaddr>)>,
+        external_addresses: Vec<Multiaddr>,
+        orders: impl IntoIterator<Item = StoredOrder>,
+    ) -> Self {
+        let mut peers = peers.into_iter().map(PeerState::from).collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        let mut routing_table = routing_table
+            .into_iter()
+            .map(|(peer_id, addresses)| {
+                RoutingEntry {
+                    peer_id: peer_id.to_base58(),
+                    addresses,
+                }
+            })
+            .collect::<Vec<_>>();
+        routing_table.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        let mut orders = orders.into_iter().map(OrderState::from).collect::<Vec<_>>();
+        orders.sort_by_cached_key(|state| state.order.hash());
+        Self {
+            version: VERSION,
+            peers,
+            routing_table,
+            external_addresses,
+            orders,
+        }
+    }
+
+    /// Check that the snapshot can be imported.
+    pub fn check_version(&self) -> Result<()> {
+        if !(MIN_VERSION..=VERSION).contains(&self.version) {
+            return Err(anyhow::anyhow!(
+                "Unsupported node state version {}, expected {} to {}",
+                self.version,
+                MIN_VERSION,
+                VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decode the peer database.
+    pub fn peer_infos(&self) -> Result<Vec<PeerInfo>> {
+        self.peers.iter().map(PeerInfo::try_from).collect()
+    }
+
+    /// Decode the routing table.
+    pub fn routing_entries(&self) -> Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+        self.routing_table
+            .iter()
+            .map(|entry| Ok((parse_peer_id(&entry.peer_id)?, entry.addresses.clone())))
+            .collect()
+    }
+
+    /// Decode the stored orders.
+    pub fn stored_orders(&self) -> Result<Vec<StoredOrder>> {
+        self.orders.iter().map(StoredOrder::try_from).collect()
+    }
+}
+
+impl From<PeerInfo> for PeerState {
+    fn from(peer_info: PeerInfo) -> Self {
+        Self {
+            peer_id:  peer_info.peer_id.to_base58(),
+            identify: peer_info.identify.map(IdentifyState::from),
+            ping:     peer_info.ping,
+        }
+    }
+}
+
+impl TryFrom<&PeerState> for PeerInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(peer_state: &PeerState) -> Result<Self> {
+        Ok(Self {
+            peer_id:  parse_peer_id(&peer_state.peer_id)?,
+            identify: peer_state
+                .identify
+                .as_ref()
+                .map(IdentifyInfo::try_from)
+                .transpose()?,
+            ping:     peer_state.ping,
+
+            // Connections and reputation are not persisted.
+            connections:  0,
+            disconnected: None,
+            reputation:   0.0,
+        })
+    }
+}
+
+impl From<StoredOrder> for OrderState {
+    fn from(stored: StoredOrder) -> Self {
+        let mut sources = stored
+            .sources
+            .iter()
+            .map(PeerId::to_base58)
+            .collect::<Vec<_>>();
+        sources.sort();
+        let received_at = stored
+            .received_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        Self {
+            order: stored.order,
+            sources,
+            received_at,
+        }
+    }
+}
+
+impl TryFrom<&OrderState> for StoredOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(state: &OrderState) -> Result<Self> {
+        Ok(Self {
+            order:       state.order.clone(),
+            sources:     state
+                .sources
+                .iter()
+                .map(|source| parse_peer_id(source))
+                .collect::<Result<_>>()?,
+            received_at: UNIX_EPOCH + Duration::from_millis(state.received_at),
+        })
+    }
+}
+
+impl From<IdentifyInfo> for IdentifyState {
+    fn from(info: IdentifyInfo) -> Self {
+        Self {
+            public_key:       hex::encode(info.public_key.into_protobuf_encoding()),
+            protocol_version: info.protocol_version,
+            agent_version:    info.agent_version,
+            listen_addrs:     info.listen_addrs,
+            protocols:        info.protocols,
+        }
+    }
+}
+
+impl TryFrom<&IdentifyState> for IdentifyInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(state: &IdentifyState) -> Result<Self> {
+        let public_key = hex::decode(&state.public_key).context("Decoding public key hex")?;
+        let public_key =
+            PublicKey::from_protobuf_encoding(&public_key).context("Decoding public key")?;
+        Ok(Self {
+            public_key,
+            protocol_version: state.protocol_version.clone(),
+            agent_version: state.agent_version.clone(),
+            listen_addrs: state.listen_addrs.clone(),
+            protocols: state.protocols.clone(),
+        })
+    }
+}
+
+fn parse_peer_id(peer_id: &str) -> Result<PeerId> {
+    peer_id
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid peer id {}", peer_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::{Node, NodeConfig},
+        test::prelude::assert_eq,
+    };
+    use libp2p::identity::Keypair;
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let mut node = Node::new(Keypair::generate_ed25519(), NodeConfig::default()).await.unwrap();
+
+        // Populate the peer database, routing table and external addresses.
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/60558".parse().unwrap();
+        let mut peer_info = PeerInfo::new(peer_id.clone());
+        peer_info.ping = Some(Duration::from_millis(42));
+        peer_info.identify = Some(IdentifyInfo {
+            public_key:       keypair.public(),
+            protocol_version: "/ipfs/0.1.0".into(),
+            agent_version:    "mesh-rs".into(),
+            listen_addrs:     vec![address.clone()],
+            protocols:        vec!["/0x-mesh/order-sync/version/0".into()],
+        });
+        node.known_peers()
+            .write()
+            .unwrap()
+            .insert(peer_id.clone(), peer_info);
+        node.swarm.add_address(&peer_id, address.clone());
+        node.import_state(NodeState::new(vec![], vec![], vec![address], vec![]))
+            .unwrap();
+
+        // Store an order received from two peers.
+        let order = Order {
+            chain_id: 1,
+            salt: 42.into(),
+            ..Order::default()
+        };
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let received_at = UNIX_EPOCH + Duration::from_millis(1_607_000_000_123);
+        let mut order_store = node.order_store.write().unwrap();
+        order_store.insert_merge_at(order.clone(), peer_id.clone(), received_at);
+        order_store.insert_merge_at(order.clone(), other, received_at + Duration::from_secs(1));
+        drop(order_store);
+
+        let state = node.export_state();
+        assert_eq!(state.peers.len(), 1);
+        assert!(state
+            .routing_table
+            .iter()
+            .any(|entry| entry.peer_id == peer_id.to_base58()));
+        assert_eq!(state.external_addresses.len(), 1);
+        assert_eq!(state.orders.len(), 1);
+        assert_eq!(state.orders[0].order, order);
+        assert_eq!(state.orders[0].sources.len(), 2);
+        assert_eq!(state.orders[0].received_at, 1_607_000_000_123);
+
+        // Serialize, import into a fresh node and export again.
+        let json = serde_json::to_string(&state).unwrap();
+        let mut fresh = Node::new(Keypair::generate_ed25519(), NodeConfig::default()).await.unwrap();
+        fresh
+            .import_state(serde_json::from_str(&json).unwrap())
+            .unwrap();
+        assert_eq!(fresh.export_state(), state);
+        assert_eq!(fresh.get_order(&order.hash()), Some(order));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut state = NodeState::new(vec![], vec![], vec![], vec![]);
+        assert!(state.check_version().is_ok());
+        state.version = VERSION + 1;
+        assert!(state.check_version().is_err());
+        state.version = 0;
+        assert!(state.check_version().is_err());
+    }
+
+    #[test]
+    fn test_version_1_without_orders() {
+        let state: NodeState = serde_json::from_value(serde_json::json!({
+            "version": 1,
+            "peers": [],
+            "routingTable": [],
+            "externalAddresses": [],
+        }))
+        .unwrap();
+        assert!(state.check_version().is_ok());
+        assert_eq!(state.stored_orders().unwrap().len(), 0);
+    }
+}
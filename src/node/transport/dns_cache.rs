@@ -0,0 +1,280 @@
+//! Cache DNS resolutions of dialed addresses.
+//!
+//! Bootnodes are `/dns4/` addresses that are dialed again on every reconnect.
+//! [`CachedDns`] resolves the host names in dialed addresses through a shared
+//! [`DnsCache`] and hands the inner [`DnsConfig`](libp2p::dns::DnsConfig)
+//! plain IP addresses. Entries expire after the TTL, and are dropped when
+//! resolving or dialing fails so the name is resolved again after an outage.
+
+use crate::prelude::*;
+use futures::future::{BoxFuture, Either};
+use libp2p::{
+    core::transport::TransportError,
+    dns::DnsErr,
+    multiaddr::{Multiaddr, Protocol},
+    Transport,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Resolves host names to IP addresses.
+pub trait Resolver: Send + Sync + 'static {
+    fn resolve(&self, host: &str) -> BoxFuture<'static, io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolver using the operating system, as `DnsConfig` does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+        let host = host.to_owned();
+        async move {
+            let addresses = tokio::net::lookup_host((host.as_str(), 0)).await?;
+            Ok(addresses.map(|address| address.ip()).collect())
+        }
+        .boxed()
+    }
+}
+
+struct Entry {
+    addresses: Vec<IpAddr>,
+    resolved:  Instant,
+}
+
+/// Resolutions keyed by host name, kept for `ttl`.
+pub struct DnsCache {
+    ttl:      Duration,
+    resolver: Arc<dyn Resolver>,
+    entries:  Mutex<HashMap<String, Entry>>,
+}
+
+impl DnsCache {
+    pub fn new(ttl: Duration, resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            ttl,
+            resolver,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Addresses of `host`, resolved if there is no fresh entry.
+    pub async fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.lock().unwrap().get(host) {
+            if now.saturating_duration_since(entry.resolved) < self.ttl {
+                trace!("Using cached resolution of {}", host);
+                return Ok(entry.addresses.clone());
+            }
+        }
+        match self.resolver.resolve(host).await {
+            Ok(addresses) if !addresses.is_empty() => {
+                let _ = self.entries.lock().unwrap().insert(host.to_owned(), Entry {
+                    addresses: addresses.clone(),
+                    resolved:  now,
+                });
+                Ok(addresses)
+            }
+            Ok(_) => {
+                self.invalidate(host);
+                Err(io::Error::new(io::ErrorKind::NotFound, "No addresses found"))
+            }
+            Err(err) => {
+                self.invalidate(host);
+                Err(err)
+            }
+        }
+    }
+
+    /// Forget the resolution of `host`.
+    pub fn invalidate(&self, host: &str) {
+        let _ = self.entries.lock().unwrap().remove(host);
+    }
+}
+
+impl std::fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DnsCache").field("ttl", &self.ttl).finish_non_exhaustive()
+    }
+}
+
+/// Transport wrapper that resolves `/dns*/` components through a
+/// [`DnsCache`] before dialing.
+#[derive(Clone, Debug)]
+pub struct CachedDns<T> {
+    inner: T,
+    cache: Arc<DnsCache>,
+}
+
+impl<T> CachedDns<T> {
+    pub fn new(inner: T, cache: Arc<DnsCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+/// Resolve the `/dns*/` components of `address`, returning the names resolved.
+async fn resolve_address(
+    cache: &DnsCache,
+    address: &Multiaddr,
+) -> Result<(Multiaddr, Vec<String>), (String, io::Error)> {
+    let mut resolved = Multiaddr::empty();
+    let mut hosts = Vec::new();
+    for component in address {
+        let (host, ipv4, ipv6) = match &component {
+            Protocol::Dns(host) => (host, true, true),
+            Protocol::Dns4(host) => (host, true, false),
+            Protocol::Dns6(host) => (host, false, true),
+            _ => {
+                resolved.push(component);
+                continue;
+            }
+        };
+        let host = host.to_string();
+        let addresses = cache
+            .resolve(&host)
+            .await
+            .map_err(|err| (host.clone(), err))?;
+        let ip = addresses
+            .into_iter()
+            .find(|ip| (ipv4 && ip.is_ipv4()) || (ipv6 && ip.is_ipv6()))
+            .ok_or_else(|| {
+                let err = io::Error::new(io::ErrorKind::NotFound, "No address of the family");
+                (host.clone(), err)
+            })?;
+        resolved.push(Protocol::from(ip));
+        hosts.push(host);
+    }
+    Ok((resolved, hosts))
+}
+
+impl<T, E> Transport for CachedDns<T>
+where
+    T: Transport<Error = DnsErr<E>> + Send + 'static,
+    T::Dial: Send + 'static,
+    T::Output: Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    type Dial = Either<T::Dial, BoxFuture<'static, Result<T::Output, T::Error>>>;
+    type Error = T::Error;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Output = T::Output;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<T::Error>> {
+        self.inner.listen_on(address)
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<T::Error>> {
+        let contains_dns = address.iter().any(|component| {
+            matches!(component, Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_))
+        });
+        if !contains_dns {
+            return Ok(Either::Left(self.inner.dial(address)?));
+        }
+        let Self { inner, cache } = self;
+        let dial = async move {
+            let (resolved, hosts) = resolve_address(&cache, &address)
+                .await
+                .map_err(|(domain_name, error)| DnsErr::ResolveError { domain_name, error })?;
+            debug!("Resolved {} to {}", address, resolved);
+            let result = match inner.dial(resolved) {
+                Ok(dial) => dial.await,
+                Err(TransportError::MultiaddrNotSupported(_)) => Err(DnsErr::MultiaddrNotSupported),
+                Err(TransportError::Other(err)) => Err(err),
+            };
+            if result.is_err() {
+                for host in &hosts {
+                    cache.invalidate(host);
+                }
+            }
+            result
+        };
+        Ok(Either::Right(dial.boxed()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::transport::TcpConfig, test::prelude::assert_eq};
+    use libp2p::dns::DnsConfig;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Resolves every name to localhost, counting resolutions.
+    #[derive(Default)]
+    struct CountingResolver {
+        count: AtomicUsize,
+        fail:  AtomicBool,
+    }
+
+    impl Resolver for Arc<CountingResolver> {
+        fn resolve(&self, _host: &str) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+            let _ = self.count.fetch_add(1, Ordering::SeqCst);
+            let result = if self.fail.load(Ordering::SeqCst) {
+                Err(io::Error::new(io::ErrorKind::Other, "Resolver is down"))
+            } else {
+                Ok(vec!["127.0.0.1".parse().unwrap()])
+            };
+            future::ready(result).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dial_reuses_resolution() {
+        let resolver = Arc::new(CountingResolver::default());
+        let cache = Arc::new(DnsCache::new(
+            Duration::from_secs(3600),
+            Arc::new(resolver.clone()),
+        ));
+        let transport = CachedDns::new(DnsConfig::new(TcpConfig::default()).unwrap(), cache.clone());
+
+        let mut listener = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let listen_address = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+        let port = match listen_address.iter().last() {
+            Some(Protocol::Tcp(port)) => port,
+            _ => panic!("Expected a TCP address"),
+        };
+
+        // The name only resolves through the cache's resolver.
+        let address: Multiaddr = format!("/dns4/bootnode.mesh.test/tcp/{}", port)
+            .parse()
+            .unwrap();
+        for _ in 0..2 {
+            assert!(transport.clone().dial(address.clone()).unwrap().await.is_ok());
+        }
+        assert_eq!(resolver.count.load(Ordering::SeqCst), 1);
+
+        // A failed resolution is not cached.
+        resolver.fail.store(true, Ordering::SeqCst);
+        cache.invalidate("bootnode.mesh.test");
+        assert!(transport.clone().dial(address.clone()).unwrap().await.is_err());
+        resolver.fail.store(false, Ordering::SeqCst);
+        assert!(transport.clone().dial(address.clone()).unwrap().await.is_ok());
+        assert!(transport.dial(address).unwrap().await.is_ok());
+        assert_eq!(resolver.count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_ttl() {
+        let resolver = Arc::new(CountingResolver::default());
+        let cache = DnsCache::new(Duration::from_secs(0), Arc::new(resolver.clone()));
+        for _ in 0..2 {
+            assert!(cache.resolve("bootnode.mesh.test").await.is_ok());
+        }
+        assert_eq!(resolver.count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,17 +1,44 @@
 //! Compose the transport stack for LibP2P
 //!
 //! TODO: Testnet memory transport
-//! TODO: pnet private network for testing
-
+//!
+//! QUIC is not supported: libp2p 0.32 ships no QUIC transport, and
+//! `libp2p-quic` is only released for much later libp2p versions. Once the
+//! dependency is upgraded it can be added behind a `quic` feature, combined
+//! with the TCP stack using `or_transport` and listening on
+//! `/ip4/0.0.0.0/udp/0/quic`. QUIC brings its own TLS security and stream
+//! multiplexing, so it must bypass the secio/noise and yamux/mplex upgrades
+//! and only be mapped to the boxed `(PeerId, StreamMuxerBox)` output before
+//! the bandwidth logging.
+
+mod dns_cache;
+mod pnet;
+mod rate_limit;
+mod tcp;
+mod wss;
+
+pub use self::{
+    pnet::read_psk_file,
+    tcp::TcpConfig,
+    wss::WssConfig,
+};
+use self::{
+    dns_cache::{CachedDns, DnsCache, SystemResolver},
+    rate_limit::RateLimited,
+};
 use crate::prelude::*;
 use libp2p::{
     bandwidth::BandwidthSinks,
     core::{
-        either::EitherOutput, muxing::StreamMuxerBox, upgrade, upgrade::SelectUpgrade, UpgradeInfo,
+        either::{EitherOutput, EitherTransport},
+        muxing::StreamMuxerBox,
+        upgrade,
+        upgrade::SelectUpgrade,
+        UpgradeInfo,
     },
     dns::DnsConfig,
     identity, mplex, noise,
-    tcp::TokioTcpConfig,
+    pnet::{PnetConfig, PreSharedKey},
     websocket::WsConfig,
     yamux, PeerId, Transport, TransportExt,
 };
@@ -24,26 +51,58 @@ pub type Libp2pTransport = libp2p::core::transport::Boxed<(PeerId, StreamMuxerBo
 
 /// Create a transport for TCP/IP and WebSockets over TCP/IP with Secio
 /// encryption and either yamux or else mplex multiplexing.
+///
+/// Inbound connections are limited to `inbound_per_ip` per minute for each
+/// source IP. Host names in dialed addresses are resolved at most once per
+/// `dns_cache_ttl`. Listening on `/wss` requires the `tls` feature and an
+/// enabled `wss_config`. With a `pnet_psk` only peers with the same
+/// pre-shared key can connect.
 pub fn make_transport(
     peer_id_keys: identity::Keypair,
+    tcp_config: TcpConfig,
+    inbound_per_ip: u32,
+    dns_cache_ttl: Duration,
+    wss_config: &WssConfig,
+    pnet_psk: Option<[u8; 32]>,
 ) -> Result<(Libp2pTransport, Arc<BandwidthSinks>)> {
     // Create transport with TCP, DNS and WS
     // TODO: WASM support
     // TODO: Circuit-relay (waiting for upstream PR)
     let transport = {
         // TCP/IP transport using Tokio
-        let tcp_transport = TokioTcpConfig::new().nodelay(true);
+        let tcp_transport = RateLimited::new(tcp_config, inbound_per_ip);
 
         // Add DNS support to the TCP transport (to resolve /dns*/ addresses)
-        let tcp_dns_transport =
-            DnsConfig::new(tcp_transport).context("Creating /dns/ transport")?;
+        let dns_transport = DnsConfig::new(tcp_transport).context("Creating /dns/ transport")?;
+        let dns_cache = DnsCache::new(dns_cache_ttl, Arc::new(SystemResolver));
+        let tcp_dns_transport = CachedDns::new(dns_transport, Arc::new(dns_cache));
 
         // Websocket transport over TCP/IP
-        // TODO: Secure websocket.
-        let ws_transport = WsConfig::new(tcp_dns_transport.clone());
+        #[allow(unused_mut)]
+        let mut ws_transport = WsConfig::new(tcp_dns_transport.clone());
+        if wss_config.enabled {
+            #[cfg(feature = "tls")]
+            let _ = ws_transport.set_tls_config(wss_config.tls_config()?);
+            #[cfg(not(feature = "tls"))]
+            anyhow::bail!("WSS requires building with the tls feature");
+        }
+
+        // Combine transports. WebSockets go first: they refuse addresses
+        // without `/ws` or `/wss` upfront, whereas the DNS transport accepts
+        // any `/dns*/` address and only fails once resolved.
+        ws_transport.or_transport(tcp_dns_transport)
+    };
 
-        // Combine transports
-        tcp_dns_transport.or_transport(ws_transport)
+    // Encrypt connections with the private network key, if any
+    let transport = match pnet_psk {
+        Some(key) => {
+            let psk = PreSharedKey::new(key);
+            info!("Private network: {}", psk.fingerprint());
+            EitherTransport::Left(
+                transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+            )
+        }
+        None => EitherTransport::Right(transport),
     };
 
     // Add bandwidth monitoring
@@ -111,3 +170,17 @@ pub fn make_transport(
 
     Ok((transport, bandwidth_logger))
 }
+
+/// Create an in-memory transport with plaintext authentication for tests.
+#[cfg(test)]
+pub fn make_memory_transport(peer_id_keys: &identity::Keypair) -> Libp2pTransport {
+    use libp2p::{core::transport::MemoryTransport, plaintext::PlainText2Config};
+
+    MemoryTransport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(PlainText2Config {
+            local_public_key: peer_id_keys.public(),
+        })
+        .multiplex(mplex::MplexConfig::new())
+        .boxed()
+}
@@ -0,0 +1,120 @@
+//! Private networks, where nodes only connect to nodes with the same
+//! pre-shared key.
+//!
+//! Connections are encrypted with the key before the security upgrade, so
+//! a node with a different or no key fails the handshake. This keeps test
+//! meshes from ever talking to the public 0x mesh.
+//!
+//! Keys are stored in the standard swarm key file format, also used by
+//! go-libp2p and IPFS:
+//!
+//! ```text
+//! /key/swarm/psk/1.0.0/
+//! /base16/
+//! <64 hex digits>
+//! ```
+
+use crate::prelude::*;
+use std::path::Path;
+
+const HEADER: &str = "/key/swarm/psk/1.0.0/";
+const ENCODING: &str = "/base16/";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+pub enum InvalidPsk {
+    #[error("Expected a {} header", HEADER)]
+    Header,
+    #[error("Unsupported key encoding, expected {}", ENCODING)]
+    Encoding,
+    #[error("Expected a key of 64 hex digits")]
+    Key,
+}
+
+/// Parse a pre-shared key in the swarm key file format.
+pub fn parse_psk(contents: &str) -> Result<[u8; 32], InvalidPsk> {
+    let mut lines = contents.lines().map(str::trim);
+    if lines.next() != Some(HEADER) {
+        return Err(InvalidPsk::Header);
+    }
+    if lines.next() != Some(ENCODING) {
+        return Err(InvalidPsk::Encoding);
+    }
+    let mut key = [0; 32];
+    let digits = lines.next().ok_or(InvalidPsk::Key)?;
+    hex::decode_to_slice(digits, &mut key).map_err(|_| InvalidPsk::Key)?;
+    Ok(key)
+}
+
+/// Read a pre-shared key from a swarm key file.
+pub fn read_psk_file(path: impl AsRef<Path>) -> anyhow::Result<[u8; 32]> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading swarm key {}", path.display()))?;
+    parse_psk(&contents).with_context(|| format!("Parsing swarm key {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::transport::{make_transport, TcpConfig, WssConfig},
+        test::prelude::assert_eq,
+    };
+    use libp2p::{core::transport::ListenerEvent, identity::Keypair, Transport};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_psk() {
+        let key = [0xab; 32];
+        let file = format!("{}\n{}\n{}\n", HEADER, ENCODING, hex::encode(key));
+        assert_eq!(parse_psk(&file), Ok(key));
+        assert_eq!(parse_psk(&file.replace('\n', "\r\n")), Ok(key));
+        assert_eq!(parse_psk(&hex::encode(key)), Err(InvalidPsk::Header));
+        assert_eq!(
+            parse_psk(&file.replace(ENCODING, "/base64/")),
+            Err(InvalidPsk::Encoding)
+        );
+        assert_eq!(parse_psk(&format!("{}\n{}\n", HEADER, ENCODING)), Err(InvalidPsk::Key));
+        assert_eq!(
+            parse_psk(&format!("{}\n{}\nabcd\n", HEADER, ENCODING)),
+            Err(InvalidPsk::Key)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_network() {
+        let make = |psk: Option<[u8; 32]>| {
+            let keypair = Keypair::generate_ed25519();
+            let tcp = TcpConfig::default();
+            let wss = WssConfig::default();
+            make_transport(keypair, tcp, 60, Duration::from_secs(60), &wss, psk)
+                .unwrap()
+                .0
+        };
+        let mut listener = make(Some([1; 32]))
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+        tokio::spawn(async move {
+            while let Some(Ok(event)) = listener.next().await {
+                if let ListenerEvent::Upgrade { upgrade, .. } = event {
+                    tokio::spawn(upgrade);
+                }
+            }
+        });
+
+        // Only nodes with the same key complete the handshake, the others
+        // fail or stall until the timeout.
+        for (psk, connects) in &[(Some([1; 32]), true), (Some([2; 32]), false), (None, false)] {
+            let dial = make(*psk).dial(address.clone()).unwrap();
+            let result = tokio::time::timeout(Duration::from_secs(5), dial).await;
+            assert_eq!(matches!(result, Ok(Ok(_))), *connects);
+        }
+    }
+}
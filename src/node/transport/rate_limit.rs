@@ -0,0 +1,196 @@
+//! Per IP rate limiting of inbound connections.
+//!
+//! Connection floods can come from many peer ids, so peer based limits do not
+//! help. Instead each source IP gets a token bucket and incoming connections
+//! are dropped before the upgrade when its bucket is empty.
+
+use crate::prelude::*;
+use futures::stream::BoxStream;
+use libp2p::{
+    core::transport::{ListenerEvent, TransportError},
+    multiaddr::Protocol,
+    Multiaddr, Transport,
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Number of tracked IPs above which idle buckets are pruned.
+const MAX_TRACKED_IPS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens:  f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by IP, allowing bursts of up to `per_minute`
+/// connections that refill over a minute.
+#[derive(Debug)]
+pub struct IpRateLimiter {
+    per_minute: u32,
+    buckets:    HashMap<IpAddr, Bucket>,
+}
+
+impl IpRateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for a connection from `ip` at time `now`. Returns `false`
+    /// if the rate is exceeded.
+    pub fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = f64::from(self.per_minute);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            (bucket.tokens + elapsed.as_secs_f64() * capacity / 60.0).min(capacity)
+        };
+        if self.buckets.len() >= MAX_TRACKED_IPS {
+            self.buckets.retain(|_, bucket| refill(bucket) < capacity);
+        }
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens:  capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Check a connection from `remote_addr`. Addresses without an IP are
+    /// always allowed.
+    pub fn allow(&mut self, remote_addr: &Multiaddr) -> bool {
+        match remote_ip(remote_addr) {
+            Some(ip) => self.allow_at(ip, Instant::now()),
+            None => true,
+        }
+    }
+}
+
+fn remote_ip(address: &Multiaddr) -> Option<IpAddr> {
+    match address.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// Transport wrapper that rate limits inbound connections per source IP.
+#[derive(Clone, Debug)]
+pub struct RateLimited<T> {
+    inner:   T,
+    limiter: Arc<Mutex<IpRateLimiter>>,
+}
+
+impl<T> RateLimited<T> {
+    pub fn new(inner: T, per_minute: u32) -> Self {
+        Self {
+            inner,
+            limiter: Arc::new(Mutex::new(IpRateLimiter::new(per_minute))),
+        }
+    }
+}
+
+impl<T> Transport for RateLimited<T>
+where
+    T: Transport,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Error: Send + 'static,
+{
+    type Dial = T::Dial;
+    type Error = T::Error;
+    type Listener =
+        BoxStream<'static, Result<ListenerEvent<T::ListenerUpgrade, T::Error>, T::Error>>;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Output = T::Output;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<T::Error>> {
+        let limiter = self.limiter;
+        let listener = self.inner.listen_on(address)?.filter(move |event| {
+            let allowed = match event {
+                Ok(ListenerEvent::Upgrade { remote_addr, .. }) => {
+                    let allowed = limiter.lock().unwrap().allow(remote_addr);
+                    if !allowed {
+                        debug!("Refusing connection from {}: rate limited", remote_addr);
+                    }
+                    allowed
+                }
+                _ => true,
+            };
+            future::ready(allowed)
+        });
+        Ok(listener.boxed())
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<T::Error>> {
+        self.inner.dial(address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::time::Duration;
+
+    /// Duration after which a drained bucket allows a connection again.
+    fn token_interval(per_minute: u32) -> Duration {
+        Duration::from_secs(60) / per_minute
+    }
+
+    #[test]
+    fn test_limit_per_ip() {
+        let mut limiter = IpRateLimiter::new(3);
+        let attacker: IpAddr = "1.2.3.4".parse().unwrap();
+        let other: IpAddr = "5.6.7.8".parse().unwrap();
+        let now = Instant::now();
+
+        let allowed = (0..10)
+            .filter(|_| limiter.allow_at(attacker, now))
+            .count();
+        assert_eq!(allowed, 3);
+        assert!(limiter.allow_at(other, now));
+        assert!(!limiter.allow_at(attacker, now + token_interval(3) / 2));
+        assert!(limiter.allow_at(attacker, now + token_interval(3)));
+    }
+
+    #[tokio::test]
+    async fn test_refuse_flood() {
+        use crate::node::transport::TcpConfig;
+
+        let transport = RateLimited::new(TcpConfig::default(), 2);
+        let mut listener = transport
+            .clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+
+        let mut dialers = Vec::new();
+        for _ in 0..3 {
+            dialers.push(transport.clone().dial(address.clone()).unwrap().await.unwrap());
+        }
+        for _ in 0..2 {
+            assert!(listener.next().await.unwrap().unwrap().into_upgrade().is_some());
+        }
+        let third = tokio::time::timeout(Duration::from_millis(200), listener.next()).await;
+        assert!(third.is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! TCP/IP transport using Tokio with configurable socket options.
+//!
+//! `libp2p::tcp::TokioTcpConfig` only exposes `TCP_NODELAY` and TTL. This
+//! transport additionally allows setting the socket buffer sizes, which matter
+//! for order sync on long-fat networks, and `SO_REUSEADDR`/`SO_REUSEPORT` for
+//! fast restarts on the same port.
+//!
+//! ## To do
+//!
+//! * Report interface changes for listeners on unspecified addresses.
+
+use crate::prelude::*;
+use futures::{
+    future::BoxFuture,
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    stream::BoxStream,
+};
+use libp2p::{
+    core::transport::{ListenerEvent, TransportError},
+    multiaddr::Protocol,
+    Multiaddr, Transport,
+};
+use std::{
+    io,
+    iter::FromIterator,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::ReadBuf,
+    net::{TcpListener, TcpSocket},
+};
+
+/// Socket options applied to listening and dialing sockets.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TcpConfig {
+    pub nodelay:          bool,
+    pub reuse_address:    bool,
+    pub reuse_port:       bool,
+    pub send_buffer_size: Option<u32>,
+    pub recv_buffer_size: Option<u32>,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nodelay:          true,
+            reuse_address:    true,
+            reuse_port:       false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+impl TcpConfig {
+    fn socket(&self, address: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = if address.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(self.reuse_address)?;
+        #[cfg(unix)]
+        socket.set_reuseport(self.reuse_port)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(socket)
+    }
+
+    fn listen(&self, address: SocketAddr) -> io::Result<TcpListener> {
+        let socket = self.socket(&address)?;
+        socket.bind(address)?;
+        socket.listen(1024)
+    }
+}
+
+/// A connected TCP stream implementing the `futures` IO traits.
+#[derive(Debug)]
+pub struct TcpStream(tokio::net::TcpStream);
+
+type Upgrade = future::Ready<io::Result<TcpStream>>;
+
+impl Transport for TcpConfig {
+    type Dial = BoxFuture<'static, io::Result<TcpStream>>;
+    type Error = io::Error;
+    type Listener = BoxStream<'static, io::Result<ListenerEvent<Upgrade, io::Error>>>;
+    type ListenerUpgrade = Upgrade;
+    type Output = TcpStream;
+
+    fn listen_on(self, address: Multiaddr) -> Result<Self::Listener, TransportError<io::Error>> {
+        let socket_address = multiaddr_to_socketaddr(&address)
+            .ok_or(TransportError::MultiaddrNotSupported(address))?;
+
+        // Binding needs to happen in the context of the runtime.
+        let listener = async move {
+            let listener = self.listen(socket_address)?;
+            let local_address = listener.local_addr()?;
+            let addresses = listen_addresses(&local_address)?;
+            debug!("Listening on {:?}", addresses);
+            let nodelay = self.nodelay;
+            let new_addresses = stream::iter(addresses)
+                .map(|address| Ok(ListenerEvent::NewAddress(address)));
+            let upgrades = stream::unfold(listener, move |listener| {
+                async move {
+                    let event = match listener.accept().await {
+                        Ok((stream, remote_address)) => {
+                            let local_address = stream.local_addr().unwrap_or(local_address);
+                            let upgrade = stream
+                                .set_nodelay(nodelay)
+                                .map(|()| TcpStream(stream));
+                            ListenerEvent::Upgrade {
+                                upgrade:     future::ready(upgrade),
+                                local_addr:  socketaddr_to_multiaddr(&local_address),
+                                remote_addr: socketaddr_to_multiaddr(&remote_address),
+                            }
+                        }
+                        Err(err) => ListenerEvent::Error(err),
+                    };
+                    Some((Ok(event), listener))
+                }
+            });
+            io::Result::Ok(new_addresses.chain(upgrades))
+        };
+        Ok(listener.try_flatten_stream().boxed())
+    }
+
+    fn dial(self, address: Multiaddr) -> Result<Self::Dial, TransportError<io::Error>> {
+        let socket_address = match multiaddr_to_socketaddr(&address) {
+            Some(socket_address)
+                if socket_address.port() != 0 && !socket_address.ip().is_unspecified() =>
+            {
+                socket_address
+            }
+            Some(_) => return Err(TransportError::Other(io::ErrorKind::ConnectionRefused.into())),
+            None => return Err(TransportError::MultiaddrNotSupported(address)),
+        };
+        debug!("Dialing {}", address);
+        Ok(async move {
+            let stream = self.socket(&socket_address)?.connect(socket_address).await?;
+            stream.set_nodelay(self.nodelay)?;
+            Ok(TcpStream(stream))
+        }
+        .boxed())
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        ready!(tokio::io::AsyncRead::poll_read(
+            Pin::new(&mut self.0),
+            cx,
+            &mut buf
+        ))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+    }
+}
+
+fn multiaddr_to_socketaddr(address: &Multiaddr) -> Option<SocketAddr> {
+    let mut iter = address.iter();
+    let address = match (iter.next()?, iter.next()?) {
+        (Protocol::Ip4(ip), Protocol::Tcp(port)) => SocketAddr::new(ip.into(), port),
+        (Protocol::Ip6(ip), Protocol::Tcp(port)) => SocketAddr::new(ip.into(), port),
+        _ => return None,
+    };
+    if iter.next().is_some() {
+        return None;
+    }
+    Some(address)
+}
+
+fn socketaddr_to_multiaddr(address: &SocketAddr) -> Multiaddr {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => Protocol::Ip4(ip),
+        IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    Multiaddr::from_iter(vec![ip, Protocol::Tcp(address.port())])
+}
+
+/// The addresses a listener is reachable on. For unspecified addresses these
+/// are the addresses of all interfaces of the same IP version.
+fn listen_addresses(local_address: &SocketAddr) -> io::Result<Vec<Multiaddr>> {
+    if !local_address.ip().is_unspecified() {
+        return Ok(vec![socketaddr_to_multiaddr(local_address)]);
+    }
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| ip.is_ipv4() == local_address.is_ipv4())
+        .map(|ip| socketaddr_to_multiaddr(&SocketAddr::new(ip, local_address.port())))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        node::transport::{make_transport, WssConfig},
+        test::prelude::assert_eq,
+    };
+    use libp2p::identity::Keypair;
+
+    #[tokio::test]
+    async fn test_custom_buffer_sizes() {
+        let config = TcpConfig {
+            send_buffer_size: Some(1 << 20),
+            recv_buffer_size: Some(1 << 20),
+            ..TcpConfig::default()
+        };
+        let dns_cache_ttl = std::time::Duration::from_secs(300);
+        let keypair = Keypair::generate_ed25519();
+        let wss_config = WssConfig::default();
+        assert!(make_transport(keypair, config, 60, dns_cache_ttl, &wss_config, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rebind_recently_used_port() {
+        let config = TcpConfig::default();
+        let mut listener = config
+            .clone()
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+
+        // Connect and close from the listening side, so the port is left in
+        // the TIME_WAIT state.
+        let dialer = config.clone().dial(address.clone()).unwrap().await.unwrap();
+        let (upgrade, _) = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_upgrade()
+            .unwrap();
+        let mut accepted = upgrade.await.unwrap();
+        accepted.close().await.unwrap();
+        drop(accepted);
+        drop(dialer);
+        drop(listener);
+
+        let mut listener = config.listen_on(address.clone()).unwrap();
+        let rebound = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+        assert_eq!(rebound, address);
+    }
+}
@@ -0,0 +1,123 @@
+//! Secure WebSocket listening, enabled by the `tls` feature.
+//!
+//! `WsConfig` dials `/wss` addresses with certificates verified against the
+//! webpki roots either way. With an enabled [`WssConfig`] the transport also
+//! listens on `/wss` with the given certificate, and trusts it when dialing.
+//! Without one, WebSockets are only served as plain `/ws`. Peers are
+//! authenticated by the libp2p handshake on top, so nodes sharing a
+//! self-signed certificate can connect to each other.
+
+#[cfg(feature = "tls")]
+use crate::prelude::*;
+#[cfg(feature = "tls")]
+use libp2p::websocket::tls;
+use std::path::PathBuf;
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct WssConfig {
+    pub enabled:     bool,
+    /// DER encoded X.509 certificate, may be self-signed.
+    pub certificate: Option<PathBuf>,
+    /// DER encoded PKCS#8 private key of the certificate.
+    pub private_key: Option<PathBuf>,
+}
+
+#[cfg(feature = "tls")]
+impl WssConfig {
+    /// Server and client TLS configuration for the WebSocket transport.
+    pub fn tls_config(&self) -> Result<tls::Config> {
+        let certificate = self
+            .certificate
+            .as_ref()
+            .context("WSS requires a certificate")?;
+        let private_key = self
+            .private_key
+            .as_ref()
+            .context("WSS requires a private key")?;
+        let certificate = std::fs::read(certificate)
+            .with_context(|| format!("Reading certificate {}", certificate.display()))?;
+        let private_key = std::fs::read(private_key)
+            .with_context(|| format!("Reading private key {}", private_key.display()))?;
+
+        let certificate = tls::Certificate::new(certificate);
+        let mut builder = tls::Config::builder();
+        let _ = builder
+            .server(tls::PrivateKey::new(private_key), vec![certificate.clone()])
+            .context("Invalid WSS certificate or private key")?
+            .add_trust(&certificate)
+            .context("Trusting WSS certificate")?;
+        Ok(builder.finish())
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod test {
+    use super::*;
+    use crate::node::transport::{make_transport, TcpConfig};
+    use libp2p::{identity::Keypair, Multiaddr, Transport};
+    use std::{path::Path, time::Duration};
+
+    fn config() -> WssConfig {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/wss");
+        WssConfig {
+            enabled:     true,
+            certificate: Some(dir.join("certificate.der")),
+            private_key: Some(dir.join("private_key.der")),
+        }
+    }
+
+    #[test]
+    fn test_tls_config() {
+        assert!(config().tls_config().is_ok());
+        let missing_key = WssConfig {
+            private_key: None,
+            ..config()
+        };
+        assert!(missing_key.tls_config().is_err());
+        let swapped = WssConfig {
+            certificate: config().private_key,
+            private_key: config().certificate,
+            ..config()
+        };
+        assert!(swapped.tls_config().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_wss() {
+        let make = || {
+            let keypair = Keypair::generate_ed25519();
+            let tcp = TcpConfig::default();
+            make_transport(keypair, tcp, 60, Duration::from_secs(60), &config(), None)
+                .unwrap()
+                .0
+        };
+        let mut listener = make()
+            .listen_on("/ip4/127.0.0.1/tcp/0/wss".parse().unwrap())
+            .unwrap();
+        let address = listener
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_new_address()
+            .unwrap();
+        let port = address
+            .iter()
+            .find_map(|component| {
+                match component {
+                    libp2p::multiaddr::Protocol::Tcp(port) => Some(port),
+                    _ => None,
+                }
+            })
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let (upgrade, _) = listener.next().await.unwrap().unwrap().into_upgrade().unwrap();
+            upgrade.await.map(|(peer_id, _)| peer_id)
+        });
+
+        // The certificate names localhost, so dial by name.
+        let dialed: Multiaddr = format!("/dns4/localhost/tcp/{}/wss", port).parse().unwrap();
+        let (_, _connection) = make().dial(dialed).unwrap().await.unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,529 @@
+//! Order validation with rejection statistics.
+//!
+//! Orders received from peers are checked before they are accepted. Rejected
+//! orders are dropped, but the reason is counted so operators can see why.
+//!
+//! Orders are only considered expired once they are past their expiration
+//! time by more than a grace period, so a slightly skewed local clock does not
+//! drop valid orders. If most orders arrive already expired, the local clock is
+//! likely wrong and a warning is logged.
+//!
+//! Only orders for allowlisted exchange contracts are accepted. By default this
+//! is the exchange of the [`OrderFilter`], operators can allow others such as
+//! an audited fork with [`Validator::set_allowed_exchanges`].
+//!
+//! Signatures are only checked structurally by default. With
+//! [`Validator::set_verify_signatures`] the signer is also recovered and
+//! compared with the maker, which rejects contract signatures as those can not
+//! be checked offline.
+//!
+//! Orders we publish ourselves go through the same rules with
+//! [`Validator::validate_for_publish`], which leaves the statistics alone.
+
+use super::behaviour::order_sync::messages::{Order, OrderFilter};
+use crate::{prelude::*, types::EthAddress};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default time past expiration during which orders are still accepted.
+pub const DEFAULT_EXPIRATION_GRACE: Duration = Duration::from_secs(60);
+
+/// Number of orders over which the fraction of expired orders is evaluated.
+const SKEW_WINDOW: u64 = 1000;
+
+/// Fraction of expired orders in a window above which clock skew is suspected.
+const SKEW_THRESHOLD: f64 = 0.5;
+
+/// Reason an order was rejected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+pub enum Rejection {
+    #[error("Order is expired")]
+    Expired,
+    #[error("Order signature is malformed")]
+    BadSignature,
+    #[error("Order is for a different chain")]
+    WrongChain,
+    #[error("Order has a zero amount")]
+    Spam,
+    #[error("Order is for an exchange contract that is not allowed")]
+    InconsistentExchange,
+}
+
+/// Counts of accepted orders and of rejected orders by reason.
+#[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationStats {
+    pub accepted:              u64,
+    pub expired:               u64,
+    pub bad_signature:         u64,
+    pub wrong_chain:           u64,
+    pub spam:                  u64,
+    pub inconsistent_exchange: u64,
+}
+
+impl ValidationStats {
+    fn record(&mut self, result: Result<(), Rejection>) {
+        let counter = match result {
+            Ok(()) => &mut self.accepted,
+            Err(Rejection::Expired) => &mut self.expired,
+            Err(Rejection::BadSignature) => &mut self.bad_signature,
+            Err(Rejection::WrongChain) => &mut self.wrong_chain,
+            Err(Rejection::Spam) => &mut self.spam,
+            Err(Rejection::InconsistentExchange) => &mut self.inconsistent_exchange,
+        };
+        *counter += 1;
+    }
+}
+
+/// Counts of validated and expired orders in the current window.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+struct SkewMonitor {
+    validated: u64,
+    expired:   u64,
+}
+
+impl SkewMonitor {
+    /// Record a result and return the fraction of expired orders when a window
+    /// completes.
+    fn record(&mut self, result: Result<(), Rejection>) -> Option<f64> {
+        self.validated += 1;
+        if result == Err(Rejection::Expired) {
+            self.expired += 1;
+        }
+        if self.validated < SKEW_WINDOW {
+            return None;
+        }
+        #[allow(clippy::cast_precision_loss)] // Counts are at most `SKEW_WINDOW`
+        let fraction = self.expired as f64 / self.validated as f64;
+        *self = Self::default();
+        Some(fraction)
+    }
+}
+
+/// Validates orders against an [`OrderFilter`] and records the outcome.
+///
+/// Clones share the same statistics.
+#[derive(Clone, Debug)]
+pub struct Validator {
+    order_filter:      OrderFilter,
+    allowed_exchanges: Vec<EthAddress>,
+    expiration_grace:  u64,
+    verify_signatures: bool,
+    stats:             Arc<RwLock<ValidationStats>>,
+    skew:              Arc<Mutex<SkewMonitor>>,
+}
+
+impl Validator {
+    pub fn new(order_filter: OrderFilter) -> Self {
+        Self {
+            order_filter,
+            allowed_exchanges: Vec::new(),
+            expiration_grace: DEFAULT_EXPIRATION_GRACE.as_secs(),
+            verify_signatures: false,
+            stats: Arc::default(),
+            skew: Arc::default(),
+        }
+    }
+
+    /// A validator with the same settings for another order filter, such as
+    /// that of another chain. The statistics are shared with this validator.
+    pub fn for_order_filter(&self, order_filter: OrderFilter) -> Self {
+        Self {
+            order_filter,
+            ..self.clone()
+        }
+    }
+
+    pub const fn order_filter(&self) -> &OrderFilter {
+        &self.order_filter
+    }
+
+    /// Set the exchange contracts orders are accepted for, replacing the
+    /// exchange of the order filter. An empty list restores that default.
+    pub fn set_allowed_exchanges(&mut self, exchanges: Vec<EthAddress>) -> &mut Self {
+        self.allowed_exchanges = exchanges;
+        self
+    }
+
+    /// Set the time past expiration during which orders are still accepted.
+    pub fn set_expiration_grace(&mut self, grace: Duration) -> &mut Self {
+        self.expiration_grace = grace.as_secs();
+        self
+    }
+
+    /// Set whether signatures must recover to the maker address.
+    pub fn set_verify_signatures(&mut self, verify_signatures: bool) -> &mut Self {
+        self.verify_signatures = verify_signatures;
+        self
+    }
+
+    /// Snapshot of the statistics so far.
+    pub fn stats(&self) -> ValidationStats {
+        self.stats.read().unwrap().clone()
+    }
+
+    /// Validate an order against the current time.
+    pub fn validate(&self, order: &Order) -> Result<(), Rejection> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        self.validate_at(order, now)
+    }
+
+    /// Validate an order against a unix timestamp in seconds.
+    pub fn validate_at(&self, order: &Order, now: u64) -> Result<(), Rejection> {
+        let result = self.check(order, now);
+        if let Err(rejection) = result {
+            debug!("Rejected order {}: {}", order.signature, rejection);
+        }
+        self.stats.write().unwrap().record(result);
+        let expired_fraction = self.skew.lock().unwrap().record(result);
+        if let Some(fraction) = expired_fraction {
+            if fraction > SKEW_THRESHOLD {
+                warn!(
+                    "{:.0}% of recent orders were already expired on arrival, check the system \
+                     clock",
+                    fraction * 100.0
+                );
+            }
+        }
+        result
+    }
+
+    /// Validate an order we are about to publish against a unix timestamp in
+    /// seconds.
+    ///
+    /// The rules are the same as for orders received from peers, but nothing
+    /// is recorded in the statistics.
+    pub fn validate_for_publish(&self, order: &Order, now: u64) -> Result<(), Rejection> {
+        self.check(order, now)
+    }
+
+    fn check(&self, order: &Order, now: u64) -> Result<(), Rejection> {
+        let default_exchange = [self.order_filter.exchange_address];
+        let allowed_exchanges = if self.allowed_exchanges.is_empty() {
+            &default_exchange[..]
+        } else {
+            &self.allowed_exchanges
+        };
+        check(
+            order,
+            self.order_filter.chain_id,
+            allowed_exchanges,
+            self.expiration_grace,
+            self.verify_signatures,
+            now,
+        )
+    }
+}
+
+fn check(
+    order: &Order,
+    chain_id: u64,
+    allowed_exchanges: &[EthAddress],
+    expiration_grace: u64,
+    verify_signatures: bool,
+    now: u64,
+) -> Result<(), Rejection> {
+    if order.chain_id != chain_id {
+        return Err(Rejection::WrongChain);
+    }
+    if !allowed_exchanges.contains(&order.exchange_address) {
+        return Err(Rejection::InconsistentExchange);
+    }
+    let expiration = order.expiration_time_seconds.saturating_u64();
+    if expiration.saturating_add(expiration_grace) <= now {
+        return Err(Rejection::Expired);
+    }
+    if order.maker_asset_amount.is_zero() || order.taker_asset_amount.is_zero() {
+        return Err(Rejection::Spam);
+    }
+    if !is_valid_signature(&order.signature) {
+        return Err(Rejection::BadSignature);
+    }
+    if verify_signatures {
+        match order.verify_signature() {
+            Ok(true) => {}
+            Ok(false) => return Err(Rejection::BadSignature),
+            Err(err) => {
+                debug!("Unverifiable signature {}: {}", order.signature, err);
+                return Err(Rejection::BadSignature);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Structural check of a 0x v3 signature: hex encoded and ending in a
+/// supported signature type byte (`EIP712` through `EIP1271Wallet`).
+fn is_valid_signature(signature: &str) -> bool {
+    hex::decode(signature.trim_start_matches("0x"))
+        .map_or(false, |bytes| matches!(bytes.last(), Some(2..=7)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{node::behaviour::order_sync::messages::Message, test::prelude::assert_eq};
+
+    fn valid_order() -> Order {
+        let order_filter = OrderFilter::mainnet_v3();
+        Order {
+            chain_id: order_filter.chain_id,
+            exchange_address: order_filter.exchange_address,
+            maker_asset_amount: 1000.into(),
+            taker_asset_amount: 2000.into(),
+            expiration_time_seconds: 2000.into(),
+            signature: format!("0x{}02", "1b".repeat(65)),
+            ..Order::default()
+        }
+    }
+
+    #[test]
+    fn test_rejection_counters() {
+        let validator = Validator::new(OrderFilter::mainnet_v3());
+        let now = 1000;
+        assert_eq!(validator.validate_at(&valid_order(), now), Ok(()));
+
+        let expired = Order {
+            expiration_time_seconds: 900.into(),
+            ..valid_order()
+        };
+        let bad_signature = Order {
+            signature: "0xzz".into(),
+            ..valid_order()
+        };
+        let wrong_chain = Order {
+            chain_id: 3,
+            ..valid_order()
+        };
+        let spam = Order {
+            taker_asset_amount: 0.into(),
+            ..valid_order()
+        };
+        let inconsistent_exchange = Order {
+            exchange_address: OrderFilter::mainnet_v2().exchange_address,
+            ..valid_order()
+        };
+        assert_eq!(validator.validate_at(&expired, now), Err(Rejection::Expired));
+        assert_eq!(
+            validator.validate_at(&bad_signature, now),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            validator.validate_at(&wrong_chain, now),
+            Err(Rejection::WrongChain)
+        );
+        assert_eq!(validator.validate_at(&spam, now), Err(Rejection::Spam));
+        assert_eq!(
+            validator.validate_at(&inconsistent_exchange, now),
+            Err(Rejection::InconsistentExchange)
+        );
+
+        assert_eq!(validator.stats(), ValidationStats {
+            accepted:              1,
+            expired:               1,
+            bad_signature:         1,
+            wrong_chain:           1,
+            spam:                  1,
+            inconsistent_exchange: 1,
+        });
+    }
+
+    #[test]
+    fn test_allowed_exchanges() {
+        let mut validator = Validator::new(OrderFilter::mainnet_v3());
+        let now = 1000;
+        let v2 = Order {
+            exchange_address: OrderFilter::mainnet_v2().exchange_address,
+            ..valid_order()
+        };
+        let fork = Order {
+            exchange_address: "0x1111111111111111111111111111111111111111".parse().unwrap(),
+            ..valid_order()
+        };
+        assert_eq!(
+            validator.validate_at(&v2, now),
+            Err(Rejection::InconsistentExchange)
+        );
+
+        validator.set_allowed_exchanges(vec![
+            OrderFilter::mainnet_v3().exchange_address,
+            OrderFilter::mainnet_v2().exchange_address,
+        ]);
+        assert_eq!(validator.validate_at(&valid_order(), now), Ok(()));
+        assert_eq!(validator.validate_at(&v2, now), Ok(()));
+        assert_eq!(
+            validator.validate_at(&fork, now),
+            Err(Rejection::InconsistentExchange)
+        );
+        assert_eq!(validator.stats().inconsistent_exchange, 2);
+
+        // Clearing the list restores the filter's exchange.
+        validator.set_allowed_exchanges(Vec::new());
+        assert_eq!(
+            validator.validate_at(&v2, now),
+            Err(Rejection::InconsistentExchange)
+        );
+        assert_eq!(validator.validate_at(&valid_order(), now), Ok(()));
+    }
+
+    #[test]
+    fn test_for_order_filter() {
+        let mainnet = Validator::new(OrderFilter::mainnet_v3());
+        let ropsten_filter = OrderFilter::v3(3).unwrap();
+        let ropsten = mainnet.for_order_filter(ropsten_filter.clone());
+        let now = 1000;
+        let ropsten_order = Order {
+            chain_id: ropsten_filter.chain_id,
+            exchange_address: ropsten_filter.exchange_address,
+            ..valid_order()
+        };
+        assert_eq!(ropsten.validate_at(&ropsten_order, now), Ok(()));
+        assert_eq!(
+            ropsten.validate_at(&valid_order(), now),
+            Err(Rejection::WrongChain)
+        );
+        assert_eq!(
+            mainnet.validate_at(&ropsten_order, now),
+            Err(Rejection::WrongChain)
+        );
+
+        // Statistics are shared between the chains.
+        assert_eq!(mainnet.stats(), ropsten.stats());
+        assert_eq!(mainnet.stats().accepted, 1);
+        assert_eq!(mainnet.stats().wrong_chain, 2);
+    }
+
+    #[test]
+    fn test_expiration_grace() {
+        let mut validator = Validator::new(OrderFilter::mainnet_v3());
+        validator.set_expiration_grace(Duration::from_secs(60));
+        let now = 10_000;
+        let expiring = |expiration: u64| {
+            Order {
+                expiration_time_seconds: expiration.into(),
+                ..valid_order()
+            }
+        };
+        assert_eq!(validator.validate_at(&expiring(now - 59), now), Ok(()));
+        assert_eq!(
+            validator.validate_at(&expiring(now - 600), now),
+            Err(Rejection::Expired)
+        );
+
+        validator.set_expiration_grace(Duration::from_secs(0));
+        assert_eq!(
+            validator.validate_at(&expiring(now - 59), now),
+            Err(Rejection::Expired)
+        );
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        let response = include_str!("../../test/response.json");
+        let orders = match serde_json::from_str::<Message>(response).unwrap() {
+            Message::Response(response) => response.orders,
+            Message::Request(_) => panic!("Expected a response"),
+        };
+        let order = orders[0].clone();
+        let tampered = Order {
+            salt: order.salt + 1,
+            ..order.clone()
+        };
+        let contract = Order {
+            signature: format!("0x{}04", "1b".repeat(65)),
+            ..order.clone()
+        };
+        let now = 0;
+
+        // Structurally valid signatures pass until verification is enabled.
+        let mut validator = Validator::new(OrderFilter::mainnet_v3());
+        assert_eq!(validator.validate_at(&tampered, now), Ok(()));
+        assert_eq!(validator.validate_at(&contract, now), Ok(()));
+
+        validator.set_verify_signatures(true);
+        assert_eq!(validator.validate_at(&order, now), Ok(()));
+        assert_eq!(
+            validator.validate_at(&tampered, now),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            validator.validate_at(&contract, now),
+            Err(Rejection::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_validate_for_publish() {
+        let validator = Validator::new(OrderFilter::mainnet_v3());
+        let now = 1000;
+        let publish = |order: Order| validator.validate_for_publish(&order, now);
+        assert_eq!(publish(valid_order()), Ok(()));
+
+        // Still within the default grace.
+        assert_eq!(
+            publish(Order {
+                expiration_time_seconds: 990.into(),
+                ..valid_order()
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            publish(Order {
+                expiration_time_seconds: 900.into(),
+                ..valid_order()
+            }),
+            Err(Rejection::Expired)
+        );
+        assert_eq!(
+            publish(Order {
+                signature: format!("0x{}01", "1b".repeat(65)),
+                ..valid_order()
+            }),
+            Err(Rejection::BadSignature)
+        );
+        assert_eq!(
+            publish(Order {
+                chain_id: 3,
+                ..valid_order()
+            }),
+            Err(Rejection::WrongChain)
+        );
+        assert_eq!(
+            publish(Order {
+                maker_asset_amount: 0.into(),
+                ..valid_order()
+            }),
+            Err(Rejection::Spam)
+        );
+        assert_eq!(
+            publish(Order {
+                exchange_address: OrderFilter::mainnet_v2().exchange_address,
+                ..valid_order()
+            }),
+            Err(Rejection::InconsistentExchange)
+        );
+
+        // The validator's own rules apply.
+        let mut validator = Validator::new(OrderFilter::mainnet_v3());
+        validator
+            .set_allowed_exchanges(vec![OrderFilter::mainnet_v2().exchange_address])
+            .set_expiration_grace(Duration::from_secs(0));
+        assert_eq!(
+            validator.validate_for_publish(&valid_order(), now),
+            Err(Rejection::InconsistentExchange)
+        );
+        let fork = Order {
+            exchange_address: OrderFilter::mainnet_v2().exchange_address,
+            expiration_time_seconds: 990.into(),
+            ..valid_order()
+        };
+        assert_eq!(validator.validate_for_publish(&fork, now), Err(Rejection::Expired));
+
+        // Publishing is not counted.
+        assert_eq!(validator.stats(), ValidationStats::default());
+    }
+}
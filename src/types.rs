@@ -0,0 +1,169 @@
+//! Ethereum value types.
+//!
+//! Addresses and hashes are stored as bytes. They are written as `0x` followed
+//! by lowercase hex digits and parsed case-insensitively, so checksummed
+//! (mixed case) addresses compare equal to their lowercase form.
+//!
+//! Unsigned 256 bit integers are written as decimal strings, as in the 0x
+//! order JSON.
+
+use crate::prelude::*;
+use serde::{de, Deserializer, Serializer};
+use std::{fmt, str::FromStr};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Expected 0x followed by {0} hex digits.")]
+pub struct InvalidHex(usize);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("Expected a decimal integer less than 2^256.")]
+pub struct InvalidDecimal;
+
+macro_rules! hex_type {
+    ($(#[$meta:meta])* $name:ident, $len:literal) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+        pub struct $name(pub [u8; $len]);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "0x{}", hex::encode(self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = InvalidHex;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let digits = s
+                    .strip_prefix("0x")
+                    .or_else(|| s.strip_prefix("0X"))
+                    .ok_or(InvalidHex($len * 2))?;
+                let mut bytes = [0; $len];
+                hex::decode_to_slice(digits, &mut bytes).map_err(|_| InvalidHex($len * 2))?;
+                Ok(Self(bytes))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+hex_type!(
+    /// A 20 byte Ethereum address.
+    EthAddress,
+    20
+);
+
+hex_type!(
+    /// A 32 byte hash, such as a Keccak-256 digest.
+    EthHash,
+    32
+);
+
+#[allow(clippy::all, clippy::pedantic, clippy::nursery)]
+mod u256 {
+    uint::construct_uint! {
+        /// An unsigned 256 bit integer, such as a Solidity `uint256`.
+        pub struct U256(4);
+    }
+}
+
+pub use self::u256::U256;
+
+impl U256 {
+    /// Parse a decimal string. Unlike `from_dec_str`, the empty string and
+    /// leading signs are rejected.
+    pub fn from_decimal(s: &str) -> Result<Self, InvalidDecimal> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(InvalidDecimal);
+        }
+        Self::from_dec_str(s).map_err(|_| InvalidDecimal)
+    }
+
+    /// The value as a `u64`, saturating at `u64::MAX`.
+    pub fn saturating_u64(&self) -> u64 {
+        if self.bits() > 64 {
+            u64::MAX
+        } else {
+            self.low_u64()
+        }
+    }
+}
+
+impl Serialize for U256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for U256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::from_decimal(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_address_case_insensitive() {
+        let checksummed: EthAddress = "0x61935CbDd02287B511119DDb11Aeb42F1593b7Ef".parse().unwrap();
+        let lowercase: EthAddress = "0x61935cbdd02287b511119ddb11aeb42f1593b7ef".parse().unwrap();
+        assert_eq!(checksummed, lowercase);
+        assert_eq!(
+            serde_json::to_string(&checksummed).unwrap(),
+            "\"0x61935cbdd02287b511119ddb11aeb42f1593b7ef\""
+        );
+        assert_eq!(
+            serde_json::from_str::<EthAddress>("\"0x61935CbDd02287B511119DDb11Aeb42F1593b7Ef\"")
+                .unwrap(),
+            lowercase
+        );
+
+        // Hashes do not fit in an address, and the prefix is required.
+        let hash = format!("0x{}", "ab".repeat(32));
+        assert_eq!(hash.parse::<EthAddress>(), Err(InvalidHex(40)));
+        assert_eq!(hash.parse::<EthHash>().unwrap().to_string(), hash);
+        assert!("61935cbdd02287b511119ddb11aeb42f1593b7ef".parse::<EthAddress>().is_err());
+    }
+
+    #[test]
+    fn test_u256_decimal() {
+        let max = "115792089237316195423570985008687907853269984665640564039457584007913129639935";
+        let value: U256 = serde_json::from_str(&format!("\"{}\"", max)).unwrap();
+        assert_eq!(value, U256::MAX);
+        assert_eq!(serde_json::to_string(&value).unwrap(), format!("\"{}\"", max));
+        assert_eq!(value.saturating_u64(), u64::MAX);
+        assert_eq!(U256::from(1_601_501_515_u64).saturating_u64(), 1_601_501_515);
+        assert_eq!(serde_json::to_string(&U256::zero()).unwrap(), "\"0\"");
+
+        // Out of range, non-decimal and numeric JSON values are rejected.
+        let overflow = "115792089237316195423570985008687907853269984665640564039457584007913129639936";
+        assert_eq!(U256::from_decimal(overflow), Err(InvalidDecimal));
+        for invalid in &["", "+1", "1e18", "0x10", " 1"] {
+            assert_eq!(U256::from_decimal(invalid), Err(InvalidDecimal));
+        }
+        assert!(serde_json::from_str::<U256>("1").is_err());
+    }
+}
@@ -1,5 +1,55 @@
+mod framing;
+
+pub use self::framing::{read_length_prefixed, write_length_prefixed, ByteOrder};
 use crate::prelude::*;
-use std::io::{Error, ErrorKind, Result};
+use futures::{
+    channel::oneshot,
+    future::{select, Either, Shared},
+};
+use std::{
+    io::{Error, ErrorKind, Result},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Signals shutdown to all clones of its [`ShutdownToken`] when triggered or
+/// dropped.
+#[derive(Debug)]
+pub struct ShutdownTrigger(oneshot::Sender<()>);
+
+impl ShutdownTrigger {
+    pub fn new() -> (Self, ShutdownToken) {
+        let (sender, receiver) = oneshot::channel();
+        (Self(sender), ShutdownToken(Some(receiver.shared())))
+    }
+
+    pub fn trigger(self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// Future that completes once the matching [`ShutdownTrigger`] is triggered
+/// or dropped.
+#[derive(Clone, Debug)]
+pub struct ShutdownToken(Option<Shared<oneshot::Receiver<()>>>);
+
+impl ShutdownToken {
+    /// A token that never signals shutdown.
+    pub const fn never() -> Self {
+        Self(None)
+    }
+}
+
+impl Future for ShutdownToken {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match &mut self.0 {
+            Some(receiver) => receiver.poll_unpin(cx).map(|_| ()),
+            None => Poll::Pending,
+        }
+    }
+}
 
 /// Read a Serde Serialize from an futures::io::AsyncRead.
 ///
@@ -7,16 +57,26 @@ use std::io::{Error, ErrorKind, Result};
 /// parse. All we can do, it seems, is to repeatedly try parsing and wait for
 /// more content to arrive if it fails.
 ///
-/// TODO: Maximum size
+/// Fails with `ErrorKind::InvalidData` once more than `max_size` bytes are
+/// read without completing a value, and with `ErrorKind::Interrupted` if
+/// `shutdown` fires while waiting for data. Dropping the future cancels the
+/// read.
 ///
 /// TODO: Use `Stream::size_hint()` ?
 ///
 /// TODO: Implement a wrapper for AsyncRead that reads all available content
 /// untill it would block.
 ///
+/// Only used by the `legacy_no_framing` feature, new code should use
+/// [`read_length_prefixed`].
+///
 /// TODO: Remove once Serde gains async support.
 /// See <https://github.com/serde-rs/json/issues/316>
-pub async fn read_json<R, T>(io: &mut R) -> Result<T>
+pub async fn read_json<R, T>(
+    io: &mut R,
+    max_size: usize,
+    mut shutdown: ShutdownToken,
+) -> Result<T>
 where
     R: AsyncRead + Unpin + Send,
     T: for<'a> Deserialize<'a>,
@@ -26,7 +86,16 @@ where
     loop {
         // Read another (partial) block
         let mut block = [0_u8; 30_000]; // Try large blocks to limit decoding attempts
-        let n = match io.read(&mut block).await {
+        let read = match select(io.read(&mut block), &mut shutdown).await {
+            Either::Left((read, _)) => read,
+            Either::Right(((), _)) => {
+                return Err(Error::new(
+                    ErrorKind::Interrupted,
+                    "Shutting down while reading JSON.",
+                ))
+            }
+        };
+        let n = match read {
             Ok(0) => {
                 Err(Error::new(
                     ErrorKind::UnexpectedEof,
@@ -37,6 +106,12 @@ where
         }?;
         buffer.extend(&block[..n]);
         trace!("Read {} more bytes, total {} in buffer", n, buffer.len());
+        if buffer.len() > max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("JSON message exceeds maximum size of {} bytes.", max_size),
+            ));
+        }
 
         // Try to parse
         let result = serde_json::de::from_slice::<T>(&buffer);
@@ -54,3 +129,95 @@ where
         return Ok(result?);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Reader that never produces data and records when it is dropped.
+    struct StalledReader(Arc<AtomicBool>);
+
+    impl futures::io::AsyncRead for StalledReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl Drop for StalledReader {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Reader for a JSON array that never ends, producing one byte per read and
+    /// counting the bytes produced.
+    struct EndlessArray(Arc<AtomicUsize>);
+
+    impl futures::io::AsyncRead for EndlessArray {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            let position = self.0.fetch_add(1, Ordering::SeqCst);
+            buf[0] = match position {
+                0 => b'[',
+                n if n % 2 == 1 => b'1',
+                _ => b',',
+            };
+            Poll::Ready(Ok(1))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_json_max_size() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let mut reader = EndlessArray(read.clone());
+        let error = read_json::<_, serde_json::Value>(&mut reader, 1024, ShutdownToken::never())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(read.load(Ordering::SeqCst), 1025);
+
+        // A value of exactly the maximum size is accepted.
+        let mut value = &b"\"abcd\""[..];
+        let parsed = read_json::<_, String>(&mut value, 6, ShutdownToken::never()).await;
+        assert_eq!(parsed.unwrap(), "abcd");
+    }
+
+    #[tokio::test]
+    async fn test_read_json_shutdown() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut reader = StalledReader(dropped.clone());
+        let (trigger, shutdown) = ShutdownTrigger::new();
+        let task = tokio::spawn(async move {
+            read_json::<_, serde_json::Value>(&mut reader, 1024, shutdown).await
+        });
+        drop(trigger);
+        let error = task.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_read_json_dropped() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut reader = StalledReader(dropped.clone());
+        let (read, abort) = future::abortable(async move {
+            read_json::<_, serde_json::Value>(&mut reader, 1024, ShutdownToken::never()).await
+        });
+        let task = tokio::spawn(read);
+        abort.abort();
+        assert!(task.await.unwrap().is_err());
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
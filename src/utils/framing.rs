@@ -0,0 +1,196 @@
+//! Length prefixed JSON framing.
+//!
+//! Every payload is a `u32` byte length followed by that many bytes of JSON.
+//! Unlike [`read_json`](super::read_json) the reader knows the payload size
+//! upfront, so it reads exactly one frame, parses it once, and rejects frames
+//! over the maximum size before reading their payload.
+//!
+//! The byte order of the length is a parameter. OrderSync v0 uses
+//! little-endian frames, big-endian frames are intended for a future OrderSync
+//! version.
+
+use super::ShutdownToken;
+use crate::prelude::*;
+use futures::future::{select, Either};
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind, Result},
+};
+
+/// Byte order of the `u32` length prefix.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    const fn decode(self, prefix: [u8; 4]) -> u32 {
+        match self {
+            Self::LittleEndian => u32::from_le_bytes(prefix),
+            Self::BigEndian => u32::from_be_bytes(prefix),
+        }
+    }
+
+    const fn encode(self, length: u32) -> [u8; 4] {
+        match self {
+            Self::LittleEndian => length.to_le_bytes(),
+            Self::BigEndian => length.to_be_bytes(),
+        }
+    }
+}
+
+/// Read one frame and parse its JSON payload.
+///
+/// Fails with `ErrorKind::InvalidData` if the frame declares more than
+/// `max_size` bytes, and with `ErrorKind::Interrupted` if `shutdown` fires
+/// while waiting for data.
+pub async fn read_length_prefixed<R, T>(
+    io: &mut R,
+    byte_order: ByteOrder,
+    max_size: usize,
+    shutdown: ShutdownToken,
+) -> Result<T>
+where
+    R: AsyncRead + Unpin + Send,
+    T: for<'a> Deserialize<'a>,
+{
+    let read = async {
+        let mut prefix = [0_u8; 4];
+        io.read_exact(&mut prefix).await?;
+        let length = usize::try_from(byte_order.decode(prefix)).unwrap_or(usize::MAX);
+        if length > max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes exceeds maximum size of {} bytes.",
+                    length, max_size
+                ),
+            ));
+        }
+        let mut payload = vec![0_u8; length];
+        io.read_exact(&mut payload).await?;
+        trace!("Read frame of {} bytes", length);
+        Ok(serde_json::from_slice(&payload)?)
+    };
+    match select(read.boxed(), shutdown).await {
+        Either::Left((result, _)) => result,
+        Either::Right(((), _)) => {
+            Err(Error::new(
+                ErrorKind::Interrupted,
+                "Shutting down while reading frame.",
+            ))
+        }
+    }
+}
+
+/// Write `value` as one frame.
+pub async fn write_length_prefixed<W, T>(io: &mut W, byte_order: ByteOrder, value: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    T: Serialize + Sync,
+{
+    let payload = serde_json::to_vec(value)?;
+    let length = u32::try_from(payload.len()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "Message does not fit in a length prefixed frame.",
+        )
+    })?;
+    io.write_all(&byte_order.encode(length)).await?;
+    io.write_all(&payload).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn test_length_prefixed_roundtrip() {
+        let value = serde_json::json!({ "orders": [1, 2, 3] });
+        let mut buffer = Cursor::new(Vec::new());
+        write_length_prefixed(&mut buffer, ByteOrder::LittleEndian, &value)
+            .await
+            .unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(&bytes[..4], &(bytes.len() as u32 - 4).to_le_bytes());
+
+        // Exactly one frame is consumed.
+        let mut stream = bytes.clone();
+        stream.extend_from_slice(b"trailing");
+        let mut reader = Cursor::new(stream);
+        let max_size = bytes.len() - 4;
+        let read: serde_json::Value = read_length_prefixed(
+            &mut reader,
+            ByteOrder::LittleEndian,
+            max_size,
+            ShutdownToken::never(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read, value);
+        assert_eq!(reader.position(), bytes.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_big_endian() {
+        let value = serde_json::json!({ "orders": [1, 2, 3] });
+        let mut buffer = Cursor::new(Vec::new());
+        write_length_prefixed(&mut buffer, ByteOrder::BigEndian, &value)
+            .await
+            .unwrap();
+        let bytes = buffer.into_inner();
+        assert_eq!(&bytes[..4], &(bytes.len() as u32 - 4).to_be_bytes());
+
+        let read: serde_json::Value = read_length_prefixed(
+            &mut Cursor::new(bytes.clone()),
+            ByteOrder::BigEndian,
+            1024,
+            ShutdownToken::never(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read, value);
+
+        // Read as little-endian, the length is far over the maximum.
+        let error = read_length_prefixed::<_, serde_json::Value>(
+            &mut Cursor::new(bytes),
+            ByteOrder::LittleEndian,
+            1024,
+            ShutdownToken::never(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_limits() {
+        // The declared length is checked before reading the payload.
+        let mut reader = Cursor::new([&1025_u32.to_le_bytes()[..], &[b' '; 1025]].concat());
+        let error = read_length_prefixed::<_, serde_json::Value>(
+            &mut reader,
+            ByteOrder::LittleEndian,
+            1024,
+            ShutdownToken::never(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(reader.position(), 4);
+
+        // A frame shorter than its declared length is an unexpected EOF.
+        let mut truncated = Cursor::new([&6_u32.to_le_bytes()[..], &b"\"abc"[..]].concat());
+        let error = read_length_prefixed::<_, String>(
+            &mut truncated,
+            ByteOrder::LittleEndian,
+            1024,
+            ShutdownToken::never(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}